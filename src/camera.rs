@@ -1,4 +1,4 @@
-use cgmath::Deg;
+use cgmath::{Deg, Rad};
 
 
 pub struct Camera {
@@ -14,4 +14,55 @@ impl Camera {
             fov: Deg(45.0) // 90 degrees
         }
     }
-}
\ No newline at end of file
+}
+
+/// Exposure settings of a real camera. Lights and the sky are in physical units, so a camera set
+/// up the way a photographer would for the scene exposes it correctly.
+#[derive(Debug, Clone)]
+pub struct PhysicalCamera {
+    /// Aperture as an f-number, e.g. 2.8 for f/2.8.
+    pub aperture: f32,
+    /// Shutter time in seconds.
+    pub shutter_time: f32,
+    /// Sensor sensitivity, as ISO arithmetic speed.
+    pub iso: f32,
+    /// Exposure compensation in stops. Positive brightens the image.
+    pub exposure_compensation: f32,
+    /// Distance to the plane in focus, in meters. Only used by depth of field.
+    pub focus_distance: f32,
+    /// Height of the sensor in meters. Together with the field of view, this sets the focal
+    /// length, and with it how shallow the depth of field is.
+    pub sensor_height: f32,
+}
+
+impl PhysicalCamera {
+    /// f/16, 1/100 s and ISO 100, the "sunny 16" rule for daylight, on a full frame sensor.
+    pub fn new() -> Self {
+        Self {
+            aperture: 16.0,
+            shutter_time: 1.0 / 100.0,
+            iso: 100.0,
+            exposure_compensation: 0.0,
+            focus_distance: 10.0,
+            sensor_height: 0.024,
+        }
+    }
+
+    /// Exposure value of the settings, normalized to ISO 100. Doesn't include the compensation.
+    pub fn ev100(&self) -> f32 {
+        let n = self.aperture.max(0.5);
+        let t = self.shutter_time.max(1e-6);
+        let s = self.iso.max(1.0);
+        (n * n / t * 100.0 / s).log2()
+    }
+
+    /// Focal length in meters that gives the vertical field of view `fovy` on this sensor.
+    pub fn focal_length(&self, fovy: Deg<f32>) -> f32 {
+        0.5 * self.sensor_height / (Rad::from(fovy).0 * 0.5).tan()
+    }
+
+    /// Exposure multiplier for the settings, including the compensation.
+    pub fn exposure(&self) -> f32 {
+        crate::compute::exposure_from_ev100(self.ev100() - self.exposure_compensation)
+    }
+}
@@ -0,0 +1,356 @@
+//! Color grading.
+//!
+//! The tonemapping pass grades in two places. White balance, contrast and saturation from
+//! `TonemappingInfo` are applied to the exposed HDR color, before the tonemapping operator. A 3D
+//! lookup table is applied after it, to the sRGB encoded display color, which is what DaVinci
+//! Resolve and other grading tools see when grading a capture of the game. The LUT's output is
+//! also sRGB encoded, so a LUT exported from a grade on top of the game's own output reproduces
+//! that grade.
+//!
+//! `CubeLut` parses Adobe and Resolve `.cube` files. Only 3D LUTs are supported; 1D LUTs and
+//! files combining a 1D shaper with a 3D LUT are rejected.
+
+use std::{error, fmt, fs, io};
+use std::path::Path;
+use std::sync::Arc;
+use cgmath::{Matrix, Matrix3, SquareMatrix, Vector3};
+use half::f16;
+use vulkano::device::Queue;
+use vulkano::format::R16G16B16A16Sfloat;
+use vulkano::image::{Dimensions, ImmutableImage};
+use vulkano::sync::GpuFuture;
+
+
+/// Largest LUT size accepted. Resolve exports up to 65, the format allows 256.
+pub const MAX_LUT_SIZE: u32 = 256;
+
+/// Color temperature that white balance treats as neutral, in kelvin.
+pub const NEUTRAL_TEMPERATURE: f32 = 6500.0;
+
+
+#[derive(Debug)]
+pub enum CubeLutError {
+    Io(io::Error),
+    /// There's no `LUT_3D_SIZE` line, or it's out of range.
+    InvalidSize,
+    /// A line that isn't a keyword, a comment or three numbers.
+    InvalidLine(String),
+    /// A valid file using a feature the loader doesn't support.
+    Unsupported(String),
+    /// The number of table entries doesn't match the size.
+    WrongEntryCount { expected: usize, found: usize },
+    /// A domain maximum isn't above its minimum.
+    InvalidDomain,
+}
+
+impl error::Error for CubeLutError {}
+
+impl fmt::Display for CubeLutError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            CubeLutError::Io(e) => write!(fmt, "failed to read cube LUT: {}", e),
+            CubeLutError::InvalidSize => write!(fmt, "cube LUT has no valid LUT_3D_SIZE"),
+            CubeLutError::InvalidLine(line) => write!(fmt, "invalid line in cube LUT: {}", line),
+            CubeLutError::Unsupported(s) => write!(fmt, "unsupported cube LUT: {}", s),
+            CubeLutError::WrongEntryCount { expected, found } => write!(fmt, "cube LUT has {} entries, expected {}", found, expected),
+            CubeLutError::InvalidDomain => write!(fmt, "cube LUT has an empty domain"),
+        }
+    }
+}
+
+impl From<io::Error> for CubeLutError {
+    fn from(e: io::Error) -> Self { CubeLutError::Io(e) }
+}
+
+
+/// A parsed 3D lookup table.
+#[derive(Debug, Clone)]
+pub struct CubeLut {
+    pub title: Option<String>,
+    /// Entries along each axis.
+    pub size: u32,
+    /// Input values mapping to the first and last entry on each axis.
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+    /// Output colors, with red changing fastest, then green, then blue.
+    pub table: Vec<[f32; 3]>,
+}
+
+impl CubeLut {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CubeLutError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, CubeLutError> {
+        let mut title = None;
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = Vec::new();
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || CubeLutError::InvalidLine(line.to_string());
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap();
+            let mut numbers = |count: usize| -> Result<Vec<f32>, CubeLutError> {
+                let values = words.by_ref().map(|s| s.parse::<f32>()).collect::<Result<Vec<f32>, _>>().map_err(|_| invalid())?;
+                if values.len() == count { Ok(values) } else { Err(invalid()) }
+            };
+
+            match keyword {
+                "TITLE" => {
+                    let quoted = line["TITLE".len()..].trim();
+                    if quoted.len() < 2 || !quoted.starts_with('"') || !quoted.ends_with('"') {
+                        return Err(invalid());
+                    }
+                    title = Some(quoted[1..quoted.len() - 1].to_string());
+                },
+                "LUT_3D_SIZE" => {
+                    let n = numbers(1)?[0];
+                    if n.fract() != 0.0 || n < 2.0 || n > MAX_LUT_SIZE as f32 {
+                        return Err(CubeLutError::InvalidSize);
+                    }
+                    size = Some(n as u32);
+                },
+                "DOMAIN_MIN" => {
+                    let v = numbers(3)?;
+                    domain_min = [v[0], v[1], v[2]];
+                },
+                "DOMAIN_MAX" => {
+                    let v = numbers(3)?;
+                    domain_max = [v[0], v[1], v[2]];
+                },
+                // Resolve's variant of DOMAIN_MIN and DOMAIN_MAX
+                "LUT_3D_INPUT_RANGE" => {
+                    let v = numbers(2)?;
+                    domain_min = [v[0]; 3];
+                    domain_max = [v[1]; 3];
+                },
+                "LUT_1D_SIZE" | "LUT_1D_INPUT_RANGE" => return Err(CubeLutError::Unsupported("1D LUT".to_string())),
+                _ if keyword.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.') => {
+                    let values: Vec<f32> = line.split_whitespace().map(|s| s.parse::<f32>())
+                        .collect::<Result<_, _>>().map_err(|_| invalid())?;
+                    if values.len() != 3 {
+                        return Err(invalid());
+                    }
+                    table.push([values[0], values[1], values[2]]);
+                },
+                _ => return Err(invalid()),
+            }
+        }
+
+        let size = size.ok_or(CubeLutError::InvalidSize)?;
+        let expected = (size * size * size) as usize;
+        if table.len() != expected {
+            return Err(CubeLutError::WrongEntryCount { expected, found: table.len() });
+        }
+        if (0..3).any(|i| !(domain_max[i] > domain_min[i])) {
+            return Err(CubeLutError::InvalidDomain);
+        }
+
+        Ok(Self { title, size, domain_min, domain_max, table })
+    }
+
+    /// A LUT that leaves colors unchanged.
+    pub fn identity(size: u32) -> Self {
+        let size = size.max(2).min(MAX_LUT_SIZE);
+        let step = 1.0 / (size - 1) as f32;
+        let mut table = Vec::with_capacity((size * size * size) as usize);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    table.push([r as f32 * step, g as f32 * step, b as f32 * step]);
+                }
+            }
+        }
+        Self { title: None, size, domain_min: [0.0; 3], domain_max: [1.0; 3], table }
+    }
+
+    /// Uploads the table as a 3D texture, with red along x, green along y and blue along z.
+    /// Blocks until the upload is finished.
+    pub fn upload(&self, queue: Arc<Queue>) -> ColorGradingLut {
+        let texels: Vec<[f16; 4]> = self.table.iter()
+            .map(|c| [f16::from_f32(c[0]), f16::from_f32(c[1]), f16::from_f32(c[2]), f16::from_f32(1.0)])
+            .collect();
+        let (texture, future) = ImmutableImage::from_iter(texels.into_iter(),
+                                                          Dimensions::Dim3d { width: self.size, height: self.size, depth: self.size },
+                                                          R16G16B16A16Sfloat, queue).unwrap();
+        future.then_signal_fence_and_flush().unwrap().wait(None).unwrap();
+        ColorGradingLut {
+            texture,
+            size: self.size,
+            domain_min: self.domain_min,
+            domain_max: self.domain_max,
+        }
+    }
+}
+
+
+/// A LUT on the GPU, set with `PhosphorRenderer::set_color_grading_lut`.
+#[derive(Clone)]
+pub struct ColorGradingLut {
+    pub texture: Arc<ImmutableImage<R16G16B16A16Sfloat>>,
+    pub size: u32,
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+}
+
+
+/// Linear sRGB to CIE XYZ, for a D65 white.
+fn srgb_to_xyz() -> Matrix3<f32> {
+    Matrix3::new(0.4124564, 0.3575761, 0.1804375,
+                 0.2126729, 0.7151522, 0.0721750,
+                 0.0193339, 0.1191920, 0.9503041).transpose()
+}
+
+/// CIE XYZ to the Bradford cone response space used for chromatic adaptation.
+fn xyz_to_bradford() -> Matrix3<f32> {
+    Matrix3::new( 0.8951,  0.2664, -0.1614,
+                 -0.7502,  1.7135,  0.0367,
+                  0.0389, -0.0685,  1.0296).transpose()
+}
+
+/// CIE xy chromaticity of a white with the given color temperature, shifted along the green to
+/// magenta axis by `tint`. Uses the Kang et al. approximation of the Planckian locus, which is
+/// valid from 1667 K to 25000 K.
+fn white_chromaticity(temperature: f32, tint: f32) -> [f32; 2] {
+    let t = temperature.max(1667.0).min(25000.0);
+    let (t2, t3) = (t * t, t * t * t);
+    let x = if t < 4000.0 {
+        -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910
+    }
+    else {
+        -3.0258469e9 / t3 + 2.1070379e6 / t2 + 0.2226347e3 / t + 0.240390
+    };
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t < 2222.0 {
+        -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+    }
+    else if t < 4000.0 {
+        -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+    }
+    else {
+        3.0817580 * x3 - 5.87338670 * x2 + 3.75112997 * x - 0.37001483
+    };
+
+    // tint moves the white along v in the CIE 1960 UCS, where green is towards +v
+    let d = -2.0 * x + 12.0 * y + 3.0;
+    let (u, v) = (4.0 * x / d, 6.0 * y / d + tint * 0.0002);
+    let d = 2.0 * u - 8.0 * v + 4.0;
+    [3.0 * u / d, 2.0 * v / d]
+}
+
+/// Linear sRGB matrix that adapts colors lit by a white of `temperature` kelvin and `tint` to
+/// look neutral at `NEUTRAL_TEMPERATURE`, like a camera's white balance setting. Positive tint
+/// compensates for green light, making the image more magenta.
+pub fn white_balance_matrix(temperature: f32, tint: f32) -> Matrix3<f32> {
+    let cone_response = |xy: [f32; 2]| xyz_to_bradford() * Vector3::new(xy[0] / xy[1], 1.0, (1.0 - xy[0] - xy[1]) / xy[1]);
+    let source = cone_response(white_chromaticity(temperature, tint));
+    let target = cone_response(white_chromaticity(NEUTRAL_TEMPERATURE, 0.0));
+    let scale = Matrix3::from_diagonal(Vector3::new(target.x / source.x, target.y / source.y, target.z / source.z));
+    let to_cone = xyz_to_bradford() * srgb_to_xyz();
+    to_cone.invert().unwrap() * scale * to_cone
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{CubeLut, CubeLutError};
+
+    const IDENTITY: &str = include_str!("../tests/fixtures/cube/identity.cube");
+
+    /// A size 2 LUT with the given header lines and entries.
+    fn cube(header: &str, entries: usize) -> String {
+        let mut text = format!("LUT_3D_SIZE 2\n{}\n", header);
+        for _ in 0..entries {
+            text.push_str("0.5 0.5 0.5\n");
+        }
+        text
+    }
+
+    #[test]
+    fn parse_identity() {
+        let lut = CubeLut::parse(IDENTITY).unwrap();
+        assert_eq!(lut.title, Some("Identity".to_string()));
+        assert_eq!(lut.size, 2);
+        assert_eq!(lut.domain_min, [0.0; 3]);
+        assert_eq!(lut.domain_max, [1.0; 3]);
+        assert_eq!(lut.table, CubeLut::identity(2).table);
+    }
+
+    #[test]
+    fn parse_input_range() {
+        let lut = CubeLut::parse(&cube("LUT_3D_INPUT_RANGE -0.5 2.0", 8)).unwrap();
+        assert_eq!(lut.title, None);
+        assert_eq!(lut.domain_min, [-0.5; 3]);
+        assert_eq!(lut.domain_max, [2.0; 3]);
+    }
+
+    #[test]
+    fn malformed_title() {
+        for title in &["TITLE", "TITLE Identity", "TITLE \"Identity", "TITLE \""] {
+            match CubeLut::parse(&cube(title, 8)) {
+                Err(CubeLutError::InvalidLine(line)) => assert_eq!(line, *title),
+                other => panic!("expected InvalidLine for {:?}, got {:?}", title, other),
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_domain() {
+        for header in &["DOMAIN_MIN 0.0 0.0", "DOMAIN_MAX 1.0 1.0 1.0 1.0", "DOMAIN_MIN 0.0 zero 0.0",
+                        "LUT_3D_INPUT_RANGE 1.0", "LUT_3D_INPUT_RANGE 0.0 1.0 2.0"] {
+            match CubeLut::parse(&cube(header, 8)) {
+                Err(CubeLutError::InvalidLine(_)) => {},
+                other => panic!("expected InvalidLine for {:?}, got {:?}", header, other),
+            }
+        }
+        for header in &["DOMAIN_MIN 0.0 1.0 0.0", "DOMAIN_MAX 1.0 1.0 -1.0", "LUT_3D_INPUT_RANGE 1.0 1.0"] {
+            match CubeLut::parse(&cube(header, 8)) {
+                Err(CubeLutError::InvalidDomain) => {},
+                other => panic!("expected InvalidDomain for {:?}, got {:?}", header, other),
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_entry_count() {
+        match CubeLut::parse(&cube("", 7)) {
+            Err(CubeLutError::WrongEntryCount { expected: 8, found: 7 }) => {},
+            other => panic!("expected WrongEntryCount, got {:?}", other),
+        }
+        match CubeLut::parse(&cube("", 9)) {
+            Err(CubeLutError::WrongEntryCount { expected: 8, found: 9 }) => {},
+            other => panic!("expected WrongEntryCount, got {:?}", other),
+        }
+        match CubeLut::parse(&cube("0.5 0.5", 8)) {
+            Err(CubeLutError::InvalidLine(line)) => assert_eq!(line, "0.5 0.5"),
+            other => panic!("expected InvalidLine, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_size() {
+        for text in &["", "LUT_3D_SIZE 1", "LUT_3D_SIZE 2.5", "LUT_3D_SIZE 257"] {
+            match CubeLut::parse(text) {
+                Err(CubeLutError::InvalidSize) => {},
+                other => panic!("expected InvalidSize for {:?}, got {:?}", text, other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_1d() {
+        for header in &["LUT_1D_SIZE 1024", "LUT_1D_INPUT_RANGE 0.0 1.0"] {
+            match CubeLut::parse(&cube(header, 8)) {
+                Err(CubeLutError::Unsupported(_)) => {},
+                other => panic!("expected Unsupported for {:?}, got {:?}", header, other),
+            }
+        }
+    }
+}
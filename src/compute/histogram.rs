@@ -0,0 +1,214 @@
+//! Auto exposure.
+//!
+//! A compute pass bins the luminance of `scene_color` into a log luminance histogram, weighted by
+//! the metering mode, and a second pass averages the histogram between two percentiles into a
+//! target exposure and adapts towards it. The result stays in `RenderInfo::exposure` on the GPU,
+//! where the bloom and tonemapping passes read it, so nothing waits on the CPU.
+
+use std::sync::Arc;
+use vulkano::buffer::BufferUsage;
+use vulkano::pipeline::{ComputePipeline, ComputePipelineAbstract};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::device::{Device, Queue};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::renderer::RenderInfo;
+
+
+/// Must match `HISTOGRAM_BINS` in `histogram.comp` and `exposure.comp`.
+pub const HISTOGRAM_BINS: usize = 128;
+
+/// Must match the workgroup size in `histogram.comp`.
+const WORKGROUP_SIZE: u32 = 16;
+
+
+/// Which pixels auto exposure looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeteringMode {
+    /// Every pixel counts the same.
+    Average,
+    /// Pixels count less the further they are from the center of the screen.
+    CenterWeighted,
+    /// Only pixels within `AutoExposureSettings::spot_radius` of the center count.
+    Spot,
+}
+
+impl MeteringMode {
+    fn shader_id(&self) -> u32 {
+        match self {
+            MeteringMode::Average => 0,
+            MeteringMode::CenterWeighted => 1,
+            MeteringMode::Spot => 2,
+        }
+    }
+}
+
+/// How auto exposure combines with the manual exposure, which is
+/// `TonemappingInfo::manual_exposure`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutoExposureMode {
+    /// The manual exposure is used as is.
+    Off,
+    /// The metered exposure replaces the manual one, within `TonemappingInfo`'s limits.
+    Override,
+    /// The metered exposure may move at most this many stops away from the manual one, so a
+    /// physical camera sets the overall look and metering only corrects it.
+    Bias { stops: f32 },
+}
+
+/// Settings for auto exposure. The adaptation speed, exposure limits and compensation are in
+/// `TonemappingInfo`.
+#[derive(Debug, Clone)]
+pub struct AutoExposureSettings {
+    pub mode: AutoExposureMode,
+    pub metering: MeteringMode,
+    /// Radius of the spot for `MeteringMode::Spot`, relative to the screen height.
+    pub spot_radius: f32,
+    /// Fraction of the darkest metered pixels to ignore.
+    pub low_percentile: f32,
+    /// Fraction of the metered pixels below which to average; brighter ones are ignored.
+    pub high_percentile: f32,
+    /// Darkest luminance the histogram covers, in log2 cd/m^2.
+    pub min_log_luminance: f32,
+    /// Brightest luminance the histogram covers, in log2 cd/m^2.
+    pub max_log_luminance: f32,
+}
+impl Default for AutoExposureSettings {
+    fn default() -> Self {
+        Self {
+            mode: AutoExposureMode::Override,
+            metering: MeteringMode::CenterWeighted,
+            spot_radius: 0.1,
+            low_percentile: 0.6,
+            high_percentile: 0.9,
+            min_log_luminance: -10.0,
+            max_log_luminance: 18.0,
+        }
+    }
+}
+
+
+/// Must match `ExposureData` in `exposure.inc`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GpuExposure {
+    /// Multiplier taking absolute luminance to display units.
+    pub value: f32,
+    /// Metered scene luminance in cd/m^2.
+    pub average_luminance: f32,
+    /// Exposure the adaptation is moving towards.
+    pub target: f32,
+    pub ev100: f32,
+}
+
+/// Exposure for a camera at `ev100`: the multiplier that maps the luminance saturating the
+/// sensor to 1.
+pub fn exposure_from_ev100(ev100: f32) -> f32 {
+    1.0 / (1.2 * 2f32.powf(ev100))
+}
+
+/// Creates the exposure state buffer. Starts at zero, which makes the first frame skip
+/// adaptation.
+pub fn exposure_buffer(device: Arc<Device>) -> Arc<CpuAccessibleBufferXalloc<GpuExposure>> {
+    let usage = BufferUsage {
+        storage_buffer: true,
+        ..BufferUsage::none()
+    };
+    CpuAccessibleBufferXalloc::from_data(device, usage, GpuExposure::default()).expect("failed to create buffer")
+}
+
+
+pub struct HistogramCompute {
+    histogram_pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    exposure_pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    bins_buffer: Arc<CpuAccessibleBufferXalloc<[u32]>>,
+    sampler: Arc<Sampler>,
+}
+
+impl HistogramCompute {
+    pub fn new(device: Arc<Device>) -> Self {
+        let histogram_pipeline = Arc::new({
+            let shader = crate::shader::histogram::Shader::load(device.clone()).unwrap();
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
+        });
+        let exposure_pipeline = Arc::new({
+            let shader = crate::shader::exposure::Shader::load(device.clone()).unwrap();
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
+        });
+
+        let storage_buf_usage = BufferUsage {
+            storage_buffer: true,
+            ..BufferUsage::none()
+        };
+        // cleared by the exposure pass after every use
+        let bins_buffer = CpuAccessibleBufferXalloc::from_iter(device.clone(), storage_buf_usage, [0u32; HISTOGRAM_BINS].iter().cloned())
+            .expect("failed to create buffer");
+
+        Self {
+            histogram_pipeline,
+            exposure_pipeline,
+            bins_buffer,
+            sampler: Sampler::new(device.clone(), Filter::Nearest, Filter::Nearest, MipmapMode::Nearest,
+                                  SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                  0.0, 1.0, 0.0, 0.0).unwrap(),
+        }
+    }
+
+    /// Meters this frame's luminance and updates `info.exposure`. Must run after everything that
+    /// writes `scene_color` before exposure is applied, including the sky and fog, and before the
+    /// passes that read the exposure.
+    pub fn build_command_buffer(&mut self, info: &RenderInfo) -> Option<(AutoCommandBuffer, Arc<Queue>)> {
+        let settings = &info.settings.auto_exposure;
+        let tonemapping = &info.tonemapping_info;
+        let manual_exposure = tonemapping.manual_exposure();
+        let enabled = settings.mode != AutoExposureMode::Off;
+        let (min_exposure, max_exposure) = match settings.mode {
+            AutoExposureMode::Bias { stops } => {
+                let range = 2f32.powf(stops.max(0.0));
+                (manual_exposure / range, manual_exposure * range)
+            },
+            _ => (tonemapping.min_exposure, tonemapping.max_exposure.max(tonemapping.min_exposure)),
+        };
+        let min_log_luminance = settings.min_log_luminance;
+        let log_luminance_range = (settings.max_log_luminance - settings.min_log_luminance).max(1.0);
+
+        let mut cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family()).unwrap();
+
+        if enabled {
+            let histogram_set = Arc::new(PersistentDescriptorSet::start(self.histogram_pipeline.clone(), 0)
+                .add_sampled_image(info.attachments.scene_color.clone(), self.sampler.clone()).unwrap()
+                .add_buffer(self.bins_buffer.clone()).unwrap()
+                .build().unwrap());
+            let workgroups = [(info.dimensions[0] + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE,
+                              (info.dimensions[1] + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, 1];
+            cb = cb.dispatch(workgroups, self.histogram_pipeline.clone(), histogram_set, crate::shader::histogram::ty::Constants {
+                min_log_luminance,
+                log_luminance_range,
+                metering: settings.metering.shader_id(),
+                spot_radius: settings.spot_radius,
+            }).unwrap();
+        }
+
+        let exposure_set = Arc::new(PersistentDescriptorSet::start(self.exposure_pipeline.clone(), 0)
+            .add_buffer(self.bins_buffer.clone()).unwrap()
+            .add_buffer(info.exposure.clone()).unwrap()
+            .build().unwrap());
+        cb = cb.dispatch([1, 1, 1], self.exposure_pipeline.clone(), exposure_set, crate::shader::exposure::ty::Constants {
+            min_log_luminance,
+            log_luminance_range,
+            low_percentile: settings.low_percentile.max(0.0).min(1.0),
+            high_percentile: settings.high_percentile.max(0.0).min(1.0),
+            compensation: tonemapping.compensation(),
+            min_exposure,
+            max_exposure,
+            adjust_speed: tonemapping.adjust_speed,
+            delta_time: info.delta_time,
+            manual_exposure,
+            enabled: if enabled { 1 } else { 0 },
+        }).unwrap();
+
+        Some((cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()))
+    }
+}
@@ -0,0 +1,188 @@
+//! Image based lighting generation.
+//!
+//! Each map is computed into a storage image and then copied into its place in an immutable,
+//! sampled image, since storage images can't have mips. Everything runs in one command buffer.
+//! `generate` blocks until it's finished, so environments should be set while loading rather than
+//! every frame; `record` only builds the command buffer, for callers that regenerate at runtime.
+
+use std::sync::Arc;
+use vulkano::pipeline::{ComputePipeline, ComputePipelineAbstract};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::device::{Device, Queue};
+use vulkano::format::{R16G16B16A16Sfloat, R16G16Sfloat};
+use vulkano::image::{Dimensions, ImageLayout, ImageUsage, ImmutableImage, MipmapsCount, StorageImage};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use vulkano::sync::GpuFuture;
+
+use crate::environment::{Environment, IRRADIANCE_SIZE, RADIANCE_SIZE, RADIANCE_MIP_COUNT, BRDF_LUT_SIZE};
+
+
+/// Must match the workgroup size in the IBL compute shaders.
+const WORKGROUP_SIZE: u32 = 8;
+
+lazy_static! {
+    static ref STORAGE_USAGE: ImageUsage = ImageUsage {
+        storage: true,
+        transfer_source: true,
+        ..ImageUsage::none()
+    };
+    static ref OUTPUT_USAGE: ImageUsage = ImageUsage {
+        sampled: true,
+        transfer_destination: true,
+        ..ImageUsage::none()
+    };
+}
+
+
+/// Settings for environment generation.
+#[derive(Debug, Clone)]
+pub struct IblSettings {
+    /// GGX samples per texel when prefiltering the rough radiance mips.
+    pub prefilter_samples: u32,
+}
+impl Default for IblSettings {
+    fn default() -> Self {
+        Self {
+            prefilter_samples: 512,
+        }
+    }
+}
+
+
+fn workgroups(size: [u32; 2]) -> [u32; 3] {
+    [(size[0] + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, (size[1] + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, 1]
+}
+
+fn mip_size(size: [u32; 2], mip: u32) -> [u32; 2] {
+    [(size[0] >> mip).max(1), (size[1] >> mip).max(1)]
+}
+
+
+pub struct IblCompute {
+    irradiance_pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    prefilter_pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    brdf_pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    /// Wraps around horizontally, clamps at the poles.
+    equirect_sampler: Arc<Sampler>,
+}
+
+impl IblCompute {
+    pub fn new(device: Arc<Device>) -> Self {
+        let irradiance_pipeline = Arc::new({
+            let shader = crate::shader::ibl::irradiance::Shader::load(device.clone()).unwrap();
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
+        });
+        let prefilter_pipeline = Arc::new({
+            let shader = crate::shader::ibl::prefilter::Shader::load(device.clone()).unwrap();
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
+        });
+        let brdf_pipeline = Arc::new({
+            let shader = crate::shader::ibl::brdf::Shader::load(device.clone()).unwrap();
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
+        });
+
+        Self {
+            irradiance_pipeline,
+            prefilter_pipeline,
+            brdf_pipeline,
+            equirect_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                           SamplerAddressMode::Repeat, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                           0.0, 1.0, 0.0, 0.0).unwrap(),
+        }
+    }
+
+    /// Integrates the split-sum BRDF lookup table. Blocks until it's finished.
+    pub fn brdf_lut(&self, queue: Arc<Queue>) -> Arc<ImmutableImage<R16G16Sfloat>> {
+        let device = queue.device().clone();
+        let size = [BRDF_LUT_SIZE, BRDF_LUT_SIZE];
+        let dimensions = Dimensions::Dim2d { width: size[0], height: size[1] };
+
+        let storage = StorageImage::with_usage(device.clone(), dimensions, R16G16Sfloat, *STORAGE_USAGE, Some(queue.family())).unwrap();
+        let (lut, lut_init) = ImmutableImage::uninitialized(device.clone(), dimensions, R16G16Sfloat, MipmapsCount::One,
+                                                            *OUTPUT_USAGE, ImageLayout::ShaderReadOnlyOptimal, Some(queue.family())).unwrap();
+
+        let set = Arc::new(PersistentDescriptorSet::start(self.brdf_pipeline.clone(), 0)
+            .add_image(storage.clone()).unwrap()
+            .build().unwrap());
+
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family()).unwrap()
+            .dispatch(workgroups(size), self.brdf_pipeline.clone(), set, ()).unwrap()
+            .copy_image(storage, [0, 0, 0], 0, 0, lut_init, [0, 0, 0], 0, 0, [size[0], size[1], 1], 1).unwrap()
+            .build().unwrap();
+
+        vulkano::sync::now(device).then_execute(queue, cb).unwrap()
+            .then_signal_fence_and_flush().unwrap()
+            .wait(None).unwrap();
+        lut
+    }
+
+    /// Generates the irradiance and radiance maps for an equirectangular HDR image. Blocks until
+    /// they're finished.
+    pub fn generate(&self, source: Arc<ImmutableImage<R16G16B16A16Sfloat>>, brdf_lut: Arc<ImmutableImage<R16G16Sfloat>>,
+                    settings: &IblSettings, queue: Arc<Queue>) -> Environment {
+        let (environment, cb) = self.record(source, brdf_lut, settings, queue.clone());
+        vulkano::sync::now(queue.device().clone()).then_execute(queue, cb).unwrap()
+            .then_signal_fence_and_flush().unwrap()
+            .wait(None).unwrap();
+        environment
+    }
+
+    /// Builds the command buffer generating the irradiance and radiance maps for an
+    /// equirectangular HDR image. The returned maps can't be used until it has finished executing.
+    pub fn record(&self, source: Arc<ImmutableImage<R16G16B16A16Sfloat>>, brdf_lut: Arc<ImmutableImage<R16G16Sfloat>>,
+                  settings: &IblSettings, queue: Arc<Queue>) -> (Environment, AutoCommandBuffer) {
+        let device = queue.device().clone();
+
+        let irradiance_dimensions = Dimensions::Dim2d { width: IRRADIANCE_SIZE[0], height: IRRADIANCE_SIZE[1] };
+        let irradiance_storage = StorageImage::with_usage(device.clone(), irradiance_dimensions, R16G16B16A16Sfloat,
+                                                          *STORAGE_USAGE, Some(queue.family())).unwrap();
+        let (irradiance, irradiance_init) = ImmutableImage::uninitialized(device.clone(), irradiance_dimensions, R16G16B16A16Sfloat,
+                                                                          MipmapsCount::One, *OUTPUT_USAGE,
+                                                                          ImageLayout::ShaderReadOnlyOptimal, Some(queue.family())).unwrap();
+
+        let (radiance, radiance_init) = ImmutableImage::uninitialized(device.clone(),
+                                                                      Dimensions::Dim2d { width: RADIANCE_SIZE[0], height: RADIANCE_SIZE[1] },
+                                                                      R16G16B16A16Sfloat, MipmapsCount::Specific(RADIANCE_MIP_COUNT),
+                                                                      *OUTPUT_USAGE, ImageLayout::ShaderReadOnlyOptimal,
+                                                                      Some(queue.family())).unwrap();
+        // copied into once per mip
+        let radiance_init = Arc::new(radiance_init);
+
+        let irradiance_set = Arc::new(PersistentDescriptorSet::start(self.irradiance_pipeline.clone(), 0)
+            .add_sampled_image(source.clone(), self.equirect_sampler.clone()).unwrap()
+            .add_image(irradiance_storage.clone()).unwrap()
+            .build().unwrap());
+
+        let mut cb = AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family()).unwrap()
+            .dispatch(workgroups(IRRADIANCE_SIZE), self.irradiance_pipeline.clone(), irradiance_set, ()).unwrap()
+            .copy_image(irradiance_storage, [0, 0, 0], 0, 0, irradiance_init, [0, 0, 0], 0, 0,
+                        [IRRADIANCE_SIZE[0], IRRADIANCE_SIZE[1], 1], 1).unwrap();
+
+        for mip in 0..RADIANCE_MIP_COUNT {
+            let size = mip_size(RADIANCE_SIZE, mip);
+            let storage = StorageImage::with_usage(device.clone(), Dimensions::Dim2d { width: size[0], height: size[1] },
+                                                   R16G16B16A16Sfloat, *STORAGE_USAGE, Some(queue.family())).unwrap();
+            let set = Arc::new(PersistentDescriptorSet::start(self.prefilter_pipeline.clone(), 0)
+                .add_sampled_image(source.clone(), self.equirect_sampler.clone()).unwrap()
+                .add_image(storage.clone()).unwrap()
+                .build().unwrap());
+
+            cb = cb.dispatch(workgroups(size), self.prefilter_pipeline.clone(), set,
+                             crate::shader::ibl::prefilter::ty::Constants {
+                                 roughness: mip as f32 / (RADIANCE_MIP_COUNT - 1) as f32,
+                                 sample_count: settings.prefilter_samples.max(1),
+                             }).unwrap()
+                .copy_image(storage, [0, 0, 0], 0, 0, radiance_init.clone(), [0, 0, 0], 0, mip,
+                            [size[0], size[1], 1], 1).unwrap();
+        }
+
+        let environment = Environment {
+            source,
+            irradiance,
+            radiance,
+            brdf_lut,
+        };
+        (environment, cb.build().unwrap())
+    }
+}
@@ -0,0 +1,171 @@
+//! Clustered light culling.
+//!
+//! The view frustum is split into screen tiles of `CLUSTER_TILE_SIZE` pixels, and each tile into
+//! `CLUSTER_DEPTH_SLICES` slices spaced logarithmically between the near and far planes. A compute
+//! pass tests every local light against every cluster and writes a list of light
+//! indices per cluster, so shading passes only loop over the lights that can reach a pixel.
+//! Lookups are in `clusters.inc`.
+
+use std::sync::Arc;
+use vulkano::buffer::BufferUsage;
+use vulkano::pipeline::{ComputePipeline, ComputePipelineAbstract};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::device::{Device, Queue};
+
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::light::{GpuLight, LocalLightCulling, light_buffer, ordered_lights};
+use crate::renderer::RenderInfo;
+
+
+/// Width and height of a cluster in pixels.
+pub const CLUSTER_TILE_SIZE: u32 = 64;
+pub const CLUSTER_DEPTH_SLICES: u32 = 24;
+/// Lights past this in a single cluster are dropped. Must match `light_culling.comp`.
+pub const MAX_LIGHTS_PER_CLUSTER: u32 = 256;
+/// Sizes the shared index list. Clusters are filled in no particular order, so if the list runs
+/// out some clusters lose lights.
+const AVERAGE_LIGHTS_PER_CLUSTER: u32 = 32;
+
+
+/// Must match `ClusterParams` in `clusters.inc`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GpuClusterParams {
+    pub grid_size: [u32; 4],
+    pub log_depth_scale: f32,
+    pub log_depth_bias: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+/// Lights uploaded for the current frame, and their per-cluster index lists. The cluster lists
+/// are only filled in with `LocalLightCulling::Clustered`.
+#[derive(Clone)]
+pub struct LightClusterData {
+    /// Directional lights first, then local lights.
+    pub lights: Arc<CpuAccessibleBufferXalloc<[GpuLight]>>,
+    pub light_count: u32,
+    pub directional_light_count: u32,
+    pub grid: Arc<CpuAccessibleBufferXalloc<[[u32; 2]]>>,
+    pub indices: Arc<CpuAccessibleBufferXalloc<[u32]>>,
+    pub params: Arc<CpuAccessibleBufferXalloc<GpuClusterParams>>,
+}
+
+
+struct ClusterBuffers {
+    grid_size: [u32; 3],
+    grid: Arc<CpuAccessibleBufferXalloc<[[u32; 2]]>>,
+    indices: Arc<CpuAccessibleBufferXalloc<[u32]>>,
+    counter: Arc<CpuAccessibleBufferXalloc<u32>>,
+}
+
+
+pub struct LightCullingCompute {
+    pub pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    buffers: Option<ClusterBuffers>,
+}
+
+impl LightCullingCompute {
+    pub fn new(device: Arc<Device>) -> Self {
+        let pipeline = Arc::new({
+            let shader = crate::shader::light_culling::Shader::load(device.clone()).unwrap();
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
+        });
+
+        Self {
+            pipeline,
+            buffers: None,
+        }
+    }
+
+    /// Cluster counts along each axis for the given screen size.
+    pub fn grid_size(dimensions: [u32; 2]) -> [u32; 3] {
+        [(dimensions[0] + CLUSTER_TILE_SIZE - 1) / CLUSTER_TILE_SIZE,
+         (dimensions[1] + CLUSTER_TILE_SIZE - 1) / CLUSTER_TILE_SIZE,
+         CLUSTER_DEPTH_SLICES]
+    }
+
+    /// Recreates the cluster buffers if the screen size changed.
+    pub fn recreate_buffers_if_needed(&mut self, info: &RenderInfo) {
+        let grid_size = Self::grid_size(info.dimensions);
+        if self.buffers.as_ref().map(|b| b.grid_size) == Some(grid_size) {
+            return;
+        }
+
+        let storage_buf_usage = BufferUsage {
+            storage_buffer: true,
+            transfer_destination: true,
+            ..BufferUsage::none()
+        };
+        let cluster_count = (grid_size[0] * grid_size[1] * grid_size[2]) as usize;
+        let index_count = cluster_count * AVERAGE_LIGHTS_PER_CLUSTER as usize;
+        self.buffers = Some(ClusterBuffers {
+            grid_size,
+            grid: CpuAccessibleBufferXalloc::from_iter(info.device.clone(), storage_buf_usage, vec![[0u32; 2]; cluster_count].into_iter())
+                .expect("failed to create buffer"),
+            indices: CpuAccessibleBufferXalloc::from_iter(info.device.clone(), storage_buf_usage, vec![0u32; index_count].into_iter())
+                .expect("failed to create buffer"),
+            counter: CpuAccessibleBufferXalloc::from_data(info.device.clone(), storage_buf_usage, 0u32)
+                .expect("failed to create buffer"),
+        });
+    }
+
+    /// Uploads the lights and publishes them to `info.light_clusters`, then culls them into
+    /// clusters if clustered culling is enabled.
+    pub fn build_command_buffer(&mut self, info: &RenderInfo) -> Option<(AutoCommandBuffer, Arc<Queue>)> {
+        self.recreate_buffers_if_needed(info);
+        let buffers = self.buffers.as_ref().unwrap();
+
+        let (lights, directional_light_count) = ordered_lights(&info.lights);
+        let light_count = lights.len() as u32;
+        let lights = light_buffer(info.device.clone(), lights.into_iter(), &info.shadow_maps.local_data.lock());
+
+        let near = info.near_plane;
+        let far = info.far_plane;
+        let log_depth_scale = CLUSTER_DEPTH_SLICES as f32 / (far / near).ln();
+        let params = CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(), GpuClusterParams {
+            grid_size: [buffers.grid_size[0], buffers.grid_size[1], buffers.grid_size[2], CLUSTER_TILE_SIZE],
+            log_depth_scale,
+            log_depth_bias: -near.ln() * log_depth_scale,
+            near,
+            far,
+        }).expect("failed to create buffer");
+
+        *info.light_clusters.lock() = Some(LightClusterData {
+            lights: lights.clone(),
+            light_count,
+            directional_light_count,
+            grid: buffers.grid.clone(),
+            indices: buffers.indices.clone(),
+            params: params.clone(),
+        });
+
+        if info.settings.light_culling != LocalLightCulling::Clustered {
+            return None;
+        }
+
+        let desc_set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_buffer(lights).unwrap()
+            .add_buffer(buffers.grid.clone()).unwrap()
+            .add_buffer(buffers.indices.clone()).unwrap()
+            .add_buffer(buffers.counter.clone()).unwrap()
+            .add_buffer(params).unwrap()
+            .build().unwrap()
+        );
+
+        let proj = info.unjittered_proj_mat;
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family()).unwrap()
+            .fill_buffer(buffers.counter.clone(), 0).unwrap()
+            .dispatch(buffers.grid_size, self.pipeline.clone(), desc_set, crate::shader::light_culling::ty::Constants {
+                view: info.view_mat.into(),
+                proj_info: [proj.x.x, proj.y.y, info.dimensions[0] as f32, info.dimensions[1] as f32],
+                first_light: directional_light_count,
+                light_count,
+                max_indices: buffers.indices.len() as u32,
+            }).unwrap()
+            .build().unwrap();
+
+        Some((cb, info.queues.main.as_ref().unwrap().clone()))
+    }
+}
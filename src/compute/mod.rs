@@ -0,0 +1,13 @@
+//! Compute passes.
+
+pub mod histogram;
+pub use self::histogram::{HistogramCompute, AutoExposureSettings, AutoExposureMode, MeteringMode, GpuExposure, exposure_buffer, exposure_from_ev100};
+
+pub mod light_culling;
+pub use self::light_culling::{LightCullingCompute, LightClusterData};
+
+pub mod ibl;
+pub use self::ibl::{IblCompute, IblSettings};
+
+pub mod sky;
+pub use self::sky::{SkyEnvironmentCompute, SKY_ENVIRONMENT_SIZE};
@@ -0,0 +1,65 @@
+//! Renders the analytic sky into an environment image, for image based lighting.
+
+use std::sync::Arc;
+use vulkano::pipeline::{ComputePipeline, ComputePipelineAbstract};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::device::{Device, Queue};
+use vulkano::format::R16G16B16A16Sfloat;
+use vulkano::image::{Dimensions, ImageLayout, ImageUsage, ImmutableImage, MipmapsCount, StorageImage};
+
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::sky::GpuSky;
+
+
+/// Size of the equirectangular sky environment. The sky is smooth, so it doesn't need to be large.
+pub const SKY_ENVIRONMENT_SIZE: [u32; 2] = [256, 128];
+
+/// Must match the workgroup size in `sky_environment.comp`.
+const WORKGROUP_SIZE: u32 = 8;
+
+
+pub struct SkyEnvironmentCompute {
+    pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+}
+
+impl SkyEnvironmentCompute {
+    pub fn new(device: Arc<Device>) -> Self {
+        let pipeline = Arc::new({
+            let shader = crate::shader::sky_environment::Shader::load(device.clone()).unwrap();
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
+        });
+
+        Self {
+            pipeline,
+        }
+    }
+
+    /// Builds the command buffer rendering the sky without the sun disk into an equirectangular
+    /// image. The image can't be used until it has finished executing.
+    pub fn record(&self, sky: Arc<CpuAccessibleBufferXalloc<GpuSky>>, queue: Arc<Queue>) -> (Arc<ImmutableImage<R16G16B16A16Sfloat>>, AutoCommandBuffer) {
+        let device = queue.device().clone();
+        let size = SKY_ENVIRONMENT_SIZE;
+        let dimensions = Dimensions::Dim2d { width: size[0], height: size[1] };
+
+        let storage = StorageImage::with_usage(device.clone(), dimensions, R16G16B16A16Sfloat,
+                                               ImageUsage { storage: true, transfer_source: true, ..ImageUsage::none() },
+                                               Some(queue.family())).unwrap();
+        let (image, image_init) = ImmutableImage::uninitialized(device.clone(), dimensions, R16G16B16A16Sfloat, MipmapsCount::One,
+                                                                ImageUsage { sampled: true, transfer_destination: true, ..ImageUsage::none() },
+                                                                ImageLayout::ShaderReadOnlyOptimal, Some(queue.family())).unwrap();
+
+        let set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_buffer(sky).unwrap()
+            .add_image(storage.clone()).unwrap()
+            .build().unwrap());
+
+        let workgroups = [(size[0] + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, (size[1] + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, 1];
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family()).unwrap()
+            .dispatch(workgroups, self.pipeline.clone(), set, ()).unwrap()
+            .copy_image(storage, [0, 0, 0], 0, 0, image_init, [0, 0, 0], 0, 0, [size[0], size[1], 1], 1).unwrap()
+            .build().unwrap();
+
+        (image, cb)
+    }
+}
@@ -0,0 +1,857 @@
+//! Image based lighting environment.
+//!
+//! The environment is an equirectangular HDR image of everything around the scene. Setting one
+//! with `PhosphorRenderer::set_environment` runs the compute passes in `compute::ibl`, which
+//! derive the diffuse irradiance map and the GGX-prefiltered radiance mip chain sampled by the
+//! lighting pass. All maps use the equirectangular mapping in `equirect.inc`.
+//!
+//! `EnvironmentImage` loads equirectangular images from Radiance RGBE (`.hdr`) and OpenEXR
+//! (`.exr`) files. Only scanline EXR files without compression or with RLE, ZIPS or ZIP
+//! compression are supported; tiled, multi-part and deep files, and the lossy compression
+//! methods, are rejected.
+
+use std::{error, fmt, fs, io};
+use std::path::Path;
+use std::sync::Arc;
+use half::f16;
+use vulkano::device::Queue;
+use vulkano::format::{R16G16B16A16Sfloat, R16G16Sfloat};
+use vulkano::image::{Dimensions, ImmutableImage};
+use vulkano::sync::GpuFuture;
+
+
+/// Size of the diffuse irradiance map. Irradiance is very low frequency, so this can be small.
+pub const IRRADIANCE_SIZE: [u32; 2] = [64, 32];
+/// Size of the first mip of the radiance map.
+pub const RADIANCE_SIZE: [u32; 2] = [512, 256];
+/// Mips in the radiance map, from mirror-like to fully rough. Must match `MAX_REFLECTION_LOD`
+/// in the lighting shaders.
+pub const RADIANCE_MIP_COUNT: u32 = 5;
+/// Width and height of the split-sum BRDF lookup table.
+pub const BRDF_LUT_SIZE: u32 = 128;
+
+/// Largest finite half float. Brighter texels, like the sun in an outdoor capture, are clamped to
+/// this instead of turning into infinity.
+const MAX_HALF: f32 = 65504.0;
+
+
+pub struct Environment {
+    /// The equirectangular image everything else was generated from.
+    pub source: Arc<ImmutableImage<R16G16B16A16Sfloat>>,
+    /// Cosine-convolved irradiance, premultiplied by pi.
+    pub irradiance: Arc<ImmutableImage<R16G16B16A16Sfloat>>,
+    /// Prefiltered radiance, with roughness increasing linearly over the mips.
+    pub radiance: Arc<ImmutableImage<R16G16B16A16Sfloat>>,
+    /// Scale and bias to F0 for the split-sum approximation, indexed by `dot(N, V)` and roughness.
+    /// Doesn't depend on the environment.
+    pub brdf_lut: Arc<ImmutableImage<R16G16Sfloat>>,
+}
+
+
+#[derive(Debug)]
+pub enum EnvironmentError {
+    Io(io::Error),
+    /// The data isn't a Radiance or OpenEXR file.
+    UnknownFormat,
+    /// The header is missing something or has an invalid value.
+    InvalidHeader(String),
+    /// A valid file using a feature the loader doesn't support.
+    Unsupported(String),
+    /// The file ended before all pixels were read.
+    UnexpectedEnd,
+    /// Compressed pixel data doesn't decompress to the expected size.
+    InvalidPixelData,
+}
+
+impl error::Error for EnvironmentError {}
+
+impl fmt::Display for EnvironmentError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            EnvironmentError::Io(e) => write!(fmt, "failed to read environment image: {}", e),
+            EnvironmentError::UnknownFormat => write!(fmt, "environment image isn't a Radiance HDR or OpenEXR file"),
+            EnvironmentError::InvalidHeader(s) => write!(fmt, "invalid environment image header: {}", s),
+            EnvironmentError::Unsupported(s) => write!(fmt, "unsupported environment image: {}", s),
+            EnvironmentError::UnexpectedEnd => write!(fmt, "environment image ended unexpectedly"),
+            EnvironmentError::InvalidPixelData => write!(fmt, "environment image has invalid pixel data"),
+        }
+    }
+}
+
+impl From<io::Error> for EnvironmentError {
+    fn from(e: io::Error) -> Self { EnvironmentError::Io(e) }
+}
+
+
+const EXR_MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+
+
+/// A floating point equirectangular image, loaded from disk.
+#[derive(Debug, Clone)]
+pub struct EnvironmentImage {
+    pub width: u32,
+    pub height: u32,
+    /// Linear RGBA, row by row from the top.
+    pub pixels: Vec<[f32; 4]>,
+}
+
+impl EnvironmentImage {
+    /// Loads a Radiance or OpenEXR file. The format is detected from the contents, not the
+    /// extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EnvironmentError> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, EnvironmentError> {
+        if bytes.starts_with(&EXR_MAGIC) {
+            Self::parse_exr(bytes)
+        }
+        else if bytes.starts_with(b"#?") {
+            Self::parse_hdr(bytes)
+        }
+        else {
+            Err(EnvironmentError::UnknownFormat)
+        }
+    }
+
+    /// Parses a Radiance RGBE file, with flat, old-style or new-style run length encoded
+    /// scanlines.
+    pub fn parse_hdr(bytes: &[u8]) -> Result<Self, EnvironmentError> {
+        let mut reader = ByteReader::new(bytes);
+
+        let mut exposure = 1.0;
+        loop {
+            let line = reader.line()?;
+            if line.is_empty() {
+                break;
+            }
+            if line.starts_with("FORMAT=") {
+                let format = line["FORMAT=".len()..].trim();
+                if format != "32-bit_rle_rgbe" {
+                    return Err(EnvironmentError::Unsupported(format!("Radiance pixel format {}", format)));
+                }
+            }
+            else if line.starts_with("EXPOSURE=") {
+                let value = line["EXPOSURE=".len()..].trim();
+                exposure *= value.parse::<f32>().map_err(|_| EnvironmentError::InvalidHeader(line.clone()))?;
+            }
+        }
+
+        // only the standard orientations, with x increasing along each scanline
+        let resolution = reader.line()?;
+        let parts: Vec<&str> = resolution.split_whitespace().collect();
+        let (flip_y, height, width) = match parts.as_slice() {
+            [y, height, "+X", width] if *y == "-Y" || *y == "+Y" => {
+                let parse = |s: &str| s.parse::<u32>().map_err(|_| EnvironmentError::InvalidHeader(resolution.clone()));
+                (*y == "+Y", parse(*height)?, parse(*width)?)
+            },
+            [_, _, _, _] => return Err(EnvironmentError::Unsupported(format!("Radiance orientation {}", resolution))),
+            _ => return Err(EnvironmentError::InvalidHeader(resolution.clone())),
+        };
+        let pixel_count = match width.checked_mul(height) {
+            Some(count) if count > 0 => count as usize,
+            _ => return Err(EnvironmentError::InvalidHeader(resolution)),
+        };
+
+        let mut rgbe = vec![[0u8; 4]; pixel_count];
+        for row in rgbe.chunks_mut(width as usize) {
+            read_rgbe_scanline(&mut reader, row)?;
+        }
+        if flip_y {
+            rgbe = rgbe.chunks(width as usize).rev().flat_map(|row| row.iter().cloned()).collect();
+        }
+
+        // pixel values had the exposure applied when they were written
+        let scale = 1.0 / exposure;
+        let pixels = rgbe.iter().map(|p| {
+            if p[3] == 0 {
+                [0.0, 0.0, 0.0, 1.0]
+            }
+            else {
+                let f = 2f32.powi(p[3] as i32 - (128 + 8)) * scale;
+                [p[0] as f32 * f, p[1] as f32 * f, p[2] as f32 * f, 1.0]
+            }
+        }).collect();
+
+        Ok(Self { width, height, pixels })
+    }
+
+    /// Parses a single part scanline OpenEXR file. Channels are matched by the last component of
+    /// their name, so layered files use whichever layer comes last. Luminance-only files (a
+    /// single `Y` channel) are loaded as gray.
+    pub fn parse_exr(bytes: &[u8]) -> Result<Self, EnvironmentError> {
+        let mut reader = ByteReader::new(bytes);
+        if reader.take(4)? != &EXR_MAGIC[..] {
+            return Err(EnvironmentError::UnknownFormat);
+        }
+        let version = reader.u32()?;
+        if version & 0xff != 2 {
+            return Err(EnvironmentError::Unsupported(format!("OpenEXR version {}", version & 0xff)));
+        }
+        if version & 0x200 != 0 {
+            return Err(EnvironmentError::Unsupported("tiled OpenEXR".to_string()));
+        }
+        if version & 0x1800 != 0 {
+            return Err(EnvironmentError::Unsupported("multi-part or deep OpenEXR".to_string()));
+        }
+
+        let mut channels = None;
+        let mut compression = None;
+        let mut data_window = None;
+        loop {
+            let name = reader.string()?;
+            if name.is_empty() {
+                break;
+            }
+            let _type = reader.string()?;
+            let size = reader.u32()? as usize;
+            let mut value = ByteReader::new(reader.take(size)?);
+            match name.as_str() {
+                "channels" => channels = Some(read_exr_channels(&mut value)?),
+                "compression" => compression = Some(ExrCompression::from_id(value.u8()?)?),
+                "dataWindow" => data_window = Some([value.i32()?, value.i32()?, value.i32()?, value.i32()?]),
+                _ => {}
+            }
+        }
+        let missing = |attribute: &str| EnvironmentError::InvalidHeader(format!("missing {} attribute", attribute));
+        let channels = channels.ok_or_else(|| missing("channels"))?;
+        let compression = compression.ok_or_else(|| missing("compression"))?;
+        let [x_min, y_min, x_max, y_max] = data_window.ok_or_else(|| missing("dataWindow"))?;
+        if x_max < x_min || y_max < y_min {
+            return Err(EnvironmentError::InvalidHeader("empty dataWindow".to_string()));
+        }
+        let too_large = || EnvironmentError::InvalidHeader("dataWindow too large".to_string());
+        let span = |min: i32, max: i32| max.checked_sub(min).and_then(|d| d.checked_add(1)).map(|d| d as u32);
+        let width = span(x_min, x_max).ok_or_else(too_large)?;
+        let height = span(y_min, y_max).ok_or_else(too_large)?;
+        let pixel_count = width.checked_mul(height).ok_or_else(too_large)? as usize;
+
+        // output component for each channel, if it's one we use
+        let luminance_only = channels.iter().all(|c| c.short_name() == "Y");
+        let targets: Vec<&[usize]> = channels.iter().map(|c| match c.short_name() {
+            "R" => &[0][..],
+            "G" => &[1][..],
+            "B" => &[2][..],
+            "A" => &[3][..],
+            "Y" if luminance_only => &[0, 1, 2][..],
+            _ => &[][..],
+        }).collect();
+        let row_bytes: usize = channels.iter().map(|c| c.sample_size() * width as usize).sum();
+
+        let lines_per_chunk = compression.lines_per_chunk();
+        let chunk_count = (height + lines_per_chunk - 1) / lines_per_chunk;
+        // chunks are read by their y coordinate, so the offset table and line order can be skipped
+        reader.take(chunk_count as usize * 8)?;
+
+        let mut pixels = vec![[0.0, 0.0, 0.0, 1.0]; pixel_count];
+        for _ in 0..chunk_count {
+            let y = reader.i32()?;
+            let size = reader.u32()? as usize;
+            let data = reader.take(size)?;
+            if y < y_min || y > y_max {
+                return Err(EnvironmentError::InvalidPixelData);
+            }
+            let first_row = (y - y_min) as u32;
+            let rows = lines_per_chunk.min(height - first_row) as usize;
+            let data = compression.decompress(data, rows * row_bytes)?;
+
+            let mut data = ByteReader::new(&data);
+            for row in 0..rows {
+                let start = (first_row as usize + row) * width as usize;
+                let row_pixels = &mut pixels[start..start + width as usize];
+                for (channel, target) in channels.iter().zip(targets.iter()) {
+                    for pixel in row_pixels.iter_mut() {
+                        let value = channel.read_sample(&mut data)?;
+                        for component in target.iter() {
+                            pixel[*component] = value;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Self { width, height, pixels })
+    }
+
+    /// Uploads the image as a half float texture for `PhosphorRenderer::set_environment`. Blocks
+    /// until the upload is finished.
+    pub fn upload(&self, queue: Arc<Queue>) -> Arc<ImmutableImage<R16G16B16A16Sfloat>> {
+        let half = |v: f32| f16::from_f32(if v.is_nan() { 0.0 } else { v.max(-MAX_HALF).min(MAX_HALF) });
+        let texels: Vec<[f16; 4]> = self.pixels.iter()
+            .map(|p| [half(p[0]), half(p[1]), half(p[2]), half(p[3])])
+            .collect();
+        let (image, future) = ImmutableImage::from_iter(texels.into_iter(),
+                                                        Dimensions::Dim2d { width: self.width, height: self.height },
+                                                        R16G16B16A16Sfloat, queue).unwrap();
+        future.then_signal_fence_and_flush().unwrap().wait(None).unwrap();
+        image
+    }
+}
+
+
+/// Little endian reads from a byte slice, failing with `UnexpectedEnd` when it runs out.
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], EnvironmentError> {
+        if self.bytes.len() - self.position < count {
+            return Err(EnvironmentError::UnexpectedEnd);
+        }
+        let slice = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, EnvironmentError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, EnvironmentError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, EnvironmentError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, EnvironmentError> {
+        Ok(self.u32()? as i32)
+    }
+
+    /// Reads up to the next newline, which is consumed but not returned.
+    fn line(&mut self) -> Result<String, EnvironmentError> {
+        let rest = &self.bytes[self.position..];
+        let end = rest.iter().position(|b| *b == b'\n').ok_or(EnvironmentError::UnexpectedEnd)?;
+        self.position += end + 1;
+        Ok(String::from_utf8_lossy(&rest[..end]).trim_end_matches('\r').to_string())
+    }
+
+    /// Reads a null terminated string. The terminator is consumed but not returned.
+    fn string(&mut self) -> Result<String, EnvironmentError> {
+        let rest = &self.bytes[self.position..];
+        let end = rest.iter().position(|b| *b == 0).ok_or(EnvironmentError::UnexpectedEnd)?;
+        self.position += end + 1;
+        Ok(String::from_utf8_lossy(&rest[..end]).to_string())
+    }
+}
+
+
+/// Reads one scanline of RGBE pixels in any of the three Radiance encodings.
+fn read_rgbe_scanline(reader: &mut ByteReader, row: &mut [[u8; 4]]) -> Result<(), EnvironmentError> {
+    let width = row.len();
+    let first = reader.take(4)?;
+
+    // new-style RLE: each component is run length encoded separately
+    if width >= 8 && width < 0x8000 && first[0] == 2 && first[1] == 2 && first[2] & 0x80 == 0 {
+        if ((first[2] as usize) << 8 | first[3] as usize) != width {
+            return Err(EnvironmentError::InvalidPixelData);
+        }
+        for component in 0..4 {
+            let mut x = 0;
+            while x < width {
+                let count = reader.u8()? as usize;
+                if count > 128 {
+                    let count = count - 128;
+                    let value = reader.u8()?;
+                    if count > width - x {
+                        return Err(EnvironmentError::InvalidPixelData);
+                    }
+                    for pixel in row[x..x + count].iter_mut() {
+                        pixel[component] = value;
+                    }
+                    x += count;
+                }
+                else {
+                    if count == 0 || count > width - x {
+                        return Err(EnvironmentError::InvalidPixelData);
+                    }
+                    for (pixel, value) in row[x..x + count].iter_mut().zip(reader.take(count)?) {
+                        pixel[component] = *value;
+                    }
+                    x += count;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // flat pixels, where (1, 1, 1, n) repeats the previous pixel, with consecutive repeats
+    // shifting n up by 8 bits each
+    let mut pixel = [first[0], first[1], first[2], first[3]];
+    let mut x = 0;
+    let mut shift = 0;
+    loop {
+        if pixel[0] == 1 && pixel[1] == 1 && pixel[2] == 1 {
+            if x == 0 {
+                return Err(EnvironmentError::InvalidPixelData);
+            }
+            let count = (pixel[3] as usize) << shift;
+            if count > width - x {
+                return Err(EnvironmentError::InvalidPixelData);
+            }
+            let previous = row[x - 1];
+            for p in row[x..x + count].iter_mut() {
+                *p = previous;
+            }
+            x += count;
+            shift += 8;
+        }
+        else {
+            row[x] = pixel;
+            x += 1;
+            shift = 0;
+        }
+        if x == width {
+            return Ok(());
+        }
+        let next = reader.take(4)?;
+        pixel = [next[0], next[1], next[2], next[3]];
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExrPixelType {
+    Uint,
+    Half,
+    Float,
+}
+
+struct ExrChannel {
+    name: String,
+    pixel_type: ExrPixelType,
+}
+
+impl ExrChannel {
+    /// Name without its layer prefix.
+    fn short_name(&self) -> &str {
+        self.name.rsplit('.').next().unwrap_or("")
+    }
+
+    fn sample_size(&self) -> usize {
+        match self.pixel_type {
+            ExrPixelType::Half => 2,
+            ExrPixelType::Uint | ExrPixelType::Float => 4,
+        }
+    }
+
+    fn read_sample(&self, reader: &mut ByteReader) -> Result<f32, EnvironmentError> {
+        Ok(match self.pixel_type {
+            ExrPixelType::Uint => reader.u32()? as f32,
+            ExrPixelType::Half => f16::from_bits(reader.u16()?).to_f32(),
+            ExrPixelType::Float => f32::from_bits(reader.u32()?),
+        })
+    }
+}
+
+/// Reads a `chlist` attribute. Channels are stored sorted by name, which is also the order their
+/// samples appear in each scanline.
+fn read_exr_channels(reader: &mut ByteReader) -> Result<Vec<ExrChannel>, EnvironmentError> {
+    let mut channels = Vec::new();
+    loop {
+        let name = reader.string()?;
+        if name.is_empty() {
+            return Ok(channels);
+        }
+        let pixel_type = match reader.i32()? {
+            0 => ExrPixelType::Uint,
+            1 => ExrPixelType::Half,
+            2 => ExrPixelType::Float,
+            t => return Err(EnvironmentError::InvalidHeader(format!("channel {} has unknown pixel type {}", name, t))),
+        };
+        // linear flag and reserved bytes
+        reader.take(4)?;
+        let x_sampling = reader.i32()?;
+        let y_sampling = reader.i32()?;
+        if x_sampling != 1 || y_sampling != 1 {
+            return Err(EnvironmentError::Unsupported(format!("subsampled channel {}", name)));
+        }
+        channels.push(ExrChannel { name, pixel_type });
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExrCompression {
+    None,
+    Rle,
+    Zips,
+    Zip,
+}
+
+impl ExrCompression {
+    fn from_id(id: u8) -> Result<Self, EnvironmentError> {
+        match id {
+            0 => Ok(ExrCompression::None),
+            1 => Ok(ExrCompression::Rle),
+            2 => Ok(ExrCompression::Zips),
+            3 => Ok(ExrCompression::Zip),
+            4 => Err(EnvironmentError::Unsupported("PIZ compressed OpenEXR".to_string())),
+            5 => Err(EnvironmentError::Unsupported("PXR24 compressed OpenEXR".to_string())),
+            6 | 7 => Err(EnvironmentError::Unsupported("B44 compressed OpenEXR".to_string())),
+            8 | 9 => Err(EnvironmentError::Unsupported("DWA compressed OpenEXR".to_string())),
+            _ => Err(EnvironmentError::InvalidHeader(format!("unknown compression {}", id))),
+        }
+    }
+
+    fn lines_per_chunk(&self) -> u32 {
+        match self {
+            ExrCompression::None | ExrCompression::Rle | ExrCompression::Zips => 1,
+            ExrCompression::Zip => 16,
+        }
+    }
+
+    /// Decompresses one chunk, which must come out to `size` bytes. Chunks that wouldn't get
+    /// smaller are stored uncompressed.
+    fn decompress(&self, data: &[u8], size: usize) -> Result<Vec<u8>, EnvironmentError> {
+        if *self == ExrCompression::None || data.len() == size {
+            return if data.len() == size { Ok(data.to_vec()) } else { Err(EnvironmentError::InvalidPixelData) };
+        }
+
+        let predicted = match self {
+            ExrCompression::Rle => exr_rle_decode(data, size)?,
+            _ => miniz_oxide::inflate::decompress_to_vec_zlib(data).map_err(|_| EnvironmentError::InvalidPixelData)?,
+        };
+        if predicted.len() != size {
+            return Err(EnvironmentError::InvalidPixelData);
+        }
+
+        // undo the delta predictor, then interleave the two halves back together
+        let mut deltas = predicted;
+        for i in 1..deltas.len() {
+            deltas[i] = deltas[i - 1].wrapping_add(deltas[i]).wrapping_sub(128);
+        }
+        let half = (size + 1) / 2;
+        let mut bytes = Vec::with_capacity(size);
+        for i in 0..half {
+            bytes.push(deltas[i]);
+            if half + i < size {
+                bytes.push(deltas[half + i]);
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+/// OpenEXR's byte RLE: a negative count is followed by that many literal bytes, a positive count
+/// by one byte repeated count + 1 times.
+fn exr_rle_decode(data: &[u8], size: usize) -> Result<Vec<u8>, EnvironmentError> {
+    let mut reader = ByteReader::new(data);
+    let mut bytes = Vec::with_capacity(size);
+    while reader.position < data.len() {
+        let count = reader.u8()? as i8;
+        if count < 0 {
+            bytes.extend_from_slice(reader.take(-(count as i32) as usize)?);
+        }
+        else {
+            let value = reader.u8()?;
+            bytes.extend(std::iter::repeat(value).take(count as usize + 1));
+        }
+        if bytes.len() > size {
+            return Err(EnvironmentError::InvalidPixelData);
+        }
+    }
+    Ok(bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use half::f16;
+    use super::{EnvironmentError, EnvironmentImage, EXR_MAGIC};
+
+    /// 8x3 RGBE test image with runs long enough to compress. Every value is exactly
+    /// representable, so decoding can be compared exactly.
+    fn rgbe_rows() -> Vec<Vec<[u8; 4]>> {
+        let a = [128, 64, 32, 129];
+        let b = [64, 128, 192, 130];
+        let c = [255, 0, 16, 136];
+        vec![
+            vec![a, a, a, a, b, b, c, a],
+            vec![c, c, c, c, c, c, c, c],
+            vec![a, b, c, a, b, c, a, b],
+        ]
+    }
+
+    fn rgbe_to_float(p: [u8; 4]) -> [f32; 4] {
+        let f = 2f32.powi(p[3] as i32 - 136);
+        [p[0] as f32 * f, p[1] as f32 * f, p[2] as f32 * f, 1.0]
+    }
+
+    fn hdr(resolution: &str, scanlines: &[u8]) -> Vec<u8> {
+        let mut bytes = format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{}\n", resolution).into_bytes();
+        bytes.extend_from_slice(scanlines);
+        bytes
+    }
+
+    fn flat_scanline(row: &[[u8; 4]]) -> Vec<u8> {
+        row.iter().flat_map(|p| p.iter().cloned()).collect()
+    }
+
+    /// Old-style RLE, where `(1, 1, 1, n)` repeats the previous pixel.
+    fn old_rle_scanline(row: &[[u8; 4]]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut x = 0;
+        while x < row.len() {
+            bytes.extend_from_slice(&row[x]);
+            let run = row[x + 1..].iter().take_while(|p| **p == row[x]).count().min(255);
+            if run > 0 {
+                bytes.extend_from_slice(&[1, 1, 1, run as u8]);
+            }
+            x += 1 + run;
+        }
+        bytes
+    }
+
+    /// New-style RLE, with each component encoded separately.
+    fn new_rle_scanline(row: &[[u8; 4]]) -> Vec<u8> {
+        let mut bytes = vec![2, 2, (row.len() >> 8) as u8, row.len() as u8];
+        for component in 0..4 {
+            let values: Vec<u8> = row.iter().map(|p| p[component]).collect();
+            let mut x = 0;
+            while x < values.len() {
+                let run = values[x..].iter().take_while(|v| **v == values[x]).count().min(127);
+                if run > 1 {
+                    bytes.extend_from_slice(&[128 + run as u8, values[x]]);
+                    x += run;
+                }
+                else {
+                    bytes.extend_from_slice(&[1, values[x]]);
+                    x += 1;
+                }
+            }
+        }
+        bytes
+    }
+
+    fn assert_hdr_matches(image: &EnvironmentImage, rows: &[Vec<[u8; 4]>]) {
+        assert_eq!(image.width as usize, rows[0].len());
+        assert_eq!(image.height as usize, rows.len());
+        let expected: Vec<[f32; 4]> = rows.iter().flat_map(|row| row.iter().map(|p| rgbe_to_float(*p))).collect();
+        assert_eq!(image.pixels, expected);
+    }
+
+    #[test]
+    fn hdr_scanline_encodings() {
+        let rows = rgbe_rows();
+        for encode in &[flat_scanline, old_rle_scanline, new_rle_scanline] {
+            let scanlines: Vec<u8> = rows.iter().flat_map(|row| encode(row)).collect();
+            let image = EnvironmentImage::parse(&hdr("-Y 3 +X 8", &scanlines)).unwrap();
+            assert_hdr_matches(&image, &rows);
+        }
+    }
+
+    #[test]
+    fn hdr_old_rle_long_run() {
+        // a repeat of 300 pixels is split over two repeat markers, 44 + (1 << 8)
+        let a = [128, 64, 32, 129];
+        let b = [16, 16, 16, 128];
+        let mut row = vec![b];
+        row.extend(std::iter::repeat(a).take(301));
+        let mut scanline = Vec::new();
+        scanline.extend_from_slice(&b);
+        scanline.extend_from_slice(&a);
+        scanline.extend_from_slice(&[1, 1, 1, 44]);
+        scanline.extend_from_slice(&[1, 1, 1, 1]);
+        let image = EnvironmentImage::parse(&hdr("-Y 1 +X 302", &scanline)).unwrap();
+        assert_hdr_matches(&image, &[row]);
+    }
+
+    #[test]
+    fn hdr_flipped_y() {
+        let rows = rgbe_rows();
+        let scanlines: Vec<u8> = rows.iter().rev().flat_map(|row| flat_scanline(row)).collect();
+        let image = EnvironmentImage::parse(&hdr("+Y 3 +X 8", &scanlines)).unwrap();
+        assert_hdr_matches(&image, &rows);
+    }
+
+    #[test]
+    fn hdr_exposure() {
+        let mut bytes = b"#?RADIANCE\nEXPOSURE=2\nEXPOSURE=0.25\n\n-Y 1 +X 1\n".to_vec();
+        bytes.extend_from_slice(&[128, 64, 32, 129]);
+        let image = EnvironmentImage::parse(&bytes).unwrap();
+        assert_eq!(image.pixels, vec![[2.0, 1.0, 0.5, 1.0]]);
+    }
+
+    #[test]
+    fn hdr_invalid() {
+        match EnvironmentImage::parse(&hdr("-Y 65536 +X 65536", &[])) {
+            Err(EnvironmentError::InvalidHeader(_)) => {},
+            other => panic!("expected InvalidHeader, got {:?}", other),
+        }
+        match EnvironmentImage::parse(&hdr("-Y 0 +X 8", &[])) {
+            Err(EnvironmentError::InvalidHeader(_)) => {},
+            other => panic!("expected InvalidHeader, got {:?}", other),
+        }
+        match EnvironmentImage::parse(&hdr("+X 8 -Y 3", &[])) {
+            Err(EnvironmentError::Unsupported(_)) => {},
+            other => panic!("expected Unsupported, got {:?}", other),
+        }
+        let rows = rgbe_rows();
+        let scanlines: Vec<u8> = rows.iter().flat_map(|row| flat_scanline(row)).collect();
+        match EnvironmentImage::parse(&hdr("-Y 3 +X 8", &scanlines[..scanlines.len() - 4])) {
+            Err(EnvironmentError::UnexpectedEnd) => {},
+            other => panic!("expected UnexpectedEnd, got {:?}", other),
+        }
+        match EnvironmentImage::parse(b"P6\n") {
+            Err(EnvironmentError::UnknownFormat) => {},
+            other => panic!("expected UnknownFormat, got {:?}", other),
+        }
+    }
+
+
+    const EXR_WIDTH: usize = 5;
+    const EXR_HEIGHT: usize = 20;
+
+    /// Test image with exactly representable values, constant along most of each row so the
+    /// compressed chunks actually get smaller.
+    fn exr_pixel(x: usize, y: usize) -> [f32; 4] {
+        let v = if x < 3 { (y % 4) as f32 * 0.5 } else { 2.0 };
+        [v, v * 0.5, 0.25, 1.0]
+    }
+
+    /// Undoes `ExrCompression::decompress`'s predictor and interleaving.
+    fn exr_predict(raw: &[u8]) -> Vec<u8> {
+        let half = (raw.len() + 1) / 2;
+        let mut split: Vec<u8> = raw.iter().step_by(2).cloned().collect();
+        split.extend(raw.iter().skip(1).step_by(2));
+        assert_eq!(split.len(), raw.len());
+        assert!(split.len() >= half);
+        let mut predicted = split.clone();
+        for i in 1..split.len() {
+            predicted[i] = split[i].wrapping_sub(split[i - 1]).wrapping_add(128);
+        }
+        predicted
+    }
+
+    fn exr_rle_encode(data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut x = 0;
+        while x < data.len() {
+            let run = data[x..].iter().take_while(|b| **b == data[x]).count().min(128);
+            if run >= 3 {
+                bytes.extend_from_slice(&[(run - 1) as u8, data[x]]);
+                x += run;
+            }
+            else {
+                let literal = data[x..].windows(3).take_while(|w| !(w[0] == w[1] && w[1] == w[2])).count()
+                    .max(1).min(data.len() - x).min(127);
+                bytes.push((-(literal as i32)) as u8);
+                bytes.extend_from_slice(&data[x..x + literal]);
+                x += literal;
+            }
+        }
+        bytes
+    }
+
+    /// Writes a scanline OpenEXR file with half float `B`, `G` and `R` channels and a float `A`
+    /// channel, in the given compression.
+    fn exr(compression: u8, data_window: [i32; 4]) -> Vec<u8> {
+        let mut bytes = EXR_MAGIC.to_vec();
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+
+        let attribute = |bytes: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]| {
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(kind.as_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(value);
+        };
+        let mut channels = Vec::new();
+        for (name, pixel_type) in &[("A", 2i32), ("B", 1), ("G", 1), ("R", 1)] {
+            channels.extend_from_slice(name.as_bytes());
+            channels.push(0);
+            channels.extend_from_slice(&pixel_type.to_le_bytes());
+            channels.extend_from_slice(&[0; 4]);
+            channels.extend_from_slice(&1i32.to_le_bytes());
+            channels.extend_from_slice(&1i32.to_le_bytes());
+        }
+        channels.push(0);
+        attribute(&mut bytes, "channels", "chlist", &channels);
+        attribute(&mut bytes, "compression", "compression", &[compression]);
+        let window: Vec<u8> = data_window.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+        attribute(&mut bytes, "dataWindow", "box2i", &window);
+        attribute(&mut bytes, "displayWindow", "box2i", &window);
+        attribute(&mut bytes, "lineOrder", "lineOrder", &[0]);
+        bytes.push(0);
+
+        let lines_per_chunk = if compression == 3 { 16 } else { 1 };
+        let chunk_count = (EXR_HEIGHT + lines_per_chunk - 1) / lines_per_chunk;
+        // offsets aren't read
+        bytes.extend(std::iter::repeat(0).take(chunk_count * 8));
+
+        for chunk in 0..chunk_count {
+            let first_row = chunk * lines_per_chunk;
+            let mut raw = Vec::new();
+            for y in first_row..(first_row + lines_per_chunk).min(EXR_HEIGHT) {
+                let row: Vec<[f32; 4]> = (0..EXR_WIDTH).map(|x| exr_pixel(x, y)).collect();
+                raw.extend(row.iter().flat_map(|p| p[3].to_bits().to_le_bytes().to_vec()));
+                for component in &[2, 1, 0] {
+                    raw.extend(row.iter().flat_map(|p| f16::from_f32(p[*component]).to_bits().to_le_bytes().to_vec()));
+                }
+            }
+            let compressed = match compression {
+                0 => raw.clone(),
+                1 => exr_rle_encode(&exr_predict(&raw)),
+                _ => miniz_oxide::deflate::compress_to_vec_zlib(&exr_predict(&raw), 6),
+            };
+            assert!(compression == 0 || compressed.len() < raw.len(), "test data doesn't compress");
+            bytes.extend_from_slice(&(data_window[1] + first_row as i32).to_le_bytes());
+            bytes.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&compressed);
+        }
+        bytes
+    }
+
+    #[test]
+    fn exr_compressions() {
+        let expected: Vec<[f32; 4]> = (0..EXR_HEIGHT).flat_map(|y| (0..EXR_WIDTH).map(move |x| exr_pixel(x, y))).collect();
+        // none, RLE, ZIPS and ZIP
+        for compression in 0..4 {
+            let window = [-2, 10, EXR_WIDTH as i32 - 3, 10 + EXR_HEIGHT as i32 - 1];
+            let image = EnvironmentImage::parse(&exr(compression, window)).unwrap();
+            assert_eq!(image.width as usize, EXR_WIDTH);
+            assert_eq!(image.height as usize, EXR_HEIGHT);
+            assert_eq!(image.pixels, expected, "compression {}", compression);
+        }
+    }
+
+    #[test]
+    fn exr_invalid() {
+        match EnvironmentImage::parse(&exr(0, [i32::MIN, 0, i32::MAX, 0])) {
+            Err(EnvironmentError::InvalidHeader(_)) => {},
+            other => panic!("expected InvalidHeader, got {:?}", other),
+        }
+        match EnvironmentImage::parse(&exr(0, [0, 0, 65535, 65535])) {
+            Err(EnvironmentError::InvalidHeader(_)) => {},
+            other => panic!("expected InvalidHeader, got {:?}", other),
+        }
+        match EnvironmentImage::parse(&exr(0, [0, 1, 0, 0])) {
+            Err(EnvironmentError::InvalidHeader(_)) => {},
+            other => panic!("expected InvalidHeader, got {:?}", other),
+        }
+        let mut piz = exr(0, [0, 0, EXR_WIDTH as i32 - 1, EXR_HEIGHT as i32 - 1]);
+        let position = piz.windows(b"compression\0compression\0".len())
+            .position(|w| w == b"compression\0compression\0").unwrap();
+        piz[position + b"compression\0compression\0".len() + 4] = 4;
+        match EnvironmentImage::parse(&piz) {
+            Err(EnvironmentError::Unsupported(_)) => {},
+            other => panic!("expected Unsupported, got {:?}", other),
+        }
+        let truncated = exr(1, [0, 0, EXR_WIDTH as i32 - 1, EXR_HEIGHT as i32 - 1]);
+        match EnvironmentImage::parse(&truncated[..truncated.len() - 1]) {
+            Err(EnvironmentError::UnexpectedEnd) => {},
+            other => panic!("expected UnexpectedEnd, got {:?}", other),
+        }
+    }
+}
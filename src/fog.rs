@@ -0,0 +1,134 @@
+//! Local fog volumes.
+//!
+//! Fog volumes add density on top of the global height fog described by
+//! `stage::volumetric_fog::FogSettings`, e.g. for mist over a lake or haze inside a building.
+//! They're injected into the froxel grid with everything else, so they're lit and shadowed the
+//! same way. The analytic height fog used by `FogQuality::Low` ignores them.
+
+use cgmath::Vector3;
+use hashbrown::HashMap;
+
+
+/// Volumes that can exist at once. Must match `MAX_FOG_VOLUMES` in `fog_inject.comp`.
+pub const MAX_FOG_VOLUMES: usize = 32;
+
+
+/// Handle for a fog volume added to the renderer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FogVolumeId(pub(crate) u32);
+
+
+/// Shape of a fog volume, scaled by its `half_extents`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FogVolumeShape {
+    /// Axis aligned box.
+    Box,
+    /// Ellipsoid inscribed in the box.
+    Ellipsoid,
+}
+
+impl FogVolumeShape {
+    /// Matching `FOG_SHAPE_*` value in `fog_inject.comp`.
+    pub fn shader_id(&self) -> u32 {
+        match self {
+            FogVolumeShape::Box => 0,
+            FogVolumeShape::Ellipsoid => 1,
+        }
+    }
+}
+
+
+/// A region of extra fog.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FogVolume {
+    pub shape: FogVolumeShape,
+    pub position: Vector3<f32>,
+    pub half_extents: Vector3<f32>,
+    /// Extinction coefficient inside the volume, per meter.
+    pub density: f32,
+    /// Fraction of extinguished light that's scattered rather than absorbed, in linear RGB.
+    pub albedo: [f32; 3],
+    /// Distance in meters over which the density fades in from the volume's surface.
+    pub edge_fade: f32,
+}
+
+impl FogVolume {
+    /// Fog that scatters all colors evenly, fading in over a meter from its surface.
+    pub fn new(shape: FogVolumeShape, position: Vector3<f32>, half_extents: Vector3<f32>, density: f32) -> Self {
+        Self {
+            shape,
+            position,
+            half_extents,
+            density,
+            albedo: [1.0, 1.0, 1.0],
+            edge_fade: 1.0,
+        }
+    }
+}
+
+
+/// Every fog volume added to the renderer.
+pub struct FogVolumes {
+    volumes: HashMap<FogVolumeId, FogVolume>,
+    next_id: u32,
+}
+
+impl FogVolumes {
+    pub fn new() -> Self {
+        Self {
+            volumes: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Returns `None` if there are already `MAX_FOG_VOLUMES` volumes.
+    pub fn add(&mut self, volume: FogVolume) -> Option<FogVolumeId> {
+        if self.volumes.len() >= MAX_FOG_VOLUMES {
+            return None;
+        }
+        let id = FogVolumeId(self.next_id);
+        self.next_id += 1;
+        self.volumes.insert(id, volume);
+        Some(id)
+    }
+
+    /// Replaces a volume. Returns false if there's no volume with that id.
+    pub fn update(&mut self, id: FogVolumeId, volume: FogVolume) -> bool {
+        match self.volumes.get_mut(&id) {
+            Some(existing) => {
+                *existing = volume;
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn remove(&mut self, id: FogVolumeId) -> Option<FogVolume> {
+        self.volumes.remove(&id)
+    }
+
+    pub fn get(&self, id: FogVolumeId) -> Option<&FogVolume> {
+        self.volumes.get(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.volumes.len()
+    }
+
+    /// Per volume `(position and density, half extents and edge fade, albedo and shape)`, in
+    /// the layout of the `FogData` uniform in `fog_inject.comp`.
+    pub(crate) fn gpu_data(&self) -> ([[f32; 4]; MAX_FOG_VOLUMES], [[f32; 4]; MAX_FOG_VOLUMES], [[f32; 4]; MAX_FOG_VOLUMES]) {
+        let mut positions = [[0.0; 4]; MAX_FOG_VOLUMES];
+        let mut extents = [[0.0; 4]; MAX_FOG_VOLUMES];
+        let mut albedos = [[0.0; 4]; MAX_FOG_VOLUMES];
+        for (i, volume) in self.volumes.values().enumerate() {
+            let p = volume.position;
+            let e = volume.half_extents;
+            let a = volume.albedo;
+            positions[i] = [p.x, p.y, p.z, volume.density.max(0.0)];
+            extents[i] = [e.x.abs(), e.y.abs(), e.z.abs(), volume.edge_fade.max(0.0)];
+            albedos[i] = [a[0], a[1], a[2], volume.shape.shader_id() as f32];
+        }
+        (positions, extents, albedos)
+    }
+}
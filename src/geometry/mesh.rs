@@ -9,12 +9,23 @@ use crate::geometry::{VertexGroup, MeshVertex};
 use toolbelt::Transform;
 
 
+/// Identifies a mesh across frames, so the renderer can remember where it was last frame. Created
+/// with `PhosphorRenderer::create_mesh_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MeshId(pub(crate) u32);
+
+
 /// A mesh object, made up of a set of vertex groups, a list of associated materials, and a transform.
 ///
 /// See [module-level documentation](self).
 #[derive(Clone)]
 pub struct Mesh {
+    /// If set, `previous_transform` is filled in from the transform this id was queued with last
+    /// frame.
+    pub id: Option<MeshId>,
     pub transform: Transform,
+    /// Transform from the previous frame, used for motion vectors. `None` means the mesh didn't move.
+    pub previous_transform: Option<Transform>,
     pub vertex_groups: Vec<Arc<VertexGroup<MeshVertex>>>,
 }
 
@@ -23,7 +34,9 @@ impl Mesh {
     /// Creates a new mesh with an identity transform and no geometry or materials.
     pub fn new() -> Mesh {
         Mesh {
+            id: None,
             transform: Transform::identity(),
+            previous_transform: None,
             vertex_groups: Vec::new(),
         }
     }
@@ -8,7 +8,7 @@ pub mod mesh;
 pub mod vertex;
 pub mod vertexgroup;
 
-pub use self::mesh::Mesh;
+pub use self::mesh::{Mesh, MeshId};
 pub use self::vertex::{VertexPositionColorAlpha, VertexPosition, MeshVertex, VertexPositionObjectId, VertexPositionUV};
 pub use self::vertexgroup::VertexGroup;
 
@@ -45,3 +45,116 @@ pub mod cube {
         ]
     }
 }
+
+
+pub mod fullscreen {
+    use std::sync::Arc;
+    use vulkano::buffer::BufferUsage;
+    use vulkano::device::Device;
+
+    use crate::buffer::CpuAccessibleBufferXalloc;
+    use crate::geometry::VertexPosition;
+
+    /// Builds a vertex buffer with two triangles covering the whole screen, for fullscreen passes.
+    pub fn vertex_buffer(device: Arc<Device>) -> Arc<CpuAccessibleBufferXalloc<[VertexPosition]>> {
+        CpuAccessibleBufferXalloc::<[VertexPosition]>::from_iter(
+            device, BufferUsage::all(), vec![
+                VertexPosition { position: [ -1.0,  1.0, 1.0 ] },
+                VertexPosition { position: [  1.0,  1.0, 1.0 ] },
+                VertexPosition { position: [  1.0, -1.0, 1.0 ] },
+                VertexPosition { position: [ -1.0,  1.0, 1.0 ] },
+                VertexPosition { position: [  1.0, -1.0, 1.0 ] },
+                VertexPosition { position: [ -1.0, -1.0, 1.0 ] },
+            ].iter().cloned()).expect("failed to create buffer")
+    }
+}
+
+
+/// Closed meshes bounding light volumes. Both are slightly larger than the shape they bound, so
+/// the flat faces never cut into it.
+pub mod volumes {
+    use std::sync::Arc;
+    use std::f32::consts::PI;
+    use vulkano::buffer::BufferUsage;
+    use vulkano::device::Device;
+
+    use crate::buffer::CpuAccessibleBufferXalloc;
+    use crate::geometry::VertexPosition;
+
+    const SPHERE_STACKS: u32 = 8;
+    const SPHERE_SLICES: u32 = 12;
+    const CONE_SLICES: u32 = 16;
+
+    pub struct VolumeMesh {
+        pub vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+        pub index_buffer: Arc<CpuAccessibleBufferXalloc<[u32]>>,
+    }
+
+    impl VolumeMesh {
+        fn new(device: Arc<Device>, vertices: Vec<VertexPosition>, indices: Vec<u32>) -> Self {
+            Self {
+                vertex_buffer: CpuAccessibleBufferXalloc::from_iter(device.clone(), BufferUsage::all(), vertices.into_iter())
+                    .expect("failed to create buffer"),
+                index_buffer: CpuAccessibleBufferXalloc::from_iter(device, BufferUsage::all(), indices.into_iter())
+                    .expect("failed to create index buffer"),
+            }
+        }
+    }
+
+    /// Sphere enclosing the unit sphere, wound counter-clockwise when seen from outside.
+    pub fn sphere(device: Arc<Device>) -> VolumeMesh {
+        // push the vertices out so the face centers still reach radius 1
+        let scale = 1.0 / ((PI / SPHERE_STACKS as f32).cos() * (PI / SPHERE_SLICES as f32).cos());
+
+        let mut vertices = Vec::new();
+        for stack in 0..=SPHERE_STACKS {
+            let theta = stack as f32 / SPHERE_STACKS as f32 * PI;
+            for slice in 0..=SPHERE_SLICES {
+                let phi = slice as f32 / SPHERE_SLICES as f32 * 2.0 * PI;
+                vertices.push(VertexPosition { position: [
+                    theta.sin() * phi.cos() * scale,
+                    theta.cos() * scale,
+                    theta.sin() * phi.sin() * scale,
+                ] });
+            }
+        }
+
+        let row = SPHERE_SLICES + 1;
+        let mut indices = Vec::new();
+        for stack in 0..SPHERE_STACKS {
+            for slice in 0..SPHERE_SLICES {
+                let a = stack * row + slice;
+                let b = a + row;
+                indices.extend_from_slice(&[a, a + 1, b, a + 1, b + 1, b]);
+            }
+        }
+
+        VolumeMesh::new(device, vertices, indices)
+    }
+
+    /// Cone with its apex at the origin, opening along -Z to a base of radius 1 at z = -1, wound
+    /// counter-clockwise when seen from outside. Scale x and y by `range * tan(angle)` and z by
+    /// `range` to bound a spot light.
+    pub fn cone(device: Arc<Device>) -> VolumeMesh {
+        let scale = 1.0 / (PI / CONE_SLICES as f32).cos();
+
+        let mut vertices = vec![
+            VertexPosition { position: [0.0, 0.0, 0.0] },
+            VertexPosition { position: [0.0, 0.0, -1.0] },
+        ];
+        for slice in 0..CONE_SLICES {
+            let phi = slice as f32 / CONE_SLICES as f32 * 2.0 * PI;
+            vertices.push(VertexPosition { position: [phi.cos() * scale, phi.sin() * scale, -1.0] });
+        }
+
+        let mut indices = Vec::new();
+        for slice in 0..CONE_SLICES {
+            let a = 2 + slice;
+            let b = 2 + (slice + 1) % CONE_SLICES;
+            // side, then base
+            indices.extend_from_slice(&[0, a, b, 1, b, a]);
+        }
+
+        VolumeMesh::new(device, vertices, indices)
+    }
+}
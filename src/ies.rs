@@ -0,0 +1,466 @@
+//! IES LM-63 photometric profiles.
+//!
+//! Parses the 1995 and 2002 revisions of the format. Only type C photometry (the one used by
+//! practically all architectural fixtures) is supported. Tilt data is parsed but ignored.
+//!
+//! A profile is resampled into an `IES_TEXTURE_SIZE` lookup table, with the vertical angle
+//! (0 pointing along the light's direction) along u and the horizontal angle around the light's
+//! direction along v. Values are normalized to the profile's peak candela, so the light's
+//! intensity still sets the brightness and the profile only shapes it.
+
+use std::{error, fmt, fs, io};
+use std::path::Path;
+use std::sync::Arc;
+use half::f16;
+use vulkano::device::Queue;
+use vulkano::format::R16Sfloat;
+use vulkano::image::{Dimensions, ImmutableImage};
+use vulkano::sync::GpuFuture;
+
+
+/// Width and height of a profile's lookup table. Width covers vertical angles 0-180 degrees,
+/// height covers horizontal angles 0-360 degrees.
+pub const IES_TEXTURE_SIZE: [u32; 2] = [128, 64];
+
+
+/// Handle for a profile added to the renderer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IesProfileId(pub(crate) u32);
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IesVersion {
+    Lm63_1995,
+    Lm63_2002,
+}
+
+
+#[derive(Debug)]
+pub enum IesError {
+    Io(io::Error),
+    /// The first line isn't a known LM-63 header.
+    UnknownVersion(String),
+    /// There's no `TILT=` line.
+    MissingTilt,
+    /// The file ended before all values were read.
+    UnexpectedEnd,
+    /// A value that should be a number isn't.
+    InvalidNumber(String),
+    /// Type A or B photometry.
+    UnsupportedPhotometricType(u32),
+    /// Angles are missing, out of order or out of range.
+    InvalidAngles,
+}
+
+impl error::Error for IesError {}
+
+impl fmt::Display for IesError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            IesError::Io(e) => write!(fmt, "failed to read IES file: {}", e),
+            IesError::UnknownVersion(header) => write!(fmt, "unknown IES header: {}", header),
+            IesError::MissingTilt => write!(fmt, "IES file has no TILT line"),
+            IesError::UnexpectedEnd => write!(fmt, "IES file ended unexpectedly"),
+            IesError::InvalidNumber(s) => write!(fmt, "invalid number in IES file: {}", s),
+            IesError::UnsupportedPhotometricType(t) => write!(fmt, "unsupported IES photometric type {}, only type C is supported", t),
+            IesError::InvalidAngles => write!(fmt, "IES file has invalid angles"),
+        }
+    }
+}
+
+impl From<io::Error> for IesError {
+    fn from(e: io::Error) -> Self { IesError::Io(e) }
+}
+
+
+/// A parsed photometric profile.
+#[derive(Debug, Clone)]
+pub struct IesProfile {
+    pub version: IesVersion,
+    /// `[KEYWORD] value` lines from the header, without the brackets.
+    pub keywords: Vec<(String, String)>,
+    /// Lumens per lamp, or `None` for absolute photometry.
+    pub lumens_per_lamp: Option<f32>,
+    /// Vertical angles in degrees, increasing, from 0 at nadir.
+    pub vertical_angles: Vec<f32>,
+    /// Horizontal angles in degrees, increasing. The last angle gives the symmetry: 0 for
+    /// rotationally symmetric, 90 for quadrant symmetric, 180 for bilaterally symmetric.
+    pub horizontal_angles: Vec<f32>,
+    /// Candela for each horizontal angle, then each vertical angle. Multipliers are applied.
+    pub candela: Vec<Vec<f32>>,
+}
+
+impl IesProfile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, IesError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, IesError> {
+        let mut lines = text.lines();
+
+        let header = lines.next().unwrap_or("").trim();
+        let version = match header {
+            "IESNA:LM-63-1995" => IesVersion::Lm63_1995,
+            "IESNA:LM-63-2002" => IesVersion::Lm63_2002,
+            _ => return Err(IesError::UnknownVersion(header.to_string())),
+        };
+
+        let mut keywords = Vec::new();
+        let tilt = loop {
+            let line = lines.next().ok_or(IesError::MissingTilt)?.trim();
+            if line.starts_with("TILT=") {
+                break line["TILT=".len()..].trim().to_string();
+            }
+            if line.starts_with('[') {
+                if let Some(end) = line.find(']') {
+                    keywords.push((line[1..end].to_string(), line[end + 1..].trim().to_string()));
+                }
+            }
+        };
+
+        // everything after the tilt line is whitespace or comma separated numbers
+        let rest: Vec<&str> = lines.collect();
+        let mut values = rest.iter().flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|s| !s.is_empty());
+        let mut next = || -> Result<f32, IesError> {
+            let s = values.next().ok_or(IesError::UnexpectedEnd)?;
+            s.parse::<f32>().map_err(|_| IesError::InvalidNumber(s.to_string()))
+        };
+
+        if tilt == "INCLUDE" {
+            // lamp to luminaire geometry, then angle and multiplier pairs
+            let _geometry = next()?;
+            let pairs = next()? as usize;
+            for _ in 0..pairs * 2 { next()?; }
+        }
+
+        let _lamp_count = next()?;
+        let lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let vertical_count = next()? as usize;
+        let horizontal_count = next()? as usize;
+        let photometric_type = next()? as u32;
+        let _units = next()?;
+        let _width = next()?;
+        let _length = next()?;
+        let _height = next()?;
+        let ballast_factor = next()?;
+        // ballast-lamp photometric factor in 1995, unused in 2002
+        let lamp_factor = match version {
+            IesVersion::Lm63_1995 => next()?,
+            IesVersion::Lm63_2002 => { next()?; 1.0 },
+        };
+        let _input_watts = next()?;
+
+        if photometric_type != 1 {
+            return Err(IesError::UnsupportedPhotometricType(photometric_type));
+        }
+
+        let vertical_angles = (0..vertical_count).map(|_| next()).collect::<Result<Vec<_>, _>>()?;
+        let horizontal_angles = (0..horizontal_count).map(|_| next()).collect::<Result<Vec<_>, _>>()?;
+        let scale = multiplier * ballast_factor * lamp_factor;
+        let candela = (0..horizontal_count).map(|_| {
+            (0..vertical_count).map(|_| next().map(|c| c * scale)).collect::<Result<Vec<_>, _>>()
+        }).collect::<Result<Vec<_>, _>>()?;
+
+        let increasing = |angles: &Vec<f32>| angles.windows(2).all(|w| w[0] < w[1]);
+        if vertical_angles.is_empty() || horizontal_angles.is_empty()
+            || !increasing(&vertical_angles) || !increasing(&horizontal_angles)
+            || vertical_angles[0] < 0.0 || *vertical_angles.last().unwrap() > 180.0
+            || horizontal_angles[0] < 0.0 || *horizontal_angles.last().unwrap() > 360.0 {
+            return Err(IesError::InvalidAngles);
+        }
+
+        Ok(Self {
+            version,
+            keywords,
+            lumens_per_lamp: if lumens_per_lamp < 0.0 { None } else { Some(lumens_per_lamp) },
+            vertical_angles,
+            horizontal_angles,
+            candela,
+        })
+    }
+
+    /// Highest candela value in the profile.
+    pub fn max_candela(&self) -> f32 {
+        self.candela.iter().flat_map(|row| row.iter()).cloned().fold(0.0, f32::max)
+    }
+
+    /// Candela at the given angles in degrees, using the profile's symmetry and interpolating
+    /// between the measured angles. Zero outside the measured vertical range, or where the
+    /// profile has no data.
+    pub fn candela_at(&self, vertical: f32, horizontal: f32) -> f32 {
+        // the fields are public, so they might not have been validated by `parse`
+        let (first_vertical, last_vertical, last_horizontal) = match (self.vertical_angles.first(),
+                                                                      self.vertical_angles.last(),
+                                                                      self.horizontal_angles.last()) {
+            (Some(first), Some(last), Some(last_horizontal)) => (*first, *last, *last_horizontal),
+            _ => return 0.0,
+        };
+        if vertical < first_vertical || vertical > last_vertical {
+            return 0.0;
+        }
+
+        let horizontal = horizontal.rem_euclid(360.0);
+        let horizontal = match last_horizontal as u32 {
+            0 => 0.0,
+            90 => {
+                let h = horizontal % 180.0;
+                if h > 90.0 { 180.0 - h } else { h }
+            },
+            180 => if horizontal > 180.0 { 360.0 - horizontal } else { horizontal },
+            _ => horizontal,
+        };
+
+        let (h0, h1, ht) = bracket(&self.horizontal_angles, horizontal);
+        let (v0, v1, vt) = bracket(&self.vertical_angles, vertical);
+        let value = |h: usize, v: usize| self.candela.get(h).and_then(|row| row.get(v)).cloned().unwrap_or(0.0);
+        let row = |h: usize| value(h, v0) * (1.0 - vt) + value(h, v1) * vt;
+        row(h0) * (1.0 - ht) + row(h1) * ht
+    }
+
+    /// Resamples the profile into an `IES_TEXTURE_SIZE` table normalized to the peak candela.
+    pub fn lookup_table(&self) -> Vec<f32> {
+        let [width, height] = IES_TEXTURE_SIZE;
+        let max = self.max_candela().max(1e-6);
+        let mut table = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            let horizontal = (y as f32 + 0.5) / height as f32 * 360.0;
+            for x in 0..width {
+                let vertical = x as f32 / (width - 1) as f32 * 180.0;
+                table.push(self.candela_at(vertical, horizontal) / max);
+            }
+        }
+        table
+    }
+}
+
+/// Indices of the angles on either side of `angle` and the interpolation factor between them.
+/// Angles past the last one wrap around to the first, for full 360 degree horizontal tables.
+fn bracket(angles: &[f32], angle: f32) -> (usize, usize, f32) {
+    if angles.len() == 1 || angle <= angles[0] {
+        return (0, 0, 0.0);
+    }
+    match angles.iter().position(|a| *a >= angle) {
+        Some(i) => {
+            let t = (angle - angles[i - 1]) / (angles[i] - angles[i - 1]);
+            (i - 1, i, t)
+        },
+        None => {
+            let last = angles.len() - 1;
+            let span = angles[0] + 360.0 - angles[last];
+            if span <= 0.0 { (last, last, 0.0) } else { (last, 0, (angle - angles[last]) / span) }
+        },
+    }
+}
+
+
+/// Lookup tables for every profile added to the renderer, as layers of one texture array. A
+/// profile's layer is its id.
+pub struct IesProfiles {
+    tables: Vec<Vec<f32>>,
+    pub texture: Arc<ImmutableImage<R16Sfloat>>,
+}
+
+impl IesProfiles {
+    pub fn new(queue: Arc<Queue>) -> Self {
+        Self {
+            tables: Vec::new(),
+            texture: Self::create_texture(&[], queue),
+        }
+    }
+
+    pub fn add(&mut self, profile: &IesProfile, queue: Arc<Queue>) -> IesProfileId {
+        let id = IesProfileId(self.tables.len() as u32);
+        self.tables.push(profile.lookup_table());
+        self.texture = Self::create_texture(&self.tables, queue);
+        id
+    }
+
+    /// Uploads the tables. Blocks until the upload finishes.
+    fn create_texture(tables: &[Vec<f32>], queue: Arc<Queue>) -> Arc<ImmutableImage<R16Sfloat>> {
+        let [width, height] = IES_TEXTURE_SIZE;
+        // images can't have zero layers, so without any profiles there's one unused layer
+        let data: Vec<f16> = if tables.is_empty() {
+            vec![f16::from_f32(1.0); (width * height) as usize]
+        }
+        else {
+            tables.iter().flat_map(|table| table.iter().map(|v| f16::from_f32(*v))).collect()
+        };
+        let (texture, future) = ImmutableImage::from_iter(
+            data.into_iter(),
+            Dimensions::Dim2dArray { width, height, array_layers: tables.len().max(1) as u32 },
+            R16Sfloat,
+            queue).unwrap();
+        future.then_signal_fence_and_flush().unwrap().wait(None).unwrap();
+        texture
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{bracket, IesError, IesProfile, IesVersion};
+
+    const LM63_1995: &str = include_str!("../tests/fixtures/ies/lm63_1995.ies");
+    const LM63_2002: &str = include_str!("../tests/fixtures/ies/lm63_2002.ies");
+    const TILT_INCLUDE: &str = include_str!("../tests/fixtures/ies/tilt_include.ies");
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn parse_1995() {
+        let profile = IesProfile::parse(LM63_1995).unwrap();
+        assert_eq!(profile.version, IesVersion::Lm63_1995);
+        assert_eq!(profile.keywords, vec![
+            ("TEST".to_string(), "Rotationally symmetric downlight".to_string()),
+            ("MANUFAC".to_string(), "Phosphor".to_string()),
+        ]);
+        assert_eq!(profile.lumens_per_lamp, Some(1000.0));
+        assert_eq!(profile.vertical_angles, vec![0.0, 45.0, 90.0]);
+        assert_eq!(profile.horizontal_angles, vec![0.0]);
+        // multiplier 2, ballast factor 0.5 and ballast-lamp factor 0.8
+        assert_eq!(profile.candela.len(), 1);
+        for (actual, expected) in profile.candela[0].iter().zip(&[80.0, 64.0, 16.0]) {
+            assert_close(*actual, *expected);
+        }
+        assert_close(profile.max_candela(), 80.0);
+    }
+
+    #[test]
+    fn parse_2002() {
+        let profile = IesProfile::parse(LM63_2002).unwrap();
+        assert_eq!(profile.version, IesVersion::Lm63_2002);
+        assert_eq!(profile.keywords.len(), 2);
+        // -1 lumens means absolute photometry
+        assert_eq!(profile.lumens_per_lamp, None);
+        assert_eq!(profile.vertical_angles.len(), 3);
+        assert_eq!(profile.horizontal_angles, vec![0.0, 90.0, 180.0]);
+        // multiplier 1.5 and ballast factor 0.5, the 2002 "future use" field is ignored
+        assert_eq!(profile.candela.len(), 3);
+        assert_close(profile.candela[0][0], 75.0);
+        assert_close(profile.candela[1][1], 75.0);
+        assert_close(profile.candela[2][0], 225.0);
+    }
+
+    #[test]
+    fn parse_tilt_include() {
+        let profile = IesProfile::parse(TILT_INCLUDE).unwrap();
+        assert_eq!(profile.version, IesVersion::Lm63_2002);
+        assert_eq!(profile.lumens_per_lamp, Some(500.0));
+        assert_eq!(profile.vertical_angles, vec![0.0, 90.0]);
+        assert_eq!(profile.horizontal_angles, vec![0.0, 90.0, 180.0, 270.0]);
+        assert_eq!(profile.candela.len(), 4);
+        assert_close(profile.candela[3][0], 40.0);
+        assert_close(profile.candela[3][1], 20.0);
+    }
+
+    #[test]
+    fn rotational_symmetry() {
+        let profile = IesProfile::parse(LM63_1995).unwrap();
+        for horizontal in &[0.0, 90.0, 180.0, 360.0, -45.0] {
+            assert_close(profile.candela_at(45.0, *horizontal), 64.0);
+        }
+        assert_close(profile.candela_at(22.5, 0.0), 72.0);
+        // outside the measured vertical range
+        assert_close(profile.candela_at(135.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn quadrant_symmetry() {
+        let profile = IesProfile {
+            version: IesVersion::Lm63_2002,
+            keywords: Vec::new(),
+            lumens_per_lamp: None,
+            vertical_angles: vec![0.0],
+            horizontal_angles: vec![0.0, 90.0],
+            candela: vec![vec![10.0], vec![20.0]],
+        };
+        assert_close(profile.candela_at(0.0, 0.0), 10.0);
+        assert_close(profile.candela_at(0.0, 90.0), 20.0);
+        assert_close(profile.candela_at(0.0, 135.0), 15.0);
+        assert_close(profile.candela_at(0.0, 180.0), 10.0);
+        assert_close(profile.candela_at(0.0, 270.0), 20.0);
+        assert_close(profile.candela_at(0.0, 360.0), 10.0);
+    }
+
+    #[test]
+    fn bilateral_symmetry() {
+        let profile = IesProfile::parse(LM63_2002).unwrap();
+        assert_close(profile.candela_at(0.0, 0.0), 75.0);
+        assert_close(profile.candela_at(0.0, 45.0), 112.5);
+        assert_close(profile.candela_at(0.0, 90.0), 150.0);
+        assert_close(profile.candela_at(0.0, 180.0), 225.0);
+        assert_close(profile.candela_at(0.0, 270.0), 150.0);
+        assert_close(profile.candela_at(0.0, 360.0), 75.0);
+    }
+
+    #[test]
+    fn full_horizontal_range_wraps_around() {
+        let profile = IesProfile::parse(TILT_INCLUDE).unwrap();
+        assert_close(profile.candela_at(0.0, 270.0), 40.0);
+        // halfway between 270 and 360, which is the 0 degree row again
+        assert_close(profile.candela_at(0.0, 315.0), 25.0);
+        assert_close(profile.candela_at(90.0, 315.0), 12.5);
+    }
+
+    #[test]
+    fn bracket_angles() {
+        let angles = [0.0, 90.0, 180.0, 270.0];
+        assert_eq!(bracket(&angles, 0.0), (0, 0, 0.0));
+        assert_eq!(bracket(&angles, 45.0), (0, 1, 0.5));
+        assert_eq!(bracket(&angles, 180.0), (1, 2, 1.0));
+        assert_eq!(bracket(&angles, 315.0), (3, 0, 0.5));
+        assert_eq!(bracket(&[30.0], 200.0), (0, 0, 0.0));
+        // covers the whole circle, so there's nothing to wrap to
+        assert_eq!(bracket(&[0.0, 360.0], 400.0), (1, 1, 0.0));
+    }
+
+    #[test]
+    fn empty_profile_has_no_candela() {
+        let profile = IesProfile {
+            version: IesVersion::Lm63_2002,
+            keywords: Vec::new(),
+            lumens_per_lamp: None,
+            vertical_angles: Vec::new(),
+            horizontal_angles: Vec::new(),
+            candela: Vec::new(),
+        };
+        assert_eq!(profile.candela_at(0.0, 0.0), 0.0);
+        assert_eq!(profile.lookup_table().iter().cloned().fold(0.0, f32::max), 0.0);
+    }
+
+    #[test]
+    fn errors() {
+        match IesProfile::parse("IESNA:LM-63-1991\nTILT=NONE") {
+            Err(IesError::UnknownVersion(header)) => assert_eq!(header, "IESNA:LM-63-1991"),
+            other => panic!("expected UnknownVersion, got {:?}", other),
+        }
+        match IesProfile::parse(&LM63_1995.replace("TILT=NONE", "")) {
+            Err(IesError::MissingTilt) => {},
+            other => panic!("expected MissingTilt, got {:?}", other),
+        }
+        match IesProfile::parse(LM63_1995.trim_end().trim_end_matches("20")) {
+            Err(IesError::UnexpectedEnd) => {},
+            other => panic!("expected UnexpectedEnd, got {:?}", other),
+        }
+        match IesProfile::parse(&LM63_1995.replace("100 80 20", "100 eighty 20")) {
+            Err(IesError::InvalidNumber(value)) => assert_eq!(value, "eighty"),
+            other => panic!("expected InvalidNumber, got {:?}", other),
+        }
+        match IesProfile::parse(&LM63_1995.replace("1 1000 2 3 1 1", "1 1000 2 3 1 2")) {
+            Err(IesError::UnsupportedPhotometricType(2)) => {},
+            other => panic!("expected UnsupportedPhotometricType, got {:?}", other),
+        }
+        match IesProfile::parse(&LM63_1995.replace("0 45 90", "0 90 45")) {
+            Err(IesError::InvalidAngles) => {},
+            other => panic!("expected InvalidAngles, got {:?}", other),
+        }
+        match IesProfile::parse(&LM63_1995.replace("0 45 90", "0 45 190")) {
+            Err(IesError::InvalidAngles) => {},
+            other => panic!("expected InvalidAngles, got {:?}", other),
+        }
+    }
+}
@@ -9,15 +9,25 @@ extern crate imgui;
 
 pub mod buffer;
 pub mod camera;
+pub mod color_grading;
 pub mod compute;
 pub mod cpu_pool;
+pub mod environment;
+pub mod fog;
 pub mod geometry;
+pub mod ies;
+pub mod light;
+pub mod ltc;
 pub mod memory;
+pub mod reflection_probe;
 #[macro_use] mod names;
 // pub mod pipeline;
 pub mod renderer;
 pub mod renderpass;
 pub mod shader;
+pub mod sky;
+pub mod subsurface;
+pub mod time_of_day;
 pub mod vulkano_win;
 pub mod stage;
 pub mod material;
@@ -0,0 +1,333 @@
+//! Light types.
+//!
+//! Lights are added to the renderer with `PhosphorRenderer::add_light`, which returns a `LightId`
+//! for updating or removing the light later. Every frame the lights are uploaded to a storage
+//! buffer. Directional lights are shaded by the fullscreen lighting pass, and local lights (point,
+//! spot and area lights) are culled according to `LocalLightCulling`.
+//!
+//! Sphere and tube lights use the representative point approximation (Karis 2013): specular
+//! shading uses the point on the light closest to the reflection ray, with the GGX lobe
+//! renormalized for the wider solid angle. Rectangle lights are integrated with linearly
+//! transformed cosines (Heitz et al. 2016), using the lookup tables in `ltc`.
+
+use std::sync::Arc;
+use cgmath::{Vector3, InnerSpace, Rad};
+use hashbrown::HashMap;
+use vulkano::buffer::BufferUsage;
+use vulkano::device::Device;
+
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::ies::IesProfileId;
+use crate::stage::local_shadow::LocalShadowData;
+
+
+/// The sun. Rendered as a directional light with cascaded shadow maps.
+#[derive(Debug, Clone)]
+pub struct SunLight {
+    /// Direction the light travels in, i.e. pointing away from the sun.
+    pub direction: Vector3<f32>,
+    pub color: Vector3<f32>,
+    /// Scale applied to `color`.
+    pub intensity: f32,
+}
+impl Default for SunLight {
+    fn default() -> Self {
+        Self {
+            direction: Vector3::new(0.5, -1.0, 0.5).normalize(),
+            color: Vector3::new(1.0, 1.0, 0.9),
+            intensity: 5.0,
+        }
+    }
+}
+
+
+/// Projection used for a local light's shadow map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShadowCasterKind {
+    /// Omnidirectional, rendered as six cube faces.
+    Point,
+    /// Single perspective shadow map covering the cone.
+    Spot {
+        /// Half-angle of the cone.
+        outer_angle: Rad<f32>,
+    },
+}
+
+/// A local light that casts shadows into the shared shadow atlas.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalShadowCaster {
+    /// Identifies the light between frames, for caching.
+    pub id: u32,
+    pub kind: ShadowCasterKind,
+    pub position: Vector3<f32>,
+    /// Direction the light points, ignored for point lights.
+    pub direction: Vector3<f32>,
+    /// Distance where the light's influence ends. Also the shadow far plane.
+    pub range: f32,
+    /// Multiplier on the screen coverage when choosing shadow map resolution.
+    pub priority: f32,
+    /// Static lights keep their shadow map from the previous frame as long as the light doesn't
+    /// change. Geometry moving through a static light won't update its shadows.
+    pub is_static: bool,
+}
+
+
+/// Handle for a light added to the renderer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LightId(pub(crate) u32);
+
+
+/// Light shapes. Must match the `LIGHT_KIND_*` constants in `lights.inc`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+    Point,
+    Spot {
+        /// Half-angle where the falloff starts.
+        inner_angle: Rad<f32>,
+        /// Half-angle where the light ends.
+        outer_angle: Rad<f32>,
+    },
+    /// Infinitely far away light, like the sun. `position` and `range` are ignored.
+    Directional,
+    /// Spherical area light centered on `position`.
+    Sphere {
+        radius: f32,
+    },
+    /// Capsule shaped area light centered on `position`, with its axis along `direction`.
+    Tube {
+        radius: f32,
+        /// Length of the axis, not counting the rounded ends.
+        length: f32,
+    },
+    /// One-sided rectangular area light centered on `position`, emitting along `direction`. Its
+    /// width runs along `cross(up, direction)`, with up being +Y, or +Z for vertical lights.
+    Rect {
+        width: f32,
+        height: f32,
+    },
+}
+
+impl LightKind {
+    pub fn shader_id(&self) -> u32 {
+        match self {
+            LightKind::Point => 0,
+            LightKind::Spot { .. } => 1,
+            LightKind::Directional => 2,
+            LightKind::Sphere { .. } => 3,
+            LightKind::Tube { .. } => 4,
+            LightKind::Rect { .. } => 5,
+        }
+    }
+
+    /// Distance from the light's position to the farthest point of its surface.
+    pub fn extent(&self) -> f32 {
+        match *self {
+            LightKind::Sphere { radius } => radius,
+            LightKind::Tube { radius, length } => radius + length * 0.5,
+            LightKind::Rect { width, height } => (width * width + height * height).sqrt() * 0.5,
+            _ => 0.0,
+        }
+    }
+}
+
+
+/// How local lights are limited to the pixels they can reach.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocalLightCulling {
+    /// Every light is evaluated for every pixel in the fullscreen lighting pass.
+    None,
+    /// Each light rasterizes a bounding sphere or cone, and only shades the pixels it covers.
+    Volumes,
+    /// Lights are sorted into per-cluster lists by a compute pass, and the lighting pass only
+    /// loops over the lights in each pixel's cluster. Scales to thousands of lights.
+    Clustered,
+}
+impl Default for LocalLightCulling {
+    fn default() -> Self { LocalLightCulling::Volumes }
+}
+
+impl LocalLightCulling {
+    /// Matching `LIGHT_CULLING_*` value in `lights.inc`.
+    pub fn shader_id(&self) -> u32 {
+        match self {
+            LocalLightCulling::None => 0,
+            LocalLightCulling::Volumes => 1,
+            LocalLightCulling::Clustered => 2,
+        }
+    }
+}
+
+
+/// A light in the scene.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: Vector3<f32>,
+    /// Scale applied to `color`. Luminous intensity for point, spot, sphere and tube lights, and
+    /// luminance of the surface for rectangle lights.
+    pub intensity: f32,
+    /// Distance from the light's surface where its influence ends. Ignored for directional
+    /// lights.
+    pub range: f32,
+    pub position: Vector3<f32>,
+    /// Direction the light points. Ignored for point and sphere lights.
+    pub direction: Vector3<f32>,
+    /// Local lights only. Directional lights other than the sun don't cast shadows. Area lights
+    /// cast shadows from their center.
+    pub casts_shadows: bool,
+    /// Multiplier on the screen coverage when choosing shadow map resolution.
+    pub shadow_priority: f32,
+    /// See `LocalShadowCaster::is_static`.
+    pub is_static: bool,
+    /// Photometric profile shaping the light's intensity by angle, for point, spot, sphere and
+    /// tube lights.
+    /// Vertical angle 0 points along `direction`.
+    pub ies_profile: Option<IesProfileId>,
+}
+
+impl Light {
+    pub fn point(position: Vector3<f32>, color: Vector3<f32>, intensity: f32, range: f32) -> Self {
+        Self {
+            kind: LightKind::Point,
+            color,
+            intensity,
+            range,
+            position,
+            direction: Vector3::new(0.0, -1.0, 0.0),
+            casts_shadows: false,
+            shadow_priority: 1.0,
+            is_static: false,
+            ies_profile: None,
+        }
+    }
+
+    pub fn spot(position: Vector3<f32>, direction: Vector3<f32>, inner_angle: Rad<f32>, outer_angle: Rad<f32>,
+                color: Vector3<f32>, intensity: f32, range: f32) -> Self {
+        Self {
+            kind: LightKind::Spot { inner_angle, outer_angle },
+            direction: direction.normalize(),
+            ..Self::point(position, color, intensity, range)
+        }
+    }
+
+    pub fn directional(direction: Vector3<f32>, color: Vector3<f32>, intensity: f32) -> Self {
+        Self {
+            kind: LightKind::Directional,
+            direction: direction.normalize(),
+            ..Self::point(Vector3::new(0.0, 0.0, 0.0), color, intensity, 0.0)
+        }
+    }
+
+    pub fn sphere(position: Vector3<f32>, radius: f32, color: Vector3<f32>, intensity: f32, range: f32) -> Self {
+        Self {
+            kind: LightKind::Sphere { radius },
+            ..Self::point(position, color, intensity, range)
+        }
+    }
+
+    pub fn tube(position: Vector3<f32>, axis: Vector3<f32>, radius: f32, length: f32,
+                color: Vector3<f32>, intensity: f32, range: f32) -> Self {
+        Self {
+            kind: LightKind::Tube { radius, length },
+            direction: axis.normalize(),
+            ..Self::point(position, color, intensity, range)
+        }
+    }
+
+    pub fn rect(position: Vector3<f32>, direction: Vector3<f32>, width: f32, height: f32,
+                color: Vector3<f32>, intensity: f32, range: f32) -> Self {
+        Self {
+            kind: LightKind::Rect { width, height },
+            direction: direction.normalize(),
+            ..Self::point(position, color, intensity, range)
+        }
+    }
+
+    /// Radius of the sphere around `position` that contains everything the light can reach.
+    pub fn influence_radius(&self) -> f32 {
+        self.range + self.kind.extent()
+    }
+
+    /// Shadow caster for this light, if it casts shadows into the local shadow atlas.
+    pub fn shadow_caster(&self, id: LightId) -> Option<LocalShadowCaster> {
+        if !self.casts_shadows { return None; }
+        let kind = match self.kind {
+            LightKind::Spot { outer_angle, .. } => ShadowCasterKind::Spot { outer_angle },
+            LightKind::Directional => return None,
+            _ => ShadowCasterKind::Point,
+        };
+        Some(LocalShadowCaster {
+            id: id.0,
+            kind,
+            position: self.position,
+            direction: self.direction,
+            range: self.influence_radius(),
+            priority: self.shadow_priority,
+            is_static: self.is_static,
+        })
+    }
+
+    /// Shader layout of this light. `shadow_tile` is its first tile in the local shadow atlas.
+    pub fn to_gpu(&self, shadow_tile: Option<u32>) -> GpuLight {
+        let shape = match self.kind {
+            LightKind::Spot { inner_angle, outer_angle } => [inner_angle.0.cos(), outer_angle.0.cos()],
+            LightKind::Sphere { radius } => [radius, 0.0],
+            LightKind::Tube { radius, length } => [radius, length * 0.5],
+            LightKind::Rect { width, height } => [width * 0.5, height * 0.5],
+            _ => [-1.0, -1.0],
+        };
+        GpuLight {
+            position: self.position.into(),
+            range: self.range,
+            direction: self.direction.into(),
+            kind: self.kind.shader_id(),
+            color: (self.color * self.intensity).into(),
+            shadow_tile: shadow_tile.map(|t| t as i32).unwrap_or(-1),
+            shape_params: [shape[0], shape[1], self.ies_profile.map(|p| p.0 as f32).unwrap_or(-1.0), 0.0],
+        }
+    }
+}
+
+
+/// A light in the lights storage buffer. Must match `LightData` in `lights.inc`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GpuLight {
+    pub position: [f32; 3],
+    pub range: f32,
+    pub direction: [f32; 3],
+    pub kind: u32,
+    /// Color times intensity.
+    pub color: [f32; 3],
+    /// First tile in the local shadow atlas, or -1.
+    pub shadow_tile: i32,
+    /// Shape in x and y: cosines of the inner and outer angles for spot lights, radius for
+    /// sphere lights, radius and half length for tube lights, half width and half height for
+    /// rectangle lights. IES profile layer or -1 in z.
+    pub shape_params: [f32; 4],
+}
+
+/// Lights in the order the lighting pass expects them, directional lights first. Also returns
+/// the number of directional lights.
+pub fn ordered_lights(lights: &HashMap<LightId, Light>) -> (Vec<(&LightId, &Light)>, u32) {
+    let mut ordered: Vec<_> = lights.iter().filter(|(_, light)| light.kind == LightKind::Directional).collect();
+    let directional_count = ordered.len() as u32;
+    ordered.extend(lights.iter().filter(|(_, light)| light.kind != LightKind::Directional));
+    (ordered, directional_count)
+}
+
+/// Uploads lights to a storage buffer, in iteration order. Shadow tiles are looked up in
+/// `shadows`. The buffer always has at least one element, since zero sized buffers aren't
+/// allowed, so shaders need the light count separately.
+pub fn light_buffer<'a, I>(device: Arc<Device>, lights: I, shadows: &LocalShadowData) -> Arc<CpuAccessibleBufferXalloc<[GpuLight]>>
+    where I: Iterator<Item = (&'a LightId, &'a Light)>
+{
+    let mut gpu_lights: Vec<GpuLight> = lights
+        .map(|(id, light)| light.to_gpu(shadows.first_tile.get(&id.0).cloned()))
+        .collect();
+    if gpu_lights.is_empty() {
+        gpu_lights.push(Light::point(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0), 0.0, 0.0).to_gpu(None));
+    }
+    CpuAccessibleBufferXalloc::from_iter(device, BufferUsage::storage_buffer(), gpu_lights.into_iter())
+        .expect("failed to create buffer")
+}
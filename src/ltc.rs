@@ -0,0 +1,59 @@
+//! Linearly transformed cosine lookup tables for rectangle lights.
+//!
+//! The tables are fitted offline by `tools/ltc_fit.rs` and bundled from `assets/ltc`. Both are
+//! indexed by roughness along u and `sqrt(1 - dot(N, V))` along v. Sampling is in
+//! `area_lights.inc`.
+
+use std::sync::Arc;
+use half::f16;
+use vulkano::device::Queue;
+use vulkano::format::{R16G16B16A16Sfloat, R16G16Sfloat};
+use vulkano::image::{Dimensions, ImmutableImage};
+use vulkano::sync::GpuFuture;
+
+
+/// Width and height of both tables.
+pub const LTC_LUT_SIZE: u32 = 64;
+
+const MATRIX_TABLE: &[u8] = include_bytes!("../assets/ltc/ltc_matrix.bin");
+const AMPLITUDE_TABLE: &[u8] = include_bytes!("../assets/ltc/ltc_amplitude.bin");
+
+
+pub struct LtcTables {
+    /// The four non-trivial elements of the inverse transform.
+    pub matrix: Arc<ImmutableImage<R16G16B16A16Sfloat>>,
+    /// Directional albedo of the GGX lobe, and its Fresnel weighted part.
+    pub amplitude: Arc<ImmutableImage<R16G16Sfloat>>,
+}
+
+impl LtcTables {
+    /// Uploads the tables. Blocks until the upload finishes.
+    pub fn new(queue: Arc<Queue>) -> Self {
+        let dimensions = Dimensions::Dim2d { width: LTC_LUT_SIZE, height: LTC_LUT_SIZE };
+
+        let matrix_data = read_f32s(MATRIX_TABLE);
+        let (matrix, matrix_future) = ImmutableImage::from_iter(
+            matrix_data.chunks(4).map(|c| [f16::from_f32(c[0]), f16::from_f32(c[1]), f16::from_f32(c[2]), f16::from_f32(c[3])]),
+            dimensions,
+            R16G16B16A16Sfloat,
+            queue.clone()).unwrap();
+
+        let amplitude_data = read_f32s(AMPLITUDE_TABLE);
+        let (amplitude, amplitude_future) = ImmutableImage::from_iter(
+            amplitude_data.chunks(2).map(|c| [f16::from_f32(c[0]), f16::from_f32(c[1])]),
+            dimensions,
+            R16G16Sfloat,
+            queue).unwrap();
+
+        matrix_future.join(amplitude_future).then_signal_fence_and_flush().unwrap().wait(None).unwrap();
+
+        Self {
+            matrix,
+            amplitude,
+        }
+    }
+}
+
+fn read_f32s(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect()
+}
@@ -1,13 +1,18 @@
 use std::sync::Arc;
-use vulkano::device::Device;
+use vulkano::device::Queue;
+use vulkano::format::R8G8B8A8Unorm;
+use vulkano::image::{ImmutableImage, Dimensions};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use vulkano::sync::GpuFuture;
 use vulkano::framebuffer::{Subpass, RenderPassAbstract};
 use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
 use vulkano::descriptor::DescriptorSet;
 
-use crate::geometry::{MeshVertex, VertexPositionUV};
-use crate::material::params::MaterialParams;
+use crate::geometry::{MeshVertex, VertexPosition, VertexPositionUV};
+use crate::material::params::{MaterialParams, MaterialParam};
 use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
 use crate::renderer::RenderInfo;
+use crate::subsurface::SubsurfaceProfileId;
 
 
 pub mod params;
@@ -31,6 +36,9 @@ impl MaterialInstanceStatic {
     pub fn pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> {
         self.definition.pipeline()
     }
+    pub fn subsurface_profile(&self) -> Option<SubsurfaceProfileId> {
+        self.definition.subsurface_profile()
+    }
 }
 
 /// An instance of a dynamic material, i.e. one whose parameters are updated, potentially every frame
@@ -54,6 +62,9 @@ impl MaterialInstanceDynamic {
     pub fn pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> {
         self.definition.pipeline()
     }
+    pub fn subsurface_profile(&self) -> Option<SubsurfaceProfileId> {
+        self.definition.subsurface_profile()
+    }
     pub fn update(&mut self) {
 
     }
@@ -77,6 +88,12 @@ impl MaterialInstance {
             MaterialInstance::Dynamic(inner) => inner.descriptor_sets(),
         }
     }
+    pub fn subsurface_profile(&self) -> Option<SubsurfaceProfileId> {
+        match self {
+            MaterialInstance::Static(inner) => inner.subsurface_profile(),
+            MaterialInstance::Dynamic(inner) => inner.subsurface_profile(),
+        }
+    }
 }
 
 
@@ -87,19 +104,32 @@ pub trait MaterialDefinition {
     fn pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync>;
     fn params_accepted(&self) -> MaterialParams;
     fn static_descriptor_sets(&self) -> Vec<Arc<dyn DescriptorSet + Send + Sync>> { Vec::new() }
+    /// Subsurface scattering profile written into the G-buffer, if the material has one.
+    fn subsurface_profile(&self) -> Option<SubsurfaceProfileId> { None }
 }
 
 
 // Material Implementations ////////////////////////////////////////////////////////////////////////
 
 
+/// 1x1 texture of a single color, as a stand-in for a material's texture. Blocks until it's uploaded.
+fn solid_texture(texel: [u8; 4], queue: Arc<Queue>) -> Arc<ImmutableImage<R8G8B8A8Unorm>> {
+    let (texture, future) = ImmutableImage::from_iter(vec![texel].into_iter(), Dimensions::Dim2d { width: 1, height: 1 },
+                                                      R8G8B8A8Unorm, queue).unwrap();
+    future.then_signal_fence_and_flush().unwrap().wait(None).unwrap();
+    texture
+}
+
 pub struct GenericMeshMaterial {
     pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
     static_descriptor_sets: Vec<Arc<dyn DescriptorSet + Send + Sync>>,
+    subsurface_profile: Option<SubsurfaceProfileId>,
 }
 
 impl GenericMeshMaterial {
-    pub fn new(device: Arc<Device>, pass: Arc<dyn RenderPassAbstract + Send + Sync>, subpass: u32) -> Self {
+    /// Untextured material: white albedo, flat normals, half rough and not metallic.
+    pub fn new(info: &RenderInfo, pass: Arc<dyn RenderPassAbstract + Send + Sync>, subpass: u32) -> Self {
+        let device = info.device.clone();
         let vs = crate::shader::mesh_generic::vertex::Shader::load(device.clone()).expect("failed to create shader module");
         let fs = crate::shader::mesh_generic::fragment::Shader::load(device.clone()).expect("failed to create shader module");
         let pipeline = Arc::new(GraphicsPipeline::start()
@@ -109,19 +139,29 @@ impl GenericMeshMaterial {
             .triangle_list()
             .viewports_dynamic_scissors_irrelevant(1)
             .fragment_shader(fs.main_entry_point(), ())
-            //.depth_stencil_simple_depth()
+            .depth_stencil(info.depth_mode.depth_stencil())
             .render_pass(Subpass::from(pass, subpass).unwrap())
             .build(device.clone())
             .unwrap());
 
-//        let pbr_texture_descriptors = Arc::new(PersistentDescriptorSet::start(pipeline.clone(), 0)
-//           .add_sampled_image(tex_registry.get("grass").unwrap().clone(), linear_sampler.clone()).unwrap()
-//           .add_sampled_image(tex_registry.get("test_normal").unwrap().clone(), linear_sampler.clone()).unwrap()
-//           .add_sampled_image(tex_registry.get("black").unwrap().clone(), linear_sampler.clone()).unwrap()
-//           .add_sampled_image(tex_registry.get("black").unwrap().clone(), linear_sampler.clone()).unwrap()
-//       .build().unwrap());
+        let linear_sampler = Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Linear,
+                                          SamplerAddressMode::Repeat, SamplerAddressMode::Repeat, SamplerAddressMode::Repeat,
+                                          0.0, 4.0, 0.0, 0.0).unwrap();
+        let queue = info.queues.main.clone().unwrap();
+        let pbr_texture_descriptors = Arc::new(PersistentDescriptorSet::start(pipeline.clone(), 0)
+            .add_sampled_image(solid_texture([255, 255, 255, 255], queue.clone()), linear_sampler.clone()).unwrap()
+            .add_sampled_image(solid_texture([128, 128, 255, 255], queue.clone()), linear_sampler.clone()).unwrap()
+            .add_sampled_image(solid_texture([128, 128, 128, 255], queue.clone()), linear_sampler.clone()).unwrap()
+            .add_sampled_image(solid_texture([0, 0, 0, 255], queue), linear_sampler).unwrap()
+            .build().unwrap());
 
-        Self { pipeline, static_descriptor_sets: vec![ ] }
+        Self { pipeline, static_descriptor_sets: vec![ pbr_texture_descriptors ], subsurface_profile: None }
+    }
+
+    /// Marks the material as scattering light under its surface, like skin, wax or leaves.
+    pub fn with_subsurface_profile(mut self, profile: SubsurfaceProfileId) -> Self {
+        self.subsurface_profile = Some(profile);
+        self
     }
 }
 
@@ -137,8 +177,13 @@ impl MaterialDefinition for GenericMeshMaterial {
     fn static_descriptor_sets(&self) -> Vec<Arc<dyn DescriptorSet + Send + Sync>> {
         self.static_descriptor_sets.clone()
     }
+
+    fn subsurface_profile(&self) -> Option<SubsurfaceProfileId> {
+        self.subsurface_profile
+    }
 }
 
+/// Procedural sky, drawn as a fullscreen pass over pixels without geometry.
 pub struct SkyboxMaterial {
     pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
     static_descriptor_sets: Vec<Arc<dyn DescriptorSet + Send + Sync>>
@@ -150,13 +195,11 @@ impl SkyboxMaterial {
         let fs = crate::shader::skybox::fragment::Shader::load(info.device.clone()).expect("failed to create shader module");
         let pipeline = Arc::new(GraphicsPipeline::start()
             .cull_mode_disabled()
-            .vertex_input_single_buffer::<MeshVertex>()
+            .vertex_input_single_buffer::<VertexPosition>()
             .vertex_shader(vs.main_entry_point(), ())
             .triangle_list()
             .viewports_dynamic_scissors_irrelevant(1)
             .fragment_shader(fs.main_entry_point(), ())
-            //.depth_stencil_simple_depth()
-            .blend_alpha_blending()
             .render_pass(Subpass::from(pass, subpass).unwrap())
             .build(info.device.clone())
             .unwrap());
@@ -176,3 +219,42 @@ impl MaterialDefinition for SkyboxMaterial {
         self.static_descriptor_sets.clone()
     }
 }
+
+/// Sky from an equirectangular HDR image, drawn as a fullscreen pass over pixels without
+/// geometry. Samples the renderer's environment, so it has no static descriptor sets; see
+/// `stage::skybox`.
+pub struct HdriSkyboxMaterial {
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl HdriSkyboxMaterial {
+    pub fn new(info: &RenderInfo, pass: Arc<dyn RenderPassAbstract + Send + Sync>, subpass: u32) -> Self {
+        let vs = crate::shader::skybox_hdri::vertex::Shader::load(info.device.clone()).expect("failed to create shader module");
+        let fs = crate::shader::skybox_hdri::fragment::Shader::load(info.device.clone()).expect("failed to create shader module");
+        let pipeline = Arc::new(GraphicsPipeline::start()
+            .cull_mode_disabled()
+            .vertex_input_single_buffer::<VertexPosition>()
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs.main_entry_point(), ())
+            .render_pass(Subpass::from(pass, subpass).unwrap())
+            .build(info.device.clone())
+            .unwrap());
+
+        Self { pipeline }
+    }
+}
+
+impl MaterialDefinition for HdriSkyboxMaterial {
+    fn pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.pipeline }
+
+    /// The `SkyboxSettings` pushed every frame, with their defaults.
+    fn params_accepted(&self) -> MaterialParams {
+        let mut params = MaterialParams::new();
+        params.add("rotation", MaterialParam::Float(0.0));
+        params.add("intensity", MaterialParam::Float(1.0));
+        params.add("blur", MaterialParam::Float(0.0));
+        params
+    }
+}
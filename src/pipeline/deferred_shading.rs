@@ -55,7 +55,7 @@ impl DeferredShadingRenderPipeline {
                 .triangle_list()
                 .viewports_dynamic_scissors_irrelevant(1)
                 .fragment_shader(fs.main_entry_point(), ())
-                .depth_stencil_simple_depth()
+                .depth_stencil(info.depth_mode.depth_stencil())
                 .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
                 .build(info.device.clone())
                 .unwrap())
@@ -72,7 +72,7 @@ impl DeferredShadingRenderPipeline {
                 .triangle_list()
                 .viewports_dynamic_scissors_irrelevant(1)
                 .fragment_shader(fs.main_entry_point(), ())
-                .depth_stencil_simple_depth()
+                .depth_stencil(info.depth_mode.depth_stencil())
                 .render_pass(Subpass::from(renderpass.clone(), 1).unwrap())
                 .build(info.device.clone())
                 .unwrap())
@@ -159,7 +159,8 @@ impl RenderPipelineAbstract for DeferredShadingRenderPipeline {
         let lock = info.render_queues.read().unwrap();
         for entry in lock.meshes.iter() {
             let uniform_data = DeferredShadingShaders::vertex::ty::InstanceData {
-                world: entry.transform.clone().into()
+                world: entry.transform.clone().into(),
+                prev_world: entry.transform.clone().into(),
             };
 
             let subbuffer = self.voxel_uniform_buffer_pool.next(uniform_data).unwrap();
@@ -172,7 +173,7 @@ impl RenderPipelineAbstract for DeferredShadingRenderPipeline {
         let mut cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queue_main.family())
             .unwrap()
             .begin_render_pass(self.framebuffers.as_ref().unwrap()[info.image_num].clone(), false,
-                               vec![CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(), 1f32.into()]).unwrap()
+                               vec![CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(), info.depth_mode.clear_value().into()]).unwrap()
                 .draw_indexed(self.skybox_pipeline.clone(), &DynamicState {
                     line_width: None,
                     viewports: Some(vec![Viewport {
@@ -213,6 +214,9 @@ impl RenderPipelineAbstract for DeferredShadingRenderPipeline {
                                  DeferredShadingShaders::vertex::ty::Constants {
                                      view: info.view_mat.into(),
                                      proj: info.proj_mat.into(),
+                                     near_plane: info.near_plane,
+                                     far_plane: info.far_plane,
+                                     depth_mode: info.depth_mode.shader_id(),
                                  }).unwrap();
         }
         cb = cb.end_render_pass().unwrap();
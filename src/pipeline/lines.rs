@@ -43,7 +43,7 @@ impl LinesRenderPipeline {
             .line_list()
             .viewports_dynamic_scissors_irrelevant(1)
             .fragment_shader(fs.main_entry_point(), ())
-            .depth_stencil_simple_depth()
+            .depth_stencil(info.depth_mode.depth_stencil())
             .blend_alpha_blending()
             .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
             .build(info.device.clone())
@@ -132,7 +132,7 @@ impl TextRenderPipeline {
             .triangle_list()
             .viewports_dynamic_scissors_irrelevant(1)
             .fragment_shader(fs.main_entry_point(), ())
-            .depth_stencil_simple_depth()
+            .depth_stencil(info.depth_mode.depth_stencil())
             .blend_alpha_blending()
             .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
             .build(info.device.clone())
@@ -229,6 +229,8 @@ impl RenderPipelineAbstract for TextRenderPipeline {
                     (*font).cache.queue_glyph(0, glyph.clone());
                 }
 
+                // text always draws over the scene
+                let depth = info.depth_mode.near_value();
                 let mut vertices = Vec::new();
                 for g in glyphs.iter() {
                     if let Ok(Some((uv_rect, screen_rect))) = (*font).cache.rect_for(0, g) {
@@ -237,32 +239,32 @@ impl RenderPipelineAbstract for TextRenderPipeline {
                         let scaled_y_min: f32 = (screen_rect.min.y + text_data.position.1) as f32 / info.dimensions[1] as f32 * 2.0 - 1.0;
                         let scaled_y_max: f32 = (screen_rect.max.y + text_data.position.1) as f32 / info.dimensions[1] as f32 * 2.0 - 1.0;
                         vertices.push(VertexPositionUVColor {
-                            position: [scaled_x_min, scaled_y_max, 0.0],
+                            position: [scaled_x_min, scaled_y_max, depth],
                             uv: [uv_rect.min.x, uv_rect.max.y],
                             color: text_data.color,
                         });
                         vertices.push(VertexPositionUVColor {
-                            position: [scaled_x_min, scaled_y_min, 0.0],
+                            position: [scaled_x_min, scaled_y_min, depth],
                             uv: [uv_rect.min.x, uv_rect.min.y],
                             color: text_data.color,
                         });
                         vertices.push(VertexPositionUVColor {
-                            position: [scaled_x_max, scaled_y_min, 0.0],
+                            position: [scaled_x_max, scaled_y_min, depth],
                             uv: [uv_rect.max.x, uv_rect.min.y],
                             color: text_data.color,
                         });
                         vertices.push(VertexPositionUVColor {
-                            position: [scaled_x_max, scaled_y_min, 0.0],
+                            position: [scaled_x_max, scaled_y_min, depth],
                             uv: [uv_rect.max.x, uv_rect.min.y],
                             color: text_data.color,
                         });
                         vertices.push(VertexPositionUVColor {
-                            position: [scaled_x_max, scaled_y_max, 0.0],
+                            position: [scaled_x_max, scaled_y_max, depth],
                             uv: [uv_rect.max.x, uv_rect.max.y],
                             color: text_data.color,
                         });
                         vertices.push(VertexPositionUVColor {
-                            position: [scaled_x_min, scaled_y_max, 0.0],
+                            position: [scaled_x_min, scaled_y_max, depth],
                             uv: [uv_rect.min.x, uv_rect.max.y],
                             color: text_data.color,
                         });
@@ -0,0 +1,266 @@
+//! Reflection capture probes.
+//!
+//! Probes are added to the renderer with `PhosphorRenderer::add_reflection_probe`. Each one
+//! renders the scene around it into a cube map, which is prefiltered into a mip chain of
+//! increasing roughness and stored in an octahedral tile of a shared atlas (see
+//! `stage::reflection_probes`). The lighting pass blends the probes a surface is inside of and
+//! fills in whatever they don't cover, such as the sky, from the environment map.
+//!
+//! Probes can optionally be box projected: reflections are looked up where the reflection ray
+//! leaves the influence box instead of from the capture position, which lines them up with the
+//! walls of rooms and corridors the box is fitted to.
+
+use std::sync::Arc;
+use cgmath::Vector3;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use vulkano::buffer::BufferUsage;
+use vulkano::device::Device;
+use vulkano::format::R16G16B16A16Sfloat;
+use vulkano::image::{AttachmentImage, ImageUsage};
+
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::renderer::RenderInfo;
+
+
+/// Size of the first mip of each probe. Tiles are twice as wide, to fit the smaller mips.
+pub const PROBE_SIZE: u32 = 128;
+/// Mips per probe, from mirror-like to fully rough.
+pub const PROBE_MIP_COUNT: u32 = 5;
+/// Tiles along each side of the atlas.
+const ATLAS_TILES: [u32; 2] = [8, 8];
+pub const MAX_REFLECTION_PROBES: usize = (ATLAS_TILES[0] * ATLAS_TILES[1]) as usize;
+
+lazy_static! {
+    static ref PROBE_ATLAS_USAGE: ImageUsage = ImageUsage {
+        color_attachment: true,
+        sampled: true,
+        ..ImageUsage::none()
+    };
+}
+
+
+/// When a probe is re-rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeUpdate {
+    /// Captured once after being added, then only on `recapture_reflection_probe` or
+    /// `invalidate_reflection_probes`.
+    Once,
+    /// Captured continuously, sharing the per-frame capture budget with other probes.
+    EveryFrame,
+}
+
+
+/// A reflection probe. The influence box is axis aligned and centered on the capture position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReflectionProbe {
+    pub position: Vector3<f32>,
+    /// Half size of the influence box.
+    pub extents: Vector3<f32>,
+    /// Whether to parallax correct reflections against the influence box.
+    pub box_projection: bool,
+    /// Distance inside the influence box over which the probe fades in.
+    pub blend_distance: f32,
+    /// Scale applied to the captured radiance.
+    pub intensity: f32,
+    pub update: ProbeUpdate,
+}
+
+impl ReflectionProbe {
+    pub fn new(position: Vector3<f32>, extents: Vector3<f32>) -> Self {
+        Self {
+            position,
+            extents,
+            box_projection: false,
+            blend_distance: 1.0,
+            intensity: 1.0,
+            update: ProbeUpdate::Once,
+        }
+    }
+
+    /// A probe with box projection, for enclosed spaces whose walls line up with the box.
+    pub fn box_projected(position: Vector3<f32>, extents: Vector3<f32>) -> Self {
+        Self {
+            box_projection: true,
+            ..Self::new(position, extents)
+        }
+    }
+
+    fn volume(&self) -> f32 {
+        self.extents.x * self.extents.y * self.extents.z
+    }
+}
+
+
+/// Handle for a reflection probe added to the renderer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReflectionProbeId(pub(crate) u32);
+
+
+struct ProbeSlot {
+    probe: ReflectionProbe,
+    tile: u32,
+    /// Incremented whenever the probe needs to be recaptured.
+    version: u64,
+}
+
+
+/// The probe atlas and the probes placed in it.
+pub struct ReflectionProbes {
+    /// Octahedral mip chains, one tile per probe.
+    pub atlas: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
+    slots: HashMap<ReflectionProbeId, ProbeSlot>,
+    free_tiles: Vec<u32>,
+    next_id: u32,
+    /// Incremented to recapture every probe.
+    pub epoch: u64,
+    /// Probes with valid contents in the atlas. Written by the reflection probe stage.
+    pub captured: Mutex<Vec<ReflectionProbeId>>,
+}
+
+impl ReflectionProbes {
+    pub fn new(device: Arc<Device>) -> Self {
+        let atlas_size = [ATLAS_TILES[0] * PROBE_SIZE * 2, ATLAS_TILES[1] * PROBE_SIZE];
+        Self {
+            atlas: AttachmentImage::with_usage(device, atlas_size, R16G16B16A16Sfloat, *PROBE_ATLAS_USAGE).unwrap(),
+            slots: HashMap::new(),
+            free_tiles: (0..MAX_REFLECTION_PROBES as u32).rev().collect(),
+            next_id: 0,
+            epoch: 0,
+            captured: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns `None` if all `MAX_REFLECTION_PROBES` tiles are in use.
+    pub fn add(&mut self, probe: ReflectionProbe) -> Option<ReflectionProbeId> {
+        let tile = self.free_tiles.pop()?;
+        let id = ReflectionProbeId(self.next_id);
+        self.next_id += 1;
+        self.slots.insert(id, ProbeSlot { probe, tile, version: 0 });
+        Some(id)
+    }
+
+    /// Replaces a probe and recaptures it. Returns false if there's no probe with that id.
+    pub fn update(&mut self, id: ReflectionProbeId, probe: ReflectionProbe) -> bool {
+        match self.slots.get_mut(&id) {
+            Some(slot) => {
+                slot.probe = probe;
+                slot.version += 1;
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn remove(&mut self, id: ReflectionProbeId) -> Option<ReflectionProbe> {
+        let slot = self.slots.remove(&id)?;
+        self.free_tiles.push(slot.tile);
+        self.captured.lock().retain(|captured| *captured != id);
+        Some(slot.probe)
+    }
+
+    /// Marks a probe for recapture. Returns false if there's no probe with that id.
+    pub fn recapture(&mut self, id: ReflectionProbeId) -> bool {
+        match self.slots.get_mut(&id) {
+            Some(slot) => {
+                slot.version += 1;
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn get(&self, id: ReflectionProbeId) -> Option<&ReflectionProbe> {
+        self.slots.get(&id).map(|slot| &slot.probe)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Every probe with its atlas tile and version, in id order.
+    pub fn entries(&self) -> Vec<(ReflectionProbeId, &ReflectionProbe, u32, u64)> {
+        let mut probes: Vec<_> = self.slots.iter()
+            .map(|(id, slot)| (*id, &slot.probe, slot.tile, slot.version))
+            .collect();
+        probes.sort_by_key(|(id, ..)| id.0);
+        probes
+    }
+
+    /// Top left corner of a tile in the atlas, in texels.
+    pub fn tile_origin(tile: u32) -> [u32; 2] {
+        [(tile % ATLAS_TILES[0]) * PROBE_SIZE * 2, (tile / ATLAS_TILES[0]) * PROBE_SIZE]
+    }
+
+    /// Offset and size of a mip within a tile, in texels. Each mip sits to the right of the one
+    /// before it.
+    pub fn mip_rect(mip: u32) -> ([u32; 2], u32) {
+        let size = PROBE_SIZE >> mip;
+        ([PROBE_SIZE * 2 - size * 2, 0], size)
+    }
+}
+
+
+/// Must match `ProbeData` in `reflection_probes.inc`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GpuReflectionProbe {
+    /// Position in xyz, blend distance in w.
+    pub center: [f32; 4],
+    /// Half size in xyz, intensity in w.
+    pub extents: [f32; 4],
+    /// Tile origin in texels in xy, 1 in z for box projection.
+    pub tile: [f32; 4],
+}
+
+/// Must match `ReflectionProbeParams` in `reflection_probes.inc`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GpuReflectionProbeParams {
+    pub atlas_size: [f32; 2],
+    pub tile_size: f32,
+    pub mip_count: u32,
+    pub probe_count: u32,
+}
+
+/// Uploads the captured probes, smallest first, for passes that sample the probe atlas.
+pub fn reflection_probe_buffers(info: &RenderInfo) -> (Arc<CpuAccessibleBufferXalloc<[GpuReflectionProbe]>>, Arc<CpuAccessibleBufferXalloc<GpuReflectionProbeParams>>) {
+    let probes = &info.reflection_probes;
+    let captured = probes.captured.lock();
+    let mut slots: Vec<&ProbeSlot> = if info.settings.reflection_probes.enabled {
+        captured.iter().filter_map(|id| probes.slots.get(id)).collect()
+    }
+    else {
+        Vec::new()
+    };
+    slots.sort_by(|a, b| a.probe.volume().partial_cmp(&b.probe.volume()).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut gpu_probes: Vec<GpuReflectionProbe> = slots.iter().map(|slot| {
+        let p = &slot.probe;
+        let origin = ReflectionProbes::tile_origin(slot.tile);
+        GpuReflectionProbe {
+            center: [p.position.x, p.position.y, p.position.z, p.blend_distance],
+            extents: [p.extents.x, p.extents.y, p.extents.z, p.intensity],
+            tile: [origin[0] as f32, origin[1] as f32, p.box_projection as u32 as f32, 0.0],
+        }
+    }).collect();
+    let probe_count = gpu_probes.len() as u32;
+    if gpu_probes.is_empty() {
+        // zero sized buffers aren't allowed
+        gpu_probes.push(GpuReflectionProbe { center: [0.0; 4], extents: [0.0; 4], tile: [0.0; 4] });
+    }
+
+    let dimensions = probes.atlas.dimensions();
+    (CpuAccessibleBufferXalloc::from_iter(info.device.clone(), BufferUsage::storage_buffer(), gpu_probes.into_iter())
+        .expect("failed to create buffer"),
+     CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(), GpuReflectionProbeParams {
+         atlas_size: [dimensions[0] as f32, dimensions[1] as f32],
+         tile_size: PROBE_SIZE as f32,
+         mip_count: PROBE_MIP_COUNT,
+         probe_count,
+     }).expect("failed to create buffer"))
+}
@@ -1,38 +1,65 @@
 //! Main renderer.
 
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use cgmath::{Matrix4, Vector4, SquareMatrix, Deg};
+use cgmath::{Matrix4, Vector4, SquareMatrix, EuclideanSpace, Deg, Rad};
 use winit::{Window, WindowBuilder, EventsLoop};
 use winit::dpi::LogicalSize;
 
 use vulkano::device::{Device, DeviceExtensions, Queue};
-use vulkano::format::{D32Sfloat, R16G16B16A16Sfloat, R32Uint, B8G8R8A8Srgb};
+use vulkano::format::{D32Sfloat, R16G16B16A16Sfloat, R16G16Sfloat, B8G8R8A8Srgb};
 use vulkano::image::attachment::AttachmentImage;
 use vulkano::image::swapchain::SwapchainImage;
 use vulkano::instance::{Instance, PhysicalDevice};
 use vulkano::swapchain::{Swapchain, Surface};
-use vulkano::sync::GpuFuture;
-use vulkano::image::ImageUsage;
-use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::sync::{GpuFuture, NowFuture, FenceSignalFuture};
+use vulkano::image::{ImageUsage, ImmutableImage, Dimensions};
+use half::f16;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, AutoCommandBuffer, CommandBufferExecFuture};
 
 use toolbelt::Transform;
 
-use crate::geometry::{Mesh, MeshVertex, VertexPosition};
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::{Mesh, MeshId};
+use crate::light::{SunLight, Light, LightId, LocalLightCulling};
+use crate::ies::{IesProfile, IesProfileId, IesProfiles};
+use crate::ltc::LtcTables;
+use crate::environment::{Environment, EnvironmentImage, EnvironmentError};
+use crate::fog::{FogVolume, FogVolumeId, FogVolumes};
+use crate::camera::PhysicalCamera;
+use crate::color_grading::{CubeLut, CubeLutError, ColorGradingLut, NEUTRAL_TEMPERATURE};
+use crate::reflection_probe::{ReflectionProbe, ReflectionProbeId, ReflectionProbes};
+use crate::sky::{SkySettings, GpuSky};
+use crate::subsurface::{SubsurfaceProfile, SubsurfaceProfileId, SubsurfaceProfiles};
+use crate::time_of_day::TimeOfDay;
 use crate::vulkano_win::VkSurfaceBuild;
-use crate::material::{MaterialDefinition, SkyboxMaterial};
+use crate::material::{MaterialDefinition, GenericMeshMaterial};
 use hashbrown::HashMap;
 use crate::stage::mesh_shading::GenericMeshShadingStage;
 use crate::stage::RenderStageDefinition;
 use parking_lot::Mutex;
-use crate::material::params::MaterialParams;
 use vulkano::sampler::Filter;
 use crate::stage::resolve_scene_color::ResolveSceneColorStage;
-use vulkano::pipeline::viewport::Viewport;
-use vulkano::pipeline::GraphicsPipeline;
-use vulkano::framebuffer::{Subpass, Framebuffer};
-use crate::buffer::CpuAccessibleBufferXalloc;
-use vulkano::buffer::BufferUsage;
+use crate::stage::skybox::{SkyboxStage, SkyboxSettings, SkyboxMode};
+use crate::stage::volumetric_fog::{VolumetricFogStage, FogSettings};
+use crate::stage::shadow::{SunShadowStage, ShadowSettings};
+use crate::stage::local_shadow::{LocalShadowStage, LocalShadowSettings, LocalShadowData};
+use crate::stage::ao::{AmbientOcclusionStage, AmbientOcclusionSettings};
+use crate::stage::deferred_lighting::DeferredLightingStage;
+use crate::stage::light_volumes::LightVolumeStage;
+use crate::stage::reflection_probes::{ReflectionProbeStage, ReflectionProbeSettings};
+use crate::stage::subsurface::{SubsurfaceStage, SubsurfaceSettings};
+use crate::stage::ssr::{ScreenSpaceReflectionStage, SsrSettings};
+use crate::compute::{LightCullingCompute, LightClusterData, IblCompute, IblSettings, SkyEnvironmentCompute};
+use crate::compute::{HistogramCompute, AutoExposureSettings, GpuExposure, exposure_buffer, exposure_from_ev100};
+use crate::stage::taa::{TemporalAAStage, TaaSettings};
+use crate::stage::dof::{DepthOfFieldStage, DepthOfFieldSettings};
+use crate::stage::motion_blur::{MotionBlurStage, MotionBlurSettings};
+use crate::stage::bloom::{BloomStage, BloomSettings};
+use crate::stage::tonemap::{TonemapStage, TonemapOperator};
+use crate::stage::fxaa::{FxaaStage, FxaaSettings};
+use vulkano::pipeline::depth_stencil::{DepthStencil, Compare};
 
 /// Matrix to correct vulkan clipping planes and flip y axis.
 /// See [https://matthewwellings.com/blog/the-new-vulkan-coordinate-system/](https://matthewwellings.com/blog/the-new-vulkan-coordinate-system/).
@@ -43,6 +70,76 @@ pub static VULKAN_CORRECT_CLIP: Matrix4<f32> = Matrix4 {
     w: Vector4 { x: 0.0, y:  0.0, z: 0.0, w: 1.0 }
 };
 
+/// Depth buffer convention used by every pass that reads or writes `main_depth`.
+///
+/// Must match the `DEPTH_MODE_*` constants in `depth.inc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthMode {
+    /// Depth 0 at the near plane and 1 at the far plane, compared with `Less`.
+    Standard,
+    /// Depth 1 at the near plane and 0 at infinity, compared with `Greater`. Keeps precision
+    /// roughly constant with distance, which avoids z-fighting far away from the camera.
+    ReverseZInfinite,
+}
+impl DepthMode {
+    /// Matching `DEPTH_MODE_*` value for push constants.
+    pub fn shader_id(&self) -> u32 {
+        match self {
+            DepthMode::Standard => 0,
+            DepthMode::ReverseZInfinite => 1,
+        }
+    }
+
+    /// Value the depth buffer is cleared to, i.e. the depth of the farthest possible surface.
+    pub fn clear_value(&self) -> f32 {
+        match self {
+            DepthMode::Standard => 1.0,
+            DepthMode::ReverseZInfinite => 0.0,
+        }
+    }
+
+    /// Depth of the nearest possible surface, for geometry that should draw over everything.
+    pub fn near_value(&self) -> f32 {
+        match self {
+            DepthMode::Standard => 0.0,
+            DepthMode::ReverseZInfinite => 1.0,
+        }
+    }
+
+    pub fn compare_op(&self) -> Compare {
+        match self {
+            DepthMode::Standard => Compare::Less,
+            DepthMode::ReverseZInfinite => Compare::Greater,
+        }
+    }
+
+    /// Depth test and write state for opaque geometry.
+    pub fn depth_stencil(&self) -> DepthStencil {
+        DepthStencil {
+            depth_compare: self.compare_op(),
+            ..DepthStencil::simple_depth_test()
+        }
+    }
+
+    /// Builds a projection matrix for this depth mode, including the vulkan clip correction.
+    /// `far` is ignored for `ReverseZInfinite`.
+    pub fn projection(&self, fovy: Deg<f32>, aspect: f32, near: f32, far: f32) -> Matrix4<f32> {
+        match self {
+            DepthMode::Standard => VULKAN_CORRECT_CLIP * cgmath::perspective(fovy, aspect, near, far),
+            DepthMode::ReverseZInfinite => {
+                let f = 1.0 / (Rad::from(fovy).0 / 2.0).tan();
+                // depth = near / -z_view, so the near plane maps to 1 and infinity maps to 0
+                Matrix4::new(
+                    f / aspect, 0.0, 0.0,  0.0,
+                    0.0,         -f, 0.0,  0.0,
+                    0.0,        0.0, 0.0, -1.0,
+                    0.0,        0.0, near, 0.0
+                )
+            }
+        }
+    }
+}
+
 pub const DEBUG_VISUALIZE_DISABLED: u32 = 0;
 pub const DEBUG_VISUALIZE_POSITION_BUFFER: u32 = 1;
 pub const DEBUG_VISUALIZE_NORMAL_BUFFER: u32 = 2;
@@ -53,10 +150,16 @@ pub const DEBUG_VISUALIZE_DIFFUSE_LIGHTING_ONLY: u32 = 6;
 pub const DEBUG_VISUALIZE_SPECULAR_LIGHTING_ONLY: u32 = 7;
 pub const DEBUG_VISUALIZE_NO_POST_PROCESSING: u32 = 8;
 pub const DEBUG_VISUALIZE_OCCLUSION_BUFFER: u32 = 9;
-pub const DEBUG_VISUALIZE_MAX: u32 = 10;
+pub const DEBUG_VISUALIZE_AMBIENT_OCCLUSION: u32 = 10;
+pub const DEBUG_VISUALIZE_SHADOW_CASCADES: u32 = 11;
+pub const DEBUG_VISUALIZE_LIGHT_CLUSTERS: u32 = 12;
+pub const DEBUG_VISUALIZE_MAX: u32 = 13;
 
 pub const OCCLUSION_FRAME_SIZE: [u32; 2] = [256, 144];
 
+pub const NEAR_PLANE: f32 = 0.1;
+pub const FAR_PLANE: f32 = 10000.0;
+
 #[derive(Debug)]
 pub enum RendererDrawError {
     WindowMinimized,
@@ -68,12 +171,14 @@ lazy_static! {
     static ref GBUFFER_USAGE: ImageUsage = ImageUsage {
         color_attachment: true,
         input_attachment: true,
+        sampled: true,
         transfer_source: true, // TODO: remove me when there's proper output
         ..ImageUsage::none()
     };
-    static ref LUMA_BUFFER_USAGE: ImageUsage = ImageUsage {
+    static ref SCENE_COLOR_USAGE: ImageUsage = ImageUsage {
         color_attachment: true,
         input_attachment: true,
+        sampled: true,
         transfer_source: true,
         transfer_destination: true,
         ..ImageUsage::none()
@@ -84,6 +189,24 @@ lazy_static! {
          sampled: true,
          ..ImageUsage::none()
      };
+    static ref LDR_BUFFER_USAGE: ImageUsage = ImageUsage {
+        color_attachment: true,
+        sampled: true,
+        transfer_source: true,
+        ..ImageUsage::none()
+    };
+    static ref AO_BUFFER_USAGE: ImageUsage = ImageUsage {
+        color_attachment: true,
+        sampled: true,
+        transfer_destination: true,
+        ..ImageUsage::none()
+    };
+    static ref DEPTH_BUFFER_USAGE: ImageUsage = ImageUsage {
+        depth_stencil_attachment: true,
+        input_attachment: true,
+        sampled: true,
+        ..ImageUsage::none()
+    };
 }
 
 pub struct Attachments {
@@ -96,7 +219,14 @@ pub struct Attachments {
     pub specular_light: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
     pub scene_color: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
     pub main_depth: Arc<AttachmentImage<D32Sfloat>>,
-    pub luma_render: Arc<AttachmentImage<R32Uint>>,
+    /// Screen space motion since the previous frame, in uv units.
+    pub velocity: Arc<AttachmentImage<R16G16Sfloat>>,
+    pub taa_output: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
+    pub taa_history: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
+    /// Tonemapped output, before anti-aliasing and UI.
+    pub ldr_color: Arc<AttachmentImage<B8G8R8A8Srgb>>,
+    /// Blurred ambient occlusion in r, linear depth in g. Half resolution.
+    pub ambient_occlusion: Arc<AttachmentImage<R16G16Sfloat>>,
 }
 
 fn recreate_attachments(device: Arc<Device>, dimensions: [u32; 2]) -> Attachments {
@@ -108,76 +238,399 @@ fn recreate_attachments(device: Arc<Device>, dimensions: [u32; 2]) -> Attachment
         metallic: AttachmentImage::with_usage(device.clone(), dimensions, R16G16B16A16Sfloat, *GBUFFER_USAGE).unwrap(),
         diffuse_light: AttachmentImage::with_usage(device.clone(), dimensions, R16G16B16A16Sfloat, *GBUFFER_USAGE).unwrap(),
         specular_light: AttachmentImage::with_usage(device.clone(), dimensions, R16G16B16A16Sfloat, *GBUFFER_USAGE).unwrap(),
-        scene_color: AttachmentImage::with_usage(device.clone(), dimensions, R16G16B16A16Sfloat, *GBUFFER_USAGE).unwrap(),
-        main_depth: AttachmentImage::transient(device.clone(), dimensions, D32Sfloat).unwrap(),
-        luma_render: AttachmentImage::with_usage(device.clone(), dimensions, R32Uint, *LUMA_BUFFER_USAGE).unwrap(),
+        scene_color: AttachmentImage::with_usage(device.clone(), dimensions, R16G16B16A16Sfloat, *SCENE_COLOR_USAGE).unwrap(),
+        main_depth: AttachmentImage::with_usage(device.clone(), dimensions, D32Sfloat, *DEPTH_BUFFER_USAGE).unwrap(),
+        velocity: AttachmentImage::with_usage(device.clone(), dimensions, R16G16Sfloat, *GBUFFER_USAGE).unwrap(),
+        taa_output: AttachmentImage::with_usage(device.clone(), dimensions, R16G16B16A16Sfloat, *SCENE_COLOR_USAGE).unwrap(),
+        taa_history: AttachmentImage::with_usage(device.clone(), dimensions, R16G16B16A16Sfloat, *SCENE_COLOR_USAGE).unwrap(),
+        ldr_color: AttachmentImage::with_usage(device.clone(), dimensions, B8G8R8A8Srgb, *LDR_BUFFER_USAGE).unwrap(),
+        ambient_occlusion: AttachmentImage::with_usage(device.clone(), [(dimensions[0] / 2).max(1), (dimensions[1] / 2).max(1)],
+                                                       R16G16Sfloat, *AO_BUFFER_USAGE).unwrap(),
     }
 }
 
+/// Shadow map render targets. Unlike `Attachments`, these don't depend on the screen size.
+pub struct ShadowMaps {
+    /// 2x2 atlas of sun cascades.
+    pub sun: Arc<AttachmentImage<D32Sfloat>>,
+    /// Shared atlas for point and spot light shadows.
+    pub local: Arc<AttachmentImage<D32Sfloat>>,
+    /// Where each local light's shadow maps ended up this frame. Written by the local shadow stage.
+    pub local_data: Mutex<LocalShadowData>,
+}
+
 pub struct RenderInfo {
     pub device: Arc<Device>,
     pub queues: Queues,
     pub dimensions: [u32; 2],
     pub camera_transform: Transform,
     pub view_mat: Matrix4<f32>,
+    /// Projection used for rasterization, including the TAA jitter.
     pub proj_mat: Matrix4<f32>,
+    /// Projection without jitter, used for motion vectors and reconstruction.
+    pub unjittered_proj_mat: Matrix4<f32>,
+    /// Unjittered `proj * view` from the previous frame.
+    pub prev_view_proj_mat: Matrix4<f32>,
+    /// Current sub-pixel jitter, in NDC units.
+    pub jitter: [f32; 2],
+    pub frame_index: u64,
+    /// Seconds since the previous frame.
+    pub delta_time: f32,
+    last_frame: Option<Instant>,
     pub fov: Deg<f32>,
+    pub depth_mode: DepthMode,
+    pub near_plane: f32,
+    pub far_plane: f32,
     pub tonemapping_info: TonemappingInfo,
+    /// Current exposure, computed on the GPU by auto exposure every frame.
+    pub exposure: Arc<CpuAccessibleBufferXalloc<GpuExposure>>,
+    /// 3D LUT applied after tonemapping, set with `PhosphorRenderer::set_color_grading_lut`.
+    pub color_grading_lut: Option<ColorGradingLut>,
+    /// Bound in place of `color_grading_lut` when there isn't one.
+    pub(crate) identity_lut: ColorGradingLut,
+    pub sun: SunLight,
+    pub settings: RendererSettings,
     pub debug_visualize_setting: u32,
     pub image_num: usize,
     pub mesh_queue: Mutex<Vec<Mesh>>,
+    /// Transforms of meshes with a `MeshId`, as queued last frame and so far this frame.
+    previous_mesh_transforms: HashMap<MeshId, Transform>,
+    mesh_transforms: HashMap<MeshId, Transform>,
+    next_mesh_id: u32,
     pub materials: HashMap<String, Arc<dyn MaterialDefinition + Send + Sync>>,
     pub attachments: Attachments,
+    pub shadow_maps: ShadowMaps,
+    pub lights: HashMap<LightId, Light>,
+    next_light_id: u32,
+    pub ies_profiles: IesProfiles,
+    pub ltc_tables: LtcTables,
+    pub reflection_probes: ReflectionProbes,
+    pub subsurface_profiles: SubsurfaceProfiles,
+    pub fog_volumes: FogVolumes,
+    /// Image based lighting maps for the current environment.
+    pub environment: Environment,
+    ibl: IblCompute,
+    sky_environment: SkyEnvironmentCompute,
+    /// Image set with `set_environment`, kept for switching back from the analytic sky.
+    hdri: Option<Arc<ImmutableImage<R16G16B16A16Sfloat>>>,
+    environment_origin: EnvironmentOrigin,
+    /// Environment being generated from the analytic sky, replacing `environment` when it's done.
+    pending_environment: Option<PendingEnvironment>,
+    /// Day and night cycle. While set, it overrides `SkySettings::sun` every frame.
+    pub time_of_day: Option<TimeOfDay>,
+    /// Lights uploaded for the current frame, and their cluster lists.
+    pub light_clusters: Mutex<Option<LightClusterData>>,
+    /// Incremented to throw away all cached local shadow maps.
+    pub local_shadow_epoch: u64,
 }
 impl RenderInfo {
-    fn new(device: Arc<Device>, queues: Queues, dimensions: [u32; 2]) -> Self {
+    fn new(device: Arc<Device>, queues: Queues, dimensions: [u32; 2], depth_mode: DepthMode) -> Self {
+        let proj_mat = depth_mode.projection(Deg(45f32), dimensions[0] as f32 / dimensions[1] as f32, NEAR_PLANE, FAR_PLANE);
+        let ies_profiles = IesProfiles::new(queues.main.clone().unwrap());
+        let ltc_tables = LtcTables::new(queues.main.clone().unwrap());
+        let ibl = IblCompute::new(device.clone());
+        let environment = Self::default_environment(&ibl, queues.main.clone().unwrap());
+        let identity_lut = CubeLut::identity(2).upload(queues.main.clone().unwrap());
         Self {
             device: device.clone(),
             queues,
             dimensions,
             camera_transform: Transform::identity(),
             view_mat: Matrix4::identity(),
-            proj_mat: cgmath::perspective(Deg(45f32), dimensions[0] as f32 / dimensions[1] as f32, 0.1, 10000.0),
+            proj_mat,
+            unjittered_proj_mat: proj_mat,
+            prev_view_proj_mat: proj_mat,
+            jitter: [0.0, 0.0],
+            frame_index: 0,
+            delta_time: 0.0,
+            last_frame: None,
             fov: Deg(45f32),
+            depth_mode,
+            near_plane: NEAR_PLANE,
+            far_plane: FAR_PLANE,
             tonemapping_info: TonemappingInfo::default(),
+            exposure: exposure_buffer(device.clone()),
+            color_grading_lut: None,
+            identity_lut,
+            sun: SunLight::default(),
+            settings: RendererSettings::default(),
             debug_visualize_setting: DEBUG_VISUALIZE_DISABLED,
             image_num: 0,
             mesh_queue: Mutex::new(Vec::new()),
+            previous_mesh_transforms: HashMap::new(),
+            mesh_transforms: HashMap::new(),
+            next_mesh_id: 0,
             materials: HashMap::new(),
             attachments: recreate_attachments(device.clone(), dimensions),
+            shadow_maps: ShadowMaps {
+                sun: crate::stage::shadow::create_sun_shadow_atlas(device.clone()),
+                local: crate::stage::local_shadow::create_local_shadow_atlas(device.clone()),
+                local_data: Mutex::new(LocalShadowData::default()),
+            },
+            lights: HashMap::new(),
+            next_light_id: 0,
+            ies_profiles,
+            ltc_tables,
+            reflection_probes: ReflectionProbes::new(device.clone()),
+            subsurface_profiles: SubsurfaceProfiles::new(),
+            fog_volumes: FogVolumes::new(),
+            environment,
+            ibl,
+            sky_environment: SkyEnvironmentCompute::new(device.clone()),
+            hdri: None,
+            environment_origin: EnvironmentOrigin::Default,
+            pending_environment: None,
+            time_of_day: None,
+            light_clusters: Mutex::new(None),
+            local_shadow_epoch: 0,
+        }
+    }
+
+    /// Black environment, until one is set.
+    fn default_environment(ibl: &IblCompute, queue: Arc<Queue>) -> Environment {
+        let black = [f16::from_f32(0.0), f16::from_f32(0.0), f16::from_f32(0.0), f16::from_f32(1.0)];
+        let (source, future) = ImmutableImage::from_iter(vec![black; 2].into_iter(),
+                                                         Dimensions::Dim2d { width: 2, height: 1 },
+                                                         R16G16B16A16Sfloat, queue.clone()).unwrap();
+        future.then_signal_fence_and_flush().unwrap().wait(None).unwrap();
+        let brdf_lut = ibl.brdf_lut(queue.clone());
+        ibl.generate(source, brdf_lut, &IblSettings::default(), queue)
+    }
+
+    /// Replaces the environment with an equirectangular HDR image, and regenerates the image
+    /// based lighting maps from it. Blocks until they're ready. Also switches the skybox to
+    /// `SkyboxMode::Hdri`, since the analytic sky would otherwise replace the environment again.
+    pub fn set_environment(&mut self, hdr_image: Arc<ImmutableImage<R16G16B16A16Sfloat>>) {
+        self.settings.skybox.mode = SkyboxMode::Hdri;
+        self.hdri = Some(hdr_image.clone());
+        self.generate_environment(hdr_image, EnvironmentOrigin::Hdri);
+    }
+
+    fn generate_environment(&mut self, source: Arc<ImmutableImage<R16G16B16A16Sfloat>>, origin: EnvironmentOrigin) {
+        // a sky environment finishing later would replace this one
+        if let Some(pending) = self.pending_environment.take() {
+            pending.future.wait(None).unwrap();
+        }
+        self.environment = self.ibl.generate(source, self.environment.brdf_lut.clone(), &self.settings.ibl,
+                                             self.queues.main.clone().unwrap());
+        self.environment_origin = origin;
+    }
+
+    /// Submits the analytic sky's environment and its image based lighting without waiting for
+    /// them. `update_sky` swaps them in once the GPU is done.
+    fn start_sky_environment(&mut self, sky: GpuSky) {
+        let queue = self.queues.main.clone().unwrap();
+        let (source, sky_cb) = self.sky_environment.record(crate::sky::sky_buffer(self), queue.clone());
+        let (environment, ibl_cb) = self.ibl.record(source, self.environment.brdf_lut.clone(), &self.settings.ibl, queue.clone());
+        let future = vulkano::sync::now(self.device.clone())
+            .then_execute(queue.clone(), sky_cb).unwrap()
+            .then_execute(queue, ibl_cb).unwrap()
+            .then_signal_fence_and_flush().unwrap();
+        self.pending_environment = Some(PendingEnvironment {
+            environment,
+            origin: EnvironmentOrigin::Sky(sky),
+            future,
+        });
+    }
+
+    /// Replaces the environment with the pending one if the GPU has finished generating it.
+    fn poll_pending_environment(&mut self) {
+        let finished = match &self.pending_environment {
+            Some(pending) => pending.future.wait(Some(Duration::from_secs(0))).is_ok(),
+            None => false,
+        };
+        if finished {
+            let pending = self.pending_environment.take().unwrap();
+            self.environment = pending.environment;
+            self.environment_origin = pending.origin;
+        }
+    }
+
+    /// Applies the time of day, points the sun light along the analytic sky's sun or moon, and
+    /// regenerates the environment when the skybox mode changed or the sky moved past
+    /// `SkySettings::environment_update_angle`. Sky environments are generated in the background,
+    /// and the previous one stays in use until they're ready.
+    fn update_sky(&mut self) {
+        self.poll_pending_environment();
+
+        if let Some(time_of_day) = &self.time_of_day {
+            time_of_day.apply(&mut self.settings.sky, &mut self.tonemapping_info);
+        }
+
+        let sky = &self.settings.sky;
+        if sky.drive_sun_light {
+            self.sun = sky.sun_light();
+        }
+
+        match self.settings.skybox.mode {
+            SkyboxMode::Procedural if sky.drive_environment => {
+                let current = GpuSky::new(sky);
+                // compared against the newest environment, so one in flight isn't started again
+                let latest = self.pending_environment.as_ref()
+                    .map(|pending| &pending.origin)
+                    .unwrap_or(&self.environment_origin);
+                let up_to_date = match latest {
+                    EnvironmentOrigin::Sky(generated) => !current.differs_from(generated, sky.environment_update_angle),
+                    _ => false,
+                };
+                // one at a time, so a fast time of day can't queue up work faster than it finishes
+                if !up_to_date && self.pending_environment.is_none() {
+                    self.start_sky_environment(current);
+                }
+            },
+            SkyboxMode::Hdri => {
+                if self.environment_origin != EnvironmentOrigin::Hdri {
+                    if let Some(hdri) = self.hdri.clone() {
+                        self.generate_environment(hdri, EnvironmentOrigin::Hdri);
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Forces static local lights to re-render their shadow maps, e.g. after static geometry
+    /// was added or moved.
+    pub fn invalidate_local_shadows(&mut self) {
+        self.local_shadow_epoch += 1;
+    }
+
+    /// Recalculates the projection matrices from the current fov, dimensions and depth mode.
+    pub fn update_projection(&mut self) {
+        let aspect = self.dimensions[0] as f32 / self.dimensions[1] as f32;
+        self.unjittered_proj_mat = self.depth_mode.projection(self.fov, aspect, self.near_plane, self.far_plane);
+        self.proj_mat = crate::stage::taa::jitter_projection(self.unjittered_proj_mat, self.jitter);
+    }
+
+    /// Updates per-frame temporal state. Call once per frame before building command buffers.
+    pub fn begin_frame(&mut self) {
+        let now = Instant::now();
+        self.delta_time = self.last_frame.map(|last| (now - last).as_secs_f32()).unwrap_or(0.0);
+        self.last_frame = Some(now);
+
+        self.jitter = if self.settings.taa.enabled {
+            crate::stage::taa::jitter_offset(self.frame_index, self.settings.taa.jitter_samples, self.dimensions)
         }
+        else {
+            [0.0, 0.0]
+        };
+        self.update_projection();
+        self.update_sky();
+    }
+
+    /// Stores this frame's matrices for reprojection next frame. Call once per frame after
+    /// building command buffers.
+    pub fn end_frame(&mut self) {
+        // meshes that weren't queued this frame are forgotten
+        self.previous_mesh_transforms = std::mem::replace(&mut self.mesh_transforms, HashMap::new());
+        self.prev_view_proj_mat = self.unjittered_proj_mat * self.view_mat;
+        *self.light_clusters.lock() = None;
+        self.frame_index += 1;
     }
 }
 
+/// Image based lighting maps still being generated on the GPU.
+struct PendingEnvironment {
+    environment: Environment,
+    origin: EnvironmentOrigin,
+    future: FenceSignalFuture<CommandBufferExecFuture<CommandBufferExecFuture<NowFuture, AutoCommandBuffer>, AutoCommandBuffer>>,
+}
+
+/// What the current image based lighting maps were generated from.
+#[derive(Debug, Clone, PartialEq)]
+enum EnvironmentOrigin {
+    /// The black placeholder.
+    Default,
+    /// The image set with `RenderInfo::set_environment`.
+    Hdri,
+    /// The analytic sky, as it was when generated.
+    Sky(GpuSky),
+}
+
+/// Exposure, tonemapping and grading parameters. Exposures are multipliers taking absolute
+/// luminance in cd/m^2 to display units; see `compute::exposure_from_ev100`.
 #[derive(Clone)]
 pub struct TonemappingInfo {
+    /// How quickly auto exposure adapts, roughly in 1/seconds.
     pub adjust_speed: f32,
-    pub hist_low_percentile_bin: f32,
-    pub hist_high_percentile_bin: f32,
-    pub avg_scene_luma: f32,
-    pub scene_ev100: f32,
+    /// Exposure used when auto exposure is off, and as the starting point when it's on. Ignored
+    /// while there's a `physical_camera`.
     pub exposure: f32,
+    /// Camera settings to derive the exposure from, instead of `exposure`.
+    pub physical_camera: Option<PhysicalCamera>,
+    /// Exposure compensation for auto exposure, in stops. Adds to the physical camera's.
     pub exposure_adjustment: f32,
+    /// Limits for auto exposure.
     pub min_exposure: f32,
     pub max_exposure: f32,
-    pub vignette_opacity: f32
+    pub vignette_opacity: f32,
+    pub operator: TonemapOperator,
+    /// White balance, as the color temperature in kelvin of the light to treat as white. Lower
+    /// makes the image cooler, higher warmer.
+    pub temperature: f32,
+    /// Green to magenta white balance shift, roughly -100 to 100. Positive is more magenta.
+    pub tint: f32,
+    /// Contrast around middle grey, applied before tonemapping. 1 leaves the image unchanged.
+    pub contrast: f32,
+    /// 0 is greyscale, 1 leaves the image unchanged.
+    pub saturation: f32,
 }
 impl Default for TonemappingInfo {
     fn default() -> Self {
         Self {
             adjust_speed: 0.5,
-            hist_low_percentile_bin: 0.0,
-            hist_high_percentile_bin: 127.0,
-            avg_scene_luma: 1.0,
-            scene_ev100: 0.0,
-            exposure: 0.5,
-            min_exposure: 0.1,
-            max_exposure: 3.0,
+            exposure: exposure_from_ev100(14.0),
+            // from a sunlit snow field down to a moonlit night
+            min_exposure: exposure_from_ev100(18.0),
+            max_exposure: exposure_from_ev100(-4.0),
+            physical_camera: None,
             exposure_adjustment: 0.0,
-            vignette_opacity: 0.2
+            vignette_opacity: 0.2,
+            operator: TonemapOperator::default(),
+            temperature: NEUTRAL_TEMPERATURE,
+            tint: 0.0,
+            contrast: 1.0,
+            saturation: 1.0,
         }
     }
 }
+impl TonemappingInfo {
+    /// Exposure without auto exposure: the physical camera's if there is one, `exposure`
+    /// otherwise.
+    pub fn manual_exposure(&self) -> f32 {
+        match &self.physical_camera {
+            Some(camera) => camera.exposure(),
+            None => self.exposure,
+        }
+    }
+
+    /// Total auto exposure compensation in stops.
+    pub fn compensation(&self) -> f32 {
+        self.exposure_adjustment + self.physical_camera.as_ref().map(|c| c.exposure_compensation).unwrap_or(0.0)
+    }
+}
+
+/// User-adjustable renderer settings. Changes take effect on the next frame.
+#[derive(Clone, Default)]
+pub struct RendererSettings {
+    pub shadows: ShadowSettings,
+    pub local_shadows: LocalShadowSettings,
+    pub light_culling: LocalLightCulling,
+    pub reflection_probes: ReflectionProbeSettings,
+    pub ibl: IblSettings,
+    pub skybox: SkyboxSettings,
+    pub sky: SkySettings,
+    pub fog: FogSettings,
+    pub auto_exposure: AutoExposureSettings,
+    pub ao: AmbientOcclusionSettings,
+    pub subsurface: SubsurfaceSettings,
+    pub ssr: SsrSettings,
+    pub taa: TaaSettings,
+    pub dof: DepthOfFieldSettings,
+    pub motion_blur: MotionBlurSettings,
+    pub bloom: BloomSettings,
+    pub fxaa: FxaaSettings,
+}
 
 #[derive(Debug, Clone)]
 pub struct Queues {
@@ -197,6 +650,7 @@ pub struct PhosphorRendererBuilder<'a> {
     embedded_info: Option<EmbeddedModeInfo>,
     device: Option<Arc<Device>>,
     queues: Queues,
+    depth_mode: DepthMode,
 }
 
 
@@ -209,6 +663,7 @@ impl<'a> PhosphorRendererBuilder<'a> {
             embedded_info: None,
             device: None,
             queues: Queues::none(),
+            depth_mode: DepthMode::Standard,
         }
     }
 
@@ -234,6 +689,7 @@ impl<'a> PhosphorRendererBuilder<'a> {
             embedded_info: Some(EmbeddedModeInfo { render_target }),
             device,
             queues,
+            depth_mode: DepthMode::Standard,
         }
     }
 
@@ -247,6 +703,12 @@ impl<'a> PhosphorRendererBuilder<'a> {
         self
     }
 
+    /// Selects the depth buffer convention. Defaults to `DepthMode::Standard`.
+    pub fn with_depth_mode(mut self, depth_mode: DepthMode) -> Self {
+        self.depth_mode = depth_mode;
+        self
+    }
+
     pub fn build(self) -> PhosphorRenderer {
         let dimensions = match self.dimensions {
             Some((width, height)) => [width as u32, height as u32],
@@ -259,12 +721,12 @@ impl<'a> PhosphorRendererBuilder<'a> {
                 let device = self.device.unwrap().clone();
                 let queues = self.queues.clone();
 
-                let mut info = RenderInfo::new(device.clone(), queues.clone(), dimensions);
+                let mut info = RenderInfo::new(device.clone(), queues.clone(), dimensions, self.depth_mode);
 
-                let stages = RendererStages::new(&info);
+                let stages = RendererStages::new(&info, Some(embedded_info.render_target.clone()));
 
-                info.materials.insert("skybox".to_string(), Arc::new(
-                    SkyboxMaterial::new(&info, stages.mesh_shading.get_renderpass().clone(), 0, MaterialParams::new()))
+                info.materials.insert("generic".to_string(), Arc::new(
+                    GenericMeshMaterial::new(&info, stages.mesh_shading.get_renderpass().clone(), 0))
                 );
 
                 PhosphorRenderer {
@@ -323,12 +785,12 @@ impl<'a> PhosphorRendererBuilder<'a> {
                         .expect("failed to create swapchain")
                 };
 
-                let mut info = RenderInfo::new(device.clone(), queues.clone(), dimensions);
+                let mut info = RenderInfo::new(device.clone(), queues.clone(), dimensions, self.depth_mode);
 
-                let stages = RendererStages::new(&info);
+                let stages = RendererStages::new(&info, None);
 
-                info.materials.insert("skybox".to_string(), Arc::new(
-                    SkyboxMaterial::new(&info, stages.mesh_shading.get_renderpass().clone(), 0, MaterialParams::new()))
+                info.materials.insert("generic".to_string(), Arc::new(
+                    GenericMeshMaterial::new(&info, stages.mesh_shading.get_renderpass().clone(), 0))
                 );
 
                 PhosphorRenderer {
@@ -383,22 +845,113 @@ impl Default for RendererParams {
     }
 }
 
+/// Render stages, in execution order.
 pub struct RendererStages {
     mesh_shading: GenericMeshShadingStage,
+    sun_shadows: SunShadowStage,
+    local_shadows: LocalShadowStage,
+    ao: AmbientOcclusionStage,
+    light_culling: LightCullingCompute,
+    reflection_probes: ReflectionProbeStage,
+    deferred_lighting: DeferredLightingStage,
+    light_volumes: LightVolumeStage,
+    subsurface: SubsurfaceStage,
+    // also copies scene_color into its history after taa, see `build_history_command_buffer`
+    ssr: ScreenSpaceReflectionStage,
     resolve_scene_color: ResolveSceneColorStage,
+    skybox: SkyboxStage,
+    volumetric_fog: VolumetricFogStage,
+    auto_exposure: HistogramCompute,
+    taa: TemporalAAStage,
+    dof: DepthOfFieldStage,
+    motion_blur: MotionBlurStage,
+    bloom: BloomStage,
+    tonemap: TonemapStage,
+    // writes to the final output, so this must come after all other post processing but before UI
+    fxaa: FxaaStage,
 }
 impl RendererStages {
-    pub fn new(info: &RenderInfo) -> Self {
+    /// `render_target` is the output image in embedded mode, or `None` to render to the swapchain.
+    pub fn new(info: &RenderInfo, render_target: Option<Arc<AttachmentImage<B8G8R8A8Srgb>>>) -> Self {
         Self {
-            mesh_shading: GenericMeshShadingStage::new(info.device.clone()),
-            resolve_scene_color: ResolveSceneColorStage::new(info.device.clone(),
-                                                             info.attachments.scene_color.clone(),
-                                                             info.attachments.luma_render.clone()),
+            mesh_shading: GenericMeshShadingStage::new(info),
+            sun_shadows: SunShadowStage::new(info.device.clone()),
+            local_shadows: LocalShadowStage::new(info.device.clone()),
+            ao: AmbientOcclusionStage::new(info.device.clone()),
+            light_culling: LightCullingCompute::new(info.device.clone()),
+            reflection_probes: ReflectionProbeStage::new(info.device.clone()),
+            deferred_lighting: DeferredLightingStage::new(info.device.clone()),
+            light_volumes: LightVolumeStage::new(info.device.clone(), info.depth_mode),
+            subsurface: SubsurfaceStage::new(info.device.clone()),
+            ssr: ScreenSpaceReflectionStage::new(info.device.clone()),
+            resolve_scene_color: ResolveSceneColorStage::new(info.device.clone()),
+            skybox: SkyboxStage::new(info),
+            volumetric_fog: VolumetricFogStage::new(info.device.clone()),
+            auto_exposure: HistogramCompute::new(info.device.clone()),
+            taa: TemporalAAStage::new(info.device.clone()),
+            dof: DepthOfFieldStage::new(info.device.clone()),
+            motion_blur: MotionBlurStage::new(info.device.clone()),
+            bloom: BloomStage::new(info.device.clone()),
+            tonemap: TonemapStage::new(info.device.clone()),
+            fxaa: FxaaStage::new(info.device.clone(), render_target),
         }
     }
     pub fn recreate_framebuffers_if_none(&mut self, images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
         self.mesh_shading.recreate_framebuffers_if_none(images, info);
+        self.sun_shadows.recreate_framebuffers_if_none(images, info);
+        self.local_shadows.recreate_framebuffers_if_none(images, info);
+        self.ao.recreate_framebuffers_if_none(images, info);
+        self.light_culling.recreate_buffers_if_needed(info);
+        self.reflection_probes.recreate_framebuffers_if_none(images, info);
+        self.deferred_lighting.recreate_framebuffers_if_none(images, info);
+        self.light_volumes.recreate_framebuffers_if_none(images, info);
+        self.subsurface.recreate_framebuffers_if_none(images, info);
+        self.ssr.recreate_framebuffers_if_none(images, info);
         self.resolve_scene_color.recreate_framebuffers_if_none(images, info);
+        self.skybox.recreate_framebuffers_if_none(images, info);
+        self.volumetric_fog.recreate_framebuffers_if_none(images, info);
+        self.taa.recreate_framebuffers_if_none(images, info);
+        self.dof.recreate_framebuffers_if_none(images, info);
+        self.motion_blur.recreate_framebuffers_if_none(images, info);
+        self.bloom.recreate_framebuffers_if_none(images, info);
+        self.tonemap.recreate_framebuffers_if_none(images, info);
+        self.fxaa.recreate_framebuffers_if_none(images, info);
+    }
+
+    /// Builds one frame's command buffers, in submission order. Ends with the tonemapped image in
+    /// `ldr_color`.
+    pub fn build_command_buffers(&mut self, info: &RenderInfo) -> Vec<(AutoCommandBuffer, Arc<Queue>)> {
+        let mut command_buffers = Vec::new();
+
+        // shadow maps and probe captures don't depend on the G-buffer
+        command_buffers.extend(self.sun_shadows.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.local_shadows.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.reflection_probes.build_command_buffers(info).into_iter().flatten());
+        // uploads this frame's lights for lighting and fog, even when not clustering
+        command_buffers.extend(self.light_culling.build_command_buffer(info));
+
+        // clears the depth buffer, so the sky only fills pixels without geometry
+        command_buffers.extend(self.mesh_shading.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.ao.build_command_buffers(info).into_iter().flatten());
+
+        command_buffers.extend(self.deferred_lighting.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.light_volumes.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.subsurface.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.ssr.build_command_buffers(info).into_iter().flatten());
+        // clears scene_color before anything else draws into it
+        command_buffers.extend(self.resolve_scene_color.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.skybox.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.volumetric_fog.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.auto_exposure.build_command_buffer(info));
+
+        command_buffers.extend(self.taa.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.ssr.build_history_command_buffer(info));
+        command_buffers.extend(self.dof.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.motion_blur.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.bloom.build_command_buffers(info).into_iter().flatten());
+        command_buffers.extend(self.tonemap.build_command_buffers(info).into_iter().flatten());
+
+        command_buffers
     }
 }
 
@@ -422,10 +975,145 @@ impl PhosphorRenderer {
         self.params = update;
     }
 
-    pub fn queue_mesh(&mut self, mesh: Mesh) {
+    /// Queues a mesh for this frame. If it has an id and no `previous_transform`, the transform it
+    /// was queued with last frame is used for its motion vectors.
+    pub fn queue_mesh(&mut self, mut mesh: Mesh) {
+        if let Some(id) = mesh.id {
+            if mesh.previous_transform.is_none() {
+                mesh.previous_transform = self.info.previous_mesh_transforms.get(&id).cloned();
+            }
+            self.info.mesh_transforms.insert(id, mesh.transform.clone());
+        }
         self.info.mesh_queue.lock().push(mesh);
     }
 
+    /// Creates an id for tracking a mesh's motion between frames.
+    pub fn create_mesh_id(&mut self) -> MeshId {
+        let id = MeshId(self.info.next_mesh_id);
+        self.info.next_mesh_id += 1;
+        id
+    }
+
+    /// Adds a light to the scene. The returned id is used to update or remove it.
+    pub fn add_light(&mut self, light: Light) -> LightId {
+        let id = LightId(self.info.next_light_id);
+        self.info.next_light_id += 1;
+        self.info.lights.insert(id, light);
+        id
+    }
+
+    /// Replaces a light. Returns false if there's no light with that id.
+    pub fn update_light(&mut self, id: LightId, light: Light) -> bool {
+        match self.info.lights.get_mut(&id) {
+            Some(existing) => {
+                *existing = light;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Uploads a photometric profile for lights to reference with `Light::ies_profile`. Profiles
+    /// can't be removed.
+    pub fn add_ies_profile(&mut self, profile: &IesProfile) -> IesProfileId {
+        self.info.ies_profiles.add(profile, self.info.queues.main.clone().unwrap())
+    }
+
+    /// Removes a light from the scene, returning it if it existed.
+    pub fn remove_light(&mut self, id: LightId) -> Option<Light> {
+        self.info.lights.remove(&id)
+    }
+
+    /// Moves the day and night cycle forward by `seconds` of real time. Does nothing without a
+    /// `RenderInfo::time_of_day`.
+    pub fn advance_time_of_day(&mut self, seconds: f32) {
+        if let Some(time_of_day) = &mut self.info.time_of_day {
+            time_of_day.advance(seconds);
+        }
+    }
+
+    /// Sets the equirectangular HDR image used for the sky's ambient light and reflections. See
+    /// `RenderInfo::set_environment`.
+    pub fn set_environment(&mut self, hdr_image: Arc<ImmutableImage<R16G16B16A16Sfloat>>) {
+        self.info.set_environment(hdr_image);
+    }
+
+    /// Loads an equirectangular Radiance `.hdr` or OpenEXR file and sets it as the environment.
+    /// Blocks until the image based lighting maps are ready.
+    pub fn load_environment<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<(), EnvironmentError> {
+        let image = EnvironmentImage::load(path)?.upload(self.info.queues.main.clone().unwrap());
+        self.info.set_environment(image);
+        Ok(())
+    }
+
+    /// Sets the 3D LUT applied after tonemapping, or removes it with `None`. Blocks until the
+    /// LUT is uploaded.
+    pub fn set_color_grading_lut(&mut self, lut: Option<&CubeLut>) {
+        self.info.color_grading_lut = lut.map(|lut| lut.upload(self.info.queues.main.clone().unwrap()));
+    }
+
+    /// Loads a `.cube` file and sets it as the color grading LUT.
+    pub fn load_color_grading_lut<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<(), CubeLutError> {
+        let lut = CubeLut::load(path)?;
+        self.set_color_grading_lut(Some(&lut));
+        Ok(())
+    }
+
+    /// Adds a reflection probe, which is captured over the next frames. Returns `None` if the
+    /// probe atlas is full.
+    pub fn add_reflection_probe(&mut self, probe: ReflectionProbe) -> Option<ReflectionProbeId> {
+        self.info.reflection_probes.add(probe)
+    }
+
+    /// Replaces a reflection probe and recaptures it. Returns false if there's no probe with
+    /// that id.
+    pub fn update_reflection_probe(&mut self, id: ReflectionProbeId, probe: ReflectionProbe) -> bool {
+        self.info.reflection_probes.update(id, probe)
+    }
+
+    /// Removes a reflection probe, returning it if it existed.
+    pub fn remove_reflection_probe(&mut self, id: ReflectionProbeId) -> Option<ReflectionProbe> {
+        self.info.reflection_probes.remove(id)
+    }
+
+    /// Recaptures one reflection probe, e.g. after the geometry around it changed. Returns false
+    /// if there's no probe with that id.
+    pub fn recapture_reflection_probe(&mut self, id: ReflectionProbeId) -> bool {
+        self.info.reflection_probes.recapture(id)
+    }
+
+    /// Recaptures every reflection probe, e.g. after loading a new scene or changing the sun.
+    pub fn invalidate_reflection_probes(&mut self) {
+        self.info.reflection_probes.epoch += 1;
+    }
+
+    /// Adds a subsurface scattering profile for materials to reference with
+    /// `GenericMeshMaterial::with_subsurface_profile`. Returns `None` if there are already
+    /// `MAX_SUBSURFACE_PROFILES`. Profiles can't be removed.
+    pub fn add_subsurface_profile(&mut self, profile: SubsurfaceProfile) -> Option<SubsurfaceProfileId> {
+        self.info.subsurface_profiles.add(profile)
+    }
+
+    /// Replaces a subsurface scattering profile. Returns false if there's no profile with that id.
+    pub fn update_subsurface_profile(&mut self, id: SubsurfaceProfileId, profile: SubsurfaceProfile) -> bool {
+        self.info.subsurface_profiles.update(id, profile)
+    }
+
+    /// Adds a local fog volume. Returns `None` if there are already `MAX_FOG_VOLUMES`.
+    pub fn add_fog_volume(&mut self, volume: FogVolume) -> Option<FogVolumeId> {
+        self.info.fog_volumes.add(volume)
+    }
+
+    /// Replaces a fog volume. Returns false if there's no volume with that id.
+    pub fn update_fog_volume(&mut self, id: FogVolumeId, volume: FogVolume) -> bool {
+        self.info.fog_volumes.update(id, volume)
+    }
+
+    /// Removes a fog volume, returning it if it existed.
+    pub fn remove_fog_volume(&mut self, id: FogVolumeId) -> Option<FogVolume> {
+        self.info.fog_volumes.remove(id)
+    }
+
 //        // minimizing window makes dimensions = [0, 0] which breaks swapchain creation.
 //        // skip draw loop until window is restored.
 //        if self.info.dimensions[0] < 1 || self.info.dimensions[1] < 1 {
@@ -462,13 +1150,6 @@ impl PhosphorRenderer {
 //            self.recreate_swapchain = false;
 //        }
 //
-//        if !crate::compute::HISTOGRAM_COMPUTE_WORKING.load(Ordering::Relaxed) {
-//            self.info.histogram_compute.lock().submit(self.info.device.clone(), self.info.queue_compute.clone());
-//        }
-//        else {
-//            println!("histogram compute busy, skipping this frame");
-//        }
-//
 //        for p in self.pipelines.iter_mut() {
 //            p.recreate_framebuffers_if_none(&self.images, &self.info);
 //        }
@@ -489,135 +1170,48 @@ impl PhosphorRenderer {
 //
 //        self.info.fov = camera.fov.clone();
 //        self.info.camera_transform = transform.clone();
-//        let tonemap_info = self.info.tonemapping_info.clone();
-//
-//        let low_bin;
-//        let high_bin;
-//        {
-//            let hist_lock = self.info.histogram_compute.lock();
-//            low_bin = hist_lock.low_percentile_bin;
-//            high_bin = hist_lock.high_percentile_bin;
-//        }
-//
-//        let bin_avg = (low_bin + high_bin) / 2.0;
-//        let avg_log_luma = bin_avg / 4.6 - 10.0;
-//        let avg_luma = 2f32.powf(avg_log_luma);
-//        let ev100 = (avg_luma * 100.0 / 12.5).log2() + tonemap_info.exposure_adjustment;
-//        let max_luma = 1.2 * 2f32.powf(ev100);
-//        let exposure = 1.0 / max_luma;
-//        //let exposure = exposure.max(tonemap_info.min_exposure);
-//
-//        self.info.tonemapping_info = TonemappingInfo {
-//            adjust_speed: 0.5,
-//            hist_low_percentile_bin: low_bin,
-//            hist_high_percentile_bin: high_bin,
-//            avg_scene_luma: avg_luma,
-//            scene_ev100: ev100,
-//            exposure,
-//            exposure_adjustment: tonemap_info.exposure_adjustment,
-//            min_exposure: tonemap_info.min_exposure,
-//            max_exposure: tonemap_info.max_exposure,
-//            vignette_opacity: tonemap_info.vignette_opacity
-//        };
-//
 //        Ok(future)
 
-    pub fn submit(&mut self, skybox: &Mesh) -> Box<dyn GpuFuture> {
+    pub fn submit(&mut self) -> Box<dyn GpuFuture> {
+        let camera = self.params.camera_transform.clone();
+        self.info.view_mat = Matrix4::from(camera.rotation) * Matrix4::from_translation((camera.position * -1.0).to_vec());
+        self.info.camera_transform = camera;
+
+        self.info.begin_frame();
         self.stages.recreate_framebuffers_if_none(&mut vec![], &self.info);
 
         match &self.mode {
             RendererMode::Standalone(_) => unimplemented!(),
-            RendererMode::Embedded(info) => {
-//                let mut command_buffers = Vec::new();
-//
-//                if let Some(cbs) = self.stages.mesh_shading.build_command_buffers(&self.info) {
-//                    command_buffers.extend(cbs.into_iter());
-//                }
-//                if let Some(cbs) = self.stages.resolve_scene_color.build_command_buffers(&self.info) {
-//                    command_buffers.extend(cbs.into_iter());
-//                }
-
-//                let mut future: Box<dyn GpuFuture> = Box::new(vulkano::sync::now(self.device.clone()));
-//
-//                for (cb, q) in command_buffers {
-//                    future = Box::new(future.then_execute(q.clone(), cb).unwrap());
-//                }
-//
-//                let output_cb = AutoCommandBufferBuilder::primary_one_time_submit(self.info.device.clone(), self.info.queues.main.as_ref().unwrap().family()).unwrap()
-//                    .blit_image(self.info.attachments.albedo.clone(), [0, 0, 0], [self.info.dimensions[0] as i32, self.info.dimensions[1] as i32, 1], 0, 0,
-//                                info.render_target.clone(), [0, 0, 0], [self.info.dimensions[0] as i32, self.info.dimensions[1] as i32, 1], 0, 0, 1, Filter::Linear).unwrap()
-//                    .build().unwrap();
-                let embedded_info = match &self.mode {
-                    RendererMode::Embedded(e) => e,
-                    RendererMode::Standalone(_) => panic!()
-                };
+            RendererMode::Embedded(embedded_info) => {
+                let mut command_buffers = self.stages.build_command_buffers(&self.info);
+
+                if self.info.settings.fxaa.enabled {
+                    command_buffers.extend(self.stages.fxaa.build_command_buffers(&self.info).into_iter().flatten());
+                } else {
+                    // nothing to filter, so skip the fullscreen pass and copy straight to the target
+                    let dimensions = [self.info.dimensions[0] as i32, self.info.dimensions[1] as i32, 1];
+                    let output_cb = AutoCommandBufferBuilder::primary_one_time_submit(self.info.device.clone(), self.info.queues.main.as_ref().unwrap().family()).unwrap()
+                        .blit_image(self.info.attachments.ldr_color.clone(), [0, 0, 0], dimensions, 0, 0,
+                                    embedded_info.render_target.clone(), [0, 0, 0], dimensions, 0, 0, 1, Filter::Nearest).unwrap()
+                        .build().unwrap();
+                    command_buffers.push((output_cb, self.info.queues.main.as_ref().unwrap().clone()));
+                }
 
-                let fb = Arc::new(Framebuffer::start(self.stages.mesh_shading.get_renderpass().clone())
-                    .add(embedded_info.render_target.clone()).unwrap()
-                    .build().unwrap());
-                let fullscreen_vertex_buffer = CpuAccessibleBufferXalloc::<[VertexPosition]>::from_iter(
-                    self.device.clone(), BufferUsage::all(), vec![
-                        VertexPosition { position: [ -1.0,  1.0, 0.5 ] },
-                        VertexPosition { position: [  1.0,  1.0, 0.5 ] },
-                        VertexPosition { position: [  1.0, -1.0, 0.5 ] },
-                        VertexPosition { position: [ -1.0,  1.0, 0.5 ] },
-                        VertexPosition { position: [  1.0, -1.0, 0.5 ] },
-                        VertexPosition { position: [ -1.0, -1.0, 0.5 ] },
-                    ].iter().cloned()).expect("failed to create buffer");
-
-                let ppvs = crate::shader::skybox::vertex::Shader::load(self.info.device.clone()).expect("failed to create shader module");
-                let ppfs = crate::shader::skybox::fragment::Shader::load(self.info.device.clone()).expect("failed to create shader module");
-                let temp_pipeline = Arc::new(GraphicsPipeline::start()
-                    .cull_mode_disabled()
-                    .vertex_input_single_buffer::<MeshVertex>()
-                    .vertex_shader(ppvs.main_entry_point(), ())
-                    .triangle_list()
-                    .viewports_dynamic_scissors_irrelevant(1)
-                    .fragment_shader(ppfs.main_entry_point(), ())
-                    //.depth_stencil_simple_depth()
-                    .blend_alpha_blending()
-                    .render_pass(Subpass::from(self.stages.mesh_shading.get_renderpass().clone(), 0).unwrap())
-                    .build(self.info.device.clone())
-                    .unwrap());
-
-                let vertgroup = skybox.vertex_groups[0].clone();
-                let mut cb = AutoCommandBufferBuilder::primary_one_time_submit(self.info.device.clone(), self.info.queues.main.as_ref().unwrap().family())
-                    .unwrap()
-                    .begin_render_pass(fb.clone(), false, vec![[0.0,0.0,0.0,1.0].into()]).unwrap()
-                    .draw_indexed(temp_pipeline.clone(), &DynamicState {
-                            line_width: None,
-                            viewports: Some(vec![Viewport {
-                                origin: [0.0, 0.0],
-                                dimensions: [self.info.dimensions[0] as f32, self.info.dimensions[1] as f32],
-                                depth_range: 0.0..1.0,
-                            }]),
-                            scissors: None,
-                            compare_mask: None,
-                            write_mask: None,
-                            reference: None
-                        },
-                                  vertgroup.vertex_buffer.clone(),
-                                  vertgroup.index_buffer.clone(),
-                                             (),
-                                             // TODO: handle actual push constants
-                                             crate::shader::skybox::vertex::ty::Constants {
-                                                 matrix: (self.info.proj_mat.clone() * Matrix4::from(self.info.camera_transform.rotation)).into(),
-                                                 sun_rotation: 0.0,
-                                                 sun_transit: 0.4,
-                                             }).unwrap()
-                    .end_render_pass().unwrap()
-                    .build().unwrap();
-
-                let mut future = Box::new(vulkano::sync::now(self.info.device.clone()).then_execute(self.info.queues.main.as_ref().unwrap().clone(), cb).unwrap()
-                    .then_signal_fence_and_flush().unwrap());
+                let mut future: Box<dyn GpuFuture> = Box::new(vulkano::sync::now(self.info.device.clone()));
+                for (cb, queue) in command_buffers {
+                    future = Box::new(future.then_execute(queue, cb).unwrap());
+                }
+                let future = Box::new(future.then_signal_fence_and_flush().unwrap());
 
                 self.info.mesh_queue.lock().clear();
+                self.info.end_frame();
 
                 future
             }
         }
     }
 
+    /// Looks up a built-in material. `"generic"` is an untextured `GenericMeshMaterial`.
     pub fn get_material(&self, name: &str) -> Option<&Arc<dyn MaterialDefinition + Send + Sync>> {
         self.info.materials.get(name)
     }
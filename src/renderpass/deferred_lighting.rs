@@ -2,6 +2,8 @@ use vulkano::framebuffer::{RenderPassDesc, AttachmentDescription, PassDescriptio
 use vulkano::image::ImageLayout;
 use vulkano::format::{Format, ClearValue};
 
+/// Render pass for deferred lighting. Reads the G-buffer as input attachments, and clears and
+/// writes the diffuse and specular light buffers.
 pub struct DeferredLightingRenderPass;
 
 const POSITION_BUFFER:  usize = 0;
@@ -16,7 +18,8 @@ const FLOAT_INPUT_DESC: AttachmentDescription = AttachmentDescription {
     format: Format::R16G16B16A16Sfloat,
     samples: 1,
     load: LoadOp::Load,
-    store: StoreOp::DontCare,
+    // read again by later passes
+    store: StoreOp::Store,
     stencil_load: LoadOp::DontCare,
     stencil_store: StoreOp::DontCare,
     initial_layout: ImageLayout::ShaderReadOnlyOptimal,
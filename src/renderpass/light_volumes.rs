@@ -0,0 +1,71 @@
+use vulkano::framebuffer::{RenderPassDesc, AttachmentDescription, PassDescription, PassDependencyDescription, LoadOp, StoreOp, RenderPassDescClearValues};
+use vulkano::image::ImageLayout;
+use vulkano::format::{Format, ClearValue};
+
+/// Render pass for light volumes. Accumulates into the diffuse and specular light buffers, and
+/// depth tests against the G-buffer depth without writing to it.
+pub struct LightVolumeRenderPass;
+
+const DIFFUSE_LIGHT:  usize = 0;
+const SPECULAR_LIGHT: usize = 1;
+const DEPTH_BUFFER:   usize = 2;
+
+const LIGHT_ACCUMULATION: AttachmentDescription = AttachmentDescription {
+    format: Format::R16G16B16A16Sfloat,
+    samples: 1,
+    load: LoadOp::Load,
+    store: StoreOp::Store,
+    stencil_load: LoadOp::DontCare,
+    stencil_store: StoreOp::DontCare,
+    initial_layout: ImageLayout::ColorAttachmentOptimal,
+    final_layout: ImageLayout::ColorAttachmentOptimal
+};
+
+unsafe impl RenderPassDesc for LightVolumeRenderPass {
+    fn num_attachments(&self) -> usize { 3 }
+    fn attachment_desc(&self, num: usize) -> Option<AttachmentDescription> {
+        match num {
+            DIFFUSE_LIGHT => Some(LIGHT_ACCUMULATION),
+            SPECULAR_LIGHT => Some(LIGHT_ACCUMULATION),
+            DEPTH_BUFFER => Some(AttachmentDescription {
+                format: Format::D32Sfloat,
+                samples: 1,
+                load: LoadOp::Load,
+                store: StoreOp::Store,
+                stencil_load: LoadOp::DontCare,
+                stencil_store: StoreOp::DontCare,
+                initial_layout: ImageLayout::DepthStencilAttachmentOptimal,
+                final_layout: ImageLayout::DepthStencilAttachmentOptimal
+            }),
+            _ => None
+        }
+    }
+
+    fn num_subpasses(&self) -> usize { 1 }
+    fn subpass_desc(&self, num: usize) -> Option<PassDescription> {
+        match num {
+            0 => Some(PassDescription {
+                color_attachments: vec![
+                    (DIFFUSE_LIGHT, ImageLayout::ColorAttachmentOptimal),
+                    (SPECULAR_LIGHT, ImageLayout::ColorAttachmentOptimal)
+                ],
+                depth_stencil: Some((DEPTH_BUFFER, ImageLayout::DepthStencilAttachmentOptimal)),
+                input_attachments: vec![],
+                resolve_attachments: vec![],
+                preserve_attachments: vec![]
+            }),
+            _ => None
+        }
+    }
+
+    fn num_dependencies(&self) -> usize { 0 }
+    fn dependency_desc(&self, _num: usize) -> Option<PassDependencyDescription> { None }
+}
+
+
+unsafe impl RenderPassDescClearValues<Vec<ClearValue>> for LightVolumeRenderPass {
+    fn convert_clear_values(&self, values: Vec<ClearValue>) -> Box<dyn Iterator<Item = ClearValue>> {
+        // FIXME: safety checks
+        Box::new(values.into_iter())
+    }
+}
@@ -3,18 +3,20 @@ use vulkano::image::ImageLayout;
 use vulkano::format::{Format, ClearValue};
 use vulkano::sync::{PipelineStages, AccessFlagBits};
 
+/// Render pass for the G-buffer. Clears and fills the surface attributes, the depth buffer and the
+/// velocity buffer.
 pub struct GenericMeshShadingRenderPass;
 
 const POSITION_BUFFER:  usize = 0;
-//const NORMAL_BUFFER:    usize = 1;
-//const ALBEDO_BUFFER:    usize = 2;
-//const ROUGHNESS_BUFFER: usize = 3;
-//const METALLIC_BUFFER:  usize = 4;
-//const DEPTH_BUFFER:     usize = 5;
+const NORMAL_BUFFER:    usize = 1;
+const ALBEDO_BUFFER:    usize = 2;
+const ROUGHNESS_BUFFER: usize = 3;
+const METALLIC_BUFFER:  usize = 4;
+const DEPTH_BUFFER:     usize = 5;
+const VELOCITY_BUFFER:  usize = 6;
 
 const FLOAT_ATTACHMENT_DESC: AttachmentDescription = AttachmentDescription {
-//    format: Format::R16G16B16A16Sfloat,
-    format: Format::B8G8R8A8Srgb,
+    format: Format::R16G16B16A16Sfloat,
     samples: 1,
     load: LoadOp::Clear,
     store: StoreOp::Store,
@@ -25,24 +27,28 @@ const FLOAT_ATTACHMENT_DESC: AttachmentDescription = AttachmentDescription {
 };
 
 unsafe impl RenderPassDesc for GenericMeshShadingRenderPass {
-    fn num_attachments(&self) -> usize { 1 } // 6 }
+    fn num_attachments(&self) -> usize { 7 }
     fn attachment_desc(&self, num: usize) -> Option<AttachmentDescription> {
         match num {
             POSITION_BUFFER => Some(FLOAT_ATTACHMENT_DESC),
-//            NORMAL_BUFFER => Some(FLOAT_ATTACHMENT_DESC),
-//            ALBEDO_BUFFER => Some(FLOAT_ATTACHMENT_DESC),
-//            ROUGHNESS_BUFFER => Some(FLOAT_ATTACHMENT_DESC),
-//            METALLIC_BUFFER => Some(FLOAT_ATTACHMENT_DESC),
-//            DEPTH_BUFFER => Some(AttachmentDescription {
-//                format: Format::D32Sfloat,
-//                samples: 1,
-//                load: LoadOp::Clear,
-//                store: StoreOp::Store,
-//                stencil_load: LoadOp::DontCare,
-//                stencil_store: StoreOp::DontCare,
-//                initial_layout: ImageLayout::Undefined,
-//                final_layout: ImageLayout::DepthStencilAttachmentOptimal
-//            }),
+            NORMAL_BUFFER => Some(FLOAT_ATTACHMENT_DESC),
+            ALBEDO_BUFFER => Some(FLOAT_ATTACHMENT_DESC),
+            ROUGHNESS_BUFFER => Some(FLOAT_ATTACHMENT_DESC),
+            METALLIC_BUFFER => Some(FLOAT_ATTACHMENT_DESC),
+            DEPTH_BUFFER => Some(AttachmentDescription {
+                format: Format::D32Sfloat,
+                samples: 1,
+                load: LoadOp::Clear,
+                store: StoreOp::Store,
+                stencil_load: LoadOp::DontCare,
+                stencil_store: StoreOp::DontCare,
+                initial_layout: ImageLayout::Undefined,
+                final_layout: ImageLayout::DepthStencilAttachmentOptimal
+            }),
+            VELOCITY_BUFFER => Some(AttachmentDescription {
+                format: Format::R16G16Sfloat,
+                ..FLOAT_ATTACHMENT_DESC
+            }),
             _ => None
         }
     }
@@ -51,14 +57,16 @@ unsafe impl RenderPassDesc for GenericMeshShadingRenderPass {
     fn subpass_desc(&self, num: usize) -> Option<PassDescription> {
         match num {
             0 => Some(PassDescription {
+                // in the order of the outputs of `deferred_shading.frag`
                 color_attachments: vec![
                     (POSITION_BUFFER, ImageLayout::ColorAttachmentOptimal),
-//                    (NORMAL_BUFFER, ImageLayout::ColorAttachmentOptimal),
-//                    (ALBEDO_BUFFER, ImageLayout::ColorAttachmentOptimal),
-//                    (ROUGHNESS_BUFFER, ImageLayout::ColorAttachmentOptimal),
-//                    (METALLIC_BUFFER, ImageLayout::ColorAttachmentOptimal),
+                    (NORMAL_BUFFER, ImageLayout::ColorAttachmentOptimal),
+                    (ALBEDO_BUFFER, ImageLayout::ColorAttachmentOptimal),
+                    (ROUGHNESS_BUFFER, ImageLayout::ColorAttachmentOptimal),
+                    (METALLIC_BUFFER, ImageLayout::ColorAttachmentOptimal),
+                    (VELOCITY_BUFFER, ImageLayout::ColorAttachmentOptimal),
                 ],
-                depth_stencil: None,//Some((DEPTH_BUFFER, ImageLayout::DepthStencilAttachmentOptimal)),
+                depth_stencil: Some((DEPTH_BUFFER, ImageLayout::DepthStencilAttachmentOptimal)),
                 input_attachments: vec![],
                 resolve_attachments: vec![],
                 preserve_attachments: vec![]
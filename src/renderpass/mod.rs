@@ -14,3 +14,18 @@ pub use self::occlusion::OcclusionRenderPass;
 
 pub mod resolve_scene_color;
 pub use self::resolve_scene_color::ResolveSceneColorRenderPass;
+
+pub mod fullscreen;
+pub use self::fullscreen::FullscreenRenderPass;
+
+pub mod shadow;
+pub use self::shadow::ShadowRenderPass;
+
+pub mod light_volumes;
+pub use self::light_volumes::LightVolumeRenderPass;
+
+pub mod probe_capture;
+pub use self::probe_capture::ProbeCaptureRenderPass;
+
+pub mod skybox;
+pub use self::skybox::SkyboxRenderPass;
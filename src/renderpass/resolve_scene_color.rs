@@ -10,25 +10,26 @@ pub struct ResolveSceneColorRenderPass;
 const DIFFUSE_IN:  usize = 0;
 const SPECULAR_IN: usize = 1;
 const SCENE_COLOR:   usize = 2;
-const LUMA_BUFFER:   usize = 3;
 
 const FLOAT_INPUT: AttachmentDescription = AttachmentDescription {
     format: Format::R16G16B16A16Sfloat,
     samples: 1,
     load: LoadOp::Load,
-    store: StoreOp::DontCare,
+    // kept for the lighting debug views
+    store: StoreOp::Store,
     stencil_load: LoadOp::DontCare,
     stencil_store: StoreOp::DontCare,
-    initial_layout: ImageLayout::Undefined,
+    initial_layout: ImageLayout::ColorAttachmentOptimal,
     final_layout: ImageLayout::ColorAttachmentOptimal
 };
 
 unsafe impl RenderPassDesc for ResolveSceneColorRenderPass {
-    fn num_attachments(&self) -> usize { 4 }
+    fn num_attachments(&self) -> usize { 3 }
     fn attachment_desc(&self, num: usize) -> Option<AttachmentDescription> {
         match num {
             DIFFUSE_IN => Some(FLOAT_INPUT),
             SPECULAR_IN => Some(FLOAT_INPUT),
+            // the first write to scene_color each frame, so its old contents don't matter
             SCENE_COLOR => Some(AttachmentDescription {
                 format: Format::R16G16B16A16Sfloat,
                 samples: 1,
@@ -36,16 +37,6 @@ unsafe impl RenderPassDesc for ResolveSceneColorRenderPass {
                 store: StoreOp::Store,
                 stencil_load: LoadOp::DontCare,
                 stencil_store: StoreOp::DontCare,
-                initial_layout: ImageLayout::ColorAttachmentOptimal,
-                final_layout: ImageLayout::ColorAttachmentOptimal
-            }),
-            LUMA_BUFFER => Some(AttachmentDescription {
-                format: Format::R32Uint,
-                samples: 1,
-                load: LoadOp::Clear,
-                store: StoreOp::Store,
-                stencil_load: LoadOp::DontCare,
-                stencil_store: StoreOp::DontCare,
                 initial_layout: ImageLayout::Undefined,
                 final_layout: ImageLayout::ColorAttachmentOptimal
             }),
@@ -58,13 +49,12 @@ unsafe impl RenderPassDesc for ResolveSceneColorRenderPass {
         match num {
             0 => Some(PassDescription {
                 color_attachments: vec![
-                    (SCENE_COLOR, ImageLayout::ColorAttachmentOptimal),
-                    (LUMA_BUFFER, ImageLayout::ColorAttachmentOptimal)
+                    (SCENE_COLOR, ImageLayout::ColorAttachmentOptimal)
                 ],
                 depth_stencil: None,
                 input_attachments: vec![
-                    (DIFFUSE_IN, ImageLayout::ColorAttachmentOptimal),
-                    (SPECULAR_IN, ImageLayout::ColorAttachmentOptimal)
+                    (DIFFUSE_IN, ImageLayout::ShaderReadOnlyOptimal),
+                    (SPECULAR_IN, ImageLayout::ShaderReadOnlyOptimal)
                 ],
                 resolve_attachments: vec![],
                 preserve_attachments: vec![]
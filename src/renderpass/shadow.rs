@@ -0,0 +1,66 @@
+use vulkano::framebuffer::{RenderPassDesc, AttachmentDescription, PassDescription, PassDependencyDescription, LoadOp, StoreOp, RenderPassDescClearValues};
+use vulkano::image::ImageLayout;
+use vulkano::format::{Format, ClearValue};
+
+/// Depth-only render pass for shadow maps. Individual shadow maps in an atlas are selected with
+/// the viewport.
+///
+/// `clear` clears the whole atlas when the pass begins. `load` keeps the existing contents, for
+/// atlases where only some shadow maps are re-rendered each frame.
+pub struct ShadowRenderPass {
+    load: LoadOp,
+}
+
+impl ShadowRenderPass {
+    pub fn clear() -> Self { Self { load: LoadOp::Clear } }
+    pub fn load() -> Self { Self { load: LoadOp::Load } }
+}
+
+const DEPTH_BUFFER: usize = 0;
+
+unsafe impl RenderPassDesc for ShadowRenderPass {
+    fn num_attachments(&self) -> usize { 1 }
+    fn attachment_desc(&self, num: usize) -> Option<AttachmentDescription> {
+        match num {
+            DEPTH_BUFFER => Some(AttachmentDescription {
+                format: Format::D32Sfloat,
+                samples: 1,
+                load: self.load,
+                store: StoreOp::Store,
+                stencil_load: LoadOp::DontCare,
+                stencil_store: StoreOp::DontCare,
+                initial_layout: match self.load {
+                    LoadOp::Load => ImageLayout::DepthStencilAttachmentOptimal,
+                    _ => ImageLayout::Undefined,
+                },
+                final_layout: ImageLayout::DepthStencilAttachmentOptimal
+            }),
+            _ => None
+        }
+    }
+
+    fn num_subpasses(&self) -> usize { 1 }
+    fn subpass_desc(&self, num: usize) -> Option<PassDescription> {
+        match num {
+            0 => Some(PassDescription {
+                color_attachments: vec![],
+                depth_stencil: Some((DEPTH_BUFFER, ImageLayout::DepthStencilAttachmentOptimal)),
+                input_attachments: vec![],
+                resolve_attachments: vec![],
+                preserve_attachments: vec![]
+            }),
+            _ => None
+        }
+    }
+
+    fn num_dependencies(&self) -> usize { 0 }
+    fn dependency_desc(&self, _num: usize) -> Option<PassDependencyDescription> { None }
+}
+
+
+unsafe impl RenderPassDescClearValues<Vec<ClearValue>> for ShadowRenderPass {
+    fn convert_clear_values(&self, values: Vec<ClearValue>) -> Box<dyn Iterator<Item = ClearValue>> {
+        // FIXME: safety checks
+        Box::new(values.into_iter())
+    }
+}
@@ -0,0 +1,109 @@
+#version 450
+
+// Ground-truth ambient occlusion (Jimenez et al. 2016), rendered at half resolution.
+// Outputs the occlusion in r and linear depth in g, for the bilateral blur.
+
+layout(set = 0, binding = 0) uniform sampler2D depth_buffer;
+layout(set = 0, binding = 1) uniform sampler2D gbufferNormal;
+
+layout(location = 0) out vec2 ao_out;
+
+layout(push_constant) uniform Constants {
+    mat4 view;
+    // (1 / proj[0][0], 1 / proj[1][1], proj[2][0], proj[2][1])
+    vec4 proj_info;
+    vec2 ao_dimensions;
+    float radius;
+    float intensity;
+    uint sample_count;
+    uint frame_index;
+    float near_plane;
+    float far_plane;
+    uint depth_mode;
+} constants;
+
+#include "constants.inc"
+#include "depth.inc"
+
+const uint SLICE_COUNT = 2;
+
+vec3 view_position(vec2 uv) {
+    float depth = textureLod(depth_buffer, uv, 0.0).r;
+    float d = linearDepth(depth, constants.near_plane, constants.far_plane, constants.depth_mode);
+    vec2 ndc = uv * 2.0 - 1.0;
+    return vec3((ndc + constants.proj_info.zw) * constants.proj_info.xy * d, -d);
+}
+
+float interleaved_gradient_noise(vec2 pixel) {
+    return fract(52.9829189 * fract(dot(pixel, vec2(0.06711056, 0.00583715))));
+}
+
+void main() {
+    vec2 uv = gl_FragCoord.xy / constants.ao_dimensions;
+    float depth = textureLod(depth_buffer, uv, 0.0).r;
+    if (depth == farDepth(constants.depth_mode)) {
+        ao_out = vec2(1.0, constants.far_plane);
+        return;
+    }
+
+    vec3 P = view_position(uv);
+    vec3 V = normalize(-P);
+    vec3 N = normalize(mat3(constants.view) * textureLod(gbufferNormal, uv, 0.0).rgb);
+
+    // project the world-space radius to pixels, and spread the samples over it
+    vec2 texel = 1.0 / constants.ao_dimensions;
+    float radius_pixels = constants.radius * 0.5 * constants.ao_dimensions.y / (abs(constants.proj_info.y) * -P.z);
+    uint steps = max(constants.sample_count / (2 * SLICE_COUNT), 1);
+    float step_pixels = max(radius_pixels / float(steps), 1.0);
+
+    float noise_slice = interleaved_gradient_noise(gl_FragCoord.xy + float(constants.frame_index % 64) * 5.588238);
+    float noise_step = fract(noise_slice + 0.5 * float(constants.frame_index % 2));
+
+    float visibility = 0.0;
+    for (uint slice = 0; slice < SLICE_COUNT; ++slice) {
+        float phi = (float(slice) + noise_slice) * PI / float(SLICE_COUNT);
+        vec2 direction = vec2(cos(phi), sin(phi));
+
+        // slice plane in view space, using the same depth so the orientation matches screen space
+        vec2 ndc_offset = direction * texel * 2.0;
+        vec3 direction_vs = normalize(vec3(ndc_offset * constants.proj_info.xy, 0.0));
+        vec3 ortho = direction_vs - dot(direction_vs, V) * V;
+        vec3 axis = normalize(cross(ortho, V));
+        vec3 projected_normal = N - axis * dot(N, axis);
+        float projected_length = length(projected_normal);
+        float cos_n = clamp(dot(projected_normal, V) / max(projected_length, 1e-5), -1.0, 1.0);
+        float n = sign(dot(ortho, projected_normal)) * acos(cos_n);
+
+        // find the highest horizon in both directions
+        float horizon_cos0 = -1.0;
+        float horizon_cos1 = -1.0;
+        for (uint i = 0; i < steps; ++i) {
+            vec2 offset = direction * (float(i) + noise_step + 1.0) * step_pixels * texel;
+
+            vec3 delta0 = view_position(uv + offset) - P;
+            vec3 delta1 = view_position(uv - offset) - P;
+            float len0 = length(delta0);
+            float len1 = length(delta1);
+
+            // fade out samples near the edge of the radius
+            float falloff0 = clamp(1.0 - len0 * len0 / (constants.radius * constants.radius), 0.0, 1.0);
+            float falloff1 = clamp(1.0 - len1 * len1 / (constants.radius * constants.radius), 0.0, 1.0);
+
+            horizon_cos0 = max(horizon_cos0, mix(-1.0, dot(delta0, V) / max(len0, 1e-5), falloff0));
+            horizon_cos1 = max(horizon_cos1, mix(-1.0, dot(delta1, V) / max(len1, 1e-5), falloff1));
+        }
+
+        // samples along +direction are on the positive side of the slice
+        float h1 = n + min(acos(horizon_cos0) - n, PI * 0.5);
+        float h0 = n + max(-acos(horizon_cos1) - n, -PI * 0.5);
+
+        // cosine weighted visible arc between the two horizons
+        float sin_n = sin(n);
+        float arc0 = (cos_n + 2.0 * h0 * sin_n - cos(2.0 * h0 - n)) * 0.25;
+        float arc1 = (cos_n + 2.0 * h1 * sin_n - cos(2.0 * h1 - n)) * 0.25;
+        visibility += projected_length * (arc0 + arc1);
+    }
+    visibility /= float(SLICE_COUNT);
+
+    ao_out = vec2(pow(clamp(visibility, 0.0, 1.0), constants.intensity), -P.z);
+}
@@ -0,0 +1,39 @@
+#version 450
+
+// Separable depth-aware blur for the ambient occlusion buffer.
+// Input and output have occlusion in r and linear depth in g.
+
+layout(set = 0, binding = 0) uniform sampler2D ao_input;
+
+layout(location = 0) out vec2 ao_out;
+
+layout(push_constant) uniform Constants {
+    vec2 texel_size;
+    vec2 direction;
+    // how quickly samples are rejected across depth discontinuities
+    float sharpness;
+} constants;
+
+const int BLUR_RADIUS = 4;
+
+void main() {
+    vec2 uv = gl_FragCoord.xy * constants.texel_size;
+    vec2 center = textureLod(ao_input, uv, 0.0).rg;
+
+    float total = center.r;
+    float total_weight = 1.0;
+    for (int i = -BLUR_RADIUS; i <= BLUR_RADIUS; ++i) {
+        if (i == 0) { continue; }
+        vec2 s = textureLod(ao_input, uv + constants.direction * constants.texel_size * float(i), 0.0).rg;
+
+        float sigma = float(BLUR_RADIUS) * 0.5;
+        float spatial = exp(-float(i * i) / (2.0 * sigma * sigma));
+        float depth_diff = abs(s.g - center.g) / max(center.g, 1e-4);
+        float weight = spatial * exp(-depth_diff * constants.sharpness);
+
+        total += s.r * weight;
+        total_weight += weight;
+    }
+
+    ao_out = vec2(total / total_weight, center.g);
+}
@@ -0,0 +1,85 @@
+#version 450
+
+// 13-tap downsample from "Next Generation Post Processing in Call of Duty: Advanced Warfare".
+// The first pass also applies the threshold and a Karis average to suppress fireflies.
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+layout(location = 0) out vec4 downsampled;
+
+layout(push_constant) uniform Constants {
+    vec2 source_texel_size;
+    vec2 target_dimensions;
+    // threshold and knee are in exposed units, so the bloom doesn't change when the exposure adapts
+    float threshold;
+    float knee;
+    uint first_pass;
+} constants;
+
+#include "constants.inc"
+#define EXPOSURE_BINDING 1
+#define EXPOSURE_ACCESS readonly
+#include "exposure.inc"
+
+float luma(vec3 color) {
+    return dot(color, LUMA_COMPONENTS);
+}
+
+float karis_weight(vec3 color) {
+    return 1.0 / (1.0 + luma(color * exposure.value));
+}
+
+// soft-knee threshold curve
+vec3 prefilter(vec3 color) {
+    if (constants.threshold <= 0.0) { return color; }
+    float brightness = max(color.r, max(color.g, color.b)) * exposure.value;
+    float soft_knee = constants.threshold * constants.knee + 0.00001;
+    float soft = clamp(brightness - constants.threshold + soft_knee, 0.0, 2.0 * soft_knee);
+    soft = soft * soft / (4.0 * soft_knee);
+    float contribution = max(soft, brightness - constants.threshold) / max(brightness, 0.00001);
+    return color * contribution;
+}
+
+void main() {
+    vec2 uv = gl_FragCoord.xy / constants.target_dimensions;
+    vec2 t = constants.source_texel_size;
+
+    vec3 a = textureLod(source, uv + t * vec2(-2.0,  2.0), 0.0).rgb;
+    vec3 b = textureLod(source, uv + t * vec2( 0.0,  2.0), 0.0).rgb;
+    vec3 c = textureLod(source, uv + t * vec2( 2.0,  2.0), 0.0).rgb;
+    vec3 d = textureLod(source, uv + t * vec2(-2.0,  0.0), 0.0).rgb;
+    vec3 e = textureLod(source, uv, 0.0).rgb;
+    vec3 f = textureLod(source, uv + t * vec2( 2.0,  0.0), 0.0).rgb;
+    vec3 g = textureLod(source, uv + t * vec2(-2.0, -2.0), 0.0).rgb;
+    vec3 h = textureLod(source, uv + t * vec2( 0.0, -2.0), 0.0).rgb;
+    vec3 i = textureLod(source, uv + t * vec2( 2.0, -2.0), 0.0).rgb;
+    vec3 j = textureLod(source, uv + t * vec2(-1.0,  1.0), 0.0).rgb;
+    vec3 k = textureLod(source, uv + t * vec2( 1.0,  1.0), 0.0).rgb;
+    vec3 l = textureLod(source, uv + t * vec2(-1.0, -1.0), 0.0).rgb;
+    vec3 m = textureLod(source, uv + t * vec2( 1.0, -1.0), 0.0).rgb;
+
+    vec3 result;
+    if (constants.first_pass != 0) {
+        // Karis average: weight each 2x2 block by its inverse luma
+        vec3 g0 = (j + k + l + m) * 0.25;
+        vec3 g1 = (a + b + d + e) * 0.25;
+        vec3 g2 = (b + c + e + f) * 0.25;
+        vec3 g3 = (d + e + g + h) * 0.25;
+        vec3 g4 = (e + f + h + i) * 0.25;
+        float w0 = karis_weight(g0) * 0.5;
+        float w1 = karis_weight(g1) * 0.125;
+        float w2 = karis_weight(g2) * 0.125;
+        float w3 = karis_weight(g3) * 0.125;
+        float w4 = karis_weight(g4) * 0.125;
+        result = (g0 * w0 + g1 * w1 + g2 * w2 + g3 * w3 + g4 * w4) / (w0 + w1 + w2 + w3 + w4);
+        result = prefilter(result);
+    }
+    else {
+        result  = e * 0.125;
+        result += (a + c + g + i) * 0.03125;
+        result += (b + d + f + h) * 0.0625;
+        result += (j + k + l + m) * 0.125;
+    }
+
+    downsampled = vec4(max(result, vec3(0.0)), 1.0);
+}
@@ -0,0 +1,33 @@
+#version 450
+
+// 3x3 tent filter upsample. Output is premultiplied by `blend_alpha`, and blended with
+// `dst * (1 - alpha) + src`, so the same shader can mix mips together or add onto the scene.
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+layout(location = 0) out vec4 upsampled;
+
+layout(push_constant) uniform Constants {
+    vec2 source_texel_size;
+    vec2 target_dimensions;
+    float intensity;
+    float blend_alpha;
+} constants;
+
+void main() {
+    vec2 uv = gl_FragCoord.xy / constants.target_dimensions;
+    vec2 t = constants.source_texel_size;
+
+    vec3 result = textureLod(source, uv, 0.0).rgb * 4.0;
+    result += textureLod(source, uv + t * vec2(-1.0,  0.0), 0.0).rgb * 2.0;
+    result += textureLod(source, uv + t * vec2( 1.0,  0.0), 0.0).rgb * 2.0;
+    result += textureLod(source, uv + t * vec2( 0.0, -1.0), 0.0).rgb * 2.0;
+    result += textureLod(source, uv + t * vec2( 0.0,  1.0), 0.0).rgb * 2.0;
+    result += textureLod(source, uv + t * vec2(-1.0, -1.0), 0.0).rgb;
+    result += textureLod(source, uv + t * vec2( 1.0, -1.0), 0.0).rgb;
+    result += textureLod(source, uv + t * vec2(-1.0,  1.0), 0.0).rgb;
+    result += textureLod(source, uv + t * vec2( 1.0,  1.0), 0.0).rgb;
+    result *= 1.0 / 16.0;
+
+    upsampled = vec4(result * constants.intensity, constants.blend_alpha);
+}
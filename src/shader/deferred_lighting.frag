@@ -9,6 +9,22 @@ layout (input_attachment_index = 4, binding = 4) uniform subpassInput gbufferMet
 layout (set = 0, binding = 5) uniform sampler2D irrCubemap;
 layout (set = 0, binding = 6) uniform sampler2D radCubemap;
 layout (set = 0, binding = 7) uniform sampler2D brdfLookup;
+layout (set = 0, binding = 8) uniform sampler2D ambientOcclusion;
+layout (set = 0, binding = 9) uniform sampler2DShadow sunShadowAtlas;
+
+layout (set = 0, binding = 10) uniform SunData {
+    mat4 cascade_view_proj[4];
+    vec4 cascade_splits;
+    // world-space size of a shadow map texel in each cascade
+    vec4 cascade_texel_sizes;
+    vec4 direction;
+    vec4 color;
+    uint cascade_count;
+    uint shadows_enabled;
+    float depth_bias;
+    float normal_bias;
+    int pcf_radius;
+} sun;
 
 layout(location = 0) out vec4 diffuse_out;
 layout(location = 1) out vec4 specular_out;
@@ -17,38 +33,76 @@ layout(push_constant) uniform Constants {
     mat4 view;
     vec3 view_pos;
     uint debug_vis_mode;
+    vec2 screen_dimensions;
+    uint light_count;
+    // lights are sorted with directional lights first
+    uint directional_light_count;
+    uint light_culling;
 } constants;
 
 #include "lights.inc"
+#include "equirect.inc"
+#include "shadows.inc"
+#define LOCAL_LIGHT_BINDINGS 11
+#include "local_lights.inc"
+#define CLUSTER_BINDINGS 18
+#include "clusters.inc"
+#define PROBE_BINDINGS 21
+#include "reflection_probes.inc"
+#include "debug_vis.inc"
 
 void main() {
-    vec3 light_positions[3];
-    vec3 light_colors[3];
-
-    light_positions[0] = vec3(16.0, 26.0, 16.0);
-    light_colors[0] = vec3(0.2, 0.4, 1.0) * 50.0;
-
-    light_positions[1] = vec3(96.0, 14.0, 14.0);
-    light_colors[1] = vec3(1.0, 0.7, 0.3) * 1000.0;
-
-    light_positions[2] = vec3(64.0, 40.0, -64.0);
-    light_colors[2] = vec3(1.0, 0.2, 0.4) * 500.0;
+    vec3 gbuffer_normal = subpassLoad(gbufferNormal).rgb;
+    // no geometry here, the sky is drawn over it later
+    if (dot(gbuffer_normal, gbuffer_normal) == 0.0) {
+        diffuse_out = vec4(0.0, 0.0, 0.0, 1.0);
+        specular_out = vec4(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
 
     vec3 frag_pos = subpassLoad(gbufferPosition).rgb;
-    vec3 N = normalize(subpassLoad(gbufferNormal).rgb);
+    vec3 N = normalize(gbuffer_normal);
     vec3 V = normalize(constants.view_pos - frag_pos);
     vec3 R = reflect(-V, N);
     vec3 albedo = subpassLoad(gbufferAlbedo).rgb;
-    float roughness = 0.9;//subpassLoad(gbufferRoughness).r;
+    float roughness = subpassLoad(gbufferRoughness).r;
     float metallic = subpassLoad(gbufferMetallic).r;
 
-    // irradiance for point lights
+    // irradiance for scene lights
     vec3 point_lights_diff = vec3(0.0);
     vec3 point_lights_spec = vec3(0.0);
-    for(int i = 0; i < 3; ++i) {
-        point_light(light_positions[i], light_colors[i], N, V, albedo, roughness, metallic, frag_pos, point_lights_diff, point_lights_spec);
+    for (uint i = 0; i < constants.directional_light_count; ++i) {
+        LightData light = scene_lights.lights[i];
+        directional_light(light.direction, light.color, N, V, albedo, roughness, metallic, frag_pos, point_lights_diff, point_lights_spec);
+    }
+    float view_depth = -(constants.view * vec4(frag_pos, 1.0)).z;
+    uvec2 cluster = uvec2(0);
+    if (constants.light_culling == LIGHT_CULLING_CLUSTERED) {
+        cluster = cluster_grid.clusters[cluster_index(gl_FragCoord.xy, view_depth)];
+        for (uint i = 0; i < cluster.y; ++i) {
+            LightData light = scene_lights.lights[cluster_light_indices.indices[cluster.x + i]];
+            local_light(light, N, V, albedo, roughness, metallic, frag_pos, point_lights_diff, point_lights_spec);
+        }
+    }
+    else if (constants.light_culling == LIGHT_CULLING_NONE) {
+        for (uint i = constants.directional_light_count; i < constants.light_count; ++i) {
+            local_light(scene_lights.lights[i], N, V, albedo, roughness, metallic, frag_pos, point_lights_diff, point_lights_spec);
+        }
     }
-    //Lo += directional_light(normalize(vec3(0.5, -1.0, 0.5)), vec3(1.0, 1.0, 0.9) * 5.0, N, V, albedo, roughness, metallic, frag_pos);
+
+    // sun, with cascaded shadows
+    uint cascade = select_cascade(view_depth, sun.cascade_splits, sun.cascade_count);
+    float sun_shadow = 1.0;
+    if (sun.shadows_enabled != 0 && cascade < MAX_CASCADES) {
+        vec3 offset_pos = frag_pos + N * sun.normal_bias * sun.cascade_texel_sizes[cascade];
+        sun_shadow = sample_cascade(sunShadowAtlas, sun.cascade_view_proj[cascade], cascade, offset_pos,
+                                    sun.depth_bias, sun.pcf_radius);
+    }
+    vec3 sun_diff = vec3(0.0);
+    vec3 sun_spec = vec3(0.0);
+    directional_light(sun.direction.xyz, sun.color.rgb * sun_shadow, N, V, albedo, roughness, metallic, frag_pos, sun_diff, sun_spec);
+    point_lights_diff += sun_diff;
+    point_lights_spec += sun_spec;
 
     // specular coefficient
     vec3 F0 = vec3(0.04);
@@ -58,26 +112,34 @@ void main() {
     vec3 kD = 1.0 - kS;
     kD *= 1.0 - metallic;
 
-    // equirectangular UVs from normal
-    vec2 uv = vec2(atan(N.z, N.x), acos(N.y));
-    uv /= vec2(2 * PI, PI);
-
     // diffuse irradiance
-    vec3 irradiance = texture(irrCubemap, uv).rgb;
+    vec3 irradiance = texture(irrCubemap, equirect_uv(N)).rgb;
     vec3 diffuse    = irradiance * albedo;
-    vec3 ibl_diffuse    = (kD * diffuse); // * ao;
-
-    // equirectangular UVs from reflected normal
-    uv = vec2(atan(R.z, R.x), acos(R.y));
-    uv /= vec2(2 * PI, PI);
+    float ao = texture(ambientOcclusion, gl_FragCoord.xy / constants.screen_dimensions).r;
+    vec3 ibl_diffuse    = kD * diffuse * ao;
 
     // specular radiance
+    // last mip of the radiance chain, `RADIANCE_MIP_COUNT - 1` in environment.rs
     const float MAX_REFLECTION_LOD = 4.0;
-    vec3 prefilteredColor = textureLod(radCubemap, uv,  roughness * MAX_REFLECTION_LOD).rgb;
+    vec3 environment = textureLod(radCubemap, equirect_uv(R), roughness * MAX_REFLECTION_LOD).rgb;
+    // local reflection probes, with the environment wherever they don't cover
+    vec4 probe_radiance = reflection_probe_radiance(frag_pos, R, roughness);
+    vec3 prefilteredColor = probe_radiance.rgb + (1.0 - probe_radiance.a) * environment;
     vec2 envBRDF  = texture(brdfLookup, vec2(max(dot(N, V), 0.0), roughness)).rg;
-    vec3 ibl_specular = prefilteredColor * (F * envBRDF.x + envBRDF.y);
+    float NdotV = max(dot(N, V), 0.0);
+    float specular_ao = clamp(pow(NdotV + ao, exp2(-16.0 * roughness - 1.0)) - 1.0 + ao, 0.0, 1.0);
+    vec3 ibl_specular = prefilteredColor * (F * envBRDF.x + envBRDF.y) * specular_ao;
 
     // absolute luminance to pipeline luminance
     diffuse_out = vec4(vec3((point_lights_diff + ibl_diffuse) / INTERNAL_HDR_DIV), 1.0);
     specular_out = vec4(vec3((point_lights_spec + ibl_specular) / INTERNAL_HDR_DIV), 1.0);
+
+    if (constants.debug_vis_mode == DEBUG_VISUALIZE_SHADOW_CASCADES) {
+        diffuse_out.rgb *= cascade_debug_color(cascade);
+        specular_out.rgb *= cascade_debug_color(cascade);
+    }
+    else if (constants.debug_vis_mode == DEBUG_VISUALIZE_LIGHT_CLUSTERS) {
+        diffuse_out.rgb = mix(diffuse_out.rgb, cluster_heatmap(cluster.y) / INTERNAL_HDR_DIV, 0.75);
+        specular_out.rgb *= 0.25;
+    }
 }
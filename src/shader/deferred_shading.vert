@@ -9,16 +9,30 @@ layout(location = 0) out vec3 normal_out;
 layout(location = 1) out vec3 tangent_out;
 layout(location = 2) out vec2 uv_out;
 layout(location = 3) out vec3 surface_pos_out;
+layout(location = 4) out vec4 curr_clip_pos_out;
+layout(location = 5) out vec4 prev_clip_pos_out;
 
 layout(push_constant) uniform Constants {
     mat4 view;
     mat4 proj;
+    float near_plane;
+    float far_plane;
+    uint depth_mode;
+    // `SubsurfaceProfileId` plus one, 0 for none
+    uint subsurface_profile;
 } constants;
 
 layout(set = 1, binding = 0) uniform InstanceData {
     mat4 world;
+    mat4 prev_world;
 } instance;
 
+layout(set = 2, binding = 0) uniform FrameData {
+    // projection without jitter, for motion vectors
+    mat4 view_proj;
+    mat4 prev_view_proj;
+} frame;
+
 
 void main() {
     normal_out = transpose(inverse(mat3(instance.world))) * normal;
@@ -26,5 +40,8 @@ void main() {
     uv_out = uv;
     surface_pos_out = (instance.world * vec4(position, 1.0)).xyz;
 
+    curr_clip_pos_out = frame.view_proj * instance.world * vec4(position, 1.0);
+    prev_clip_pos_out = frame.prev_view_proj * instance.prev_world * vec4(position, 1.0);
+
     gl_Position = constants.proj * constants.view * instance.world * vec4(position, 1.0);
 }
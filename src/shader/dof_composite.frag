@@ -0,0 +1,40 @@
+#version 450
+
+// Blends the half resolution gather over the full resolution scene, with
+// `dst * (1 - alpha) + src`. In focus pixels keep the sharp scene, unless blurred foreground
+// from the near field covers them.
+
+layout(set = 0, binding = 0) uniform sampler2D gathered;
+layout(set = 0, binding = 1) uniform sampler2D depth_buffer;
+
+layout(location = 0) out vec4 composited;
+
+layout(push_constant) uniform Constants {
+    vec4 focus_ranges;
+    vec2 screen_dimensions;
+    float coc_scale;
+    float focus_distance;
+    float max_coc;
+    float near_plane;
+    float far_plane;
+    uint focus_mode;
+    uint depth_mode;
+} constants;
+
+#include "depth.inc"
+#include "dof.inc"
+
+void main() {
+    vec2 uv = gl_FragCoord.xy / constants.screen_dimensions;
+    float depth = texelFetch(depth_buffer, ivec2(gl_FragCoord.xy), 0).r;
+    float linear = linearDepth(depth, constants.near_plane, constants.far_plane, constants.depth_mode);
+    float coc = circle_of_confusion(linear, constants.focus_mode, constants.coc_scale, constants.focus_distance,
+                                    constants.focus_ranges, constants.max_coc);
+
+    vec4 blurred = textureLod(gathered, uv, 0.0);
+    // CoCs under a pixel look sharp, fade to the blurred image over the next two pixels
+    float blur = clamp((abs(coc) - 1.0) * 0.5, 0.0, 1.0);
+    float alpha = max(blur, blurred.a);
+
+    composited = vec4(blurred.rgb * alpha, alpha);
+}
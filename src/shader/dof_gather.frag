@@ -0,0 +1,59 @@
+#version 450
+
+// Half resolution bokeh gather over a disc of golden angle spiral samples. Samples are treated
+// as discs of their own CoC and contribute where they reach this pixel. The far field is limited
+// to this pixel's own CoC, so sharper things in front don't smear over the blurred background.
+// The near field isn't, so blurred foreground spreads over whatever is behind it. The two are
+// mixed by how much of the disc the near field covers, which is also output as alpha.
+
+layout(set = 0, binding = 0) uniform sampler2D prefiltered;
+
+layout(location = 0) out vec4 gathered;
+
+layout(push_constant) uniform Constants {
+    vec2 target_dimensions;
+    // in half resolution pixels
+    float max_coc;
+    uint sample_count;
+} constants;
+
+const float GOLDEN_ANGLE = 2.39996323;
+
+void main() {
+    vec2 texel = 1.0 / constants.target_dimensions;
+    vec2 uv = gl_FragCoord.xy * texel;
+
+    vec4 center = textureLod(prefiltered, uv, 0.0);
+    float center_far = max(center.a, 0.0);
+
+    vec3 far_sum = center.rgb;
+    float far_weight = 1.0;
+    vec3 near_sum = vec3(0.0);
+    float near_weight = 0.0;
+
+    uint count = max(constants.sample_count, 1);
+    for (uint i = 0; i < count; ++i) {
+        float r = sqrt((float(i) + 0.5) / float(count)) * constants.max_coc;
+        float theta = float(i) * GOLDEN_ANGLE;
+        vec2 offset = vec2(cos(theta), sin(theta)) * r;
+        vec4 s = textureLod(prefiltered, uv + offset * texel, 0.0);
+
+        // soften the disc edge over one pixel
+        float far_reach = min(max(s.a, 0.0), center_far);
+        float w = clamp(far_reach - r + 1.0, 0.0, 1.0);
+        far_sum += s.rgb * w;
+        far_weight += w;
+
+        float near_reach = max(-s.a, 0.0);
+        float n = clamp(near_reach - r + 1.0, 0.0, 1.0);
+        near_sum += s.rgb * n;
+        near_weight += n;
+    }
+
+    vec3 far_color = far_sum / far_weight;
+    vec3 near_color = near_weight > 0.0 ? near_sum / near_weight : far_color;
+    // a foreground edge covers about half the disc, count that as fully covered
+    float near_alpha = clamp(2.0 * near_weight / float(count), 0.0, 1.0);
+
+    gathered = vec4(mix(far_color, near_color, near_alpha), near_alpha);
+}
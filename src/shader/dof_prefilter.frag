@@ -0,0 +1,48 @@
+#version 450
+
+// Downsamples scene color to half resolution and stores the circle of confusion in alpha, in
+// half resolution pixels. Of the four full resolution depths, the nearest one sets the CoC, so
+// the near field covers the edges of foreground objects.
+
+layout(set = 0, binding = 0) uniform sampler2D scene_color;
+layout(set = 0, binding = 1) uniform sampler2D depth_buffer;
+
+layout(location = 0) out vec4 prefiltered;
+
+layout(push_constant) uniform Constants {
+    vec4 focus_ranges;
+    vec2 screen_dimensions;
+    float coc_scale;
+    float focus_distance;
+    float max_coc;
+    float near_plane;
+    float far_plane;
+    uint focus_mode;
+    uint depth_mode;
+} constants;
+
+#include "depth.inc"
+#include "dof.inc"
+
+void main() {
+    // gl_FragCoord is at the center of a 2x2 block of full resolution texels
+    ivec2 coord = ivec2(gl_FragCoord.xy) * 2;
+    vec2 uv = gl_FragCoord.xy * 2.0 / constants.screen_dimensions;
+    ivec2 max_coord = ivec2(constants.screen_dimensions) - 1;
+
+    float nearest = farDepth(constants.depth_mode);
+    for (int y = 0; y < 2; ++y) {
+        for (int x = 0; x < 2; ++x) {
+            float d = texelFetch(depth_buffer, min(coord + ivec2(x, y), max_coord), 0).r;
+            if (depthCloser(d, nearest, constants.depth_mode)) {
+                nearest = d;
+            }
+        }
+    }
+    float linear = linearDepth(nearest, constants.near_plane, constants.far_plane, constants.depth_mode);
+    float coc = circle_of_confusion(linear, constants.focus_mode, constants.coc_scale, constants.focus_distance,
+                                    constants.focus_ranges, constants.max_coc);
+
+    vec3 color = textureLod(scene_color, uv, 0.0).rgb;
+    prefiltered = vec4(color, coc * 0.5);
+}
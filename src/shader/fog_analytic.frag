@@ -0,0 +1,61 @@
+#version 450
+
+// Height fog evaluated in closed form along each view ray, for low quality settings. Only the
+// global height fog is included, lit by the sun without shadows and by the sky. Blended into
+// `scene_color` the same way as the froxel fog.
+
+layout(set = 0, binding = 0) uniform sampler2D depthBuffer;
+layout(set = 0, binding = 1) uniform sampler2D irrCubemap;
+layout(set = 0, binding = 2) uniform FogData {
+    // with the same jitter the depth buffer was rendered with
+    mat4 inv_view_proj;
+    vec4 view_pos;
+    vec4 view_forward;
+    // rgb: scattering albedo
+    vec4 albedo;
+    // xyz: direction the sun's light travels in
+    vec4 sun_direction;
+    // rgb: sun color * intensity
+    vec4 sun_color;
+    float density;
+    float height_falloff;
+    float base_height;
+    float anisotropy;
+    float near_plane;
+    float far_plane;
+    // fog ends here, so it doesn't cover the sky completely
+    float max_distance;
+    float ambient_intensity;
+    uint depth_mode;
+} fog;
+
+layout(location = 0) out vec4 fog_out;
+
+#include "constants.inc"
+#include "depth.inc"
+#include "equirect.inc"
+#include "fog.inc"
+
+void main() {
+    vec2 uv = gl_FragCoord.xy / vec2(textureSize(depthBuffer, 0));
+    float depth = texelFetch(depthBuffer, ivec2(gl_FragCoord.xy), 0).r;
+    float view_depth = min(linearDepth(depth, fog.near_plane, fog.far_plane, fog.depth_mode), fog.max_distance);
+
+    vec3 ray = normalize(reconstructPosition(uv, 0.5, fog.inv_view_proj) - fog.view_pos.xyz);
+    float ray_length = view_depth / dot(ray, fog.view_forward.xyz);
+
+    // integral of the exponential height fog along the ray, approaching the plain length times
+    // the density at the camera as the ray becomes horizontal
+    float camera_density = height_fog_density(fog.view_pos.y, fog.density, fog.height_falloff, fog.base_height);
+    float height_change = fog.height_falloff * ray.y * ray_length;
+    float falloff_integral = abs(height_change) > 1e-4 ? (1.0 - exp(-height_change)) / height_change : 1.0;
+    float optical_depth = camera_density * ray_length * falloff_integral;
+    float transmittance = exp(-optical_depth);
+
+    vec3 ambient = 0.5 * (textureLod(irrCubemap, equirect_uv(vec3(0.0, 1.0, 0.0)), 0.0).rgb
+                        + textureLod(irrCubemap, equirect_uv(vec3(0.0, -1.0, 0.0)), 0.0).rgb);
+    vec3 radiance = ambient * fog.ambient_intensity
+                  + fog.sun_color.rgb * henyey_greenstein(dot(fog.sun_direction.xyz, -ray), fog.anisotropy);
+
+    fog_out = vec4((1.0 - transmittance) * fog.albedo.rgb * radiance, transmittance);
+}
@@ -0,0 +1,38 @@
+#version 450
+
+// Applies the integrated froxel fog to `scene_color`. Looks up the scattering and transmittance
+// between the camera and each pixel's depth, clamped to the end of the froxel grid for the sky.
+// Blended as `scene_color * transmittance + scattered`.
+
+layout(set = 0, binding = 0) uniform sampler3D integratedFog;
+layout(set = 0, binding = 1) uniform sampler2D depthBuffer;
+
+layout(location = 0) out vec4 fog_out;
+
+layout(push_constant) uniform Constants {
+    // one over the size of the froxel grid in pixels, which may overhang the screen
+    vec2 froxel_uv_scale;
+    float near_plane;
+    float far_plane;
+    // view depth range covered by the froxels
+    float fog_near;
+    float fog_far;
+    uint depth_mode;
+} constants;
+
+#include "constants.inc"
+#include "depth.inc"
+#include "fog.inc"
+
+void main() {
+    float depth = texelFetch(depthBuffer, ivec2(gl_FragCoord.xy), 0).r;
+    float view_depth = min(linearDepth(depth, constants.near_plane, constants.far_plane, constants.depth_mode), constants.fog_far);
+
+    // each froxel holds the fog up to its far edge, so the slice ending at this depth is sampled
+    // at its center
+    float slice_count = float(textureSize(integratedFog, 0).z);
+    float slice = froxel_slice(view_depth, slice_count, constants.fog_near, constants.fog_far);
+    vec3 uvw = vec3(gl_FragCoord.xy * constants.froxel_uv_scale, (slice - 0.5) / slice_count);
+
+    fog_out = textureLod(integratedFog, uvw, 0.0);
+}
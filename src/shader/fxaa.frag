@@ -0,0 +1,157 @@
+#version 450
+
+// FXAA 3.11 quality path, operating on the tonemapped LDR image.
+
+layout(set = 0, binding = 0) uniform sampler2D ldr_color;
+
+layout(location = 0) out vec4 out_color;
+
+layout(push_constant) uniform Constants {
+    vec2 inv_screen_dimensions;
+    // amount of sub-pixel aliasing removal, 0 (sharp) to 1 (soft)
+    float subpix;
+    // minimum local contrast relative to the max luma needed to process a pixel
+    float edge_threshold;
+    // minimum absolute contrast, skips processing in dark areas
+    float edge_threshold_min;
+    uint search_steps;
+    uint enabled;
+} constants;
+
+#include "constants.inc"
+
+// step sizes for the edge end search, taken from the "extreme" preset. shorter searches use the
+// tail of the table so they still cover a long edge, just less precisely.
+const uint MAX_SEARCH_STEPS = 12;
+const float STEP_SIZES[MAX_SEARCH_STEPS] = float[](1.0, 1.0, 1.0, 1.0, 1.0, 1.5, 2.0, 2.0, 2.0, 2.0, 4.0, 8.0);
+
+float step_size(uint i) {
+    if (i == 0) { return 1.0; }
+    return STEP_SIZES[min(i + MAX_SEARCH_STEPS - constants.search_steps, MAX_SEARCH_STEPS - 1)];
+}
+
+// the input is sRGB, so texture reads are linear. sqrt is a cheap approximation of gamma.
+float luma(vec3 color) {
+    return sqrt(dot(color, LUMA_COMPONENTS));
+}
+
+float luma_at(vec2 uv) {
+    return luma(textureLod(ldr_color, uv, 0.0).rgb);
+}
+
+void main() {
+    vec2 inv = constants.inv_screen_dimensions;
+    vec2 uv = gl_FragCoord.xy * inv;
+    vec3 color_center = textureLod(ldr_color, uv, 0.0).rgb;
+
+    if (constants.enabled == 0) {
+        out_color = vec4(color_center, 1.0);
+        return;
+    }
+
+    float luma_center = luma(color_center);
+    float luma_down  = luma(textureLodOffset(ldr_color, uv, 0.0, ivec2( 0, -1)).rgb);
+    float luma_up    = luma(textureLodOffset(ldr_color, uv, 0.0, ivec2( 0,  1)).rgb);
+    float luma_left  = luma(textureLodOffset(ldr_color, uv, 0.0, ivec2(-1,  0)).rgb);
+    float luma_right = luma(textureLodOffset(ldr_color, uv, 0.0, ivec2( 1,  0)).rgb);
+
+    float luma_min = min(luma_center, min(min(luma_down, luma_up), min(luma_left, luma_right)));
+    float luma_max = max(luma_center, max(max(luma_down, luma_up), max(luma_left, luma_right)));
+    float luma_range = luma_max - luma_min;
+
+    // early exit for pixels that aren't on an edge
+    if (luma_range < max(constants.edge_threshold_min, luma_max * constants.edge_threshold)) {
+        out_color = vec4(color_center, 1.0);
+        return;
+    }
+
+    float luma_down_left  = luma(textureLodOffset(ldr_color, uv, 0.0, ivec2(-1, -1)).rgb);
+    float luma_up_right   = luma(textureLodOffset(ldr_color, uv, 0.0, ivec2( 1,  1)).rgb);
+    float luma_up_left    = luma(textureLodOffset(ldr_color, uv, 0.0, ivec2(-1,  1)).rgb);
+    float luma_down_right = luma(textureLodOffset(ldr_color, uv, 0.0, ivec2( 1, -1)).rgb);
+
+    float luma_down_up = luma_down + luma_up;
+    float luma_left_right = luma_left + luma_right;
+    float luma_left_corners = luma_down_left + luma_up_left;
+    float luma_down_corners = luma_down_left + luma_down_right;
+    float luma_right_corners = luma_down_right + luma_up_right;
+    float luma_up_corners = luma_up_right + luma_up_left;
+
+    // estimate whether the edge is horizontal or vertical
+    float edge_horizontal = abs(-2.0 * luma_left + luma_left_corners)
+                          + abs(-2.0 * luma_center + luma_down_up) * 2.0
+                          + abs(-2.0 * luma_right + luma_right_corners);
+    float edge_vertical   = abs(-2.0 * luma_up + luma_up_corners)
+                          + abs(-2.0 * luma_center + luma_left_right) * 2.0
+                          + abs(-2.0 * luma_down + luma_down_corners);
+    bool is_horizontal = edge_horizontal >= edge_vertical;
+
+    // pick the side of the edge with the steepest gradient
+    float luma1 = is_horizontal ? luma_down : luma_left;
+    float luma2 = is_horizontal ? luma_up : luma_right;
+    float gradient1 = luma1 - luma_center;
+    float gradient2 = luma2 - luma_center;
+    bool is1_steepest = abs(gradient1) >= abs(gradient2);
+    float gradient_scaled = 0.25 * max(abs(gradient1), abs(gradient2));
+
+    float step_length = is_horizontal ? inv.y : inv.x;
+    float luma_local_average;
+    if (is1_steepest) {
+        step_length = -step_length;
+        luma_local_average = 0.5 * (luma1 + luma_center);
+    }
+    else {
+        luma_local_average = 0.5 * (luma2 + luma_center);
+    }
+
+    // move half a pixel onto the edge
+    vec2 current_uv = uv;
+    if (is_horizontal) { current_uv.y += step_length * 0.5; }
+    else { current_uv.x += step_length * 0.5; }
+
+    // search along the edge in both directions until the luma changes enough
+    vec2 offset = is_horizontal ? vec2(inv.x, 0.0) : vec2(0.0, inv.y);
+    vec2 uv1 = current_uv - offset * step_size(0);
+    vec2 uv2 = current_uv + offset * step_size(0);
+    float luma_end1 = luma_at(uv1) - luma_local_average;
+    float luma_end2 = luma_at(uv2) - luma_local_average;
+    bool reached1 = abs(luma_end1) >= gradient_scaled;
+    bool reached2 = abs(luma_end2) >= gradient_scaled;
+
+    if (!reached1) { uv1 -= offset * step_size(1); }
+    if (!reached2) { uv2 += offset * step_size(1); }
+
+    for (uint i = 2; i < constants.search_steps && !(reached1 && reached2); ++i) {
+        if (!reached1) { luma_end1 = luma_at(uv1) - luma_local_average; }
+        if (!reached2) { luma_end2 = luma_at(uv2) - luma_local_average; }
+        reached1 = abs(luma_end1) >= gradient_scaled;
+        reached2 = abs(luma_end2) >= gradient_scaled;
+        if (!reached1) { uv1 -= offset * step_size(i); }
+        if (!reached2) { uv2 += offset * step_size(i); }
+    }
+
+    float distance1 = is_horizontal ? (uv.x - uv1.x) : (uv.y - uv1.y);
+    float distance2 = is_horizontal ? (uv2.x - uv.x) : (uv2.y - uv.y);
+    bool is_direction1 = distance1 < distance2;
+    float distance_final = min(distance1, distance2);
+    float edge_thickness = distance1 + distance2;
+    float pixel_offset = -distance_final / edge_thickness + 0.5;
+
+    // only offset if the luma at the closer edge end varies in the right direction
+    bool is_luma_center_smaller = luma_center < luma_local_average;
+    bool correct_variation = ((is_direction1 ? luma_end1 : luma_end2) < 0.0) != is_luma_center_smaller;
+    float final_offset = correct_variation ? pixel_offset : 0.0;
+
+    // sub-pixel antialiasing
+    float luma_average = (1.0 / 12.0) * (2.0 * (luma_down_up + luma_left_right) + luma_left_corners + luma_right_corners);
+    float subpixel_offset1 = clamp(abs(luma_average - luma_center) / luma_range, 0.0, 1.0);
+    float subpixel_offset2 = (-2.0 * subpixel_offset1 + 3.0) * subpixel_offset1 * subpixel_offset1;
+    float subpixel_offset_final = subpixel_offset2 * subpixel_offset2 * constants.subpix;
+    final_offset = max(final_offset, subpixel_offset_final);
+
+    vec2 final_uv = uv;
+    if (is_horizontal) { final_uv.y += final_offset * step_length; }
+    else { final_uv.x += final_offset * step_length; }
+
+    out_color = vec4(textureLod(ldr_color, final_uv, 0.0).rgb, 1.0);
+}
@@ -0,0 +1,44 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D gbufferPosition;
+layout(set = 0, binding = 1) uniform sampler2D gbufferNormal;
+layout(set = 0, binding = 2) uniform sampler2D gbufferAlbedo;
+layout(set = 0, binding = 3) uniform sampler2D gbufferRoughness;
+layout(set = 0, binding = 4) uniform sampler2D gbufferMetallic;
+
+layout(location = 0) out vec4 diffuse_out;
+layout(location = 1) out vec4 specular_out;
+
+layout(push_constant) uniform Constants {
+    mat4 mvp;
+    vec3 view_pos;
+    uint light_index;
+} constants;
+
+#include "constants.inc"
+#include "lights.inc"
+#include "shadows.inc"
+#define LOCAL_LIGHT_BINDINGS 5
+#include "local_lights.inc"
+
+void main() {
+    ivec2 coord = ivec2(gl_FragCoord.xy);
+    vec3 normal = texelFetch(gbufferNormal, coord, 0).rgb;
+    // the G-buffer normal is cleared to zero where there is no geometry
+    if (dot(normal, normal) < 0.5) { discard; }
+
+    vec3 frag_pos = texelFetch(gbufferPosition, coord, 0).rgb;
+    vec3 N = normalize(normal);
+    vec3 V = normalize(constants.view_pos - frag_pos);
+    vec3 albedo = texelFetch(gbufferAlbedo, coord, 0).rgb;
+    float roughness = texelFetch(gbufferRoughness, coord, 0).r;
+    float metallic = texelFetch(gbufferMetallic, coord, 0).r;
+
+    vec3 diffuse = vec3(0.0);
+    vec3 specular = vec3(0.0);
+    local_light(scene_lights.lights[constants.light_index], N, V, albedo, roughness, metallic, frag_pos, diffuse, specular);
+
+    // the light buffers are stored scaled down, like in deferred_lighting.frag
+    diffuse_out = vec4(diffuse / INTERNAL_HDR_DIV, 0.0);
+    specular_out = vec4(specular / INTERNAL_HDR_DIV, 0.0);
+}
@@ -0,0 +1,13 @@
+#version 450
+
+layout (location = 0) in vec3 position;
+
+layout(push_constant) uniform Constants {
+    mat4 mvp;
+    vec3 view_pos;
+    uint light_index;
+} constants;
+
+void main() {
+    gl_Position = constants.mvp * vec4(position, 1.0);
+}
@@ -2,6 +2,14 @@
 
 pub mod runtime;
 
+/// Vertex shader shared by fullscreen passes.
+pub mod fullscreen {
+    vulkano_shaders::shader!{
+        ty: "vertex",
+        path: "src/shader/fullscreen.vert"
+    }
+}
+
 /// Shader for rendering line sets.
 pub mod lines {
     pub mod vertex {
@@ -18,7 +26,7 @@ pub mod lines {
     }
 }
 
-/// Shader for rendering the skybox.
+/// Procedural skybox.
 pub mod skybox {
     pub mod vertex {
         vulkano_shaders::shader!{
@@ -34,6 +42,22 @@ pub mod skybox {
     }
 }
 
+/// Skybox sampling an equirectangular HDR environment.
+pub mod skybox_hdri {
+    pub mod vertex {
+        vulkano_shaders::shader!{
+            ty: "vertex",
+            path: "src/shader/skybox_hdri.vert"
+        }
+    }
+    pub mod fragment {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/skybox_hdri.frag"
+        }
+    }
+}
+
 /// Shader for rendering text.
 pub mod text {
     pub mod vertex {
@@ -66,6 +90,22 @@ pub mod mesh_generic {
     }
 }
 
+/// Depth-only shaders for rendering shadow maps
+pub mod shadow_depth {
+    pub mod vertex {
+        vulkano_shaders::shader!{
+            ty: "vertex",
+            path: "src/shader/shadow_depth.vert"
+        }
+    }
+    pub mod fragment {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/shadow_depth.frag"
+        }
+    }
+}
+
 /// Deferred pipeline lighting shaders
 pub mod deferred_lighting {
     pub mod vertex {
@@ -82,6 +122,48 @@ pub mod deferred_lighting {
     }
 }
 
+/// Per-light bounding volumes for local lights
+pub mod light_volume {
+    pub mod vertex {
+        vulkano_shaders::shader!{
+            ty: "vertex",
+            path: "src/shader/light_volume.vert"
+        }
+    }
+    pub mod fragment {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/light_volume.frag"
+        }
+    }
+}
+
+/// Forward shading for reflection probe captures
+pub mod probe_capture {
+    pub mod vertex {
+        vulkano_shaders::shader!{
+            ty: "vertex",
+            path: "src/shader/probe_capture.vert"
+        }
+    }
+    pub mod fragment {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/probe_capture.frag"
+        }
+    }
+}
+
+/// GGX prefiltering of reflection probe captures
+pub mod probe_filter {
+    pub mod fragment {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/probe_filter.frag"
+        }
+    }
+}
+
 /// Tonemapping pass shaders
 pub mod tonemapper {
     pub mod vertex {
@@ -130,10 +212,221 @@ pub mod occlusion {
     }
 }
 
+/// Temporal anti-aliasing resolve
+pub mod taa {
+    pub mod fragment {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/taa.frag"
+        }
+    }
+}
+
+/// FXAA 3.11 post processing
+pub mod fxaa {
+    pub mod fragment {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/fxaa.frag"
+        }
+    }
+}
+
+/// Ambient occlusion and its bilateral blur
+pub mod ao {
+    pub mod gtao {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/ao.frag"
+        }
+    }
+    pub mod blur {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/ao_blur.frag"
+        }
+    }
+}
+
+/// Separable screen-space subsurface scattering blur
+pub mod subsurface {
+    pub mod blur {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/subsurface_blur.frag"
+        }
+    }
+}
+
+/// Screen-space reflections: hierarchical-Z pyramid, trace and composite
+pub mod ssr {
+    pub mod hiz {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/ssr_hiz.frag"
+        }
+    }
+    pub mod trace {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/ssr_trace.frag"
+        }
+    }
+    pub mod composite {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/ssr_composite.frag"
+        }
+    }
+}
+
+/// Volumetric fog: froxel injection and integration, apply, and the analytic fallback
+pub mod volumetric_fog {
+    pub mod inject {
+        vulkano_shaders::shader!{
+            ty: "compute",
+            path: "src/shader/fog_inject.comp"
+        }
+    }
+    pub mod integrate {
+        vulkano_shaders::shader!{
+            ty: "compute",
+            path: "src/shader/fog_integrate.comp"
+        }
+    }
+    pub mod apply {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/fog_apply.frag"
+        }
+    }
+    pub mod analytic {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/fog_analytic.frag"
+        }
+    }
+}
+
 
+/// Depth of field: CoC prefilter, bokeh gather and composite
+pub mod dof {
+    pub mod prefilter {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/dof_prefilter.frag"
+        }
+    }
+    pub mod gather {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/dof_gather.frag"
+        }
+    }
+    pub mod composite {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/dof_composite.frag"
+        }
+    }
+}
+
+/// Motion blur: velocity, tile max, neighbor max and reconstruction
+pub mod motion_blur {
+    pub mod velocity {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/motion_blur_velocity.frag"
+        }
+    }
+    pub mod tile_max {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/motion_blur_tile_max.frag"
+        }
+    }
+    pub mod neighbor_max {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/motion_blur_neighbor_max.frag"
+        }
+    }
+    pub mod reconstruct {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/motion_blur.frag"
+        }
+    }
+}
+
+/// Bloom downsample/upsample chain
+pub mod bloom {
+    pub mod downsample {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/bloom_downsample.frag"
+        }
+    }
+    pub mod upsample {
+        vulkano_shaders::shader!{
+            ty: "fragment",
+            path: "src/shader/bloom_upsample.frag"
+        }
+    }
+}
+
+
+/// Clustered light culling
+pub mod light_culling {
+    vulkano_shaders::shader!{
+        ty: "compute",
+        path: "src/shader/light_culling.comp"
+    }
+}
+
+
+/// Image based lighting generation from an equirectangular environment
+pub mod ibl {
+    pub mod irradiance {
+        vulkano_shaders::shader!{
+            ty: "compute",
+            path: "src/shader/ibl_irradiance.comp"
+        }
+    }
+    pub mod prefilter {
+        vulkano_shaders::shader!{
+            ty: "compute",
+            path: "src/shader/ibl_prefilter.comp"
+        }
+    }
+    pub mod brdf {
+        vulkano_shaders::shader!{
+            ty: "compute",
+            path: "src/shader/ibl_brdf.comp"
+        }
+    }
+}
+
+
+/// Equirectangular environment of the analytic sky
+pub mod sky_environment {
+    vulkano_shaders::shader!{
+        ty: "compute",
+        path: "src/shader/sky_environment.comp"
+    }
+}
+
+
+/// Auto exposure: luminance histogram, then adaptation
 pub mod histogram {
     vulkano_shaders::shader!{
         ty: "compute",
         path: "src/shader/histogram.comp"
     }
 }
+pub mod exposure {
+    vulkano_shaders::shader!{
+        ty: "compute",
+        path: "src/shader/exposure.comp"
+    }
+}
@@ -0,0 +1,90 @@
+#version 450
+
+// Reconstruction filter from McGuire et al. 2012, "A Reconstruction Filter for Plausible Motion
+// Blur". Samples along the dominant velocity of the neighborhood, and weighs each sample by
+// whether it's in front of this pixel and moving over it, or behind and this pixel's own blur
+// reaches it.
+
+layout(set = 0, binding = 0) uniform sampler2D scene_color;
+layout(set = 0, binding = 1) uniform sampler2D velocity;
+layout(set = 0, binding = 2) uniform sampler2D neighbor_max;
+layout(set = 0, binding = 3) uniform sampler2D depth_buffer;
+
+layout(location = 0) out vec4 blurred;
+
+layout(push_constant) uniform Constants {
+    vec2 screen_dimensions;
+    float near_plane;
+    float far_plane;
+    uint sample_count;
+    uint depth_mode;
+} constants;
+
+#include "depth.inc"
+#include "motion_blur.inc"
+
+// depth difference in meters over which samples blend from in front to behind
+const float SOFT_Z_EXTENT = 0.1;
+
+float cone(float dist, float len) {
+    return clamp(1.0 - dist / max(len, 1e-4), 0.0, 1.0);
+}
+
+float cylinder(float dist, float len) {
+    return 1.0 - smoothstep(0.95 * len, 1.05 * len, dist);
+}
+
+// 1 if a is in front of b
+float soft_depth_compare(float a, float b) {
+    return clamp(1.0 - (a - b) / SOFT_Z_EXTENT, 0.0, 1.0);
+}
+
+float linear_depth_at(ivec2 coord) {
+    return linearDepth(texelFetch(depth_buffer, coord, 0).r, constants.near_plane, constants.far_plane, constants.depth_mode);
+}
+
+void main() {
+    ivec2 coord = ivec2(gl_FragCoord.xy);
+    ivec2 max_coord = ivec2(constants.screen_dimensions) - 1;
+    vec3 center_color = texelFetch(scene_color, coord, 0).rgb;
+
+    vec2 vn = texelFetch(neighbor_max, coord / MOTION_BLUR_TILE_SIZE, 0).rg;
+    if (length(vn) <= 0.5) {
+        blurred = vec4(center_color, 1.0);
+        return;
+    }
+
+    vec2 vx = texelFetch(velocity, coord, 0).rg;
+    float len_x = max(length(vx), 0.5);
+    float zx = linear_depth_at(coord);
+
+    // interleaved gradient noise, hides the banding between samples
+    float jitter = fract(52.9829189 * fract(dot(gl_FragCoord.xy, vec2(0.06711056, 0.00583715)))) - 0.5;
+
+    float weight = 1.0 / len_x;
+    vec3 sum = center_color * weight;
+
+    uint count = max(constants.sample_count, 2);
+    for (uint i = 0; i < count; ++i) {
+        float t = mix(-1.0, 1.0, (float(i) + jitter + 1.0) / float(count + 1));
+        ivec2 sample_coord = clamp(ivec2(gl_FragCoord.xy + vn * t), ivec2(0), max_coord);
+        if (sample_coord == coord) {
+            continue;
+        }
+
+        float dist = length(vec2(sample_coord - coord));
+        float zy = linear_depth_at(sample_coord);
+        float len_y = length(texelFetch(velocity, sample_coord, 0).rg);
+
+        float front = soft_depth_compare(zy, zx);
+        float back = soft_depth_compare(zx, zy);
+        float alpha = front * cone(dist, len_y)
+                    + back * cone(dist, len_x)
+                    + cylinder(dist, len_y) * cylinder(dist, len_x) * 2.0;
+
+        weight += alpha;
+        sum += texelFetch(scene_color, sample_coord, 0).rgb * alpha;
+    }
+
+    blurred = vec4(sum / weight, 1.0);
+}
@@ -0,0 +1,23 @@
+#version 450
+
+// Longest tile velocity in the 3x3 tiles around each tile. Velocities are clamped to a tile, so
+// this covers everything that can blur into the center tile.
+
+layout(set = 0, binding = 0) uniform sampler2D tile_max;
+
+layout(location = 0) out vec2 neighbor_max;
+
+#include "motion_blur.inc"
+
+void main() {
+    ivec2 coord = ivec2(gl_FragCoord.xy);
+    ivec2 max_coord = textureSize(tile_max, 0) - 1;
+
+    vec2 result = vec2(0.0);
+    for (int y = -1; y <= 1; ++y) {
+        for (int x = -1; x <= 1; ++x) {
+            result = max_velocity(result, texelFetch(tile_max, clamp(coord + ivec2(x, y), ivec2(0), max_coord), 0).rg);
+        }
+    }
+    neighbor_max = result;
+}
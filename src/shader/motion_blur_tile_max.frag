@@ -0,0 +1,22 @@
+#version 450
+
+// Longest velocity in each tile.
+
+layout(set = 0, binding = 0) uniform sampler2D velocity;
+
+layout(location = 0) out vec2 tile_max;
+
+#include "motion_blur.inc"
+
+void main() {
+    ivec2 origin = ivec2(gl_FragCoord.xy) * MOTION_BLUR_TILE_SIZE;
+    ivec2 max_coord = textureSize(velocity, 0) - 1;
+
+    vec2 result = vec2(0.0);
+    for (int y = 0; y < MOTION_BLUR_TILE_SIZE; ++y) {
+        for (int x = 0; x < MOTION_BLUR_TILE_SIZE; ++x) {
+            result = max_velocity(result, texelFetch(velocity, min(origin + ivec2(x, y), max_coord), 0).rg);
+        }
+    }
+    tile_max = result;
+}
@@ -0,0 +1,45 @@
+#version 450
+
+// Velocity for motion blur, in pixels, halved so it's the radius of the blur around each pixel.
+// Uses the G-buffer's per-object motion vectors, or reprojects depth with last frame's camera
+// when `per_object` is off. Scaled by the fraction of the frame the shutter is open, and clamped
+// to a tile so the tile max pass finds every velocity that can reach a pixel.
+
+layout(set = 0, binding = 0) uniform sampler2D velocity_buffer;
+layout(set = 0, binding = 1) uniform sampler2D depth_buffer;
+
+layout(location = 0) out vec2 velocity_out;
+
+layout(push_constant) uniform Constants {
+    // from this frame's clip space to last frame's
+    mat4 reprojection;
+    vec2 screen_dimensions;
+    float shutter_fraction;
+    uint per_object;
+} constants;
+
+#include "motion_blur.inc"
+
+void main() {
+    ivec2 coord = ivec2(gl_FragCoord.xy);
+    vec2 uv = gl_FragCoord.xy / constants.screen_dimensions;
+
+    vec2 velocity_uv;
+    if (constants.per_object != 0) {
+        velocity_uv = texelFetch(velocity_buffer, coord, 0).rg;
+    }
+    else {
+        float depth = texelFetch(depth_buffer, coord, 0).r;
+        // kept homogeneous, so points at infinity (the sky with reverse z) still reproject
+        vec4 prev_clip = constants.reprojection * vec4(uv * 2.0 - 1.0, depth, 1.0);
+        vec2 prev_uv = prev_clip.xy / prev_clip.w * 0.5 + 0.5;
+        velocity_uv = uv - prev_uv;
+    }
+
+    vec2 velocity = velocity_uv * constants.screen_dimensions * constants.shutter_fraction * 0.5;
+    float len = length(velocity);
+    if (len > float(MOTION_BLUR_TILE_SIZE)) {
+        velocity *= float(MOTION_BLUR_TILE_SIZE) / len;
+    }
+    velocity_out = velocity;
+}
@@ -0,0 +1,72 @@
+#version 450
+
+layout(location = 0) in vec3 ws_normal;
+layout(location = 1) in vec3 surface_pos;
+
+layout(location = 0) out vec4 color_out;
+
+layout(set = 0, binding = 0) uniform CaptureData {
+    mat4 face_view_proj[6];
+    vec4 probe_position;
+    uint light_count;
+    uint directional_light_count;
+} capture;
+
+layout(set = 0, binding = 1) uniform sampler2DShadow sunShadowAtlas;
+layout(set = 0, binding = 2) uniform SunData {
+    mat4 cascade_view_proj[4];
+    vec4 cascade_splits;
+    vec4 cascade_texel_sizes;
+    vec4 direction;
+    vec4 color;
+    uint cascade_count;
+    uint shadows_enabled;
+    float depth_bias;
+    float normal_bias;
+    int pcf_radius;
+} sun;
+
+#include "lights.inc"
+#include "shadows.inc"
+#define LOCAL_LIGHT_BINDINGS 3
+#include "local_lights.inc"
+
+// Materials don't expose their textures to other passes, so captured surfaces use a neutral
+// dielectric.
+const vec3 CAPTURE_ALBEDO = vec3(0.5);
+const float CAPTURE_ROUGHNESS = 0.8;
+
+// Sun shadow from the first cascade that covers the point. Cascades follow the main camera, so
+// anything outside all of them is treated as lit.
+float capture_sun_shadow(vec3 frag_pos, vec3 N) {
+    if (sun.shadows_enabled == 0) { return 1.0; }
+    for (uint i = 0; i < sun.cascade_count; ++i) {
+        vec4 clip = sun.cascade_view_proj[i] * vec4(frag_pos, 1.0);
+        vec3 ndc = clip.xyz / clip.w;
+        if (all(lessThan(abs(ndc.xy), vec2(1.0))) && ndc.z >= 0.0 && ndc.z <= 1.0) {
+            vec3 offset_pos = frag_pos + N * sun.normal_bias * sun.cascade_texel_sizes[i];
+            return sample_cascade(sunShadowAtlas, sun.cascade_view_proj[i], i, offset_pos, sun.depth_bias, sun.pcf_radius);
+        }
+    }
+    return 1.0;
+}
+
+void main() {
+    vec3 N = normalize(ws_normal);
+    vec3 V = normalize(capture.probe_position.xyz - surface_pos);
+
+    vec3 diffuse = vec3(0.0);
+    vec3 specular = vec3(0.0);
+    for (uint i = 0; i < capture.directional_light_count; ++i) {
+        LightData light = scene_lights.lights[i];
+        directional_light(light.direction, light.color, N, V, CAPTURE_ALBEDO, CAPTURE_ROUGHNESS, 0.0, surface_pos, diffuse, specular);
+    }
+    for (uint i = capture.directional_light_count; i < capture.light_count; ++i) {
+        local_light(scene_lights.lights[i], N, V, CAPTURE_ALBEDO, CAPTURE_ROUGHNESS, 0.0, surface_pos, diffuse, specular);
+    }
+    float sun_shadow = capture_sun_shadow(surface_pos, N);
+    directional_light(sun.direction.xyz, sun.color.rgb * sun_shadow, N, V, CAPTURE_ALBEDO, CAPTURE_ROUGHNESS, 0.0, surface_pos, diffuse, specular);
+
+    // absolute radiance, alpha marks geometry so the sky can come from the environment instead
+    color_out = vec4(diffuse + specular, 1.0);
+}
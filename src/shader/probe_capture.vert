@@ -0,0 +1,29 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec3 tangent;
+layout(location = 3) in vec2 uv;
+
+layout(location = 0) out vec3 normal_out;
+layout(location = 1) out vec3 surface_pos_out;
+
+layout(set = 0, binding = 0) uniform CaptureData {
+    // proj * view for each cube face, in the order +X, -X, +Y, -Y, +Z, -Z
+    mat4 face_view_proj[6];
+    vec4 probe_position;
+    uint light_count;
+    // lights are sorted with directional lights first
+    uint directional_light_count;
+} capture;
+
+layout(push_constant) uniform Constants {
+    mat4 world;
+    uint face;
+} constants;
+
+void main() {
+    normal_out = transpose(inverse(mat3(constants.world))) * normal;
+    surface_pos_out = (constants.world * vec4(position, 1.0)).xyz;
+    gl_Position = capture.face_view_proj[constants.face] * vec4(surface_pos_out, 1.0);
+}
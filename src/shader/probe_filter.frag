@@ -0,0 +1,53 @@
+#version 450
+
+#include "util.inc"
+#include "bsdf.inc"
+#include "shadows.inc"
+
+// six cube faces side by side, in the order +X, -X, +Y, -Y, +Z, -Z
+layout(set = 0, binding = 0) uniform sampler2D captureAtlas;
+layout(set = 0, binding = 1) uniform FaceData {
+    // rotation-only proj * view for each face
+    mat4 face_view_proj[6];
+} faces;
+
+layout(location = 0) out vec4 color_out;
+
+layout(push_constant) uniform Constants {
+    // mip being written, in atlas pixels: xy origin, zw size
+    vec4 target_rect;
+    float roughness;
+    uint sample_count;
+} constants;
+
+vec4 sample_capture(vec3 dir) {
+    uint face = cube_face(dir);
+    vec4 clip = faces.face_view_proj[face] * vec4(dir, 1.0);
+    vec2 face_texel = 1.0 / vec2(textureSize(captureAtlas, 0).y);
+    vec2 uv = clamp(clip.xy / clip.w * 0.5 + 0.5, face_texel * 0.5, 1.0 - face_texel * 0.5);
+    return textureLod(captureAtlas, vec2((float(face) + uv.x) / 6.0, uv.y), 0.0);
+}
+
+void main() {
+    vec2 uv = (gl_FragCoord.xy - constants.target_rect.xy) / constants.target_rect.zw;
+    vec3 N = oct_decode(uv * 2.0 - 1.0);
+    if (constants.roughness <= 0.0) {
+        color_out = sample_capture(N);
+        return;
+    }
+
+    // GGX lobe around the direction, assuming N = V = R
+    vec4 result = vec4(0.0);
+    float total_weight = 0.0;
+    for (uint i = 0; i < constants.sample_count; ++i) {
+        vec3 H = ImportanceSampleGGX(Hammersley(i, constants.sample_count), N, constants.roughness);
+        vec3 L = normalize(2.0 * dot(N, H) * H - N);
+        float NdotL = dot(N, L);
+        if (NdotL > 0.0) {
+            result += sample_capture(L) * NdotL;
+            total_weight += NdotL;
+        }
+    }
+    // stays premultiplied, alpha is coverage
+    color_out = result / max(total_weight, 0.0001);
+}
@@ -0,0 +1,4 @@
+#version 450
+
+// depth only, nothing to write
+void main() {}
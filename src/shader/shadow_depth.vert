@@ -0,0 +1,12 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+
+layout(push_constant) uniform Constants {
+    // light view_proj * world
+    mat4 light_mvp;
+} constants;
+
+void main() {
+    gl_Position = constants.light_mvp * vec4(position, 1.0);
+}
@@ -1,23 +1,18 @@
 #version 450
 
 layout (location = 0) in vec3 position;
-layout (location = 1) in vec3 normal;
-layout (location = 2) in vec3 tangent;
-layout (location = 3) in vec2 uv;
 
-//layout(location = 0) out vec3 out_position;
-//layout(location = 1) out vec3 out_normal;
-//layout(location = 2) out vec2 out_uv;
+layout (location = 0) out vec3 view_dir;
 
 layout(push_constant) uniform Constants {
+	// inverse of proj * view, without the camera's translation
 	mat4 matrix;
-	float sun_rotation;
-	float sun_transit;
+	uint depth_mode;
 } constants;
 
 void main() {
-	gl_Position = /*constants.matrix * */ vec4(position, 0.0);
-//	out_position = position;
-//	out_normal = normal;
-//	out_uv = vec2( uv.x, -abs(uv.y - 0.5) + 0.5 );
+	gl_Position = vec4(position.xy, 0.5, 1.0);
+	// any depth between the clip planes lies on the same view ray
+	vec4 world = constants.matrix * vec4(position.xy, 0.5, 1.0);
+	view_dir = world.xyz / world.w;
 }
@@ -0,0 +1,50 @@
+#version 450
+
+#include "constants.inc"
+#include "util.inc"
+#include "depth.inc"
+#include "equirect.inc"
+
+layout (location = 0) in vec3 view_dir;
+
+layout (location = 0) out vec4 scene_color;
+
+layout(set = 0, binding = 0) uniform sampler2D depthBuffer;
+layout(set = 0, binding = 1) uniform sampler2D environmentSource;
+layout(set = 0, binding = 2) uniform sampler2D environmentRadiance;
+
+layout(push_constant) uniform Constants {
+	mat4 matrix;
+	float rotation;
+	float intensity;
+	float blur;
+	uint depth_mode;
+} constants;
+
+// Must match `RADIANCE_MIP_COUNT` - 1 in the renderer.
+const float MAX_RADIANCE_LOD = 4.0;
+
+void main() {
+	// only fill in pixels without geometry
+	if (texelFetch(depthBuffer, ivec2(gl_FragCoord.xy), 0).r != farDepth(constants.depth_mode)) {
+		discard;
+	}
+
+	vec3 dir = normalize(view_dir);
+	float c = cos(constants.rotation);
+	float s = sin(constants.rotation);
+	dir = vec3(c * dir.x - s * dir.z, dir.y, s * dir.x + c * dir.z);
+	vec2 uv = equirect_uv(dir);
+
+	// explicit lods, since derivatives jump across the seam at u = 0
+	vec3 color = textureLod(environmentSource, uv, 0.0).rgb;
+	float lod = saturate(constants.blur) * MAX_RADIANCE_LOD;
+	if (lod > 0.0) {
+		// fade from the full resolution source into the prefiltered mips
+		vec3 blurred = textureLod(environmentRadiance, uv, lod).rgb;
+		color = mix(color, blurred, saturate(lod));
+	}
+
+	color *= constants.intensity;
+	scene_color = vec4(color, 1.0);
+}
@@ -0,0 +1,23 @@
+#version 450
+
+layout (location = 0) in vec3 position;
+
+layout (location = 0) out vec3 view_dir;
+
+layout(push_constant) uniform Constants {
+	// inverse of proj * view, without the camera's translation
+	mat4 matrix;
+	// radians around +Y
+	float rotation;
+	float intensity;
+	// 0 for the sharp source image, 1 for the roughest radiance mip
+	float blur;
+	uint depth_mode;
+} constants;
+
+void main() {
+	gl_Position = vec4(position.xy, 0.5, 1.0);
+	// any depth between the clip planes lies on the same view ray
+	vec4 world = constants.matrix * vec4(position.xy, 0.5, 1.0);
+	view_dir = world.xyz / world.w;
+}
@@ -0,0 +1,74 @@
+#version 450
+
+// Resolves screen-space reflection hits from the previous frame's `scene_color`, and adds them to
+// the specular lighting in place of the image based specular that lighting already added. Where
+// rays missed, the probe and environment reflections are left as they were.
+
+layout(set = 0, binding = 0) uniform sampler2D ssrHits;
+layout(set = 0, binding = 1) uniform sampler2D history;
+layout(set = 0, binding = 2) uniform sampler2D velocity;
+layout(set = 0, binding = 3) uniform sampler2D gbufferPosition;
+layout(set = 0, binding = 4) uniform sampler2D gbufferNormal;
+layout(set = 0, binding = 5) uniform sampler2D gbufferAlbedo;
+layout(set = 0, binding = 6) uniform sampler2D gbufferRoughness;
+layout(set = 0, binding = 7) uniform sampler2D gbufferMetallic;
+layout(set = 0, binding = 8) uniform sampler2D radCubemap;
+layout(set = 0, binding = 9) uniform sampler2D brdfLookup;
+layout(set = 0, binding = 10) uniform sampler2D ambientOcclusion;
+
+layout(location = 0) out vec4 specular_out;
+
+layout(push_constant) uniform Constants {
+    vec3 view_pos;
+    // scales how much the reflections replace the image based specular
+    float intensity;
+    vec2 screen_dimensions;
+} constants;
+
+#include "bsdf.inc"
+#include "util.inc"
+#include "equirect.inc"
+#define PROBE_BINDINGS 11
+#include "reflection_probes.inc"
+
+void main() {
+    vec2 uv = gl_FragCoord.xy / constants.screen_dimensions;
+    ivec2 coord = ivec2(gl_FragCoord.xy);
+    vec4 ssr_hit = texelFetch(ssrHits, coord, 0);
+    float confidence = ssr_hit.b * constants.intensity;
+    if (confidence <= 0.0) { discard; }
+
+    vec3 frag_pos = texelFetch(gbufferPosition, coord, 0).rgb;
+    vec3 N = normalize(texelFetch(gbufferNormal, coord, 0).rgb);
+    vec3 V = normalize(constants.view_pos - frag_pos);
+    vec3 R = reflect(-V, N);
+    vec3 albedo = texelFetch(gbufferAlbedo, coord, 0).rgb;
+    float roughness = texelFetch(gbufferRoughness, coord, 0).r;
+    float metallic = texelFetch(gbufferMetallic, coord, 0).r;
+
+    // reflected color, reprojected to where the hit point was last frame
+    vec2 history_uv = ssr_hit.rg - textureLod(velocity, ssr_hit.rg, 0.0).rg;
+    if (any(lessThan(history_uv, vec2(0.0))) || any(greaterThan(history_uv, vec2(1.0)))) { discard; }
+    // absolute luminance to pipeline luminance
+    vec3 reflected = textureLod(history, history_uv, 0.0).rgb / INTERNAL_HDR_DIV;
+
+    // the image based specular radiance that lighting used, which the hit replaces; must match
+    // deferred_lighting.frag
+    const float MAX_REFLECTION_LOD = 4.0;
+    vec3 environment = textureLod(radCubemap, equirect_uv(R), roughness * MAX_REFLECTION_LOD).rgb;
+    vec4 probe_radiance = reflection_probe_radiance(frag_pos, R, roughness);
+    vec3 prefiltered = probe_radiance.rgb + (1.0 - probe_radiance.a) * environment;
+
+    vec3 F0 = mix(vec3(0.04), albedo, metallic);
+    float NdotV = max(dot(N, V), 0.0);
+    vec3 F = FresnelSchlickRoughness(NdotV, F0, roughness);
+    vec2 env_brdf = texture(brdfLookup, vec2(NdotV, roughness)).rg;
+    vec3 specular_scale = F * env_brdf.x + env_brdf.y;
+    float ao = texture(ambientOcclusion, uv).r;
+    float specular_ao = clamp(pow(NdotV + ao, exp2(-16.0 * roughness - 1.0)) - 1.0 + ao, 0.0, 1.0);
+
+    // blended additively, so this is the change to the specular lighting
+    vec3 ibl_specular = prefiltered * specular_scale * specular_ao;
+    vec3 ssr_specular = reflected * specular_scale;
+    specular_out = vec4(confidence * (ssr_specular - ibl_specular), 0.0);
+}
@@ -0,0 +1,43 @@
+#version 450
+
+// Builds one level of the hierarchical-Z pyramid for screen-space reflections. Each texel holds
+// the closest linear depth of the texels it covers, so a ray in front of a texel is in front of
+// everything beneath it. The first level is converted from the depth buffer.
+
+layout(set = 0, binding = 0) uniform sampler2D depth_input;
+
+layout(location = 0) out float hiz_out;
+
+layout(push_constant) uniform Constants {
+    // size of the level being read
+    ivec2 input_dimensions;
+    float near_plane;
+    float far_plane;
+    uint depth_mode;
+    // 1 when reading the depth buffer rather than the previous level
+    uint first_level;
+} constants;
+
+#include "depth.inc"
+
+void main() {
+    ivec2 coord = ivec2(gl_FragCoord.xy);
+    if (constants.first_level != 0) {
+        float depth = texelFetch(depth_input, coord, 0).r;
+        hiz_out = linearDepth(depth, constants.near_plane, constants.far_plane, constants.depth_mode);
+        return;
+    }
+
+    // levels are half the size rounded down, so the last texel of an odd sized row or column
+    // also covers the one left over
+    ivec2 base = coord * 2;
+    ivec2 extent = ivec2(2) + ivec2(equal(base + 3, constants.input_dimensions));
+    float closest = 3.4e38;
+    for (int y = 0; y < extent.y; ++y) {
+        for (int x = 0; x < extent.x; ++x) {
+            ivec2 c = min(base + ivec2(x, y), constants.input_dimensions - 1);
+            closest = min(closest, texelFetch(depth_input, c, 0).r);
+        }
+    }
+    hiz_out = closest;
+}
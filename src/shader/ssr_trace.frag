@@ -0,0 +1,148 @@
+#version 450
+
+// Traces a mirror reflection ray per pixel through the hierarchical-Z pyramid. The ray steps
+// over whole cells while it's in front of their closest depth, going up a level each time, and
+// goes back down a level when it might intersect. Writes the hit's screen uv in rg and its
+// confidence in b, which is 0 where the ray missed, left the screen or the surface is too rough.
+
+// must match `HIZ_LEVELS` in stage/ssr.rs
+#define HIZ_LEVELS 6
+
+layout(set = 0, binding = 0) uniform sampler2D hiz0;
+layout(set = 0, binding = 1) uniform sampler2D hiz1;
+layout(set = 0, binding = 2) uniform sampler2D hiz2;
+layout(set = 0, binding = 3) uniform sampler2D hiz3;
+layout(set = 0, binding = 4) uniform sampler2D hiz4;
+layout(set = 0, binding = 5) uniform sampler2D hiz5;
+layout(set = 0, binding = 6) uniform sampler2D gbufferPosition;
+layout(set = 0, binding = 7) uniform sampler2D gbufferNormal;
+layout(set = 0, binding = 8) uniform sampler2D gbufferRoughness;
+
+layout(location = 0) out vec4 hit_out;
+
+layout(push_constant) uniform Constants {
+    // projection with the same jitter the depth buffer was rendered with
+    mat4 view_proj;
+    vec3 view_pos;
+    float near_plane;
+    vec2 screen_dimensions;
+    // world-space length of a ray
+    float max_distance;
+    // how far behind a surface a ray still counts as hitting it, in meters
+    float thickness;
+    // rougher surfaces aren't traced, and reflections fade out approaching it
+    float max_roughness;
+    uint max_iterations;
+    uint frame_index;
+} constants;
+
+#include "util.inc"
+
+float hiz_depth(int level, ivec2 coord) {
+    switch (level) {
+        case 0: return texelFetch(hiz0, coord, 0).r;
+        case 1: return texelFetch(hiz1, coord, 0).r;
+        case 2: return texelFetch(hiz2, coord, 0).r;
+        case 3: return texelFetch(hiz3, coord, 0).r;
+        case 4: return texelFetch(hiz4, coord, 0).r;
+        default: return texelFetch(hiz5, coord, 0).r;
+    }
+}
+
+ivec2 hiz_size(int level) {
+    switch (level) {
+        case 0: return textureSize(hiz0, 0);
+        case 1: return textureSize(hiz1, 0);
+        case 2: return textureSize(hiz2, 0);
+        case 3: return textureSize(hiz3, 0);
+        case 4: return textureSize(hiz4, 0);
+        default: return textureSize(hiz5, 0);
+    }
+}
+
+float interleaved_gradient_noise(vec2 pixel) {
+    return fract(52.9829189 * fract(dot(pixel, vec2(0.06711056, 0.00583715))));
+}
+
+void main() {
+    ivec2 coord = ivec2(gl_FragCoord.xy);
+    hit_out = vec4(0.0);
+
+    vec3 normal = texelFetch(gbufferNormal, coord, 0).rgb;
+    // the G-buffer normal is cleared to zero where there is no geometry
+    if (dot(normal, normal) < 0.5) { return; }
+    float roughness = texelFetch(gbufferRoughness, coord, 0).r;
+    if (roughness >= constants.max_roughness) { return; }
+
+    vec3 P = texelFetch(gbufferPosition, coord, 0).rgb;
+    vec3 N = normalize(normal);
+    vec3 V = normalize(constants.view_pos - P);
+    vec3 R = reflect(-V, N);
+
+    // end points in clip space, where w is linear depth, stopping short of the near plane
+    vec4 clip0 = constants.view_proj * vec4(P, 1.0);
+    vec4 clip1 = constants.view_proj * vec4(P + R * constants.max_distance, 1.0);
+    float min_w = constants.near_plane * 1.01;
+    if (clip1.w < min_w) {
+        clip1 = mix(clip0, clip1, (clip0.w - min_w) / (clip0.w - clip1.w));
+    }
+
+    // march in pixels, interpolating 1 / w, which is linear in screen space
+    float k0 = 1.0 / clip0.w;
+    float k1 = 1.0 / clip1.w;
+    vec2 s0 = (clip0.xy * k0 * 0.5 + 0.5) * constants.screen_dimensions;
+    vec2 s1 = (clip1.xy * k1 * 0.5 + 0.5) * constants.screen_dimensions;
+    float ray_pixels = length(s1 - s0);
+    if (ray_pixels < 1.0) { return; }
+    vec2 dir = (s1 - s0) / ray_pixels;
+
+    // start past the pixel itself, jittered to trade banding for noise that TAA removes
+    float t = 1.0 + interleaved_gradient_noise(gl_FragCoord.xy + float(constants.frame_index % 64) * 5.588238);
+    int level = 0;
+    bool hit = false;
+    for (uint i = 0; i < constants.max_iterations && t < ray_pixels; ++i) {
+        vec2 p = s0 + dir * t;
+        if (any(lessThan(p, vec2(0.0))) || any(greaterThanEqual(p, constants.screen_dimensions))) { break; }
+
+        float cell_size = float(1 << level);
+        vec2 cell = floor(p / cell_size);
+        vec2 boundary = (cell + step(0.0, dir)) * cell_size;
+        float exit_x = dir.x != 0.0 ? (boundary.x - s0.x) / dir.x : 3.4e38;
+        float exit_y = dir.y != 0.0 ? (boundary.y - s0.y) / dir.y : 3.4e38;
+        // nudged past the boundary, so the next step lands in the next cell
+        float t_exit = min(min(exit_x, exit_y) + 0.01, ray_pixels);
+
+        float depth_entry = 1.0 / mix(k0, k1, t / ray_pixels);
+        float depth_exit = 1.0 / mix(k0, k1, t_exit / ray_pixels);
+        float scene_depth = hiz_depth(level, min(ivec2(cell), hiz_size(level) - 1));
+
+        if (max(depth_entry, depth_exit) < scene_depth) {
+            // in front of everything in the cell, so skip it and try bigger steps
+            t = t_exit;
+            level = min(level + 1, HIZ_LEVELS - 1);
+        }
+        else if (level > 0) {
+            level -= 1;
+        }
+        else if (min(depth_entry, depth_exit) - scene_depth < constants.thickness) {
+            hit = true;
+            break;
+        }
+        else {
+            // passes behind the surface
+            t = t_exit;
+        }
+    }
+    if (!hit) { return; }
+
+    vec2 hit_pixel = s0 + dir * t;
+    vec2 hit_uv = hit_pixel / constants.screen_dimensions;
+    // the back of something can't be seen in a reflection, and doesn't have lighting to show
+    vec3 hit_normal = texelFetch(gbufferNormal, ivec2(hit_pixel), 0).rgb;
+    if (dot(hit_normal, R) > 0.0) { return; }
+
+    float edge_fade = saturate(min(min(hit_uv.x, 1.0 - hit_uv.x), min(hit_uv.y, 1.0 - hit_uv.y)) * 10.0);
+    float distance_fade = 1.0 - smoothstep(0.75, 1.0, t / ray_pixels);
+    float roughness_fade = saturate((constants.max_roughness - roughness) / (constants.max_roughness * 0.4));
+    hit_out = vec4(hit_uv, edge_fade * distance_fade * roughness_fade, 0.0);
+}
@@ -0,0 +1,83 @@
+#version 450
+
+// Separable screen-space subsurface scattering, run horizontally into a temporary image and then
+// vertically back into the diffuse lighting. Each channel is blurred by a gaussian covering its
+// scatter distance, so red bleeds further than blue on skin. Pixels without a profile, 0 in the
+// w channel of the G-buffer normal, are passed through unchanged.
+
+// must match `MAX_SUBSURFACE_PROFILES` in subsurface.rs
+#define MAX_SUBSURFACE_PROFILES 16
+
+layout(set = 0, binding = 0) uniform sampler2D diffuse_input;
+layout(set = 0, binding = 1) uniform sampler2D gbufferPosition;
+layout(set = 0, binding = 2) uniform sampler2D gbufferNormal;
+layout(set = 0, binding = 3) uniform SubsurfaceProfiles {
+    // per channel scatter distance in meters
+    vec4 scatter_distance[MAX_SUBSURFACE_PROFILES];
+} profiles;
+
+layout(location = 0) out vec4 diffuse_out;
+
+layout(push_constant) uniform Constants {
+    vec2 texel_size;
+    vec2 direction;
+    // screen pixels covered by one meter at a linear depth of one meter
+    float pixels_per_meter;
+    // samples on each side of the center
+    uint sample_count;
+} constants;
+
+#include "util.inc"
+
+uint profile_at(vec2 uv) {
+    return uint(textureLod(gbufferNormal, uv, 0.0).w + 0.5);
+}
+
+void main() {
+    vec2 uv = gl_FragCoord.xy * constants.texel_size;
+    vec4 center = textureLod(diffuse_input, uv, 0.0);
+    uint profile = profile_at(uv);
+    if (profile == 0 || profile > MAX_SUBSURFACE_PROFILES) {
+        diffuse_out = center;
+        return;
+    }
+
+    vec3 scatter = profiles.scatter_distance[profile - 1].rgb;
+    float max_distance = max(scatter.r, max(scatter.g, scatter.b));
+    float depth = textureLod(gbufferPosition, uv, 0.0).w;
+    float radius_px = max_distance * constants.pixels_per_meter / max(depth, 1e-4);
+    if (max_distance <= 0.0 || radius_px < 1.0 || constants.sample_count == 0) {
+        diffuse_out = center;
+        return;
+    }
+
+    // three standard deviations reach the scatter distance
+    vec3 sigma = max(scatter / 3.0, vec3(1e-6));
+    vec3 total = center.rgb;
+    vec3 total_weight = vec3(1.0);
+    for (uint i = 1; i <= constants.sample_count; ++i) {
+        float t = float(i) / float(constants.sample_count);
+        float offset = t * max_distance;
+        vec3 weight = exp(-(offset * offset) / (2.0 * sigma * sigma));
+        vec2 step_uv = constants.direction * t * radius_px * constants.texel_size;
+
+        for (int side = -1; side <= 1; side += 2) {
+            vec2 sample_uv = uv + step_uv * float(side);
+            vec3 s = textureLod(diffuse_input, sample_uv, 0.0).rgb;
+
+            // stay on the same surface: other materials and depth discontinuities fall back to
+            // the center, so light doesn't bleed onto the background
+            float sample_depth = textureLod(gbufferPosition, sample_uv, 0.0).w;
+            float follow = saturate(1.0 - abs(sample_depth - depth) / max_distance);
+            if (profile_at(sample_uv) != profile) {
+                follow = 0.0;
+            }
+            s = mix(center.rgb, s, follow);
+
+            total += s * weight;
+            total_weight += weight;
+        }
+    }
+
+    diffuse_out = vec4(total / total_weight, center.a);
+}
@@ -0,0 +1,142 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D scene_color;
+layout(set = 0, binding = 1) uniform sampler2D history;
+layout(set = 0, binding = 2) uniform sampler2D velocity_buffer;
+layout(set = 0, binding = 3) uniform sampler2D depth_buffer;
+
+layout(location = 0) out vec4 resolved;
+
+layout(push_constant) uniform Constants {
+    vec2 screen_dimensions;
+    float feedback_min;
+    float feedback_max;
+    float sharpness;
+    uint reset_history;
+    uint depth_mode;
+} constants;
+
+#include "constants.inc"
+#include "depth.inc"
+
+// clipping in YCoCg gives a tighter box around the neighborhood than RGB
+vec3 rgb_to_ycocg(vec3 c) {
+    return vec3(
+         0.25 * c.r + 0.5 * c.g + 0.25 * c.b,
+         0.5  * c.r             - 0.5  * c.b,
+        -0.25 * c.r + 0.5 * c.g - 0.25 * c.b
+    );
+}
+
+vec3 ycocg_to_rgb(vec3 c) {
+    return vec3(
+        c.x + c.y - c.z,
+        c.x       + c.z,
+        c.x - c.y - c.z
+    );
+}
+
+// 5-tap Catmull-Rom history filter, keeps the history sharp under sub-pixel motion
+vec3 sample_history(vec2 uv) {
+    vec2 texel = 1.0 / constants.screen_dimensions;
+    vec2 pos = uv * constants.screen_dimensions;
+    vec2 center = floor(pos - 0.5) + 0.5;
+    vec2 f = pos - center;
+
+    vec2 w0 = f * (-0.5 + f * (1.0 - 0.5 * f));
+    vec2 w1 = 1.0 + f * f * (-2.5 + 1.5 * f);
+    vec2 w2 = f * (0.5 + f * (2.0 - 1.5 * f));
+    vec2 w3 = f * f * (-0.5 + 0.5 * f);
+    vec2 w12 = w1 + w2;
+
+    vec2 tc0 = (center - 1.0) * texel;
+    vec2 tc3 = (center + 2.0) * texel;
+    vec2 tc12 = (center + w2 / w12) * texel;
+
+    vec3 result =
+        texture(history, vec2(tc12.x, tc0.y)).rgb * (w12.x * w0.y) +
+        texture(history, vec2(tc0.x, tc12.y)).rgb * (w0.x * w12.y) +
+        texture(history, vec2(tc12.x, tc12.y)).rgb * (w12.x * w12.y) +
+        texture(history, vec2(tc3.x, tc12.y)).rgb * (w3.x * w12.y) +
+        texture(history, vec2(tc12.x, tc3.y)).rgb * (w12.x * w3.y);
+    float weight = (w12.x * w0.y) + (w0.x * w12.y) + (w12.x * w12.y) + (w3.x * w12.y) + (w12.x * w3.y);
+    return max(result / weight, vec3(0.0));
+}
+
+// clips the history color towards the neighborhood average instead of clamping per channel
+vec3 clip_aabb(vec3 aabb_min, vec3 aabb_max, vec3 avg, vec3 history_color) {
+    vec3 center = 0.5 * (aabb_max + aabb_min);
+    vec3 extents = 0.5 * (aabb_max - aabb_min) + 0.0001;
+    vec3 offset = history_color - center;
+    vec3 units = abs(offset / extents);
+    float max_unit = max(units.x, max(units.y, units.z));
+    if (max_unit > 1.0) {
+        return center + offset / max_unit;
+    }
+    return history_color;
+}
+
+void main() {
+    vec2 texel = 1.0 / constants.screen_dimensions;
+    vec2 uv = gl_FragCoord.xy * texel;
+
+    // gather the 3x3 neighborhood, and find the closest depth for velocity dilation
+    vec3 center_color = vec3(0.0);
+    vec3 m1 = vec3(0.0);
+    vec3 m2 = vec3(0.0);
+    vec3 cross_sum = vec3(0.0);
+    vec2 closest_offset = vec2(0.0);
+    float closest_depth = farDepth(constants.depth_mode);
+    for (int y = -1; y <= 1; ++y) {
+        for (int x = -1; x <= 1; ++x) {
+            vec2 offset = vec2(x, y);
+            vec3 c = rgb_to_ycocg(texture(scene_color, uv + offset * texel).rgb);
+            if (x == 0 && y == 0) { center_color = c; }
+            else if (x == 0 || y == 0) { cross_sum += c; }
+            m1 += c;
+            m2 += c * c;
+
+            float d = texture(depth_buffer, uv + offset * texel).r;
+            if (depthCloser(d, closest_depth, constants.depth_mode)) {
+                closest_depth = d;
+                closest_offset = offset;
+            }
+        }
+    }
+
+    if (constants.sharpness > 0.0) {
+        center_color += (center_color - cross_sum * 0.25) * constants.sharpness;
+        center_color.x = max(center_color.x, 0.0);
+    }
+
+    vec2 velocity = texture(velocity_buffer, uv + closest_offset * texel).rg;
+    vec2 prev_uv = uv - velocity;
+
+    if (constants.reset_history != 0 || any(lessThan(prev_uv, vec2(0.0))) || any(greaterThan(prev_uv, vec2(1.0)))) {
+        resolved = vec4(ycocg_to_rgb(center_color), 1.0);
+        return;
+    }
+
+    // variance clipping box
+    vec3 mean = m1 / 9.0;
+    vec3 sigma = sqrt(max(m2 / 9.0 - mean * mean, vec3(0.0)));
+    vec3 box_min = mean - 1.25 * sigma;
+    vec3 box_max = mean + 1.25 * sigma;
+
+    vec3 history_color = rgb_to_ycocg(sample_history(prev_uv));
+    history_color = clip_aabb(box_min, box_max, mean, history_color);
+
+    // trust the history less when it had to be clipped a lot, or the pixel moved quickly
+    float history_luma = history_color.x;
+    float current_luma = center_color.x;
+    float diff = abs(current_luma - history_luma) / max(current_luma, max(history_luma, 0.2));
+    float feedback = mix(constants.feedback_max, constants.feedback_min, diff * diff);
+    feedback *= 1.0 - clamp(length(velocity * constants.screen_dimensions) / 32.0, 0.0, 0.5);
+
+    // luminance weighting reduces flickering of bright sub-pixel highlights
+    float w_current = (1.0 - feedback) / (1.0 + current_luma / INTERNAL_HDR_DIV);
+    float w_history = feedback / (1.0 + history_luma / INTERNAL_HDR_DIV);
+    vec3 result = (center_color * w_current + history_color * w_history) / (w_current + w_history);
+
+    resolved = vec4(ycocg_to_rgb(result), 1.0);
+}
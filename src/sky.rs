@@ -0,0 +1,564 @@
+//! Analytic daylight sky.
+//!
+//! The sky uses the Preetham model (Preetham, Shirley and Smits 1999, "A Practical Analytic Model
+//! for Daylight"), evaluated per pixel by the skybox stage. The same model drives the rest of the
+//! outdoor lighting: with `SkySettings::drive_sun_light` the renderer's `SunLight` follows the
+//! sun's position and atmospheric attenuation, and with `SkySettings::drive_environment` the
+//! image based lighting environment is regenerated from the sky whenever it changes noticeably.
+//!
+//! When the sun position comes from a `SolarTime`, the moon and stars are placed as well, and
+//! the night sky takes over once the sun has set. See `time_of_day` for advancing the time.
+//!
+//! World space has +Y up, +X east and -Z north.
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+use cgmath::{Matrix, Matrix3, Matrix4, Vector3, InnerSpace, Rad, Deg};
+use vulkano::buffer::BufferUsage;
+
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::light::SunLight;
+use crate::renderer::RenderInfo;
+
+
+/// Illuminance of sunlight at the top of the atmosphere, in lux.
+pub const SOLAR_ILLUMINANCE: f32 = 128_000.0;
+
+/// Illuminance of the full moon at the top of the atmosphere, in lux.
+pub const FULL_MOON_ILLUMINANCE: f32 = 0.25;
+
+/// Wavelengths in micrometers used for the red, green and blue sun transmittance.
+const RGB_WAVELENGTHS: [f32; 3] = [0.65, 0.57, 0.475];
+
+
+/// A date, time and location on earth, for computing where the sun is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SolarTime {
+    pub year: i32,
+    /// 1 to 12.
+    pub month: u32,
+    /// 1 to 31.
+    pub day: u32,
+    /// Local time of day in hours, from 0 to 24.
+    pub hours: f32,
+    /// Offset of local time from UTC in hours, e.g. -5 for US eastern standard time.
+    pub utc_offset: f32,
+    /// Degrees north of the equator.
+    pub latitude: f32,
+    /// Degrees east of Greenwich.
+    pub longitude: f32,
+}
+
+impl SolarTime {
+    /// Day of the year, starting at 1 on January 1st.
+    pub fn day_of_year(&self) -> u32 {
+        (days_from_civil(self.year, self.month, self.day) - days_from_civil(self.year, 1, 1) + 1) as u32
+    }
+
+    /// Moves the time forward by `hours`, or backward if negative, rolling over into the next or
+    /// previous days.
+    pub fn add_hours(&mut self, hours: f32) {
+        let total = self.hours + hours;
+        let days = (total / 24.0).floor();
+        self.hours = total - days * 24.0;
+        if days != 0.0 {
+            let (year, month, day) = civil_from_days(days_from_civil(self.year, self.month, self.day) + days as i64);
+            self.year = year;
+            self.month = month;
+            self.day = day;
+        }
+    }
+
+    /// Days since the J2000.0 epoch, 2000-01-01 12:00 UTC.
+    fn j2000_days(&self) -> f64 {
+        (days_from_civil(self.year, self.month, self.day) - days_from_civil(2000, 1, 1)) as f64
+            + (self.hours as f64 - self.utc_offset as f64 - 12.0) / 24.0
+    }
+
+    /// Local sidereal time as an angle, in radians.
+    fn local_sidereal_angle(&self) -> f64 {
+        let gmst = 280.46061837 + 360.98564736629 * self.j2000_days();
+        (gmst + self.longitude as f64).rem_euclid(360.0).to_radians()
+    }
+
+    /// World direction of a point on the celestial sphere.
+    fn equatorial_direction(&self, right_ascension: f64, declination: f64) -> Vector3<f32> {
+        let hour_angle = self.local_sidereal_angle() - right_ascension;
+        horizontal_direction(Rad::from(Deg(self.latitude)).0, declination as f32, hour_angle as f32)
+    }
+
+    /// Unit vector pointing towards the sun, using the NOAA approximations for the equation of
+    /// time and solar declination. Accurate to a fraction of a degree.
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        let utc_hours = self.hours - self.utc_offset;
+        // fractional year in radians
+        let year = 2.0 * PI / 365.0 * (self.day_of_year() as f32 - 1.0 + (utc_hours - 12.0) / 24.0);
+
+        // minutes
+        let equation_of_time = 229.18 * (0.000075 + 0.001868 * year.cos() - 0.032077 * year.sin()
+            - 0.014615 * (2.0 * year).cos() - 0.040849 * (2.0 * year).sin());
+        let declination = 0.006918 - 0.399912 * year.cos() + 0.070257 * year.sin()
+            - 0.006758 * (2.0 * year).cos() + 0.000907 * (2.0 * year).sin()
+            - 0.002697 * (3.0 * year).cos() + 0.00148 * (3.0 * year).sin();
+
+        let solar_minutes = utc_hours * 60.0 + equation_of_time + 4.0 * self.longitude;
+        let hour_angle = Rad::from(Deg(solar_minutes / 4.0 - 180.0)).0;
+        horizontal_direction(Rad::from(Deg(self.latitude)).0, declination, hour_angle)
+    }
+
+    /// Unit vector pointing towards the moon, from its unperturbed orbital elements (Schlyter,
+    /// "How to compute planetary positions"). Accurate to a degree or two, which is plenty for
+    /// the sky; parallax is ignored.
+    pub fn moon_direction(&self) -> Vector3<f32> {
+        // the orbital elements are given relative to 2000 Jan 0.0 UTC
+        let d = self.j2000_days() + 1.5;
+
+        let ascending_node = (125.1228 - 0.0529538083 * d).to_radians();
+        let inclination = 5.1454f64.to_radians();
+        let perigee = (318.0634 + 0.1643573223 * d).to_radians();
+        let eccentricity = 0.0549;
+        let mean_anomaly = (115.3654 + 13.0649929509 * d).to_radians();
+
+        // eccentric anomaly, refined with one Newton step
+        let e = mean_anomaly + eccentricity * mean_anomaly.sin() * (1.0 + eccentricity * mean_anomaly.cos());
+        let e = e - (e - eccentricity * e.sin() - mean_anomaly) / (1.0 - eccentricity * e.cos());
+        let true_anomaly = ((1.0 - eccentricity * eccentricity).sqrt() * e.sin()).atan2(e.cos() - eccentricity);
+
+        // ecliptic coordinates
+        let argument = true_anomaly + perigee;
+        let x = ascending_node.cos() * argument.cos() - ascending_node.sin() * argument.sin() * inclination.cos();
+        let y = ascending_node.sin() * argument.cos() + ascending_node.cos() * argument.sin() * inclination.cos();
+        let z = argument.sin() * inclination.sin();
+
+        // to equatorial
+        let obliquity = (23.4393 - 3.563e-7 * d).to_radians();
+        let y_eq = y * obliquity.cos() - z * obliquity.sin();
+        let z_eq = y * obliquity.sin() + z * obliquity.cos();
+        let right_ascension = y_eq.atan2(x);
+        let declination = z_eq.atan2((x * x + y_eq * y_eq).sqrt());
+
+        self.equatorial_direction(right_ascension, declination)
+    }
+
+    /// Rotation from equatorial coordinates (x towards the vernal equinox, z towards the north
+    /// celestial pole) to world space, for placing the stars.
+    pub fn celestial_to_world(&self) -> Matrix3<f32> {
+        use std::f64::consts::FRAC_PI_2;
+        Matrix3::from_cols(
+            self.equatorial_direction(0.0, 0.0),
+            self.equatorial_direction(FRAC_PI_2, 0.0),
+            self.equatorial_direction(0.0, FRAC_PI_2),
+        )
+    }
+}
+
+/// World direction of a body at `declination` and `hour_angle`, seen from `latitude`. All in
+/// radians.
+fn horizontal_direction(latitude: f32, declination: f32, hour_angle: f32) -> Vector3<f32> {
+    let east = -declination.cos() * hour_angle.sin();
+    let north = latitude.cos() * declination.sin() - latitude.sin() * declination.cos() * hour_angle.cos();
+    let up = latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+    Vector3::new(east, up, -north).normalize()
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar (Hinnant, "chrono-Compatible
+/// Low-Level Date Algorithms").
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let month = month.max(1).min(12) as i64;
+    let year = year as i64 - if month <= 2 { 1 } else { 0 };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }) as i32;
+    (year, month, day)
+}
+
+
+/// Where the sun is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SunPosition {
+    /// Unit vector pointing towards the sun.
+    Direction(Vector3<f32>),
+    /// Computed from a date, time and location.
+    Time(SolarTime),
+}
+
+
+/// Settings for the analytic sky.
+#[derive(Debug, Clone)]
+pub struct SkySettings {
+    /// Haziness of the atmosphere, from about 2 (very clear) to 10 (hazy).
+    pub turbidity: f32,
+    /// Color of the ground below the horizon.
+    pub ground_albedo: Vector3<f32>,
+    pub sun: SunPosition,
+    /// Angular radius of the sun disk. The real sun is about 0.27 degrees.
+    pub sun_angular_radius: Rad<f32>,
+    /// Angular radius of the moon disk. The real moon is about 0.26 degrees. The moon is only
+    /// shown when the sun is positioned with `SunPosition::Time`.
+    pub moon_angular_radius: Rad<f32>,
+    /// Luminance of the moonless night sky in cd/m^2, from airglow and starlight. Also keeps the
+    /// ambient light from going completely black at night.
+    pub night_sky_luminance: f32,
+    /// Multiplier for the stars' brightness.
+    pub star_brightness: f32,
+    /// Sets the renderer's `SunLight` from the sky every frame.
+    pub drive_sun_light: bool,
+    /// Regenerates the image based lighting environment from the sky when it changes, while the
+    /// skybox is in `SkyboxMode::Procedural`.
+    pub drive_environment: bool,
+    /// How far the sun has to move before the environment is regenerated. Regeneration runs in
+    /// the background and is swapped in when it's done, one at a time, so this mostly sets how
+    /// far the lighting can lag behind the sky.
+    pub environment_update_angle: Rad<f32>,
+}
+impl Default for SkySettings {
+    fn default() -> Self {
+        Self {
+            turbidity: 2.5,
+            ground_albedo: Vector3::new(0.3, 0.3, 0.3),
+            sun: SunPosition::Direction(Vector3::new(-0.5, 1.0, -0.5).normalize()),
+            sun_angular_radius: Deg(0.27).into(),
+            moon_angular_radius: Deg(0.26).into(),
+            night_sky_luminance: 0.002,
+            star_brightness: 1.0,
+            drive_sun_light: true,
+            drive_environment: true,
+            environment_update_angle: Deg(1.0).into(),
+        }
+    }
+}
+
+impl SkySettings {
+    /// Unit vector pointing towards the sun.
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        match &self.sun {
+            SunPosition::Direction(direction) => direction.normalize(),
+            SunPosition::Time(time) => time.sun_direction(),
+        }
+    }
+
+    /// Unit vector pointing towards the moon, if the sky has one.
+    pub fn moon_direction(&self) -> Option<Vector3<f32>> {
+        match &self.sun {
+            SunPosition::Direction(_) => None,
+            SunPosition::Time(time) => Some(time.moon_direction()),
+        }
+    }
+
+    /// Rotation from equatorial coordinates to world space. Without a `SolarTime`, the north
+    /// celestial pole points straight up.
+    pub fn celestial_to_world(&self) -> Matrix3<f32> {
+        match &self.sun {
+            SunPosition::Direction(_) => Matrix3::new(1.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 1.0, 0.0),
+            SunPosition::Time(time) => time.celestial_to_world(),
+        }
+    }
+
+    /// Turbidity clamped to the range the model was fitted for.
+    pub fn clamped_turbidity(&self) -> f32 {
+        self.turbidity.max(1.7).min(10.0)
+    }
+
+    /// Fraction of sunlight reaching the ground through the atmosphere, per color channel.
+    pub fn sun_transmittance(&self) -> Vector3<f32> {
+        self.transmittance(self.sun_direction())
+    }
+
+    /// Fraction of light from `direction` reaching the ground through the atmosphere, per color
+    /// channel, from Rayleigh and aerosol scattering. Ozone and water vapor absorption are
+    /// ignored.
+    pub fn transmittance(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        let zenith_degrees = Deg::from(Rad(direction.y.max(-1.0).min(1.0).acos())).0.min(93.0);
+        // relative optical air mass (Kasten and Young 1989)
+        let air_mass = 1.0 / (Rad::from(Deg(zenith_degrees)).0.cos() + 0.15 * (93.885 - zenith_degrees).powf(-1.253));
+
+        let turbidity = self.clamped_turbidity();
+        let beta = 0.04608365 * turbidity - 0.04586025;
+        let channel = |wavelength: f32| {
+            let rayleigh = (-0.008735 * wavelength.powf(-4.08) * air_mass).exp();
+            let aerosol = (-beta * wavelength.powf(-1.3) * air_mass).exp();
+            rayleigh * aerosol
+        };
+        Vector3::new(channel(RGB_WAVELENGTHS[0]), channel(RGB_WAVELENGTHS[1]), channel(RGB_WAVELENGTHS[2]))
+    }
+
+    /// Sun illuminance on a surface facing it, in lux per color channel. Fades out as the sun
+    /// sets below the horizon.
+    pub fn sun_illuminance(&self) -> Vector3<f32> {
+        let horizon_fade = smoothstep(-0.01, 0.01, self.sun_direction().y);
+        self.sun_transmittance() * SOLAR_ILLUMINANCE * horizon_fade
+    }
+
+    /// Brightness of the moon's current phase relative to the full moon, from the angle between
+    /// the sun and the moon. Falls off faster than the lit fraction of the disk, since the
+    /// lunar surface is rough.
+    pub fn moon_phase_brightness(&self) -> f32 {
+        match self.moon_direction() {
+            Some(moon) => {
+                // angle between the sun and the earth as seen from the moon
+                let phase_angle = 180.0 - Deg::from(moon.angle(self.sun_direction())).0;
+                10f32.powf(-0.4 * (0.026 * phase_angle + 4e-9 * phase_angle.powi(4)))
+            },
+            None => 0.0,
+        }
+    }
+
+    /// Illuminance of the full moon on a surface facing it, after the atmosphere, in lux per
+    /// color channel. Zero below the horizon, or if there's no moon.
+    pub fn full_moon_illuminance(&self) -> Vector3<f32> {
+        match self.moon_direction() {
+            Some(moon) => self.transmittance(moon) * FULL_MOON_ILLUMINANCE * smoothstep(-0.01, 0.01, moon.y),
+            None => Vector3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Moon illuminance for the current phase, in lux per color channel.
+    pub fn moon_illuminance(&self) -> Vector3<f32> {
+        self.full_moon_illuminance() * self.moon_phase_brightness()
+    }
+
+    /// Scale on the sky's luminance, fading it to black over civil twilight since the model
+    /// doesn't cover the sun below the horizon.
+    pub fn sky_luminance_scale(&self) -> f32 {
+        let twilight = Rad::from(Deg(6.0f32)).0.sin();
+        smoothstep(-twilight, 0.0, self.sun_direction().y)
+    }
+
+    /// How much daylight there is, from 0 at night to 1 with the sun well above the horizon.
+    /// Changes gradually through dawn and dusk, between astronomical twilight and about 15
+    /// degrees of sun elevation.
+    pub fn daylight(&self) -> f32 {
+        let night = Rad::from(Deg(-12.0f32)).0.sin();
+        let day = Rad::from(Deg(15.0f32)).0.sin();
+        smoothstep(night, day, self.sun_direction().y)
+    }
+
+    /// The directional light matching the sky: the sun, or the moon once it's the brighter of
+    /// the two.
+    pub fn sun_light(&self) -> SunLight {
+        let sun = self.sun_illuminance();
+        let moon = self.moon_illuminance();
+        let illuminance = sun + moon;
+        let intensity = illuminance.x.max(illuminance.y).max(illuminance.z);
+        let direction = match self.moon_direction() {
+            Some(moon_direction) if moon.y > sun.y => moon_direction,
+            _ => self.sun_direction(),
+        };
+        SunLight {
+            direction: -direction,
+            color: if intensity > 0.0 { illuminance / intensity } else { Vector3::new(1.0, 1.0, 1.0) },
+            intensity,
+        }
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+
+/// Must match `SkyData` in `sky.inc`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpuSky {
+    /// Direction towards the sun in xyz, turbidity in w.
+    pub sun_direction: [f32; 4],
+    /// Sun illuminance in lux in rgb, cosine of the sun's angular radius in w.
+    pub sun_illuminance: [f32; 4],
+    /// Ground albedo in rgb, sky luminance scale in w.
+    pub ground_albedo: [f32; 4],
+    /// Direction towards the moon in xyz, cosine of the moon's angular radius in w.
+    pub moon_direction: [f32; 4],
+    /// Full moon illuminance in lux in rgb, moonlit sky luminance relative to daylight in w.
+    pub moon_illuminance: [f32; 4],
+    /// Night sky luminance, star brightness and moon phase brightness in xyz.
+    pub night: [f32; 4],
+    pub world_to_celestial: [[f32; 4]; 4],
+}
+
+impl GpuSky {
+    pub fn new(settings: &SkySettings) -> Self {
+        let direction = settings.sun_direction();
+        let illuminance = settings.sun_illuminance();
+        let albedo = settings.ground_albedo;
+        // below the horizon when there's no moon, so it's never drawn
+        let moon = settings.moon_direction().unwrap_or(Vector3::new(0.0, -1.0, 0.0));
+        let moon_full = settings.full_moon_illuminance();
+        let phase = settings.moon_phase_brightness();
+        let moon_sky_scale = moon_full.y * phase / SOLAR_ILLUMINANCE;
+        // the matrix is orthonormal, so its transpose is its inverse
+        let world_to_celestial = Matrix4::from(settings.celestial_to_world().transpose());
+        Self {
+            sun_direction: [direction.x, direction.y, direction.z, settings.clamped_turbidity()],
+            sun_illuminance: [illuminance.x, illuminance.y, illuminance.z, settings.sun_angular_radius.0.cos()],
+            ground_albedo: [albedo.x, albedo.y, albedo.z, settings.sky_luminance_scale()],
+            moon_direction: [moon.x, moon.y, moon.z, settings.moon_angular_radius.0.cos()],
+            moon_illuminance: [moon_full.x, moon_full.y, moon_full.z, moon_sky_scale],
+            night: [settings.night_sky_luminance, settings.star_brightness, phase, 0.0],
+            world_to_celestial: world_to_celestial.into(),
+        }
+    }
+
+    /// Whether the sky has changed enough since `previous` to be worth regenerating the
+    /// environment: a body moved by more than `angle`, or the light changed by more than a few
+    /// percent.
+    pub fn differs_from(&self, previous: &GpuSky, angle: Rad<f32>) -> bool {
+        let moved = |a: [f32; 4], b: [f32; 4]| {
+            Vector3::new(a[0], a[1], a[2]).angle(Vector3::new(b[0], b[1], b[2])) >= angle
+        };
+        let changed = |a: f32, b: f32| (a - b).abs() > 0.05 * a.abs().max(b.abs()).max(1e-6);
+        moved(self.sun_direction, previous.sun_direction)
+            || moved(self.moon_direction, previous.moon_direction)
+            || self.sun_direction[3] != previous.sun_direction[3]
+            || self.ground_albedo[..3] != previous.ground_albedo[..3]
+            || changed(self.ground_albedo[3], previous.ground_albedo[3])
+            || changed(self.sun_illuminance[1], previous.sun_illuminance[1])
+            || changed(self.moon_illuminance[3], previous.moon_illuminance[3])
+            || self.night[..2] != previous.night[..2]
+    }
+}
+
+/// Uploads the current sky for passes that evaluate it.
+pub fn sky_buffer(info: &RenderInfo) -> Arc<CpuAccessibleBufferXalloc<GpuSky>> {
+    CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(), GpuSky::new(&info.settings.sky))
+        .expect("failed to create buffer")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Deg, InnerSpace, Rad, Vector3};
+    use super::{civil_from_days, days_from_civil, SkySettings, SolarTime, SunPosition};
+
+    fn time(year: i32, month: u32, day: u32, hours: f32) -> SolarTime {
+        SolarTime { year, month, day, hours, utc_offset: 0.0, latitude: 0.0, longitude: 0.0 }
+    }
+
+    fn elevation(direction: Vector3<f32>) -> f32 {
+        Deg::from(Rad(direction.y.asin())).0
+    }
+
+    #[test]
+    fn civil_days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 1, 1), 10957);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+        assert_eq!(days_from_civil(1600, 1, 1), -135140);
+        assert_eq!(days_from_civil(1, 1, 1), -719162);
+
+        for days in (-800_000..800_000).step_by(97) {
+            let (year, month, day) = civil_from_days(days);
+            assert!((1..=12).contains(&month) && (1..=31).contains(&day));
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn day_of_year() {
+        assert_eq!(time(2023, 1, 1, 0.0).day_of_year(), 1);
+        assert_eq!(time(2023, 12, 31, 0.0).day_of_year(), 365);
+        assert_eq!(time(2024, 3, 1, 0.0).day_of_year(), 61);
+        assert_eq!(time(2024, 12, 31, 0.0).day_of_year(), 366);
+    }
+
+    #[test]
+    fn add_hours_rollover() {
+        let check = |start: SolarTime, hours: f32, expected: SolarTime| {
+            let mut time = start;
+            time.add_hours(hours);
+            assert_eq!(time, expected);
+        };
+        check(time(2023, 5, 10, 8.0), 4.5, time(2023, 5, 10, 12.5));
+        // month, year and leap day
+        check(time(2023, 1, 31, 23.0), 2.0, time(2023, 2, 1, 1.0));
+        check(time(2023, 12, 31, 22.0), 3.0, time(2024, 1, 1, 1.0));
+        check(time(2024, 2, 28, 20.0), 6.0, time(2024, 2, 29, 2.0));
+        check(time(2023, 2, 28, 20.0), 6.0, time(2023, 3, 1, 2.0));
+        check(time(2024, 2, 29, 12.0), 48.0, time(2024, 3, 2, 12.0));
+        // backwards
+        check(time(2024, 3, 1, 1.0), -2.0, time(2024, 2, 29, 23.0));
+        check(time(2000, 1, 1, 0.5), -1.0, time(1999, 12, 31, 23.5));
+    }
+
+    /// Highest elevation of the sun over a day in degrees, and the UTC time it's reached.
+    fn solar_noon(mut time: SolarTime) -> (f32, f32) {
+        let mut best = (-90.0, 0.0);
+        for minute in 0..24 * 60 {
+            time.hours = minute as f32 / 60.0;
+            let elevation = elevation(time.sun_direction());
+            if elevation > best.0 {
+                best = (elevation, time.hours - time.utc_offset);
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn sun_matches_noaa() {
+        // Greenwich, from the NOAA solar calculator
+        let greenwich = |month, day| SolarTime { latitude: 51.4769, longitude: 0.0, ..time(2024, month, day, 0.0) };
+        let (elevation, noon) = solar_noon(greenwich(6, 21));
+        assert!((elevation - 61.97).abs() < 0.2, "summer solstice noon elevation {}", elevation);
+        assert!((noon - (12.0 + 1.7 / 60.0)).abs() < 2.0 / 60.0, "summer solstice noon at {}", noon);
+        let (elevation, noon) = solar_noon(greenwich(12, 21));
+        assert!((elevation - 15.09).abs() < 0.2, "winter solstice noon elevation {}", elevation);
+        assert!((noon - (11.0 + 58.3 / 60.0)).abs() < 2.0 / 60.0, "winter solstice noon at {}", noon);
+
+        // Boulder in daylight saving time, where solar noon is past 13:00 local time
+        let boulder = SolarTime { latitude: 40.015, longitude: -105.27, utc_offset: -6.0, ..time(2024, 6, 20, 0.0) };
+        let (elevation, noon) = solar_noon(boulder);
+        assert!((elevation - 73.42).abs() < 0.2, "Boulder noon elevation {}", elevation);
+        assert!((noon - 6.0 - (13.0 + 2.4 / 60.0)).abs() < 2.0 / 60.0, "Boulder noon at {}", noon + 6.0);
+
+        // at solar noon in the northern hemisphere the sun is due south, which is +Z
+        let mut noon_time = greenwich(6, 21);
+        noon_time.hours = 12.0 + 1.7 / 60.0;
+        let direction = noon_time.sun_direction();
+        assert!(direction.x.abs() < 0.01 && direction.z > 0.0, "noon direction {:?}", direction);
+
+        // rises in the east and sets in the west
+        let mut equinox = time(2024, 3, 20, 6.2);
+        assert!(equinox.sun_direction().x > 0.99);
+        equinox.hours = 18.2;
+        assert!(equinox.sun_direction().x < -0.99);
+    }
+
+    fn sky_at(time: SolarTime) -> SkySettings {
+        SkySettings { sun: SunPosition::Time(time), ..SkySettings::default() }
+    }
+
+    #[test]
+    fn moon_phases() {
+        // full moon on 2024-01-25 at 17:54 UTC, new moon on 2024-02-09 at 22:59 UTC
+        let full = sky_at(SolarTime { latitude: 51.4769, ..time(2024, 1, 25, 17.9) });
+        let elongation = Deg::from(full.moon_direction().unwrap().angle(full.sun_direction())).0;
+        assert!(elongation > 174.0, "full moon elongation {}", elongation);
+        assert!(full.moon_phase_brightness() > 0.8, "full moon brightness {}", full.moon_phase_brightness());
+
+        let new = sky_at(SolarTime { latitude: 51.4769, ..time(2024, 2, 9, 23.0) });
+        let elongation = Deg::from(new.moon_direction().unwrap().angle(new.sun_direction())).0;
+        assert!(elongation < 6.0, "new moon elongation {}", elongation);
+        assert!(new.moon_phase_brightness() < 0.01, "new moon brightness {}", new.moon_phase_brightness());
+
+        // without a time there's no moon
+        assert_eq!(SkySettings::default().moon_phase_brightness(), 0.0);
+    }
+}
@@ -0,0 +1,236 @@
+//! Screen-space ambient occlusion.
+//!
+//! Ground-truth AO is computed at half resolution from the depth buffer and G-buffer normals,
+//! then blurred with a separable depth-aware blur into the `ambient_occlusion` attachment, which
+//! is applied to the ambient/IBL lighting.
+
+use std::sync::Arc;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::{SwapchainImage, AttachmentImage, ImageUsage};
+use vulkano::format::{ClearValue, Format, R16G16Sfloat};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::FullscreenRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::shader::ao as AoShaders;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::RenderInfo;
+
+
+/// Settings for screen-space ambient occlusion.
+#[derive(Debug, Clone)]
+pub struct AmbientOcclusionSettings {
+    pub enabled: bool,
+    /// World-space radius of the occlusion search.
+    pub radius: f32,
+    /// Exponent applied to the result, higher values give darker occlusion.
+    pub intensity: f32,
+    /// Number of depth samples per pixel.
+    pub sample_count: u32,
+}
+impl Default for AmbientOcclusionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            radius: 1.0,
+            intensity: 1.0,
+            sample_count: 16,
+        }
+    }
+}
+
+/// Rejection strength of the bilateral blur across depth edges.
+const BLUR_SHARPNESS: f32 = 16.0;
+
+
+pub struct AmbientOcclusionStage {
+    ao_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    blur_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    /// Framebuffer for the final blur pass, writing to the `ambient_occlusion` attachment.
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    nearest_sampler: Arc<Sampler>,
+    raw_ao: Option<Arc<AttachmentImage<R16G16Sfloat>>>,
+    blur_temp: Option<Arc<AttachmentImage<R16G16Sfloat>>>,
+    raw_framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    blur_temp_framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+}
+
+
+impl AmbientOcclusionStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::overwrite(Format::R16G16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let vs = crate::shader::fullscreen::Shader::load(device.clone()).expect("failed to create shader module");
+
+        let ao_pipeline = {
+            let fs = AoShaders::gtao::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let blur_pipeline = {
+            let fs = AoShaders::blur::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        AmbientOcclusionStage {
+            ao_pipeline,
+            blur_pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            nearest_sampler: Sampler::new(device.clone(), Filter::Nearest, Filter::Nearest, MipmapMode::Nearest,
+                                          SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                          0.0, 1.0, 0.0, 0.0).unwrap(),
+            raw_ao: None,
+            blur_temp: None,
+            raw_framebuffer: None,
+            blur_temp_framebuffer: None,
+        }
+    }
+}
+
+impl RenderStageDefinition for AmbientOcclusionStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.ao_pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        let settings = &info.settings.ao;
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap();
+
+        if !settings.enabled {
+            // fully unoccluded, so lighting doesn't need to check whether AO is enabled
+            let cb = cb.clear_color_image(info.attachments.ambient_occlusion.clone(), ClearValue::Float([1.0, 0.0, 0.0, 0.0])).unwrap();
+            return Some(vec![
+                (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+            ]);
+        }
+
+        let ao_dimensions = info.attachments.ambient_occlusion.dimensions();
+        let texel_size = [1.0 / ao_dimensions[0] as f32, 1.0 / ao_dimensions[1] as f32];
+        let dynamic_state = DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [ao_dimensions[0] as f32, ao_dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+            compare_mask: None,
+            write_mask: None,
+            reference: None
+        };
+
+        let ao_set = Arc::new(PersistentDescriptorSet::start(self.ao_pipeline.clone(), 0)
+            .add_sampled_image(info.attachments.main_depth.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.normal.clone(), self.nearest_sampler.clone()).unwrap()
+            .build().unwrap());
+        let horizontal_set = Arc::new(PersistentDescriptorSet::start(self.blur_pipeline.clone(), 0)
+            .add_sampled_image(self.raw_ao.as_ref().unwrap().clone(), self.nearest_sampler.clone()).unwrap()
+            .build().unwrap());
+        let vertical_set = Arc::new(PersistentDescriptorSet::start(self.blur_pipeline.clone(), 0)
+            .add_sampled_image(self.blur_temp.as_ref().unwrap().clone(), self.nearest_sampler.clone()).unwrap()
+            .build().unwrap());
+
+        let proj = info.proj_mat;
+        let cb = cb
+            .begin_render_pass(self.raw_framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.ao_pipeline.clone(), &dynamic_state,
+                vec![self.fullscreen_vertex_buffer.clone()],
+                ao_set, AoShaders::gtao::ty::Constants {
+                    view: info.view_mat.into(),
+                    proj_info: [1.0 / proj.x.x, 1.0 / proj.y.y, proj.z.x, proj.z.y],
+                    ao_dimensions: [ao_dimensions[0] as f32, ao_dimensions[1] as f32],
+                    radius: settings.radius,
+                    intensity: settings.intensity,
+                    sample_count: settings.sample_count,
+                    frame_index: info.frame_index as u32,
+                    near_plane: info.near_plane,
+                    far_plane: info.far_plane,
+                    depth_mode: info.depth_mode.shader_id(),
+                }).unwrap()
+            .end_render_pass().unwrap()
+            .begin_render_pass(self.blur_temp_framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.blur_pipeline.clone(), &dynamic_state,
+                vec![self.fullscreen_vertex_buffer.clone()],
+                horizontal_set, AoShaders::blur::ty::Constants {
+                    texel_size,
+                    direction: [1.0, 0.0],
+                    sharpness: BLUR_SHARPNESS,
+                }).unwrap()
+            .end_render_pass().unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.blur_pipeline.clone(), &dynamic_state,
+                vec![self.fullscreen_vertex_buffer.clone()],
+                vertical_set, AoShaders::blur::ty::Constants {
+                    texel_size,
+                    direction: [0.0, 1.0],
+                    sharpness: BLUR_SHARPNESS,
+                }).unwrap()
+            .end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            let dimensions = info.attachments.ambient_occlusion.dimensions();
+            let usage = ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                ..ImageUsage::none()
+            };
+            let raw_ao = AttachmentImage::with_usage(info.device.clone(), dimensions, R16G16Sfloat, usage).unwrap();
+            let blur_temp = AttachmentImage::with_usage(info.device.clone(), dimensions, R16G16Sfloat, usage).unwrap();
+
+            self.raw_framebuffer = Some(Arc::new(Framebuffer::start(self.renderpass.clone())
+                .add(raw_ao.clone()).unwrap()
+                .build().unwrap()));
+            self.blur_temp_framebuffer = Some(Arc::new(Framebuffer::start(self.renderpass.clone())
+                .add(blur_temp.clone()).unwrap()
+                .build().unwrap()));
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.renderpass.clone())
+                .add(info.attachments.ambient_occlusion.clone()).unwrap()
+                .build().unwrap()));
+            self.raw_ao = Some(raw_ao);
+            self.blur_temp = Some(blur_temp);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Physically-based bloom.
+//!
+//! `scene_color` is downsampled into a chain of progressively smaller images (the first pass uses
+//! a Karis average to keep single bright pixels from flickering), then the chain is upsampled
+//! back with a tent filter, mixing each level into the one above it. The result is blended into
+//! `scene_color` before tonemapping.
+
+use std::sync::Arc;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendOp, BlendFactor};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::{SwapchainImage, AttachmentImage, ImageUsage};
+use vulkano::format::{ClearValue, Format, R16G16B16A16Sfloat};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::FullscreenRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::shader::bloom as BloomShaders;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::RenderInfo;
+
+
+/// Maximum number of levels in the bloom chain. The chain also stops once a level would be
+/// smaller than `MIN_MIP_SIZE` pixels on a side.
+pub const MAX_BLOOM_MIPS: usize = 8;
+const MIN_MIP_SIZE: u32 = 4;
+
+/// Settings for bloom.
+#[derive(Debug, Clone)]
+pub struct BloomSettings {
+    pub enabled: bool,
+    /// Fraction of the final image that comes from the bloom.
+    pub intensity: f32,
+    /// Exposed brightness where bloom starts. 0 lets every pixel bloom, which is the physically
+    /// based behaviour; higher values give a more stylized look.
+    pub threshold: f32,
+    /// Softness of the threshold transition, from 0 (hard cutoff) to 1.
+    pub knee: f32,
+    /// How far the bloom spreads, from 0 to 1. Controls how much of each smaller level is mixed
+    /// into the level above it.
+    pub radius: f32,
+}
+impl Default for BloomSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            intensity: 0.04,
+            threshold: 0.0,
+            knee: 0.5,
+            radius: 0.75,
+        }
+    }
+}
+
+
+/// Returns the dimensions of each level in the bloom chain for the given screen dimensions.
+pub fn mip_dimensions(dimensions: [u32; 2]) -> Vec<[u32; 2]> {
+    let mut result = Vec::new();
+    let mut dim = [dimensions[0] / 2, dimensions[1] / 2];
+    while result.len() < MAX_BLOOM_MIPS && dim[0] >= MIN_MIP_SIZE && dim[1] >= MIN_MIP_SIZE {
+        result.push(dim);
+        dim = [dim[0] / 2, dim[1] / 2];
+    }
+    result
+}
+
+
+struct BloomMip {
+    image: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
+    dimensions: [u32; 2],
+    downsample_framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+    upsample_framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+}
+
+
+pub struct BloomStage {
+    downsample_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    upsample_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    /// Framebuffer for compositing onto `scene_color`.
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    downsample_renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    upsample_renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    sampler: Arc<Sampler>,
+    mips: Vec<BloomMip>,
+}
+
+
+impl BloomStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let downsample_renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::overwrite(Format::R16G16B16A16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+        let upsample_renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::blend(Format::R16G16B16A16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let vs = crate::shader::fullscreen::Shader::load(device.clone()).expect("failed to create shader module");
+
+        let downsample_pipeline = {
+            let fs = BloomShaders::downsample::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(downsample_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let upsample_pipeline = {
+            let fs = BloomShaders::upsample::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                // premultiplied: dst * (1 - alpha) + src
+                .blend_collective(AttachmentBlend {
+                    enabled: true,
+                    color_op: BlendOp::Add,
+                    color_source: BlendFactor::One,
+                    color_destination: BlendFactor::OneMinusSrcAlpha,
+                    alpha_op: BlendOp::Add,
+                    alpha_source: BlendFactor::Zero,
+                    alpha_destination: BlendFactor::One,
+                    mask_red: true,
+                    mask_green: true,
+                    mask_blue: true,
+                    mask_alpha: true,
+                })
+                .render_pass(Subpass::from(upsample_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        BloomStage {
+            downsample_pipeline,
+            upsample_pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            downsample_renderpass,
+            upsample_renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                  SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                  0.0, 1.0, 0.0, 0.0).unwrap(),
+            mips: Vec::new(),
+        }
+    }
+
+    fn dynamic_state(dimensions: [u32; 2]) -> DynamicState {
+        DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+            compare_mask: None,
+            write_mask: None,
+            reference: None
+        }
+    }
+}
+
+impl RenderStageDefinition for BloomStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.upsample_pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.upsample_renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        let settings = &info.settings.bloom;
+        if !settings.enabled || self.mips.is_empty() {
+            return None;
+        }
+
+        let mut cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap();
+
+        // downsample chain, starting from the full resolution scene color
+        for (i, mip) in self.mips.iter().enumerate() {
+            let (descriptor_set, source_dimensions) = if i == 0 {
+                (Arc::new(PersistentDescriptorSet::start(self.downsample_pipeline.clone(), 0)
+                    .add_sampled_image(info.attachments.scene_color.clone(), self.sampler.clone()).unwrap()
+                    .add_buffer(info.exposure.clone()).unwrap()
+                    .build().unwrap()), info.dimensions)
+            }
+            else {
+                let source = &self.mips[i - 1];
+                (Arc::new(PersistentDescriptorSet::start(self.downsample_pipeline.clone(), 0)
+                    .add_sampled_image(source.image.clone(), self.sampler.clone()).unwrap()
+                    .add_buffer(info.exposure.clone()).unwrap()
+                    .build().unwrap()), source.dimensions)
+            };
+
+            cb = cb.begin_render_pass(mip.downsample_framebuffer.clone(), false, vec![ClearValue::None]).unwrap()
+                .draw(self.downsample_pipeline.clone(), &Self::dynamic_state(mip.dimensions),
+                    vec![self.fullscreen_vertex_buffer.clone()],
+                    descriptor_set, BloomShaders::downsample::ty::Constants {
+                        source_texel_size: [1.0 / source_dimensions[0] as f32, 1.0 / source_dimensions[1] as f32],
+                        target_dimensions: [mip.dimensions[0] as f32, mip.dimensions[1] as f32],
+                        threshold: settings.threshold,
+                        knee: settings.knee,
+                        first_pass: if i == 0 { 1 } else { 0 },
+                    }).unwrap()
+                .end_render_pass().unwrap();
+        }
+
+        // upsample back up the chain, mixing each level into the next larger one
+        let radius = settings.radius.max(0.0).min(1.0);
+        for i in (1..self.mips.len()).rev() {
+            let source = &self.mips[i];
+            let target = &self.mips[i - 1];
+            let descriptor_set = Arc::new(PersistentDescriptorSet::start(self.upsample_pipeline.clone(), 0)
+                .add_sampled_image(source.image.clone(), self.sampler.clone()).unwrap()
+                .build().unwrap());
+            cb = cb.begin_render_pass(target.upsample_framebuffer.clone(), false, vec![ClearValue::None]).unwrap()
+                .draw(self.upsample_pipeline.clone(), &Self::dynamic_state(target.dimensions),
+                    vec![self.fullscreen_vertex_buffer.clone()],
+                    descriptor_set, BloomShaders::upsample::ty::Constants {
+                        source_texel_size: [1.0 / source.dimensions[0] as f32, 1.0 / source.dimensions[1] as f32],
+                        target_dimensions: [target.dimensions[0] as f32, target.dimensions[1] as f32],
+                        intensity: radius,
+                        blend_alpha: radius,
+                    }).unwrap()
+                .end_render_pass().unwrap();
+        }
+
+        // composite onto the scene. without a threshold the bloom replaces part of the image, which
+        // conserves energy. with a threshold only the bright parts were kept, so add it instead.
+        let top = &self.mips[0];
+        let blend_alpha = if settings.threshold > 0.0 { 0.0 } else { settings.intensity };
+        let descriptor_set = Arc::new(PersistentDescriptorSet::start(self.upsample_pipeline.clone(), 0)
+            .add_sampled_image(top.image.clone(), self.sampler.clone()).unwrap()
+            .build().unwrap());
+        cb = cb.begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.upsample_pipeline.clone(), &Self::dynamic_state(info.dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                descriptor_set, BloomShaders::upsample::ty::Constants {
+                    source_texel_size: [1.0 / top.dimensions[0] as f32, 1.0 / top.dimensions[1] as f32],
+                    target_dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                    intensity: settings.intensity,
+                    blend_alpha,
+                }).unwrap()
+            .end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.upsample_renderpass.clone())
+                .add(info.attachments.scene_color.clone()).unwrap()
+                .build().unwrap()));
+
+            let usage = ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                ..ImageUsage::none()
+            };
+            self.mips = mip_dimensions(info.dimensions).into_iter().map(|dimensions| {
+                let image = AttachmentImage::with_usage(info.device.clone(), dimensions, R16G16B16A16Sfloat, usage).unwrap();
+                BloomMip {
+                    downsample_framebuffer: Arc::new(Framebuffer::start(self.downsample_renderpass.clone())
+                        .add(image.clone()).unwrap()
+                        .build().unwrap()),
+                    upsample_framebuffer: Arc::new(Framebuffer::start(self.upsample_renderpass.clone())
+                        .add(image.clone()).unwrap()
+                        .build().unwrap()),
+                    image,
+                    dimensions,
+                }
+            }).collect();
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Deferred lighting.
+//!
+//! A fullscreen pass over the G-buffer that writes the diffuse and specular light buffers: the sun
+//! with its shadow cascades, the lights uploaded by light culling, and image based lighting from
+//! the environment and reflection probes, darkened by ambient occlusion. Local lights are only
+//! shaded here with `LocalLightCulling::None` or `Clustered`; see `light_volumes` for `Volumes`.
+
+use std::sync::Arc;
+use vulkano::buffer::BufferUsage;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::SwapchainImage;
+use vulkano::format::ClearValue;
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::DeferredLightingRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::shader::deferred_lighting as DeferredLightingShaders;
+use crate::stage::RenderStageDefinition;
+use crate::stage::shadow::SunCascades;
+use crate::stage::local_shadow::local_shadow_buffers;
+use crate::reflection_probe::reflection_probe_buffers;
+use crate::renderer::RenderInfo;
+
+
+pub struct DeferredLightingStage {
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    linear_sampler: Arc<Sampler>,
+    /// Linear filtering clamped to the edges, for lookup tables.
+    lut_sampler: Arc<Sampler>,
+    shadow_sampler: Arc<Sampler>,
+}
+
+
+impl DeferredLightingStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            DeferredLightingRenderPass {}
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let pipeline = {
+            let vs = DeferredLightingShaders::vertex::Shader::load(device.clone()).expect("failed to create shader module");
+            let fs = DeferredLightingShaders::fragment::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        DeferredLightingStage {
+            pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            linear_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Linear,
+                                         SamplerAddressMode::Repeat, SamplerAddressMode::Repeat, SamplerAddressMode::Repeat,
+                                         0.0, 4.0, 0.0, 4.0).unwrap(),
+            lut_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                      SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                      0.0, 1.0, 0.0, 0.0).unwrap(),
+            shadow_sampler: Sampler::compare(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                             SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                             0.0, 1.0, 0.0, 0.0, Compare::LessOrEqual).unwrap(),
+        }
+    }
+}
+
+impl RenderStageDefinition for DeferredLightingStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    /// Skipped until light culling has uploaded this frame's lights, since the cluster lists
+    /// index into that upload.
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        let clusters = info.light_clusters.lock().clone()?;
+
+        let cascades = SunCascades::compute(info);
+        let shadow_settings = &info.settings.shadows;
+        let sun_color = info.sun.color * info.sun.intensity;
+        let sun_buffer = CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(),
+            DeferredLightingShaders::fragment::ty::SunData {
+                cascade_view_proj: [cascades.view_proj[0].into(), cascades.view_proj[1].into(),
+                                    cascades.view_proj[2].into(), cascades.view_proj[3].into()],
+                cascade_splits: cascades.splits,
+                cascade_texel_sizes: cascades.texel_sizes,
+                direction: [info.sun.direction.x, info.sun.direction.y, info.sun.direction.z, 0.0],
+                color: [sun_color.x, sun_color.y, sun_color.z, 1.0],
+                cascade_count: cascades.count as u32,
+                shadows_enabled: shadow_settings.enabled as u32,
+                depth_bias: shadow_settings.depth_bias,
+                normal_bias: shadow_settings.normal_bias,
+                pcf_radius: shadow_settings.pcf_radius as i32,
+            }).expect("failed to create buffer");
+
+        let (local_shadow_tiles, local_shadow_params) = local_shadow_buffers(info);
+        let (probes, probe_params) = reflection_probe_buffers(info);
+
+        let descriptor_set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_image(info.attachments.position.clone()).unwrap()
+            .add_image(info.attachments.normal.clone()).unwrap()
+            .add_image(info.attachments.albedo.clone()).unwrap()
+            .add_image(info.attachments.roughness.clone()).unwrap()
+            .add_image(info.attachments.metallic.clone()).unwrap()
+            .add_sampled_image(info.environment.irradiance.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.environment.radiance.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.environment.brdf_lut.clone(), self.lut_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.ambient_occlusion.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.shadow_maps.sun.clone(), self.shadow_sampler.clone()).unwrap()
+            .add_buffer(sun_buffer).unwrap()
+            .add_buffer(local_shadow_tiles).unwrap()
+            .add_sampled_image(info.shadow_maps.local.clone(), self.shadow_sampler.clone()).unwrap()
+            .add_buffer(local_shadow_params).unwrap()
+            .add_buffer(clusters.lights).unwrap()
+            .add_sampled_image(info.ies_profiles.texture.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.ltc_tables.matrix.clone(), self.lut_sampler.clone()).unwrap()
+            .add_sampled_image(info.ltc_tables.amplitude.clone(), self.lut_sampler.clone()).unwrap()
+            .add_buffer(clusters.grid).unwrap()
+            .add_buffer(clusters.indices).unwrap()
+            .add_buffer(clusters.params).unwrap()
+            .add_sampled_image(info.reflection_probes.atlas.clone(), self.lut_sampler.clone()).unwrap()
+            .add_buffer(probes).unwrap()
+            .add_buffer(probe_params).unwrap()
+            .build().unwrap());
+
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false,
+                               vec![ClearValue::None, ClearValue::None, ClearValue::None, ClearValue::None, ClearValue::None,
+                                    [0.0, 0.0, 0.0, 1.0].into(), [0.0, 0.0, 0.0, 1.0].into()]).unwrap()
+            .draw(self.pipeline.clone(), &DynamicState {
+                    line_width: None,
+                    viewports: Some(vec![Viewport {
+                        origin: [0.0, 0.0],
+                        dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                        depth_range: 0.0..1.0,
+                    }]),
+                    scissors: None,
+                    compare_mask: None,
+                    write_mask: None,
+                    reference: None
+                },
+                vec![self.fullscreen_vertex_buffer.clone()],
+                descriptor_set, DeferredLightingShaders::fragment::ty::Constants {
+                    view: info.view_mat.into(),
+                    view_pos: info.camera_transform.position.into(),
+                    debug_vis_mode: info.debug_visualize_setting,
+                    screen_dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                    light_count: clusters.light_count,
+                    directional_light_count: clusters.directional_light_count,
+                    light_culling: info.settings.light_culling.shader_id(),
+                }).unwrap()
+            .end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.renderpass.clone())
+                .add(info.attachments.position.clone()).unwrap()
+                .add(info.attachments.normal.clone()).unwrap()
+                .add(info.attachments.albedo.clone()).unwrap()
+                .add(info.attachments.roughness.clone()).unwrap()
+                .add(info.attachments.metallic.clone()).unwrap()
+                .add(info.attachments.diffuse_light.clone()).unwrap()
+                .add(info.attachments.specular_light.clone()).unwrap()
+                .build().unwrap()));
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Depth of field.
+//!
+//! The circle of confusion comes either from a thin lens model of the physical camera, or from
+//! artist set focus ranges. `scene_color` is downsampled to half resolution with the CoC in
+//! alpha, then a bokeh gather blurs the near and far fields separately and mixes them by the near
+//! field's coverage. The result is blended back over `scene_color`, keeping in focus pixels at
+//! full resolution.
+
+use std::sync::Arc;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendOp, BlendFactor};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::{SwapchainImage, AttachmentImage, ImageUsage};
+use vulkano::format::{ClearValue, Format, R16G16B16A16Sfloat};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::FullscreenRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::shader::dof as DofShaders;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::RenderInfo;
+
+
+/// What sets the circle of confusion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DepthOfFieldFocus {
+    /// Thin lens model of `TonemappingInfo::physical_camera`, using its aperture, focus distance
+    /// and sensor size. Depth of field is off while there's no physical camera.
+    Physical,
+    /// Distances in meters. Blur fades in from `near_end` down to `near_start`, and from
+    /// `far_start` up to `far_end`, reaching `DepthOfFieldSettings::max_blur` at either end.
+    Ranges { near_start: f32, near_end: f32, far_start: f32, far_end: f32 },
+}
+
+/// Settings for depth of field.
+#[derive(Debug, Clone)]
+pub struct DepthOfFieldSettings {
+    pub enabled: bool,
+    pub focus: DepthOfFieldFocus,
+    /// Largest blur radius, as a fraction of the screen height. Also limits the physical CoC.
+    pub max_blur: f32,
+    /// Samples in the bokeh gather.
+    pub sample_count: u32,
+}
+impl Default for DepthOfFieldSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            focus: DepthOfFieldFocus::Physical,
+            max_blur: 0.02,
+            sample_count: 48,
+        }
+    }
+}
+
+
+pub struct DepthOfFieldStage {
+    prefilter_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    gather_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    composite_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    /// Framebuffer for compositing onto `scene_color`.
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    half_res_renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    composite_renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    linear_sampler: Arc<Sampler>,
+    nearest_sampler: Arc<Sampler>,
+    half_res: Option<HalfResTargets>,
+}
+
+/// Half resolution images, with framebuffers rendering into them.
+struct HalfResTargets {
+    dimensions: [u32; 2],
+    prefiltered: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
+    prefiltered_framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+    gathered: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
+    gathered_framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+}
+
+
+impl DepthOfFieldStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let half_res_renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::overwrite(Format::R16G16B16A16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+        let composite_renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::blend(Format::R16G16B16A16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let vs = crate::shader::fullscreen::Shader::load(device.clone()).expect("failed to create shader module");
+
+        let prefilter_pipeline = {
+            let fs = DofShaders::prefilter::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(half_res_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let gather_pipeline = {
+            let fs = DofShaders::gather::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(half_res_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let composite_pipeline = {
+            let fs = DofShaders::composite::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                // premultiplied: dst * (1 - alpha) + src
+                .blend_collective(AttachmentBlend {
+                    enabled: true,
+                    color_op: BlendOp::Add,
+                    color_source: BlendFactor::One,
+                    color_destination: BlendFactor::OneMinusSrcAlpha,
+                    alpha_op: BlendOp::Add,
+                    alpha_source: BlendFactor::Zero,
+                    alpha_destination: BlendFactor::One,
+                    mask_red: true,
+                    mask_green: true,
+                    mask_blue: true,
+                    mask_alpha: true,
+                })
+                .render_pass(Subpass::from(composite_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        DepthOfFieldStage {
+            prefilter_pipeline,
+            gather_pipeline,
+            composite_pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            half_res_renderpass,
+            composite_renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            linear_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                         SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                         0.0, 1.0, 0.0, 0.0).unwrap(),
+            nearest_sampler: Sampler::new(device.clone(), Filter::Nearest, Filter::Nearest, MipmapMode::Nearest,
+                                          SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                          0.0, 1.0, 0.0, 0.0).unwrap(),
+            half_res: None,
+        }
+    }
+
+    fn dynamic_state(dimensions: [u32; 2]) -> DynamicState {
+        DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+            compare_mask: None,
+            write_mask: None,
+            reference: None
+        }
+    }
+
+    /// `(focus_mode, coc_scale, focus_distance, focus_ranges)` for `dof.inc`, or `None` if
+    /// there's nothing to focus with. `coc_scale` is the CoC radius in pixels of something
+    /// infinitely far away.
+    fn focus_params(info: &RenderInfo) -> Option<(u32, f32, f32, [f32; 4])> {
+        match info.settings.dof.focus {
+            DepthOfFieldFocus::Physical => {
+                let camera = info.tonemapping_info.physical_camera.as_ref()?;
+                let focal_length = camera.focal_length(info.fov);
+                let focus_distance = camera.focus_distance.max(focal_length * 1.01);
+                let aperture_diameter = focal_length / camera.aperture.max(0.5);
+                let pixels_per_meter = info.dimensions[1] as f32 / camera.sensor_height.max(1e-4);
+                let coc_scale = 0.5 * aperture_diameter * focal_length / (focus_distance - focal_length) * pixels_per_meter;
+                Some((0, coc_scale, focus_distance, [0.0; 4]))
+            },
+            DepthOfFieldFocus::Ranges { near_start, near_end, far_start, far_end } => {
+                Some((1, 0.0, 0.0, [near_start, near_end, far_start, far_end]))
+            },
+        }
+    }
+}
+
+impl RenderStageDefinition for DepthOfFieldStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.composite_pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.composite_renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        let settings = &info.settings.dof;
+        if !settings.enabled {
+            return None;
+        }
+        let (focus_mode, coc_scale, focus_distance, focus_ranges) = Self::focus_params(info)?;
+        let half_res = self.half_res.as_ref()?;
+
+        let max_coc = settings.max_blur.max(0.0) * info.dimensions[1] as f32;
+        let screen_dimensions = [info.dimensions[0] as f32, info.dimensions[1] as f32];
+        let depth_mode = info.depth_mode.shader_id();
+
+        let prefilter_set = Arc::new(PersistentDescriptorSet::start(self.prefilter_pipeline.clone(), 0)
+            .add_sampled_image(info.attachments.scene_color.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.main_depth.clone(), self.nearest_sampler.clone()).unwrap()
+            .build().unwrap());
+        let gather_set = Arc::new(PersistentDescriptorSet::start(self.gather_pipeline.clone(), 0)
+            .add_sampled_image(half_res.prefiltered.clone(), self.linear_sampler.clone()).unwrap()
+            .build().unwrap());
+        let composite_set = Arc::new(PersistentDescriptorSet::start(self.composite_pipeline.clone(), 0)
+            .add_sampled_image(half_res.gathered.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.main_depth.clone(), self.nearest_sampler.clone()).unwrap()
+            .build().unwrap());
+
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(half_res.prefiltered_framebuffer.clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.prefilter_pipeline.clone(), &Self::dynamic_state(half_res.dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                prefilter_set, DofShaders::prefilter::ty::Constants {
+                    focus_ranges,
+                    screen_dimensions,
+                    coc_scale,
+                    focus_distance,
+                    max_coc,
+                    near_plane: info.near_plane,
+                    far_plane: info.far_plane,
+                    focus_mode,
+                    depth_mode,
+                }).unwrap()
+            .end_render_pass().unwrap()
+            .begin_render_pass(half_res.gathered_framebuffer.clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.gather_pipeline.clone(), &Self::dynamic_state(half_res.dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                gather_set, DofShaders::gather::ty::Constants {
+                    target_dimensions: [half_res.dimensions[0] as f32, half_res.dimensions[1] as f32],
+                    max_coc: max_coc * 0.5,
+                    sample_count: settings.sample_count,
+                }).unwrap()
+            .end_render_pass().unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.composite_pipeline.clone(), &Self::dynamic_state(info.dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                composite_set, DofShaders::composite::ty::Constants {
+                    focus_ranges,
+                    screen_dimensions,
+                    coc_scale,
+                    focus_distance,
+                    max_coc,
+                    near_plane: info.near_plane,
+                    far_plane: info.far_plane,
+                    focus_mode,
+                    depth_mode,
+                }).unwrap()
+            .end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.composite_renderpass.clone())
+                .add(info.attachments.scene_color.clone()).unwrap()
+                .build().unwrap()));
+
+            let usage = ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                ..ImageUsage::none()
+            };
+            let dimensions = [(info.dimensions[0] + 1) / 2, (info.dimensions[1] + 1) / 2];
+            let prefiltered = AttachmentImage::with_usage(info.device.clone(), dimensions, R16G16B16A16Sfloat, usage).unwrap();
+            let gathered = AttachmentImage::with_usage(info.device.clone(), dimensions, R16G16B16A16Sfloat, usage).unwrap();
+            self.half_res = Some(HalfResTargets {
+                dimensions,
+                prefiltered_framebuffer: Arc::new(Framebuffer::start(self.half_res_renderpass.clone())
+                    .add(prefiltered.clone()).unwrap()
+                    .build().unwrap()),
+                gathered_framebuffer: Arc::new(Framebuffer::start(self.half_res_renderpass.clone())
+                    .add(gathered.clone()).unwrap()
+                    .build().unwrap()),
+                prefiltered,
+                gathered,
+            });
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::sync::Arc;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::{SwapchainImage, AttachmentImage};
+use vulkano::format::{ClearValue, Format, B8G8R8A8Srgb};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::FullscreenRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::shader::fxaa as FxaaShaders;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::RenderInfo;
+
+
+/// FXAA quality presets, roughly matching the FXAA 3.11 presets 10, 12, 29 and 39.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FxaaQuality {
+    Low,
+    Medium,
+    High,
+    Ultra,
+}
+impl FxaaQuality {
+    /// Returns `(edge_threshold, edge_threshold_min, search_steps)` for this preset.
+    pub fn parameters(&self) -> (f32, f32, u32) {
+        match self {
+            FxaaQuality::Low    => (0.250, 0.0833, 4),
+            FxaaQuality::Medium => (0.166, 0.0833, 8),
+            FxaaQuality::High   => (0.125, 0.0625, 12),
+            FxaaQuality::Ultra  => (0.063, 0.0312, 12),
+        }
+    }
+}
+
+/// Settings for FXAA.
+#[derive(Debug, Clone)]
+pub struct FxaaSettings {
+    pub enabled: bool,
+    pub quality: FxaaQuality,
+    /// Amount of sub-pixel aliasing removal, from 0 (sharper) to 1 (softer).
+    pub subpixel_quality: f32,
+}
+impl Default for FxaaSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            quality: FxaaQuality::Medium,
+            subpixel_quality: 0.75,
+        }
+    }
+}
+
+
+/// Final output stage. Reads the tonemapped `ldr_color` attachment and writes it to the output
+/// image, applying FXAA if it's enabled.
+///
+/// In standalone mode the output is the current swapchain image, in embedded mode it's the
+/// render target.
+pub struct FxaaStage {
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    sampler: Arc<Sampler>,
+    render_target: Option<Arc<AttachmentImage<B8G8R8A8Srgb>>>,
+}
+
+
+impl FxaaStage {
+    pub fn new(device: Arc<Device>, render_target: Option<Arc<AttachmentImage<B8G8R8A8Srgb>>>) -> Self {
+        let renderpass = Arc::new(
+            FullscreenRenderPass::overwrite(Format::B8G8R8A8Srgb)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let pipeline = {
+            let vs = crate::shader::fullscreen::Shader::load(device.clone()).expect("failed to create shader module");
+            let fs = FxaaShaders::fragment::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        FxaaStage {
+            pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                  SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                  0.0, 1.0, 0.0, 0.0).unwrap(),
+            render_target,
+        }
+    }
+}
+
+impl RenderStageDefinition for FxaaStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        let framebuffer = match &self.framebuffer {
+            Some(fb) => fb.clone(),
+            None => self.framebuffers.as_ref().unwrap()[info.image_num].clone(),
+        };
+
+        let descriptor_set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_sampled_image(info.attachments.ldr_color.clone(), self.sampler.clone()).unwrap()
+            .build().unwrap());
+
+        let settings = &info.settings.fxaa;
+        let (edge_threshold, edge_threshold_min, search_steps) = settings.quality.parameters();
+
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(framebuffer, false, vec![ClearValue::None]).unwrap()
+            .draw(self.pipeline.clone(), &DynamicState {
+                    line_width: None,
+                    viewports: Some(vec![Viewport {
+                        origin: [0.0, 0.0],
+                        dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                        depth_range: 0.0..1.0,
+                    }]),
+                    scissors: None,
+                    compare_mask: None,
+                    write_mask: None,
+                    reference: None
+                },
+                vec![self.fullscreen_vertex_buffer.clone()],
+                descriptor_set, FxaaShaders::fragment::ty::Constants {
+                    inv_screen_dimensions: [1.0 / info.dimensions[0] as f32, 1.0 / info.dimensions[1] as f32],
+                    subpix: settings.subpixel_quality,
+                    edge_threshold,
+                    edge_threshold_min,
+                    search_steps,
+                    enabled: settings.enabled as u32,
+                }).unwrap()
+            .end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, images: &Vec<Arc<SwapchainImage<Window>>>, _info: &RenderInfo) {
+        match &self.render_target {
+            Some(target) => {
+                if self.framebuffer.is_none() {
+                    self.framebuffer = Some(Arc::new(Framebuffer::start(self.get_renderpass().clone())
+                        .add(target.clone()).unwrap()
+                        .build().unwrap()));
+                }
+            },
+            None => {
+                if self.get_framebuffers_mut().is_none() {
+                    let new_framebuffers = Some(images.iter().map(|image| {
+                        let arc: Arc<dyn FramebufferAbstract + Send + Sync> = Arc::new(Framebuffer::start(self.get_renderpass().clone())
+                            .add(image.clone()).unwrap()
+                            .build().unwrap());
+                        arc
+                    }).collect::<Vec<_>>());
+                    ::std::mem::replace(self.get_framebuffers_mut(), new_framebuffers);
+                }
+            }
+        }
+    }
+}
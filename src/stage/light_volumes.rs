@@ -0,0 +1,276 @@
+//! Local lights shaded with bounding volumes.
+//!
+//! Each light rasterizes a cone (spot lights) or a sphere (everything else) around its range, and only
+//! the covered pixels evaluate the light, additively blended into the diffuse and specular light
+//! buffers. The depth buffer has no stencil, so instead of a stencil mask each volume is drawn
+//! single-sided: front faces with a normal depth test when the camera is outside the volume, which
+//! rejects pixels in front of the light, and back faces with an inverted depth test when the camera
+//! is inside, since the front faces would be clipped by the near plane. Pixels behind the volume
+//! that pass the test are rejected by the range check in the shader.
+
+use std::sync::Arc;
+use cgmath::{Matrix4, Vector3, InnerSpace};
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::depth_stencil::{DepthStencil, Compare};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendOp, BlendFactor};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::SwapchainImage;
+use vulkano::format::ClearValue;
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::LightVolumeRenderPass;
+use crate::geometry::VertexPosition;
+use crate::geometry::volumes::{self, VolumeMesh};
+use crate::light::{Light, LightKind, LocalLightCulling, light_buffer};
+use crate::shader::light_volume as LightVolumeShaders;
+use crate::stage::RenderStageDefinition;
+use crate::stage::local_shadow::local_shadow_buffers;
+use crate::renderer::{RenderInfo, DepthMode};
+
+
+/// Spot lights wider than this use a sphere, since the cone's base gets huge.
+const MAX_CONE_ANGLE: f32 = 1.2;
+/// How much bigger the volume meshes are than the light, at most.
+const VOLUME_MARGIN: f32 = 1.15;
+
+
+/// Model matrix placing a unit volume mesh around a light.
+fn volume_transform(light: &Light) -> (Matrix4<f32>, bool) {
+    match light.kind {
+        LightKind::Spot { outer_angle, .. } if outer_angle.0 <= MAX_CONE_ANGLE => {
+            // cone mesh opens along -Z
+            let z = -light.direction.normalize();
+            let up = if z.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+            let x = up.cross(z).normalize();
+            let y = z.cross(x);
+            let radius = light.range * outer_angle.0.tan();
+            (Matrix4::from_cols((x * radius).extend(0.0), (y * radius).extend(0.0), (z * light.range).extend(0.0),
+                                light.position.extend(1.0)), true)
+        },
+        _ => (Matrix4::from_translation(light.position) * Matrix4::from_scale(light.influence_radius()), false),
+    }
+}
+
+/// Whether the camera might be inside a light's volume, or close enough that the near plane clips
+/// its front faces. Errs on the side of inside, which is correct either way, just slower.
+fn camera_inside(light: &Light, is_cone: bool, camera_pos: Vector3<f32>, margin: f32) -> bool {
+    let to_camera = camera_pos - light.position;
+    if to_camera.magnitude() > light.influence_radius() * VOLUME_MARGIN + margin { return false; }
+
+    match light.kind {
+        LightKind::Spot { outer_angle, .. } if is_cone => {
+            let direction = light.direction.normalize();
+            let along = to_camera.dot(direction);
+            let across = (to_camera - direction * along).magnitude();
+            across <= along.max(0.0) * outer_angle.0.tan() * VOLUME_MARGIN + margin
+        },
+        _ => true,
+    }
+}
+
+
+pub struct LightVolumeStage {
+    /// Front faces, for lights the camera is outside of.
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    /// Back faces with an inverted depth test, for lights the camera is inside of.
+    inside_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    sphere: VolumeMesh,
+    cone: VolumeMesh,
+    nearest_sampler: Arc<Sampler>,
+    linear_sampler: Arc<Sampler>,
+    lut_sampler: Arc<Sampler>,
+    shadow_sampler: Arc<Sampler>,
+}
+
+
+impl LightVolumeStage {
+    pub fn new(device: Arc<Device>, depth_mode: DepthMode) -> Self {
+        let renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            LightVolumeRenderPass {}
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let vs = LightVolumeShaders::vertex::Shader::load(device.clone()).expect("failed to create shader module");
+        let fs = LightVolumeShaders::fragment::Shader::load(device.clone()).expect("failed to create shader module");
+
+        let additive = AttachmentBlend {
+            enabled: true,
+            color_op: BlendOp::Add,
+            color_source: BlendFactor::One,
+            color_destination: BlendFactor::One,
+            alpha_op: BlendOp::Add,
+            alpha_source: BlendFactor::One,
+            alpha_destination: BlendFactor::One,
+            mask_red: true,
+            mask_green: true,
+            mask_blue: true,
+            mask_alpha: true,
+        };
+        let (outside_compare, inside_compare) = match depth_mode {
+            DepthMode::Standard => (Compare::LessOrEqual, Compare::GreaterOrEqual),
+            DepthMode::ReverseZInfinite => (Compare::GreaterOrEqual, Compare::LessOrEqual),
+        };
+
+        let pipeline = Arc::new(GraphicsPipeline::start()
+            .vertex_input_single_buffer::<VertexPosition>()
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs.main_entry_point(), ())
+            .cull_mode_back()
+            .depth_stencil(DepthStencil {
+                depth_write: false,
+                depth_compare: outside_compare,
+                ..DepthStencil::simple_depth_test()
+            })
+            .blend_collective(additive.clone())
+            .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+            .build(device.clone())
+            .unwrap());
+
+        let inside_pipeline = Arc::new(GraphicsPipeline::start()
+            .vertex_input_single_buffer::<VertexPosition>()
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs.main_entry_point(), ())
+            .cull_mode_front()
+            .depth_stencil(DepthStencil {
+                depth_write: false,
+                depth_compare: inside_compare,
+                ..DepthStencil::simple_depth_test()
+            })
+            .blend_collective(additive)
+            .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+            .build(device.clone())
+            .unwrap());
+
+        LightVolumeStage {
+            pipeline,
+            inside_pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            renderpass,
+            sphere: volumes::sphere(device.clone()),
+            cone: volumes::cone(device.clone()),
+            nearest_sampler: Sampler::new(device.clone(), Filter::Nearest, Filter::Nearest, MipmapMode::Nearest,
+                                          SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                          0.0, 1.0, 0.0, 0.0).unwrap(),
+            linear_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                         SamplerAddressMode::ClampToEdge, SamplerAddressMode::Repeat, SamplerAddressMode::ClampToEdge,
+                                         0.0, 1.0, 0.0, 0.0).unwrap(),
+            lut_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                      SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                      0.0, 1.0, 0.0, 0.0).unwrap(),
+            shadow_sampler: Sampler::compare(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                             SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                             0.0, 1.0, 0.0, 0.0, Compare::LessOrEqual).unwrap(),
+        }
+    }
+}
+
+impl RenderStageDefinition for LightVolumeStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        if info.settings.light_culling != LocalLightCulling::Volumes {
+            return None;
+        }
+        let local_lights: Vec<_> = info.lights.iter()
+            .filter(|(_, light)| light.kind != LightKind::Directional)
+            .collect();
+        if local_lights.is_empty() {
+            return None;
+        }
+
+        let (local_shadow_tiles, local_shadow_params) = local_shadow_buffers(info);
+        let lights_buffer = light_buffer(info.device.clone(), local_lights.iter().cloned(), &info.shadow_maps.local_data.lock());
+
+        // both pipelines share a layout
+        let descriptor_set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_sampled_image(info.attachments.position.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.normal.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.albedo.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.roughness.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.metallic.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_buffer(local_shadow_tiles).unwrap()
+            .add_sampled_image(info.shadow_maps.local.clone(), self.shadow_sampler.clone()).unwrap()
+            .add_buffer(local_shadow_params).unwrap()
+            .add_buffer(lights_buffer).unwrap()
+            .add_sampled_image(info.ies_profiles.texture.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.ltc_tables.matrix.clone(), self.lut_sampler.clone()).unwrap()
+            .add_sampled_image(info.ltc_tables.amplitude.clone(), self.lut_sampler.clone()).unwrap()
+            .build().unwrap());
+
+        let dynamic_state = DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+            compare_mask: None,
+            write_mask: None,
+            reference: None
+        };
+
+        let camera_pos: [f32; 3] = info.camera_transform.position.into();
+        let camera_pos = Vector3::from(camera_pos);
+        let view_proj = info.proj_mat * info.view_mat;
+        // distance from the camera to the corners of the near plane
+        let near_margin = info.near_plane * 2.0;
+
+        let mut cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false,
+                               vec![ClearValue::None, ClearValue::None, ClearValue::None]).unwrap();
+
+        for (index, (_, light)) in local_lights.iter().enumerate() {
+            let (model, is_cone) = volume_transform(light);
+            let mesh = if is_cone { &self.cone } else { &self.sphere };
+            let pipeline = if camera_inside(light, is_cone, camera_pos, near_margin) {
+                self.inside_pipeline.clone()
+            }
+            else {
+                self.pipeline.clone()
+            };
+            cb = cb.draw_indexed(pipeline, &dynamic_state,
+                vec![mesh.vertex_buffer.clone()],
+                mesh.index_buffer.clone(),
+                descriptor_set.clone(), LightVolumeShaders::fragment::ty::Constants {
+                    mvp: (view_proj * model).into(),
+                    view_pos: camera_pos.into(),
+                    light_index: index as u32,
+                }).unwrap();
+        }
+
+        let cb = cb.end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.renderpass.clone())
+                .add(info.attachments.diffuse_light.clone()).unwrap()
+                .add(info.attachments.specular_light.clone()).unwrap()
+                .add(info.attachments.main_depth.clone()).unwrap()
+                .build().unwrap()));
+        }
+    }
+}
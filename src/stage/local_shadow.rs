@@ -0,0 +1,663 @@
+//! Shadows for local point and spot lights.
+//!
+//! All local shadow maps share one depth atlas. Each frame the shadow casters are ranked by
+//! screen coverage times priority, and get a power-of-two tile size from that. Tiles are handed
+//! out by a quadtree allocator, so any mix of sizes packs without fragmentation. Point lights use
+//! six tiles (one per cube face), spot lights use one.
+//!
+//! Static casters keep their tiles and contents between frames, and are only re-rendered when
+//! the caster changes, its tile size changes, or the cache is invalidated.
+
+use std::sync::Arc;
+use cgmath::{Matrix4, Vector3, Point3, SquareMatrix, InnerSpace, EuclideanSpace, Rad, Deg};
+use hashbrown::HashMap;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::depth_stencil::{DepthStencil, Compare};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::buffer::BufferUsage;
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::{SwapchainImage, AttachmentImage, ImageUsage};
+use vulkano::format::{ClearValue, D32Sfloat};
+use winit::Window;
+
+use crate::renderpass::ShadowRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::{MeshVertex, VertexPosition};
+use crate::light::{LocalShadowCaster, ShadowCasterKind};
+use crate::shader::shadow_depth as ShadowShaders;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::{RenderInfo, VULKAN_CORRECT_CLIP};
+
+
+pub const LOCAL_SHADOW_ATLAS_SIZE: u32 = 4096;
+/// Near plane for local light shadow projections.
+const SHADOW_NEAR_PLANE: f32 = 0.05;
+
+lazy_static! {
+    static ref SHADOW_MAP_USAGE: ImageUsage = ImageUsage {
+        depth_stencil_attachment: true,
+        sampled: true,
+        ..ImageUsage::none()
+    };
+}
+
+/// Creates the shared atlas for local light shadows.
+pub fn create_local_shadow_atlas(device: Arc<Device>) -> Arc<AttachmentImage<D32Sfloat>> {
+    AttachmentImage::with_usage(device, [LOCAL_SHADOW_ATLAS_SIZE, LOCAL_SHADOW_ATLAS_SIZE], D32Sfloat, *SHADOW_MAP_USAGE).unwrap()
+}
+
+
+/// Settings for local light shadows.
+#[derive(Debug, Clone)]
+pub struct LocalShadowSettings {
+    pub enabled: bool,
+    /// Tile size for a light covering the whole screen at priority 1. Must be a power of two.
+    pub max_tile_size: u32,
+    /// Smallest tile size handed out. Lights that would get less than this still get this size.
+    pub min_tile_size: u32,
+    /// Constant depth bias, in light clip space.
+    pub depth_bias: f32,
+    /// Offset along the surface normal before sampling, in world units at 1 unit from the light.
+    pub normal_bias: f32,
+    /// PCF kernel radius in texels.
+    pub pcf_radius: u32,
+}
+impl Default for LocalShadowSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_tile_size: 1024,
+            min_tile_size: 128,
+            depth_bias: 0.0002,
+            normal_bias: 0.02,
+            pcf_radius: 1,
+        }
+    }
+}
+
+
+/// A square region of the shadow atlas, in texels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtlasRect {
+    pub x: u32,
+    pub y: u32,
+    pub size: u32,
+}
+
+/// Quadtree allocator for power-of-two tiles in a square atlas.
+///
+/// Level 0 is the whole atlas, each level below splits its cells into four.
+pub struct ShadowAtlasAllocator {
+    size: u32,
+    /// Per level, one state per cell.
+    levels: Vec<Vec<CellState>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CellState {
+    Free,
+    Allocated,
+    /// Some descendant is allocated.
+    Split,
+}
+
+impl ShadowAtlasAllocator {
+    pub fn new(size: u32, min_tile_size: u32) -> Self {
+        let mut levels = Vec::new();
+        let mut cell_size = size;
+        let mut cells_per_side = 1usize;
+        while cell_size >= min_tile_size.max(1) {
+            levels.push(vec![CellState::Free; cells_per_side * cells_per_side]);
+            cell_size /= 2;
+            cells_per_side *= 2;
+        }
+        Self { size, levels }
+    }
+
+    pub fn clear(&mut self) {
+        for level in self.levels.iter_mut() {
+            for cell in level.iter_mut() { *cell = CellState::Free; }
+        }
+    }
+
+    fn level_for_size(&self, size: u32) -> Option<usize> {
+        if size == 0 || !size.is_power_of_two() || size > self.size { return None; }
+        let level = (self.size / size).trailing_zeros() as usize;
+        if level < self.levels.len() { Some(level) } else { None }
+    }
+
+    fn ancestors_allow(&self, level: usize, x: usize, y: usize) -> bool {
+        (0..level).all(|l| {
+            let shift = level - l;
+            let side = 1usize << l;
+            self.levels[l][(y >> shift) * side + (x >> shift)] != CellState::Allocated
+        })
+    }
+
+    fn mark(&mut self, level: usize, x: usize, y: usize) {
+        let side = 1usize << level;
+        self.levels[level][y * side + x] = CellState::Allocated;
+        for l in 0..level {
+            let shift = level - l;
+            let side = 1usize << l;
+            let cell = &mut self.levels[l][(y >> shift) * side + (x >> shift)];
+            if *cell == CellState::Free { *cell = CellState::Split; }
+        }
+    }
+
+    /// Allocates a free tile of the given size, if there's room.
+    pub fn allocate(&mut self, size: u32) -> Option<AtlasRect> {
+        let level = self.level_for_size(size)?;
+        let side = 1usize << level;
+        for y in 0..side {
+            for x in 0..side {
+                if self.levels[level][y * side + x] == CellState::Free && self.ancestors_allow(level, x, y) {
+                    self.mark(level, x, y);
+                    return Some(AtlasRect { x: x as u32 * size, y: y as u32 * size, size });
+                }
+            }
+        }
+        None
+    }
+
+    /// Allocates a specific tile, e.g. one kept from the previous frame. Returns false if it's
+    /// already taken.
+    pub fn reserve(&mut self, rect: AtlasRect) -> bool {
+        let level = match self.level_for_size(rect.size) { Some(l) => l, None => return false };
+        let (x, y) = ((rect.x / rect.size) as usize, (rect.y / rect.size) as usize);
+        let side = 1usize << level;
+        if self.levels[level][y * side + x] != CellState::Free || !self.ancestors_allow(level, x, y) {
+            return false;
+        }
+        self.mark(level, x, y);
+        true
+    }
+
+    /// Allocates `count` tiles of the given size, or none at all if they don't all fit.
+    pub fn allocate_all(&mut self, size: u32, count: usize) -> Option<Vec<AtlasRect>> {
+        let mut rects = Vec::with_capacity(count);
+        for _ in 0..count {
+            match self.allocate(size) {
+                Some(rect) => rects.push(rect),
+                None => {
+                    for rect in rects { self.release(rect); }
+                    return None;
+                },
+            }
+        }
+        Some(rects)
+    }
+
+    /// Reserves all of the given tiles, or none of them if any is taken.
+    pub fn reserve_all(&mut self, rects: &[AtlasRect]) -> bool {
+        for (i, rect) in rects.iter().enumerate() {
+            if !self.reserve(*rect) {
+                for reserved in rects[..i].iter() { self.release(*reserved); }
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Frees a tile returned by `allocate` or `reserve`.
+    pub fn release(&mut self, rect: AtlasRect) {
+        let level = match self.level_for_size(rect.size) { Some(l) => l, None => return };
+        let (mut x, mut y) = ((rect.x / rect.size) as usize, (rect.y / rect.size) as usize);
+        let side = 1usize << level;
+        if self.levels[level][y * side + x] != CellState::Allocated {
+            return;
+        }
+        self.levels[level][y * side + x] = CellState::Free;
+
+        // ancestors stay split only while another descendant is in use
+        for l in (0..level).rev() {
+            x >>= 1;
+            y >>= 1;
+            let child_side = 1usize << (l + 1);
+            let children_free = [(0, 0), (1, 0), (0, 1), (1, 1)].iter()
+                .all(|(dx, dy)| self.levels[l + 1][(y * 2 + dy) * child_side + x * 2 + dx] == CellState::Free);
+            if !children_free {
+                break;
+            }
+            let side = 1usize << l;
+            self.levels[l][y * side + x] = CellState::Free;
+        }
+    }
+}
+
+
+/// A rendered shadow map in the atlas.
+#[derive(Debug, Clone)]
+pub struct LocalShadowTile {
+    pub view_proj: Matrix4<f32>,
+    /// Offset and scale from the tile's [0, 1] uv to atlas uv.
+    pub uv_rect: [f32; 4],
+}
+
+/// Shadow tiles for the current frame. Point lights have six consecutive tiles in the order
+/// +X, -X, +Y, -Y, +Z, -Z.
+#[derive(Debug, Clone, Default)]
+pub struct LocalShadowData {
+    pub tiles: Vec<LocalShadowTile>,
+    /// First tile for each caster id that got space in the atlas this frame.
+    pub first_tile: HashMap<u32, u32>,
+}
+
+
+/// A shadow tile in the local shadow storage buffer. Must match `ShadowTile` in `local_lights.inc`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GpuShadowTile {
+    pub view_proj: [[f32; 4]; 4],
+    pub uv_rect: [f32; 4],
+}
+
+/// Must match `LocalShadowParams` in `local_lights.inc`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GpuLocalShadowParams {
+    pub depth_bias: f32,
+    pub normal_bias: f32,
+    pub pcf_radius: i32,
+    pub tile_count: u32,
+}
+
+/// Uploads this frame's shadow tiles and the local shadow settings, for passes that shade local
+/// lights.
+pub fn local_shadow_buffers(info: &RenderInfo) -> (Arc<CpuAccessibleBufferXalloc<[GpuShadowTile]>>, Arc<CpuAccessibleBufferXalloc<GpuLocalShadowParams>>) {
+    let local_data = info.shadow_maps.local_data.lock();
+    let settings = &info.settings.local_shadows;
+    let mut tiles: Vec<GpuShadowTile> = local_data.tiles.iter().map(|tile| GpuShadowTile {
+        view_proj: tile.view_proj.into(),
+        uv_rect: tile.uv_rect,
+    }).collect();
+    let tile_count = tiles.len() as u32;
+    if tiles.is_empty() {
+        // zero sized buffers aren't allowed
+        tiles.push(GpuShadowTile { view_proj: [[0.0; 4]; 4], uv_rect: [0.0; 4] });
+    }
+    (CpuAccessibleBufferXalloc::from_iter(info.device.clone(), BufferUsage::storage_buffer(), tiles.into_iter())
+        .expect("failed to create buffer"),
+     CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(), GpuLocalShadowParams {
+         depth_bias: settings.depth_bias,
+         normal_bias: settings.normal_bias,
+         pcf_radius: settings.pcf_radius as i32,
+         tile_count,
+     }).expect("failed to create buffer"))
+}
+
+
+/// Cube face directions and up vectors, matching the tile order in `LocalShadowData` and
+/// `cube_face` in `shadows.inc`.
+pub fn cube_faces() -> [(Vector3<f32>, Vector3<f32>); 6] {
+    [
+        (Vector3::unit_x(),  -Vector3::unit_y()),
+        (-Vector3::unit_x(), -Vector3::unit_y()),
+        (Vector3::unit_y(),   Vector3::unit_z()),
+        (-Vector3::unit_y(), -Vector3::unit_z()),
+        (Vector3::unit_z(),  -Vector3::unit_y()),
+        (-Vector3::unit_z(), -Vector3::unit_y()),
+    ]
+}
+
+/// View-projection matrices for each shadow map of a caster.
+pub fn caster_view_projs(caster: &LocalShadowCaster) -> Vec<Matrix4<f32>> {
+    let eye = Point3::from_vec(caster.position);
+    match caster.kind {
+        ShadowCasterKind::Point => {
+            let proj = VULKAN_CORRECT_CLIP * cgmath::perspective(Deg(90.0), 1.0, SHADOW_NEAR_PLANE, caster.range);
+            cube_faces().iter().map(|(dir, up)| {
+                proj * Matrix4::look_at(eye, eye + dir, *up)
+            }).collect()
+        },
+        ShadowCasterKind::Spot { outer_angle } => {
+            let fov = Rad((outer_angle.0 * 2.0 + 0.05).min(3.0));
+            let proj = VULKAN_CORRECT_CLIP * cgmath::perspective(fov, 1.0, SHADOW_NEAR_PLANE, caster.range);
+            let dir = caster.direction.normalize();
+            let up = if dir.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+            vec![proj * Matrix4::look_at(eye, eye + dir, up)]
+        },
+    }
+}
+
+/// Picks a tile size from the light's approximate screen coverage and priority.
+pub fn desired_tile_size(caster: &LocalShadowCaster, info: &RenderInfo) -> u32 {
+    let settings = &info.settings.local_shadows;
+    let camera_pos: [f32; 3] = info.camera_transform.position.into();
+    let distance = (caster.position - Vector3::from(camera_pos)).magnitude();
+    let coverage = if distance <= caster.range {
+        1.0
+    }
+    else {
+        let tan_half_fov = (Rad::from(info.fov).0 * 0.5).tan();
+        (caster.range / (distance * tan_half_fov)).min(1.0)
+    };
+    let size = (settings.max_tile_size as f32 * coverage * caster.priority.max(0.0)) as u32;
+    size.max(settings.min_tile_size).min(settings.max_tile_size).next_power_of_two().min(settings.max_tile_size)
+}
+
+
+struct CachedCaster {
+    caster: LocalShadowCaster,
+    rects: Vec<AtlasRect>,
+}
+
+
+/// Renders local light shadow maps into `info.shadow_maps.local`.
+pub struct LocalShadowStage {
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    /// Writes the far plane over a single tile, since only parts of the atlas are redrawn.
+    clear_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    allocator: ShadowAtlasAllocator,
+    cache: HashMap<u32, CachedCaster>,
+    cache_epoch: u64,
+}
+
+
+impl LocalShadowStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let renderpass = Arc::new(
+            ShadowRenderPass::load()
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let vs = ShadowShaders::vertex::Shader::load(device.clone()).expect("failed to create shader module");
+        let fs = ShadowShaders::fragment::Shader::load(device.clone()).expect("failed to create shader module");
+
+        let pipeline = Arc::new(GraphicsPipeline::start()
+            .cull_mode_front()
+            .vertex_input_single_buffer::<MeshVertex>()
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs.main_entry_point(), ())
+            .depth_stencil_simple_depth()
+            .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+            .build(device.clone())
+            .unwrap());
+
+        let clear_pipeline = Arc::new(GraphicsPipeline::start()
+            .vertex_input_single_buffer::<VertexPosition>()
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs.main_entry_point(), ())
+            .depth_stencil(DepthStencil {
+                depth_compare: Compare::Always,
+                ..DepthStencil::simple_depth_test()
+            })
+            .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+            .build(device.clone())
+            .unwrap());
+
+        LocalShadowStage {
+            pipeline,
+            clear_pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            allocator: ShadowAtlasAllocator::new(LOCAL_SHADOW_ATLAS_SIZE, LocalShadowSettings::default().min_tile_size),
+            cache: HashMap::new(),
+            cache_epoch: 0,
+        }
+    }
+
+    /// Assigns atlas tiles for this frame. Returns the tiles in order, the first tile index for
+    /// each caster, and which casters need to be re-rendered.
+    fn allocate(&mut self, info: &RenderInfo) -> (Vec<(AtlasRect, Matrix4<f32>)>, HashMap<u32, u32>, Vec<bool>) {
+        let settings = &info.settings.local_shadows;
+        let casters: Vec<LocalShadowCaster> = info.lights.iter()
+            .filter_map(|(id, light)| light.shadow_caster(*id))
+            .collect();
+
+        if self.cache_epoch != info.local_shadow_epoch {
+            self.cache.clear();
+            self.cache_epoch = info.local_shadow_epoch;
+        }
+        self.allocator = ShadowAtlasAllocator::new(LOCAL_SHADOW_ATLAS_SIZE, settings.min_tile_size);
+
+        let mut ranked: Vec<(&LocalShadowCaster, u32)> = casters.iter()
+            .map(|c| (c, desired_tile_size(c, info)))
+            .collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1));
+
+        // keep static casters that haven't changed in the same place
+        let mut placed: HashMap<u32, (Vec<AtlasRect>, bool)> = HashMap::new();
+        let allocator = &mut self.allocator;
+        for (caster, size) in ranked.iter() {
+            if !caster.is_static { continue; }
+            if let Some(cached) = self.cache.get(&caster.id) {
+                if &cached.caster == *caster && cached.rects.iter().all(|r| r.size == *size)
+                    && allocator.reserve_all(&cached.rects) {
+                    placed.insert(caster.id, (cached.rects.clone(), false));
+                }
+            }
+        }
+
+        // everything else, shrinking tiles until they fit
+        for (caster, size) in ranked.iter() {
+            if placed.contains_key(&caster.id) { continue; }
+            let count = match caster.kind { ShadowCasterKind::Point => 6, ShadowCasterKind::Spot { .. } => 1 };
+            let mut size = *size;
+            while size >= settings.min_tile_size {
+                // all or nothing, so a point light that doesn't fit doesn't take space from others
+                if let Some(rects) = self.allocator.allocate_all(size, count) {
+                    placed.insert(caster.id, (rects, true));
+                    break;
+                }
+                size /= 2;
+            }
+        }
+
+        self.cache.retain(|id, _| placed.get(id).map(|p| !p.1).unwrap_or(false));
+
+        let mut tiles = Vec::new();
+        let mut first_tile = HashMap::new();
+        let mut dirty = Vec::new();
+        for (caster, _) in ranked.iter() {
+            if let Some((rects, needs_render)) = placed.get(&caster.id) {
+                first_tile.insert(caster.id, tiles.len() as u32);
+                for (rect, view_proj) in rects.iter().zip(caster_view_projs(caster).into_iter()) {
+                    tiles.push((*rect, view_proj));
+                    dirty.push(*needs_render);
+                }
+                if caster.is_static && *needs_render {
+                    self.cache.insert(caster.id, CachedCaster { caster: (*caster).clone(), rects: rects.clone() });
+                }
+            }
+        }
+        (tiles, first_tile, dirty)
+    }
+}
+
+impl RenderStageDefinition for LocalShadowStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        if !info.settings.local_shadows.enabled {
+            *info.shadow_maps.local_data.lock() = LocalShadowData::default();
+            self.cache.clear();
+            return None;
+        }
+
+        let (tiles, first_tile, dirty) = self.allocate(info);
+
+        let atlas_size = LOCAL_SHADOW_ATLAS_SIZE as f32;
+        *info.shadow_maps.local_data.lock() = LocalShadowData {
+            tiles: tiles.iter().map(|(rect, view_proj)| LocalShadowTile {
+                view_proj: *view_proj,
+                uv_rect: [rect.x as f32 / atlas_size, rect.y as f32 / atlas_size,
+                          rect.size as f32 / atlas_size, rect.size as f32 / atlas_size],
+            }).collect(),
+            first_tile,
+        };
+
+        if !dirty.iter().any(|d| *d) {
+            return None;
+        }
+
+        let mut cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap();
+
+        let lock = info.mesh_queue.lock();
+        for ((rect, view_proj), _) in tiles.iter().zip(dirty.iter()).filter(|(_, d)| **d) {
+            let dynamic_state = DynamicState {
+                line_width: None,
+                viewports: Some(vec![Viewport {
+                    origin: [rect.x as f32, rect.y as f32],
+                    dimensions: [rect.size as f32, rect.size as f32],
+                    depth_range: 0.0..1.0,
+                }]),
+                scissors: None,
+                compare_mask: None,
+                write_mask: None,
+                reference: None
+            };
+
+            let identity: Matrix4<f32> = Matrix4::identity();
+            cb = cb.draw(self.clear_pipeline.clone(), &dynamic_state,
+                vec![self.fullscreen_vertex_buffer.clone()],
+                (), ShadowShaders::vertex::ty::Constants {
+                    light_mvp: identity.into(),
+                }).unwrap();
+
+            for mesh in lock.iter() {
+                let world: Matrix4<f32> = mesh.transform.clone().into();
+                let light_mvp = *view_proj * world;
+                for vertgroup in mesh.vertex_groups.iter() {
+                    cb = cb.draw_indexed(self.pipeline.clone(), &dynamic_state,
+                        vec![vertgroup.vertex_buffer.clone()],
+                        vertgroup.index_buffer.clone(),
+                        (), ShadowShaders::vertex::ty::Constants {
+                            light_mvp: light_mvp.into(),
+                        }).unwrap();
+                }
+            }
+        }
+        cb = cb.end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.get_renderpass().clone())
+                .add(info.shadow_maps.local.clone()).unwrap()
+                .build().unwrap()));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{AtlasRect, ShadowAtlasAllocator};
+
+    fn rect(x: u32, y: u32, size: u32) -> AtlasRect {
+        AtlasRect { x, y, size }
+    }
+
+    #[test]
+    fn levels() {
+        let allocator = ShadowAtlasAllocator::new(1024, 128);
+        assert_eq!(allocator.levels.len(), 4);
+        assert_eq!(allocator.level_for_size(1024), Some(0));
+        assert_eq!(allocator.level_for_size(512), Some(1));
+        assert_eq!(allocator.level_for_size(128), Some(3));
+        // too small, too big, or not a power of two
+        assert_eq!(allocator.level_for_size(64), None);
+        assert_eq!(allocator.level_for_size(2048), None);
+        assert_eq!(allocator.level_for_size(384), None);
+        assert_eq!(allocator.level_for_size(0), None);
+    }
+
+    #[test]
+    fn allocate_packs_mixed_sizes() {
+        let mut allocator = ShadowAtlasAllocator::new(1024, 128);
+        assert_eq!(allocator.allocate(512), Some(rect(0, 0, 512)));
+        assert_eq!(allocator.allocate(256), Some(rect(512, 0, 256)));
+        assert_eq!(allocator.allocate(512), Some(rect(0, 512, 512)));
+        assert_eq!(allocator.allocate(512), Some(rect(512, 512, 512)));
+        // only the three 256 tiles next to the first one are left
+        assert_eq!(allocator.allocate(512), None);
+        assert_eq!(allocator.allocate(256), Some(rect(768, 0, 256)));
+        assert_eq!(allocator.allocate(256), Some(rect(512, 256, 256)));
+        assert_eq!(allocator.allocate(256), Some(rect(768, 256, 256)));
+        assert_eq!(allocator.allocate(128), None);
+        assert_eq!(allocator.allocate(300), None);
+    }
+
+    #[test]
+    fn reserve() {
+        let mut allocator = ShadowAtlasAllocator::new(1024, 128);
+        assert!(allocator.reserve(rect(512, 512, 256)));
+        assert!(!allocator.reserve(rect(512, 512, 256)));
+        // overlaps the reserved tile, as an ancestor or a descendant
+        assert!(!allocator.reserve(rect(512, 512, 512)));
+        assert!(!allocator.reserve(rect(640, 640, 128)));
+        assert!(!allocator.reserve(rect(0, 0, 1024)));
+        assert!(allocator.reserve(rect(768, 512, 256)));
+        assert_eq!(allocator.allocate(512), Some(rect(0, 0, 512)));
+    }
+
+    #[test]
+    fn release_merges_back() {
+        let mut allocator = ShadowAtlasAllocator::new(1024, 128);
+        let small = allocator.allocate(128).unwrap();
+        assert_eq!(allocator.allocate(1024), None);
+        allocator.release(small);
+        assert_eq!(allocator.allocate(1024), Some(rect(0, 0, 1024)));
+        allocator.release(rect(0, 0, 1024));
+
+        // a split parent stays split while a sibling is still allocated
+        let a = allocator.allocate(256).unwrap();
+        let b = allocator.allocate(256).unwrap();
+        allocator.release(a);
+        assert_eq!(allocator.allocate(1024), None);
+        assert_eq!(allocator.allocate(512), Some(rect(512, 0, 512)));
+        allocator.release(b);
+        assert_eq!(allocator.allocate(512), Some(rect(0, 0, 512)));
+
+        // releasing something that isn't allocated does nothing
+        allocator.release(rect(0, 512, 512));
+        allocator.release(rect(0, 0, 256));
+        assert_eq!(allocator.allocate(512), Some(rect(0, 512, 512)));
+    }
+
+    #[test]
+    fn allocate_all_rolls_back() {
+        let mut allocator = ShadowAtlasAllocator::new(1024, 128);
+        assert!(allocator.reserve(rect(0, 0, 512)));
+        // only 12 tiles of 256 left, so six fit once but not twice
+        assert_eq!(allocator.allocate_all(256, 6).map(|r| r.len()), Some(6));
+        assert_eq!(allocator.allocate_all(256, 7), None);
+        // the failed set didn't keep the six tiles it got
+        assert_eq!(allocator.allocate_all(256, 6).map(|r| r.len()), Some(6));
+        assert_eq!(allocator.allocate(128), None);
+    }
+
+    #[test]
+    fn reserve_all_rolls_back() {
+        let mut allocator = ShadowAtlasAllocator::new(1024, 128);
+        assert!(allocator.reserve(rect(512, 512, 512)));
+        let cached = [rect(0, 0, 512), rect(512, 0, 512), rect(512, 512, 512)];
+        assert!(!allocator.reserve_all(&cached));
+        // neither of the tiles reserved before the conflict is still taken
+        assert!(allocator.reserve_all(&cached[..2]));
+        assert_eq!(allocator.allocate(512), Some(rect(0, 512, 512)));
+    }
+}
@@ -1,23 +1,28 @@
 use std::sync::Arc;
 use cgmath::Matrix4;
-use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
-use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
-use vulkano::device::{Device, Queue};
-use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, RenderPassAbstract};
 use vulkano::buffer::BufferUsage;
 use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::DescriptorSet;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
 use vulkano::pipeline::viewport::Viewport;
 use vulkano::image::SwapchainImage;
 use winit::Window;
 
 use crate::renderpass::GenericMeshShadingRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
 use crate::cpu_pool::XallocCpuBufferPool;
-use crate::geometry::MeshVertex;
+use crate::material::{MaterialDefinition, GenericMeshMaterial};
 use crate::shader::mesh_generic as MeshShaders;
 use crate::stage::RenderStageDefinition;
 use crate::renderer::RenderInfo;
 
+/// Fills the G-buffer, depth buffer and velocity buffer with the queued meshes.
 pub struct GenericMeshShadingStage {
+    /// Pipeline of the default material. Every material drawn in this stage uses the same
+    /// descriptor set layouts for the per-instance and per-frame data.
     pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
     pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
     pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
@@ -27,36 +32,21 @@ pub struct GenericMeshShadingStage {
 
 
 impl GenericMeshShadingStage {
-    pub fn new(device: Arc<Device>) -> Self {
-        let renderpass = Arc::new(
+    pub fn new(info: &RenderInfo) -> Self {
+        let renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
             GenericMeshShadingRenderPass {}
-                .build_render_pass(device.clone())
+                .build_render_pass(info.device.clone())
                 .unwrap()
         );
 
-        let pipeline = {
-            let vs = crate::shader::skybox::vertex::Shader::load(device.clone()).expect("failed to create shader module");
-            let fs = crate::shader::skybox::fragment::Shader::load(device.clone()).expect("failed to create shader module");
-
-            Arc::new(GraphicsPipeline::start()
-                .cull_mode_back()
-                .vertex_input_single_buffer::<MeshVertex>()
-                .vertex_shader(vs.main_entry_point(), ())
-                .triangle_list()
-                .viewports_dynamic_scissors_irrelevant(1)
-                .fragment_shader(fs.main_entry_point(), ())
-                //.depth_stencil_simple_depth()
-                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
-                .build(device.clone())
-                .unwrap())
-        };
+        let pipeline = GenericMeshMaterial::new(info, renderpass.clone(), 0).pipeline().clone();
 
         GenericMeshShadingStage {
             pipeline,
             framebuffers: None,
             framebuffer: None,
             renderpass,
-            uniform_buffer_pool: XallocCpuBufferPool::<MeshShaders::vertex::ty::InstanceData>::new(device.clone(), BufferUsage::all()),
+            uniform_buffer_pool: XallocCpuBufferPool::<MeshShaders::vertex::ty::InstanceData>::new(info.device.clone(), BufferUsage::all()),
         }
     }
 }
@@ -70,35 +60,70 @@ impl RenderStageDefinition for GenericMeshShadingStage {
     fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
 
     fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        // motion vectors compare unjittered positions, so the jitter doesn't show up as motion
+        let frame_buffer = CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(),
+            MeshShaders::vertex::ty::FrameData {
+                view_proj: (info.unjittered_proj_mat * info.view_mat).into(),
+                prev_view_proj: info.prev_view_proj_mat.into(),
+            }).expect("failed to create buffer");
+        let frame_set: Arc<dyn DescriptorSet + Send + Sync> = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 2)
+            .add_buffer(frame_buffer).unwrap()
+            .build().unwrap());
+
+        let dynamic_state = DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+            compare_mask: None,
+            write_mask: None,
+            reference: None
+        };
+
         let mut cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
             .unwrap()
             .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false,
-                               vec![CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(), 1f32.into()]).unwrap();
+                               vec![CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(), CLEAR_BLACK.into(),
+                                    info.depth_mode.clear_value().into(), [0.0, 0.0].into()]).unwrap();
 
         let lock = info.mesh_queue.lock();
         for mesh in lock.iter() {
+            let world: Matrix4<f32> = mesh.transform.clone().into();
+            // meshes without a previous transform didn't move
+            let prev_world: Matrix4<f32> = match &mesh.previous_transform {
+                Some(previous) => previous.clone().into(),
+                None => world,
+            };
+            let instance_data = MeshShaders::vertex::ty::InstanceData {
+                world: world.into(),
+                prev_world: prev_world.into(),
+            };
+            let instance_set: Arc<dyn DescriptorSet + Send + Sync> = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 1)
+                .add_buffer(self.uniform_buffer_pool.next(instance_data).unwrap()).unwrap()
+                .build().unwrap());
+
             for vertgroup in mesh.vertex_groups.iter() {
-                cb = cb.draw_indexed(vertgroup.material.pipeline().clone(), &DynamicState {
-                    line_width: None,
-                    viewports: Some(vec![Viewport {
-                        origin: [0.0, 0.0],
-                        dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
-                        depth_range: 0.0..1.0,
-                    }]),
-                    scissors: None,
-                    compare_mask: None,
-                    write_mask: None,
-                    reference: None
-                },
-                vec![vertgroup.vertex_buffer.clone()],
-                vertgroup.index_buffer.clone(),
-                vertgroup.material.descriptor_sets(),
-                // TODO: handle actual push constants
-                crate::shader::skybox::vertex::ty::Constants {
-                    matrix: (info.proj_mat.clone() * Matrix4::from(info.camera_transform.rotation)).into(),
-                    sun_rotation: 0.0,
-                    sun_transit: 0.4,
-                }).unwrap();
+                let mut descriptor_sets = vertgroup.material.descriptor_sets();
+                descriptor_sets.push(instance_set.clone());
+                descriptor_sets.push(frame_set.clone());
+
+                cb = cb.draw_indexed(vertgroup.material.pipeline().clone(), &dynamic_state,
+                    vec![vertgroup.vertex_buffer.clone()],
+                    vertgroup.index_buffer.clone(),
+                    descriptor_sets,
+                    MeshShaders::vertex::ty::Constants {
+                        view: info.view_mat.into(),
+                        proj: info.proj_mat.into(),
+                        near_plane: info.near_plane,
+                        far_plane: info.far_plane,
+                        depth_mode: info.depth_mode.shader_id(),
+                        subsurface_profile: vertgroup.material.subsurface_profile()
+                            .map(|profile| profile.gbuffer_value())
+                            .unwrap_or(0),
+                    }).unwrap();
             }
         }
         cb = cb.end_render_pass().unwrap();
@@ -108,31 +133,18 @@ impl RenderStageDefinition for GenericMeshShadingStage {
         ])
     }
 
-    fn recreate_framebuffers_if_none(&mut self, images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
         // TODO: framebuffer sets for standalone mode
-//        if self.framebuffer.is_none() {
-//            self.framebuffer = Some(Arc::new(Framebuffer::start(self.get_renderpass().clone())
-//                .add(info.attachments.position.clone()).unwrap()
-//                .add(info.attachments.normal.clone()).unwrap()
-//                .add(info.attachments.albedo.clone()).unwrap()
-//                .add(info.attachments.roughness.clone()).unwrap()
-//                .add(info.attachments.metallic.clone()).unwrap()
-//                .add(info.attachments.main_depth.clone()).unwrap()
-//                .build().unwrap()))
-//        }
-//        if self.get_framebuffers_mut().is_none() {
-//            let new_framebuffers = Some(images.iter().map(|_| {
-//                let arc: Arc<dyn FramebufferAbstract + Send + Sync> = Arc::new(Framebuffer::start(self.get_renderpass().clone())
-//                    .add(registry.get(name!(Position)).unwrap().as_floatbuf().unwrap().clone()).unwrap()
-//                    .add(registry.get(name!(Albedo)).unwrap().as_floatbuf().unwrap().clone()).unwrap()
-//                    .add(registry.get(name!(Normal)).unwrap().as_floatbuf().unwrap().clone()).unwrap()
-//                    .add(registry.get(name!(Roughness)).unwrap().as_floatbuf().unwrap().clone()).unwrap()
-//                    .add(registry.get(name!(Metallic)).unwrap().as_floatbuf().unwrap().clone()).unwrap()
-//                    .add(registry.get(name!(MainDepth)).unwrap().as_depth().unwrap().clone()).unwrap()
-//                    .build().unwrap());
-//                arc
-//            }).collect::<Vec<_>>());
-//            ::std::mem::replace(self.get_framebuffers_mut(), new_framebuffers);
-//        }
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.get_renderpass().clone())
+                .add(info.attachments.position.clone()).unwrap()
+                .add(info.attachments.normal.clone()).unwrap()
+                .add(info.attachments.albedo.clone()).unwrap()
+                .add(info.attachments.roughness.clone()).unwrap()
+                .add(info.attachments.metallic.clone()).unwrap()
+                .add(info.attachments.main_depth.clone()).unwrap()
+                .add(info.attachments.velocity.clone()).unwrap()
+                .build().unwrap()))
+        }
     }
 }
@@ -8,7 +8,23 @@ use vulkano::command_buffer::{AutoCommandBuffer};
 use vulkano::device::Queue;
 
 pub mod mesh_shading;
+pub mod shadow;
+pub mod local_shadow;
+pub mod ao;
+pub mod deferred_lighting;
+pub mod light_volumes;
+pub mod reflection_probes;
+pub mod subsurface;
+pub mod ssr;
 pub mod resolve_scene_color;
+pub mod skybox;
+pub mod volumetric_fog;
+pub mod taa;
+pub mod dof;
+pub mod motion_blur;
+pub mod bloom;
+pub mod tonemap;
+pub mod fxaa;
 
 
 //pub struct RenderStageDefinition {
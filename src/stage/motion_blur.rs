@@ -0,0 +1,309 @@
+//! Motion blur.
+//!
+//! Per-pixel velocities come from the G-buffer's motion vectors, which cover both camera and
+//! object motion, or from reprojecting depth with last frame's camera. They're scaled by the
+//! shutter angle, reduced to the longest velocity per tile and then per 3x3 neighborhood of tiles,
+//! and a reconstruction filter samples `scene_color` along the neighborhood's velocity.
+
+use std::sync::Arc;
+use cgmath::{Matrix4, SquareMatrix};
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::{SwapchainImage, AttachmentImage, ImageUsage};
+use vulkano::format::{ClearValue, Format, R16G16Sfloat, R16G16B16A16Sfloat};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::FullscreenRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::shader::motion_blur as MotionBlurShaders;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::RenderInfo;
+
+
+/// Size of the velocity tiles in pixels, which is also the longest blur in either direction.
+/// Must match `MOTION_BLUR_TILE_SIZE` in `motion_blur.inc`.
+pub const MOTION_BLUR_TILE_SIZE: u32 = 20;
+
+/// Settings for motion blur.
+#[derive(Debug, Clone)]
+pub struct MotionBlurSettings {
+    pub enabled: bool,
+    /// How long the shutter is open, in degrees of a frame. 360 blurs over the whole frame, 180
+    /// is the film standard.
+    pub shutter_angle: f32,
+    /// Samples along the velocity in the reconstruction filter.
+    pub sample_count: u32,
+    /// Use the G-buffer's motion vectors, which include moving objects. If false, velocity is
+    /// reconstructed from depth and camera motion only.
+    pub per_object: bool,
+}
+impl Default for MotionBlurSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            shutter_angle: 180.0,
+            sample_count: 15,
+            per_object: true,
+        }
+    }
+}
+
+
+/// Images owned by the stage, resized with the screen.
+struct MotionBlurTargets {
+    tile_dimensions: [u32; 2],
+    velocity: Arc<AttachmentImage<R16G16Sfloat>>,
+    velocity_framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+    tile_max: Arc<AttachmentImage<R16G16Sfloat>>,
+    tile_max_framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+    neighbor_max: Arc<AttachmentImage<R16G16Sfloat>>,
+    neighbor_max_framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+    output: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
+}
+
+
+pub struct MotionBlurStage {
+    velocity_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    tile_max_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    neighbor_max_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    /// Framebuffer for the reconstruction, which is then copied into `scene_color`.
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    velocity_renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    sampler: Arc<Sampler>,
+    targets: Option<MotionBlurTargets>,
+}
+
+
+impl MotionBlurStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let velocity_renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::overwrite(Format::R16G16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+        let renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::overwrite(Format::R16G16B16A16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let vs = crate::shader::fullscreen::Shader::load(device.clone()).expect("failed to create shader module");
+
+        let velocity_pipeline = {
+            let fs = MotionBlurShaders::velocity::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(velocity_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let tile_max_pipeline = {
+            let fs = MotionBlurShaders::tile_max::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(velocity_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let neighbor_max_pipeline = {
+            let fs = MotionBlurShaders::neighbor_max::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(velocity_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let pipeline = {
+            let fs = MotionBlurShaders::reconstruct::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        MotionBlurStage {
+            velocity_pipeline,
+            tile_max_pipeline,
+            neighbor_max_pipeline,
+            pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            velocity_renderpass,
+            renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            sampler: Sampler::new(device.clone(), Filter::Nearest, Filter::Nearest, MipmapMode::Nearest,
+                                  SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                  0.0, 1.0, 0.0, 0.0).unwrap(),
+            targets: None,
+        }
+    }
+
+    fn dynamic_state(dimensions: [u32; 2]) -> DynamicState {
+        DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+            compare_mask: None,
+            write_mask: None,
+            reference: None
+        }
+    }
+}
+
+impl RenderStageDefinition for MotionBlurStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        let settings = &info.settings.motion_blur;
+        if !settings.enabled || settings.shutter_angle <= 0.0 {
+            return None;
+        }
+        let targets = self.targets.as_ref()?;
+
+        // from this frame's clip space back to last frame's, for camera-only velocity
+        let view_proj = info.unjittered_proj_mat * info.view_mat;
+        let reprojection = info.prev_view_proj_mat * view_proj.invert().unwrap_or(Matrix4::identity());
+
+        let velocity_set = Arc::new(PersistentDescriptorSet::start(self.velocity_pipeline.clone(), 0)
+            .add_sampled_image(info.attachments.velocity.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.main_depth.clone(), self.sampler.clone()).unwrap()
+            .build().unwrap());
+        let tile_max_set = Arc::new(PersistentDescriptorSet::start(self.tile_max_pipeline.clone(), 0)
+            .add_sampled_image(targets.velocity.clone(), self.sampler.clone()).unwrap()
+            .build().unwrap());
+        let neighbor_max_set = Arc::new(PersistentDescriptorSet::start(self.neighbor_max_pipeline.clone(), 0)
+            .add_sampled_image(targets.tile_max.clone(), self.sampler.clone()).unwrap()
+            .build().unwrap());
+        let reconstruct_set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_sampled_image(info.attachments.scene_color.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(targets.velocity.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(targets.neighbor_max.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.main_depth.clone(), self.sampler.clone()).unwrap()
+            .build().unwrap());
+
+        let screen_dimensions = [info.dimensions[0] as f32, info.dimensions[1] as f32];
+        let dimensions = [info.dimensions[0], info.dimensions[1], 1];
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(targets.velocity_framebuffer.clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.velocity_pipeline.clone(), &Self::dynamic_state(info.dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                velocity_set, MotionBlurShaders::velocity::ty::Constants {
+                    reprojection: reprojection.into(),
+                    screen_dimensions,
+                    shutter_fraction: settings.shutter_angle.min(360.0) / 360.0,
+                    per_object: if settings.per_object { 1 } else { 0 },
+                }).unwrap()
+            .end_render_pass().unwrap()
+            .begin_render_pass(targets.tile_max_framebuffer.clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.tile_max_pipeline.clone(), &Self::dynamic_state(targets.tile_dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                tile_max_set, ()).unwrap()
+            .end_render_pass().unwrap()
+            .begin_render_pass(targets.neighbor_max_framebuffer.clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.neighbor_max_pipeline.clone(), &Self::dynamic_state(targets.tile_dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                neighbor_max_set, ()).unwrap()
+            .end_render_pass().unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.pipeline.clone(), &Self::dynamic_state(info.dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                reconstruct_set, MotionBlurShaders::reconstruct::ty::Constants {
+                    screen_dimensions,
+                    near_plane: info.near_plane,
+                    far_plane: info.far_plane,
+                    sample_count: settings.sample_count,
+                    depth_mode: info.depth_mode.shader_id(),
+                }).unwrap()
+            .end_render_pass().unwrap()
+            .copy_image(targets.output.clone(), [0, 0, 0], 0, 0,
+                        info.attachments.scene_color.clone(), [0, 0, 0], 0, 0, dimensions, 1).unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            let usage = ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                ..ImageUsage::none()
+            };
+            let output_usage = ImageUsage {
+                color_attachment: true,
+                transfer_source: true,
+                ..ImageUsage::none()
+            };
+            let tile_dimensions = [(info.dimensions[0] + MOTION_BLUR_TILE_SIZE - 1) / MOTION_BLUR_TILE_SIZE,
+                                   (info.dimensions[1] + MOTION_BLUR_TILE_SIZE - 1) / MOTION_BLUR_TILE_SIZE];
+
+            let velocity = AttachmentImage::with_usage(info.device.clone(), info.dimensions, R16G16Sfloat, usage).unwrap();
+            let tile_max = AttachmentImage::with_usage(info.device.clone(), tile_dimensions, R16G16Sfloat, usage).unwrap();
+            let neighbor_max = AttachmentImage::with_usage(info.device.clone(), tile_dimensions, R16G16Sfloat, usage).unwrap();
+            let output = AttachmentImage::with_usage(info.device.clone(), info.dimensions, R16G16B16A16Sfloat, output_usage).unwrap();
+
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.renderpass.clone())
+                .add(output.clone()).unwrap()
+                .build().unwrap()));
+            self.targets = Some(MotionBlurTargets {
+                tile_dimensions,
+                velocity_framebuffer: Arc::new(Framebuffer::start(self.velocity_renderpass.clone())
+                    .add(velocity.clone()).unwrap()
+                    .build().unwrap()),
+                tile_max_framebuffer: Arc::new(Framebuffer::start(self.velocity_renderpass.clone())
+                    .add(tile_max.clone()).unwrap()
+                    .build().unwrap()),
+                neighbor_max_framebuffer: Arc::new(Framebuffer::start(self.velocity_renderpass.clone())
+                    .add(neighbor_max.clone()).unwrap()
+                    .build().unwrap()),
+                velocity,
+                tile_max,
+                neighbor_max,
+                output,
+            });
+        }
+    }
+}
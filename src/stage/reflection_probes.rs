@@ -0,0 +1,371 @@
+//! Reflection probe capture and prefiltering.
+//!
+//! A capture forward shades the queued meshes into six cube faces laid out side by side, lit by
+//! the sun and the scene lights. Materials aren't available outside their own pipelines, so
+//! captured surfaces use a neutral albedo. Anything that isn't geometry is left transparent, and
+//! the lighting pass fills it in from the environment map.
+//!
+//! The capture is then prefiltered into the probe's tile of `RenderInfo::reflection_probes`:
+//! each mip is an octahedral map, importance sampled with a GGX lobe of increasing roughness.
+//!
+//! Probes that were added, changed or invalidated are captured first, then `EveryFrame` probes
+//! take turns, up to `ReflectionProbeSettings::captures_per_frame` a frame.
+
+use std::sync::Arc;
+use cgmath::{Matrix4, Vector3, Point3, EuclideanSpace, Deg, Zero};
+use hashbrown::HashMap;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::buffer::BufferUsage;
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::image::{SwapchainImage, AttachmentImage, ImageUsage};
+use vulkano::format::{ClearValue, Format, D32Sfloat, R16G16B16A16Sfloat};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::{ProbeCaptureRenderPass, FullscreenRenderPass};
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::{MeshVertex, VertexPosition};
+use crate::light::{light_buffer, ordered_lights};
+use crate::reflection_probe::{ReflectionProbeId, ProbeUpdate, ReflectionProbes, PROBE_MIP_COUNT};
+use crate::shader::probe_capture as ProbeCaptureShaders;
+use crate::shader::probe_filter as ProbeFilterShaders;
+use crate::stage::RenderStageDefinition;
+use crate::stage::shadow::SunCascades;
+use crate::stage::local_shadow::{cube_faces, local_shadow_buffers};
+use crate::renderer::{RenderInfo, VULKAN_CORRECT_CLIP};
+
+
+/// Size of each cube face in a capture.
+pub const CAPTURE_FACE_SIZE: u32 = 256;
+const CAPTURE_NEAR_PLANE: f32 = 0.05;
+
+lazy_static! {
+    static ref CAPTURE_USAGE: ImageUsage = ImageUsage {
+        color_attachment: true,
+        sampled: true,
+        ..ImageUsage::none()
+    };
+    static ref CAPTURE_DEPTH_USAGE: ImageUsage = ImageUsage {
+        depth_stencil_attachment: true,
+        ..ImageUsage::none()
+    };
+}
+
+
+/// Settings for reflection probes.
+#[derive(Debug, Clone)]
+pub struct ReflectionProbeSettings {
+    pub enabled: bool,
+    /// Most probes captured in one frame.
+    pub captures_per_frame: u32,
+    /// Far plane for captures. Geometry further away shows the environment instead.
+    pub capture_distance: f32,
+    /// GGX samples per texel when prefiltering the rough mips.
+    pub filter_samples: u32,
+}
+impl Default for ReflectionProbeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            captures_per_frame: 1,
+            capture_distance: 500.0,
+            filter_samples: 64,
+        }
+    }
+}
+
+
+/// `proj * view` for each cube face around `position`, in the order of `cube_faces`.
+fn face_view_projs(position: Vector3<f32>, far: f32) -> [[[f32; 4]; 4]; 6] {
+    let proj = VULKAN_CORRECT_CLIP * cgmath::perspective(Deg(90.0), 1.0, CAPTURE_NEAR_PLANE, far);
+    let eye = Point3::from_vec(position);
+    let mut result = [[[0.0; 4]; 4]; 6];
+    for (face, (dir, up)) in cube_faces().iter().enumerate() {
+        result[face] = (proj * Matrix4::look_at(eye, eye + dir, *up)).into();
+    }
+    result
+}
+
+fn viewport(origin: [u32; 2], dimensions: [u32; 2]) -> DynamicState {
+    DynamicState {
+        line_width: None,
+        viewports: Some(vec![Viewport {
+            origin: [origin[0] as f32, origin[1] as f32],
+            dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+            depth_range: 0.0..1.0,
+        }]),
+        scissors: None,
+        compare_mask: None,
+        write_mask: None,
+        reference: None
+    }
+}
+
+
+pub struct ReflectionProbeStage {
+    capture_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    filter_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    capture_framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    /// Framebuffer for the probe atlas.
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    capture_renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    filter_renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    /// Six cube faces side by side.
+    capture_color: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
+    capture_depth: Arc<AttachmentImage<D32Sfloat>>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    linear_sampler: Arc<Sampler>,
+    lut_sampler: Arc<Sampler>,
+    shadow_sampler: Arc<Sampler>,
+    /// Version and epoch of each probe's current contents in the atlas.
+    captured: HashMap<ReflectionProbeId, (u64, u64)>,
+    /// Next `EveryFrame` probe to recapture.
+    next_realtime: usize,
+}
+
+
+impl ReflectionProbeStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let capture_renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            ProbeCaptureRenderPass {}
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+        // only the mips being filtered are drawn to, the rest of the atlas has to be kept
+        let filter_renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::blend(Format::R16G16B16A16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let capture_pipeline = {
+            let vs = ProbeCaptureShaders::vertex::Shader::load(device.clone()).expect("failed to create shader module");
+            let fs = ProbeCaptureShaders::fragment::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .cull_mode_back()
+                .vertex_input_single_buffer::<MeshVertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil_simple_depth()
+                .render_pass(Subpass::from(capture_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let filter_pipeline = {
+            let vs = crate::shader::fullscreen::Shader::load(device.clone()).expect("failed to create shader module");
+            let fs = ProbeFilterShaders::fragment::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(filter_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let capture_dimensions = [CAPTURE_FACE_SIZE * 6, CAPTURE_FACE_SIZE];
+
+        ReflectionProbeStage {
+            capture_pipeline,
+            filter_pipeline,
+            framebuffers: None,
+            capture_framebuffer: None,
+            framebuffer: None,
+            capture_renderpass,
+            filter_renderpass,
+            capture_color: AttachmentImage::with_usage(device.clone(), capture_dimensions, R16G16B16A16Sfloat, *CAPTURE_USAGE).unwrap(),
+            capture_depth: AttachmentImage::with_usage(device.clone(), capture_dimensions, D32Sfloat, *CAPTURE_DEPTH_USAGE).unwrap(),
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            linear_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                         SamplerAddressMode::ClampToEdge, SamplerAddressMode::Repeat, SamplerAddressMode::ClampToEdge,
+                                         0.0, 1.0, 0.0, 0.0).unwrap(),
+            lut_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                      SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                      0.0, 1.0, 0.0, 0.0).unwrap(),
+            shadow_sampler: Sampler::compare(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                             SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                             0.0, 1.0, 0.0, 0.0, Compare::LessOrEqual).unwrap(),
+            captured: HashMap::new(),
+            next_realtime: 0,
+        }
+    }
+
+    /// Picks the probes to capture this frame, as (id, atlas tile, version).
+    fn schedule(&mut self, info: &RenderInfo) -> Vec<(ReflectionProbeId, u32, u64)> {
+        let epoch = info.reflection_probes.epoch;
+        let entries = info.reflection_probes.entries();
+        self.captured.retain(|id, _| entries.iter().any(|(other, ..)| other == id));
+
+        let budget = info.settings.reflection_probes.captures_per_frame as usize;
+        let mut scheduled: Vec<_> = entries.iter()
+            .filter(|(id, _, _, version)| self.captured.get(id) != Some(&(*version, epoch)))
+            .map(|(id, _, tile, version)| (*id, *tile, *version))
+            .take(budget)
+            .collect();
+
+        let realtime: Vec<_> = entries.iter()
+            .filter(|(id, probe, _, version)| probe.update == ProbeUpdate::EveryFrame && self.captured.get(id) == Some(&(*version, epoch)))
+            .map(|(id, _, tile, version)| (*id, *tile, *version))
+            .collect();
+        let extra = budget.saturating_sub(scheduled.len()).min(realtime.len());
+        for i in 0..extra {
+            scheduled.push(realtime[(self.next_realtime + i) % realtime.len()]);
+        }
+        if !realtime.is_empty() {
+            self.next_realtime = (self.next_realtime + extra) % realtime.len();
+        }
+        scheduled
+    }
+}
+
+impl RenderStageDefinition for ReflectionProbeStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.capture_pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.capture_renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        let settings = &info.settings.reflection_probes;
+        if !settings.enabled {
+            self.captured.clear();
+            info.reflection_probes.captured.lock().clear();
+            return None;
+        }
+
+        let scheduled = self.schedule(info);
+        if scheduled.is_empty() {
+            return None;
+        }
+
+        let cascades = SunCascades::compute(info);
+        let shadow_settings = &info.settings.shadows;
+        let sun_color = info.sun.color * info.sun.intensity;
+        let sun_buffer = CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(),
+            ProbeCaptureShaders::fragment::ty::SunData {
+                cascade_view_proj: [cascades.view_proj[0].into(), cascades.view_proj[1].into(),
+                                    cascades.view_proj[2].into(), cascades.view_proj[3].into()],
+                cascade_splits: cascades.splits,
+                cascade_texel_sizes: cascades.texel_sizes,
+                direction: [info.sun.direction.x, info.sun.direction.y, info.sun.direction.z, 0.0],
+                color: [sun_color.x, sun_color.y, sun_color.z, 1.0],
+                cascade_count: cascades.count as u32,
+                shadows_enabled: shadow_settings.enabled as u32,
+                depth_bias: shadow_settings.depth_bias,
+                normal_bias: shadow_settings.normal_bias,
+                pcf_radius: shadow_settings.pcf_radius as i32,
+            }).expect("failed to create buffer");
+
+        let (local_shadow_tiles, local_shadow_params) = local_shadow_buffers(info);
+        let (lights, directional_light_count) = ordered_lights(&info.lights);
+        let light_count = lights.len() as u32;
+        let lights_buffer = light_buffer(info.device.clone(), lights.into_iter(), &info.shadow_maps.local_data.lock());
+
+        let face_buffer = CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(),
+            ProbeFilterShaders::fragment::ty::FaceData {
+                face_view_proj: face_view_projs(Vector3::zero(), settings.capture_distance),
+            }).expect("failed to create buffer");
+        let filter_set = Arc::new(PersistentDescriptorSet::start(self.filter_pipeline.clone(), 0)
+            .add_sampled_image(self.capture_color.clone(), self.lut_sampler.clone()).unwrap()
+            .add_buffer(face_buffer).unwrap()
+            .build().unwrap());
+
+        let mut cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap();
+
+        let lock = info.mesh_queue.lock();
+        for (id, tile, version) in scheduled.iter() {
+            let probe = info.reflection_probes.get(*id).unwrap();
+
+            let capture_buffer = CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(),
+                ProbeCaptureShaders::fragment::ty::CaptureData {
+                    face_view_proj: face_view_projs(probe.position, settings.capture_distance),
+                    probe_position: probe.position.extend(1.0).into(),
+                    light_count,
+                    directional_light_count,
+                }).expect("failed to create buffer");
+            let capture_set = Arc::new(PersistentDescriptorSet::start(self.capture_pipeline.clone(), 0)
+                .add_buffer(capture_buffer).unwrap()
+                .add_sampled_image(info.shadow_maps.sun.clone(), self.shadow_sampler.clone()).unwrap()
+                .add_buffer(sun_buffer.clone()).unwrap()
+                .add_buffer(local_shadow_tiles.clone()).unwrap()
+                .add_sampled_image(info.shadow_maps.local.clone(), self.shadow_sampler.clone()).unwrap()
+                .add_buffer(local_shadow_params.clone()).unwrap()
+                .add_buffer(lights_buffer.clone()).unwrap()
+                .add_sampled_image(info.ies_profiles.texture.clone(), self.linear_sampler.clone()).unwrap()
+                .add_sampled_image(info.ltc_tables.matrix.clone(), self.lut_sampler.clone()).unwrap()
+                .add_sampled_image(info.ltc_tables.amplitude.clone(), self.lut_sampler.clone()).unwrap()
+                .build().unwrap());
+
+            // clear to transparent, so only geometry has coverage
+            cb = cb.begin_render_pass(self.capture_framebuffer.as_ref().unwrap().clone(), false,
+                                      vec![[0.0, 0.0, 0.0, 0.0].into(), 1f32.into()]).unwrap();
+            for face in 0..6 {
+                let dynamic_state = viewport([face * CAPTURE_FACE_SIZE, 0], [CAPTURE_FACE_SIZE, CAPTURE_FACE_SIZE]);
+                for mesh in lock.iter() {
+                    let world: Matrix4<f32> = mesh.transform.clone().into();
+                    for vertgroup in mesh.vertex_groups.iter() {
+                        cb = cb.draw_indexed(self.capture_pipeline.clone(), &dynamic_state,
+                            vec![vertgroup.vertex_buffer.clone()],
+                            vertgroup.index_buffer.clone(),
+                            capture_set.clone(), ProbeCaptureShaders::vertex::ty::Constants {
+                                world: world.into(),
+                                face,
+                            }).unwrap();
+                    }
+                }
+            }
+            cb = cb.end_render_pass().unwrap();
+
+            let origin = ReflectionProbes::tile_origin(*tile);
+            cb = cb.begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap();
+            for mip in 0..PROBE_MIP_COUNT {
+                let (offset, size) = ReflectionProbes::mip_rect(mip);
+                let rect_origin = [origin[0] + offset[0], origin[1] + offset[1]];
+                cb = cb.draw(self.filter_pipeline.clone(), &viewport(rect_origin, [size, size]),
+                    vec![self.fullscreen_vertex_buffer.clone()],
+                    filter_set.clone(), ProbeFilterShaders::fragment::ty::Constants {
+                        target_rect: [rect_origin[0] as f32, rect_origin[1] as f32, size as f32, size as f32],
+                        roughness: mip as f32 / (PROBE_MIP_COUNT - 1) as f32,
+                        sample_count: settings.filter_samples.max(1),
+                    }).unwrap();
+            }
+            cb = cb.end_render_pass().unwrap();
+
+            self.captured.insert(*id, (*version, info.reflection_probes.epoch));
+        }
+
+        // stale probes keep their old contents until they're recaptured
+        *info.reflection_probes.captured.lock() = self.captured.keys().cloned().collect();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.capture_framebuffer.is_none() {
+            self.capture_framebuffer = Some(Arc::new(Framebuffer::start(self.capture_renderpass.clone())
+                .add(self.capture_color.clone()).unwrap()
+                .add(self.capture_depth.clone()).unwrap()
+                .build().unwrap()));
+        }
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.filter_renderpass.clone())
+                .add(info.reflection_probes.atlas.clone()).unwrap()
+                .build().unwrap()));
+        }
+    }
+}
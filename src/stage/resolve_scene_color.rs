@@ -7,8 +7,8 @@ use vulkano::buffer::BufferUsage;
 use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
 use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
 use vulkano::pipeline::viewport::Viewport;
-use vulkano::image::{SwapchainImage, AttachmentImage};
-use vulkano::format::{ClearValue, R16G16B16A16Sfloat, R32Uint};
+use vulkano::image::SwapchainImage;
+use vulkano::format::ClearValue;
 use winit::Window;
 
 use crate::renderpass::ResolveSceneColorRenderPass;
@@ -24,12 +24,13 @@ pub struct ResolveSceneColorStage {
     pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
     renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
     fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
-    descriptor_set: Arc<dyn DescriptorSet + Send + Sync>,
+    /// Reads the diffuse and specular light buffers. Created with the framebuffer.
+    descriptor_set: Option<Arc<dyn DescriptorSet + Send + Sync>>,
 }
 
 
 impl ResolveSceneColorStage {
-    pub fn new(device: Arc<Device>, scene_color: Arc<AttachmentImage<R16G16B16A16Sfloat>>, luma_out: Arc<AttachmentImage<R32Uint>>) -> Self {
+    pub fn new(device: Arc<Device>) -> Self {
         let renderpass = Arc::new(
             ResolveSceneColorRenderPass {}
                 .build_render_pass(device.clone())
@@ -62,18 +63,13 @@ impl ResolveSceneColorStage {
                 VertexPosition { position: [ -1.0, -1.0, 1.0 ] },
             ].iter().cloned()).expect("failed to create buffer");
 
-        let descriptor_set = Arc::new(PersistentDescriptorSet::start(pipeline.clone(), 0)
-            .add_image(scene_color).unwrap()
-            .add_image(luma_out).unwrap()
-            .build().unwrap());
-
         ResolveSceneColorStage {
             pipeline,
             framebuffers: None,
             framebuffer: None,
             renderpass,
             fullscreen_vertex_buffer,
-            descriptor_set,
+            descriptor_set: None,
         }
     }
 }
@@ -88,7 +84,7 @@ impl RenderStageDefinition for ResolveSceneColorStage {
         let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
             .unwrap()
             .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false,
-                               vec![ClearValue::None, ClearValue::None, [0.0, 0.0, 0.0, 1.0].into(), [0u32, 0, 0, 1].into()]).unwrap()
+                               vec![ClearValue::None, ClearValue::None, [0.0, 0.0, 0.0, 1.0].into()]).unwrap()
             .draw(self.get_pipeline().clone(), &DynamicState {
                     line_width: None,
                     viewports: Some(vec![Viewport {
@@ -102,7 +98,7 @@ impl RenderStageDefinition for ResolveSceneColorStage {
                     reference: None
                 },
                                      vec![self.fullscreen_vertex_buffer.clone()],
-                                     self.descriptor_set.clone(), ()).unwrap()
+                                     self.descriptor_set.as_ref().unwrap().clone(), ()).unwrap()
             .end_render_pass().unwrap();
 
         Some(vec![
@@ -114,12 +110,14 @@ impl RenderStageDefinition for ResolveSceneColorStage {
         // TODO: framebuffer sets for standalone mode
         if self.framebuffer.is_none() {
             self.framebuffer = Some(Arc::new(Framebuffer::start(self.get_renderpass().clone())
-                // TODO: replace albedo hack with diffuse lighting
-                .add(info.attachments.albedo.clone()).unwrap()
+                .add(info.attachments.diffuse_light.clone()).unwrap()
                 .add(info.attachments.specular_light.clone()).unwrap()
                 .add(info.attachments.scene_color.clone()).unwrap()
-                .add(info.attachments.luma_render.clone()).unwrap()
-                .build().unwrap()))
+                .build().unwrap()));
+            self.descriptor_set = Some(Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+                .add_image(info.attachments.diffuse_light.clone()).unwrap()
+                .add_image(info.attachments.specular_light.clone()).unwrap()
+                .build().unwrap()));
         }
 //        if self.get_framebuffers_mut().is_none() {
 //            let new_framebuffers = Some(images.iter().map(|_| {
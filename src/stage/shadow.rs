@@ -0,0 +1,265 @@
+//! Cascaded shadow maps for the sun.
+//!
+//! The view frustum is split into up to `MAX_CASCADES` slices, and each slice gets its own
+//! orthographic shadow map in a 2x2 atlas. Each cascade is fit to a bounding sphere of its
+//! slice, so its size doesn't change as the camera rotates, and the projection is snapped to
+//! whole shadow map texels so the shadows don't shimmer as the camera moves.
+
+use std::sync::Arc;
+use cgmath::{Matrix4, Vector3, Vector4, Point3, SquareMatrix, InnerSpace, EuclideanSpace, Rad};
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::{SwapchainImage, AttachmentImage, ImageUsage};
+use vulkano::format::D32Sfloat;
+use winit::Window;
+
+use crate::renderpass::ShadowRenderPass;
+use crate::geometry::MeshVertex;
+use crate::shader::shadow_depth as ShadowShaders;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::{RenderInfo, VULKAN_CORRECT_CLIP};
+
+
+pub const MAX_CASCADES: usize = 4;
+/// Resolution of a single cascade. The atlas is twice this size on each side.
+pub const CASCADE_RESOLUTION: u32 = 2048;
+pub const SUN_SHADOW_ATLAS_SIZE: u32 = CASCADE_RESOLUTION * 2;
+/// How far behind each cascade to look for shadow casters, in world units.
+const SHADOW_CASTER_DISTANCE: f32 = 100.0;
+
+lazy_static! {
+    static ref SHADOW_MAP_USAGE: ImageUsage = ImageUsage {
+        depth_stencil_attachment: true,
+        sampled: true,
+        ..ImageUsage::none()
+    };
+}
+
+/// Creates the shadow atlas for the sun cascades.
+pub fn create_sun_shadow_atlas(device: Arc<Device>) -> Arc<AttachmentImage<D32Sfloat>> {
+    AttachmentImage::with_usage(device, [SUN_SHADOW_ATLAS_SIZE, SUN_SHADOW_ATLAS_SIZE], D32Sfloat, *SHADOW_MAP_USAGE).unwrap()
+}
+
+
+/// Settings for sun shadows.
+#[derive(Debug, Clone)]
+pub struct ShadowSettings {
+    pub enabled: bool,
+    /// Number of cascades, from 1 to `MAX_CASCADES`.
+    pub cascade_count: u32,
+    /// Distance from the camera where the shadows end.
+    pub max_distance: f32,
+    /// Blend between uniform (0) and logarithmic (1) cascade splits.
+    pub split_lambda: f32,
+    /// Constant depth bias, in light clip space.
+    pub depth_bias: f32,
+    /// Offset along the surface normal before sampling, in shadow map texels.
+    pub normal_bias: f32,
+    /// PCF kernel radius in texels. The kernel is `(2 * radius + 1)^2` taps.
+    pub pcf_radius: u32,
+}
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cascade_count: 4,
+            max_distance: 150.0,
+            split_lambda: 0.8,
+            depth_bias: 0.0005,
+            normal_bias: 1.5,
+            pcf_radius: 1,
+        }
+    }
+}
+
+
+/// Cascade matrices and split distances for the current frame.
+#[derive(Debug, Clone)]
+pub struct SunCascades {
+    pub count: usize,
+    /// Light `proj * view` for each cascade, mapping into that cascade's [-1, 1] clip space.
+    pub view_proj: [Matrix4<f32>; MAX_CASCADES],
+    /// Far view-space distance of each cascade.
+    pub splits: [f32; MAX_CASCADES],
+    /// World-space size of one shadow map texel in each cascade.
+    pub texel_sizes: [f32; MAX_CASCADES],
+}
+
+impl SunCascades {
+    /// Fits the cascades to the camera frustum.
+    pub fn compute(info: &RenderInfo) -> SunCascades {
+        let settings = &info.settings.shadows;
+        let count = (settings.cascade_count as usize).max(1).min(MAX_CASCADES);
+        let near = info.near_plane;
+        let far = settings.max_distance.min(info.far_plane);
+
+        let mut cascades = SunCascades {
+            count,
+            view_proj: [Matrix4::identity(); MAX_CASCADES],
+            splits: [far; MAX_CASCADES],
+            texel_sizes: [0.0; MAX_CASCADES],
+        };
+
+        // practical split scheme, between uniform and logarithmic
+        for i in 0..count {
+            let p = (i + 1) as f32 / count as f32;
+            let log = near * (far / near).powf(p);
+            let uniform = near + (far - near) * p;
+            cascades.splits[i] = settings.split_lambda * log + (1.0 - settings.split_lambda) * uniform;
+        }
+
+        let inv_view = info.view_mat.invert().unwrap_or(Matrix4::identity());
+        let tan_y = (Rad::from(info.fov).0 * 0.5).tan();
+        let tan_x = tan_y * info.dimensions[0] as f32 / info.dimensions[1] as f32;
+        let light_dir = info.sun.direction.normalize();
+        let up = if light_dir.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+
+        let mut slice_near = near;
+        for i in 0..count {
+            let slice_far = cascades.splits[i];
+
+            let mut corners = [Vector3::new(0.0, 0.0, 0.0); 8];
+            for (j, d) in [slice_near, slice_far].iter().enumerate() {
+                for (k, (sx, sy)) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)].iter().enumerate() {
+                    let world = inv_view * Vector4::new(sx * tan_x * d, sy * tan_y * d, -d, 1.0);
+                    corners[j * 4 + k] = world.truncate() / world.w;
+                }
+            }
+            let center = corners.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, c| acc + c) / 8.0;
+            let radius = corners.iter().map(|c| (c - center).magnitude()).fold(0.0f32, f32::max);
+            // quantize the radius so the cascade size doesn't flicker from float error
+            let radius = (radius * 16.0).ceil() / 16.0;
+
+            let eye = center - light_dir * (radius + SHADOW_CASTER_DISTANCE);
+            let light_view = Matrix4::look_at(Point3::from_vec(eye), Point3::from_vec(center), up);
+            let light_proj = cgmath::ortho(-radius, radius, -radius, radius, 0.0, 2.0 * radius + SHADOW_CASTER_DISTANCE);
+            let mut view_proj = VULKAN_CORRECT_CLIP * light_proj * light_view;
+
+            // snap the world origin to a texel so the shadow map only moves in whole texels
+            let half_res = CASCADE_RESOLUTION as f32 * 0.5;
+            let origin = view_proj * Vector4::new(0.0, 0.0, 0.0, 1.0);
+            let texel_x = origin.x * half_res;
+            let texel_y = origin.y * half_res;
+            view_proj.w.x += (texel_x.round() - texel_x) / half_res;
+            view_proj.w.y += (texel_y.round() - texel_y) / half_res;
+
+            cascades.view_proj[i] = view_proj;
+            cascades.texel_sizes[i] = 2.0 * radius / CASCADE_RESOLUTION as f32;
+            slice_near = slice_far;
+        }
+
+        cascades
+    }
+
+    /// Viewport of the given cascade in the shadow atlas.
+    pub fn viewport(cascade: usize) -> Viewport {
+        Viewport {
+            origin: [(cascade % 2) as f32 * CASCADE_RESOLUTION as f32, (cascade / 2) as f32 * CASCADE_RESOLUTION as f32],
+            dimensions: [CASCADE_RESOLUTION as f32, CASCADE_RESOLUTION as f32],
+            depth_range: 0.0..1.0,
+        }
+    }
+}
+
+
+/// Renders the sun cascades into `info.shadow_maps.sun`.
+pub struct SunShadowStage {
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+}
+
+
+impl SunShadowStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let renderpass = Arc::new(
+            ShadowRenderPass::clear()
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let pipeline = {
+            let vs = ShadowShaders::vertex::Shader::load(device.clone()).expect("failed to create shader module");
+            let fs = ShadowShaders::fragment::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                // culling front faces moves most of the acne to the back of objects, where it's already dark
+                .cull_mode_front()
+                .vertex_input_single_buffer::<MeshVertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil_simple_depth()
+                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        SunShadowStage {
+            pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            renderpass,
+        }
+    }
+}
+
+impl RenderStageDefinition for SunShadowStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        if !info.settings.shadows.enabled {
+            return None;
+        }
+
+        let cascades = SunCascades::compute(info);
+        let mut cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![1f32.into()]).unwrap();
+
+        let lock = info.mesh_queue.lock();
+        for cascade in 0..cascades.count {
+            let dynamic_state = DynamicState {
+                line_width: None,
+                viewports: Some(vec![SunCascades::viewport(cascade)]),
+                scissors: None,
+                compare_mask: None,
+                write_mask: None,
+                reference: None
+            };
+            for mesh in lock.iter() {
+                let world: Matrix4<f32> = mesh.transform.clone().into();
+                let light_mvp = cascades.view_proj[cascade] * world;
+                for vertgroup in mesh.vertex_groups.iter() {
+                    cb = cb.draw_indexed(self.pipeline.clone(), &dynamic_state,
+                        vec![vertgroup.vertex_buffer.clone()],
+                        vertgroup.index_buffer.clone(),
+                        (), ShadowShaders::vertex::ty::Constants {
+                            light_mvp: light_mvp.into(),
+                        }).unwrap();
+                }
+            }
+        }
+        cb = cb.end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.get_renderpass().clone())
+                .add(info.shadow_maps.sun.clone()).unwrap()
+                .build().unwrap()));
+        }
+    }
+}
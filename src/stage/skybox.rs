@@ -0,0 +1,173 @@
+use std::sync::Arc;
+use cgmath::{Matrix4, SquareMatrix, Vector4};
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::SwapchainImage;
+use vulkano::format::ClearValue;
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::SkyboxRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::material::{MaterialDefinition, SkyboxMaterial, HdriSkyboxMaterial};
+use crate::material::params::MaterialParams;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::RenderInfo;
+
+
+/// What's drawn behind the scene.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkyboxMode {
+    /// The analytic sky described by `SkySettings`.
+    Procedural,
+    /// The environment image set with `PhosphorRenderer::set_environment`.
+    Hdri,
+}
+
+/// Settings for the sky backdrop.
+#[derive(Debug, Clone)]
+pub struct SkyboxSettings {
+    pub mode: SkyboxMode,
+    /// Rotation of the HDRI around the vertical axis, in radians. Only affects the backdrop, not
+    /// the image based lighting.
+    pub rotation: f32,
+    /// Multiplier for the HDRI's radiance.
+    pub intensity: f32,
+    /// Blurs the HDRI through the environment's prefiltered radiance mips, from 0 (sharp) to 1
+    /// (as rough as the roughest reflections).
+    pub blur: f32,
+}
+impl Default for SkyboxSettings {
+    fn default() -> Self {
+        Self {
+            mode: SkyboxMode::Procedural,
+            rotation: 0.0,
+            intensity: 1.0,
+            blur: 0.0,
+        }
+    }
+}
+
+
+/// Fills the pixels of `scene_color` that have no geometry in front of them with the sky.
+pub struct SkyboxStage {
+    procedural: SkyboxMaterial,
+    hdri: HdriSkyboxMaterial,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    depth_sampler: Arc<Sampler>,
+    /// Wraps around horizontally, clamps at the poles.
+    environment_sampler: Arc<Sampler>,
+}
+
+
+impl SkyboxStage {
+    pub fn new(info: &RenderInfo) -> Self {
+        let device: Arc<Device> = info.device.clone();
+        let renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            SkyboxRenderPass
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        SkyboxStage {
+            procedural: SkyboxMaterial::new(info, renderpass.clone(), 0, MaterialParams::new()),
+            hdri: HdriSkyboxMaterial::new(info, renderpass.clone(), 0),
+            framebuffers: None,
+            framebuffer: None,
+            renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            depth_sampler: Sampler::new(device.clone(), Filter::Nearest, Filter::Nearest, MipmapMode::Nearest,
+                                        SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                        0.0, 1.0, 0.0, 0.0).unwrap(),
+            environment_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Linear,
+                                              SamplerAddressMode::Repeat, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                              0.0, 1.0, 0.0, 1000.0).unwrap(),
+        }
+    }
+}
+
+impl RenderStageDefinition for SkyboxStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { self.procedural.pipeline() }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        let settings = &info.settings.skybox;
+
+        // the sky is infinitely far away, so only the camera's rotation matters
+        let mut view = info.view_mat;
+        view.w = Vector4::new(0.0, 0.0, 0.0, 1.0);
+        let inverse_view_proj: [[f32; 4]; 4] = (info.proj_mat * view).invert().unwrap_or(Matrix4::identity()).into();
+
+        let dynamic_state = DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+            compare_mask: None,
+            write_mask: None,
+            reference: None
+        };
+
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap();
+
+        let cb = match settings.mode {
+            SkyboxMode::Procedural => {
+                let descriptor_set = Arc::new(PersistentDescriptorSet::start(self.procedural.pipeline().clone(), 0)
+                    .add_sampled_image(info.attachments.main_depth.clone(), self.depth_sampler.clone()).unwrap()
+                    .add_buffer(crate::sky::sky_buffer(info)).unwrap()
+                    .build().unwrap());
+                cb.draw(self.procedural.pipeline().clone(), &dynamic_state,
+                        vec![self.fullscreen_vertex_buffer.clone()],
+                        descriptor_set, crate::shader::skybox::vertex::ty::Constants {
+                            matrix: inverse_view_proj,
+                            depth_mode: info.depth_mode.shader_id(),
+                        }).unwrap()
+            },
+            SkyboxMode::Hdri => {
+                let descriptor_set = Arc::new(PersistentDescriptorSet::start(self.hdri.pipeline().clone(), 0)
+                    .add_sampled_image(info.attachments.main_depth.clone(), self.depth_sampler.clone()).unwrap()
+                    .add_sampled_image(info.environment.source.clone(), self.environment_sampler.clone()).unwrap()
+                    .add_sampled_image(info.environment.radiance.clone(), self.environment_sampler.clone()).unwrap()
+                    .build().unwrap());
+                cb.draw(self.hdri.pipeline().clone(), &dynamic_state,
+                        vec![self.fullscreen_vertex_buffer.clone()],
+                        descriptor_set, crate::shader::skybox_hdri::vertex::ty::Constants {
+                            matrix: inverse_view_proj,
+                            rotation: settings.rotation,
+                            intensity: settings.intensity,
+                            blur: settings.blur,
+                            depth_mode: info.depth_mode.shader_id(),
+                        }).unwrap()
+            },
+        };
+
+        let cb = cb.end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.renderpass.clone())
+                .add(info.attachments.scene_color.clone()).unwrap()
+                .build().unwrap()))
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! Screen-space reflections.
+//!
+//! Linear depth is reduced into a hierarchical-Z pyramid of closest depths, which mirror
+//! reflection rays are traced through using G-buffer normals and roughness. Hits are resolved
+//! from the previous frame's `scene_color`, reprojected with the velocity at the hit, and added
+//! to `specular_light` in place of the probe and environment specular that lighting already
+//! added. Where rays miss or leave the screen, that specular is kept, so reflections fade to it.
+//!
+//! The previous frame's color is a copy of `scene_color` taken by `build_history_command_buffer`
+//! after TAA, before depth of field, motion blur and bloom, which shouldn't show up in
+//! reflections.
+
+use std::sync::Arc;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendOp, BlendFactor};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::{SwapchainImage, AttachmentImage, ImageUsage};
+use vulkano::format::{ClearValue, Format, R16G16B16A16Sfloat, R32Sfloat};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::FullscreenRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::reflection_probe::reflection_probe_buffers;
+use crate::shader::ssr as SsrShaders;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::RenderInfo;
+
+
+/// Levels in the hierarchical-Z pyramid, including the full resolution one. Must match
+/// `HIZ_LEVELS` in `ssr_trace.frag`.
+pub const HIZ_LEVELS: usize = 6;
+
+/// Settings for screen-space reflections.
+#[derive(Debug, Clone)]
+pub struct SsrSettings {
+    pub enabled: bool,
+    /// How much reflections replace the probe and environment specular where rays hit, from 0
+    /// to 1.
+    pub intensity: f32,
+    /// World-space length of a reflection ray.
+    pub max_distance: f32,
+    /// How far behind a surface a ray still counts as hitting it, in meters. Larger values
+    /// close gaps behind thin objects, but can make reflections stretch.
+    pub thickness: f32,
+    /// Surfaces at least this rough only use probe and environment reflections. Reflections
+    /// fade out approaching it.
+    pub max_roughness: f32,
+    /// Steps per ray through the pyramid.
+    pub max_iterations: u32,
+}
+impl Default for SsrSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            intensity: 1.0,
+            max_distance: 50.0,
+            thickness: 0.5,
+            max_roughness: 0.6,
+            max_iterations: 64,
+        }
+    }
+}
+
+
+/// Returns the dimensions of each level in the pyramid, starting at full resolution.
+fn hiz_dimensions(dimensions: [u32; 2]) -> Vec<[u32; 2]> {
+    let mut result = vec![dimensions];
+    for _ in 1..HIZ_LEVELS {
+        let last = result[result.len() - 1];
+        result.push([(last[0] / 2).max(1), (last[1] / 2).max(1)]);
+    }
+    result
+}
+
+
+struct HizLevel {
+    image: Arc<AttachmentImage<R32Sfloat>>,
+    dimensions: [u32; 2],
+    framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+}
+
+/// Images that depend on the screen size.
+struct SsrTargets {
+    hiz: Vec<HizLevel>,
+    /// Hit uv in rg, confidence in b.
+    hits: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
+    hits_framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+    /// Copy of last frame's `scene_color`.
+    history: Arc<AttachmentImage<R16G16B16A16Sfloat>>,
+    /// Whether `history` has been written since it was created.
+    history_valid: bool,
+}
+
+
+pub struct ScreenSpaceReflectionStage {
+    hiz_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    trace_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    composite_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    /// Framebuffer for adding reflections to `specular_light`.
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    hiz_renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    hits_renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    composite_renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    nearest_sampler: Arc<Sampler>,
+    linear_sampler: Arc<Sampler>,
+    /// Trilinear, for the environment's radiance mips.
+    environment_sampler: Arc<Sampler>,
+    targets: Option<SsrTargets>,
+}
+
+
+impl ScreenSpaceReflectionStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let hiz_renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::overwrite(Format::R32Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+        let hits_renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::overwrite(Format::R16G16B16A16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+        let composite_renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::blend(Format::R16G16B16A16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let vs = crate::shader::fullscreen::Shader::load(device.clone()).expect("failed to create shader module");
+
+        let hiz_pipeline = {
+            let fs = SsrShaders::hiz::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(hiz_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let trace_pipeline = {
+            let fs = SsrShaders::trace::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(hits_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let composite_pipeline = {
+            let fs = SsrShaders::composite::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                // additive: the shader outputs the change to the specular lighting
+                .blend_collective(AttachmentBlend {
+                    enabled: true,
+                    color_op: BlendOp::Add,
+                    color_source: BlendFactor::One,
+                    color_destination: BlendFactor::One,
+                    alpha_op: BlendOp::Add,
+                    alpha_source: BlendFactor::Zero,
+                    alpha_destination: BlendFactor::One,
+                    mask_red: true,
+                    mask_green: true,
+                    mask_blue: true,
+                    mask_alpha: true,
+                })
+                .render_pass(Subpass::from(composite_renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        ScreenSpaceReflectionStage {
+            hiz_pipeline,
+            trace_pipeline,
+            composite_pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            hiz_renderpass,
+            hits_renderpass,
+            composite_renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            nearest_sampler: Sampler::new(device.clone(), Filter::Nearest, Filter::Nearest, MipmapMode::Nearest,
+                                          SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                          0.0, 1.0, 0.0, 0.0).unwrap(),
+            linear_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                         SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                         0.0, 1.0, 0.0, 0.0).unwrap(),
+            environment_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Linear,
+                                              SamplerAddressMode::Repeat, SamplerAddressMode::Repeat, SamplerAddressMode::Repeat,
+                                              0.0, 4.0, 0.0, 4.0).unwrap(),
+            targets: None,
+        }
+    }
+
+    fn dynamic_state(dimensions: [u32; 2]) -> DynamicState {
+        DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+            compare_mask: None,
+            write_mask: None,
+            reference: None
+        }
+    }
+
+    /// Copies `scene_color` into the history that next frame's reflections are resolved from.
+    /// Runs after TAA, before the post processing that shouldn't be reflected.
+    pub fn build_history_command_buffer(&mut self, info: &RenderInfo) -> Option<(AutoCommandBuffer, Arc<Queue>)> {
+        if !info.settings.ssr.enabled {
+            return None;
+        }
+        let targets = self.targets.as_mut()?;
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .copy_image(info.attachments.scene_color.clone(), [0, 0, 0], 0, 0,
+                        targets.history.clone(), [0, 0, 0], 0, 0, [info.dimensions[0], info.dimensions[1], 1], 1).unwrap();
+        targets.history_valid = true;
+        Some((cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()))
+    }
+}
+
+impl RenderStageDefinition for ScreenSpaceReflectionStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.composite_pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.composite_renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        let settings = &info.settings.ssr;
+        if !settings.enabled {
+            return None;
+        }
+        let targets = self.targets.as_ref()?;
+        // nothing to reflect until a frame has been captured
+        if !targets.history_valid {
+            return None;
+        }
+
+        let mut cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap();
+
+        // closest depth pyramid, starting from the depth buffer
+        for (i, level) in targets.hiz.iter().enumerate() {
+            let (descriptor_set, input_dimensions) = if i == 0 {
+                (Arc::new(PersistentDescriptorSet::start(self.hiz_pipeline.clone(), 0)
+                    .add_sampled_image(info.attachments.main_depth.clone(), self.nearest_sampler.clone()).unwrap()
+                    .build().unwrap()), info.dimensions)
+            }
+            else {
+                let source = &targets.hiz[i - 1];
+                (Arc::new(PersistentDescriptorSet::start(self.hiz_pipeline.clone(), 0)
+                    .add_sampled_image(source.image.clone(), self.nearest_sampler.clone()).unwrap()
+                    .build().unwrap()), source.dimensions)
+            };
+
+            cb = cb.begin_render_pass(level.framebuffer.clone(), false, vec![ClearValue::None]).unwrap()
+                .draw(self.hiz_pipeline.clone(), &Self::dynamic_state(level.dimensions),
+                    vec![self.fullscreen_vertex_buffer.clone()],
+                    descriptor_set, SsrShaders::hiz::ty::Constants {
+                        input_dimensions: [input_dimensions[0] as i32, input_dimensions[1] as i32],
+                        near_plane: info.near_plane,
+                        far_plane: info.far_plane,
+                        depth_mode: info.depth_mode.shader_id(),
+                        first_level: if i == 0 { 1 } else { 0 },
+                    }).unwrap()
+                .end_render_pass().unwrap();
+        }
+
+        let hiz = &targets.hiz;
+        let trace_set = Arc::new(PersistentDescriptorSet::start(self.trace_pipeline.clone(), 0)
+            .add_sampled_image(hiz[0].image.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(hiz[1].image.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(hiz[2].image.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(hiz[3].image.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(hiz[4].image.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(hiz[5].image.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.position.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.normal.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.roughness.clone(), self.nearest_sampler.clone()).unwrap()
+            .build().unwrap());
+
+        let (probes, probe_params) = reflection_probe_buffers(info);
+        let composite_set = Arc::new(PersistentDescriptorSet::start(self.composite_pipeline.clone(), 0)
+            .add_sampled_image(targets.hits.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(targets.history.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.velocity.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.position.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.normal.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.albedo.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.roughness.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.metallic.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.environment.radiance.clone(), self.environment_sampler.clone()).unwrap()
+            .add_sampled_image(info.environment.brdf_lut.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.ambient_occlusion.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.reflection_probes.atlas.clone(), self.linear_sampler.clone()).unwrap()
+            .add_buffer(probes).unwrap()
+            .add_buffer(probe_params).unwrap()
+            .build().unwrap());
+
+        let screen_dimensions = [info.dimensions[0] as f32, info.dimensions[1] as f32];
+        let view_pos: [f32; 3] = info.camera_transform.position.into();
+        cb = cb
+            .begin_render_pass(targets.hits_framebuffer.clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.trace_pipeline.clone(), &Self::dynamic_state(info.dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                trace_set, SsrShaders::trace::ty::Constants {
+                    view_proj: (info.proj_mat * info.view_mat).into(),
+                    view_pos,
+                    near_plane: info.near_plane,
+                    screen_dimensions,
+                    max_distance: settings.max_distance,
+                    thickness: settings.thickness,
+                    max_roughness: settings.max_roughness,
+                    max_iterations: settings.max_iterations,
+                    frame_index: info.frame_index as u32,
+                }).unwrap()
+            .end_render_pass().unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.composite_pipeline.clone(), &Self::dynamic_state(info.dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                composite_set, SsrShaders::composite::ty::Constants {
+                    view_pos,
+                    intensity: settings.intensity.max(0.0).min(1.0),
+                    screen_dimensions,
+                }).unwrap()
+            .end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.composite_renderpass.clone())
+                .add(info.attachments.specular_light.clone()).unwrap()
+                .build().unwrap()));
+
+            let usage = ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                ..ImageUsage::none()
+            };
+            let hiz = hiz_dimensions(info.dimensions).into_iter().map(|dimensions| {
+                let image = AttachmentImage::with_usage(info.device.clone(), dimensions, R32Sfloat, usage).unwrap();
+                HizLevel {
+                    framebuffer: Arc::new(Framebuffer::start(self.hiz_renderpass.clone())
+                        .add(image.clone()).unwrap()
+                        .build().unwrap()),
+                    image,
+                    dimensions,
+                }
+            }).collect();
+            let hits = AttachmentImage::with_usage(info.device.clone(), info.dimensions, R16G16B16A16Sfloat, usage).unwrap();
+            let history_usage = ImageUsage {
+                sampled: true,
+                transfer_destination: true,
+                ..ImageUsage::none()
+            };
+            self.targets = Some(SsrTargets {
+                hiz,
+                hits_framebuffer: Arc::new(Framebuffer::start(self.hits_renderpass.clone())
+                    .add(hits.clone()).unwrap()
+                    .build().unwrap()),
+                hits,
+                history: AttachmentImage::with_usage(info.device.clone(), info.dimensions, R16G16B16A16Sfloat, history_usage).unwrap(),
+                history_valid: false,
+            });
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Screen-space subsurface scattering.
+//!
+//! Blurs `diffuse_light` over pixels with a subsurface profile, in a horizontal pass into a
+//! temporary image and a vertical pass back into `diffuse_light`. Runs after all lighting and
+//! before `resolve_scene_color` combines diffuse and specular. See `subsurface` for profiles.
+
+use std::sync::Arc;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::buffer::BufferUsage;
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::{SwapchainImage, AttachmentImage, ImageUsage};
+use vulkano::format::{ClearValue, Format, R16G16B16A16Sfloat};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::FullscreenRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::shader::subsurface as SubsurfaceShaders;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::RenderInfo;
+
+
+/// Settings for subsurface scattering.
+#[derive(Debug, Clone)]
+pub struct SubsurfaceSettings {
+    pub enabled: bool,
+    /// Samples on each side of a pixel, per blur direction.
+    pub sample_count: u32,
+}
+impl Default for SubsurfaceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sample_count: 8,
+        }
+    }
+}
+
+
+pub struct SubsurfaceStage {
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    /// Framebuffer for the vertical pass, writing to the `diffuse_light` attachment.
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    linear_sampler: Arc<Sampler>,
+    nearest_sampler: Arc<Sampler>,
+    blur_temp: Option<Arc<AttachmentImage<R16G16B16A16Sfloat>>>,
+    blur_temp_framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+}
+
+
+impl SubsurfaceStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::overwrite(Format::R16G16B16A16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let pipeline = {
+            let vs = crate::shader::fullscreen::Shader::load(device.clone()).expect("failed to create shader module");
+            let fs = SubsurfaceShaders::blur::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        SubsurfaceStage {
+            pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            linear_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                         SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                         0.0, 1.0, 0.0, 0.0).unwrap(),
+            nearest_sampler: Sampler::new(device.clone(), Filter::Nearest, Filter::Nearest, MipmapMode::Nearest,
+                                          SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                          0.0, 1.0, 0.0, 0.0).unwrap(),
+            blur_temp: None,
+            blur_temp_framebuffer: None,
+        }
+    }
+}
+
+impl RenderStageDefinition for SubsurfaceStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        let settings = &info.settings.subsurface;
+        // without profiles, no pixel can be marked for scattering
+        if !settings.enabled || info.subsurface_profiles.is_empty() {
+            return None;
+        }
+
+        let texel_size = [1.0 / info.dimensions[0] as f32, 1.0 / info.dimensions[1] as f32];
+        // the projection's y scale maps a meter at unit depth to half the screen height
+        let pixels_per_meter = info.unjittered_proj_mat.y.y.abs() * 0.5 * info.dimensions[1] as f32;
+        let dynamic_state = DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+            compare_mask: None,
+            write_mask: None,
+            reference: None
+        };
+
+        let profile_buffer = CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(),
+                                                                  info.subsurface_profiles.gpu_data())
+            .expect("failed to create buffer");
+        let horizontal_set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_sampled_image(info.attachments.diffuse_light.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.position.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.normal.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_buffer(profile_buffer.clone()).unwrap()
+            .build().unwrap());
+        let vertical_set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_sampled_image(self.blur_temp.as_ref().unwrap().clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.position.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.normal.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_buffer(profile_buffer).unwrap()
+            .build().unwrap());
+
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(self.blur_temp_framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.pipeline.clone(), &dynamic_state,
+                vec![self.fullscreen_vertex_buffer.clone()],
+                horizontal_set, SubsurfaceShaders::blur::ty::Constants {
+                    texel_size,
+                    direction: [1.0, 0.0],
+                    pixels_per_meter,
+                    sample_count: settings.sample_count,
+                }).unwrap()
+            .end_render_pass().unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.pipeline.clone(), &dynamic_state,
+                vec![self.fullscreen_vertex_buffer.clone()],
+                vertical_set, SubsurfaceShaders::blur::ty::Constants {
+                    texel_size,
+                    direction: [0.0, 1.0],
+                    pixels_per_meter,
+                    sample_count: settings.sample_count,
+                }).unwrap()
+            .end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            let usage = ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                ..ImageUsage::none()
+            };
+            let blur_temp = AttachmentImage::with_usage(info.device.clone(), info.dimensions, R16G16B16A16Sfloat, usage).unwrap();
+
+            self.blur_temp_framebuffer = Some(Arc::new(Framebuffer::start(self.renderpass.clone())
+                .add(blur_temp.clone()).unwrap()
+                .build().unwrap()));
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.renderpass.clone())
+                .add(info.attachments.diffuse_light.clone()).unwrap()
+                .build().unwrap()));
+            self.blur_temp = Some(blur_temp);
+        }
+    }
+}
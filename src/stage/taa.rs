@@ -0,0 +1,204 @@
+//! Temporal anti-aliasing.
+//!
+//! The projection matrix is jittered by a sub-pixel offset every frame, and this stage blends the
+//! current frame into a history buffer reprojected with the G-buffer velocity. The history is
+//! clipped to the current frame's neighborhood to avoid ghosting.
+
+use std::sync::Arc;
+use cgmath::Matrix4;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::SwapchainImage;
+use vulkano::format::{ClearValue, Format};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::FullscreenRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::shader::taa as TaaShaders;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::RenderInfo;
+
+
+/// Settings for temporal anti-aliasing.
+#[derive(Debug, Clone)]
+pub struct TaaSettings {
+    pub enabled: bool,
+    /// Number of jitter positions before the sequence repeats.
+    pub jitter_samples: u32,
+    /// History weight for pixels that differ a lot from the history. Lower values ghost less.
+    pub feedback_min: f32,
+    /// History weight for stable pixels. Higher values are smoother but blurrier in motion.
+    pub feedback_max: f32,
+    /// Sharpening applied to the current frame before blending, 0 to disable.
+    pub sharpness: f32,
+}
+impl Default for TaaSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            jitter_samples: 8,
+            feedback_min: 0.88,
+            feedback_max: 0.97,
+            sharpness: 0.25,
+        }
+    }
+}
+
+
+/// Returns the `index`th element of the Halton sequence with the given base, in [0, 1).
+pub fn halton(mut index: u32, base: u32) -> f32 {
+    let mut f = 1.0;
+    let mut result = 0.0;
+    while index > 0 {
+        f /= base as f32;
+        result += f * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+/// Sub-pixel jitter for the given frame, in NDC units.
+pub fn jitter_offset(frame_index: u64, sample_count: u32, dimensions: [u32; 2]) -> [f32; 2] {
+    // skip index 0, which is (0, 0) for every base
+    let i = (frame_index % sample_count.max(1) as u64) as u32 + 1;
+    [
+        (halton(i, 2) - 0.5) * 2.0 / dimensions[0] as f32,
+        (halton(i, 3) - 0.5) * 2.0 / dimensions[1] as f32,
+    ]
+}
+
+/// Offsets a projection matrix by `jitter` (in NDC units).
+pub fn jitter_projection(mut proj: Matrix4<f32>, jitter: [f32; 2]) -> Matrix4<f32> {
+    // clip.w = -z_view, so subtracting here adds the jitter after the perspective divide
+    proj.z.x -= jitter[0];
+    proj.z.y -= jitter[1];
+    proj
+}
+
+
+pub struct TemporalAAStage {
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    linear_sampler: Arc<Sampler>,
+    nearest_sampler: Arc<Sampler>,
+    history_valid: bool,
+}
+
+
+impl TemporalAAStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let renderpass = Arc::new(
+            FullscreenRenderPass::overwrite(Format::R16G16B16A16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let pipeline = {
+            let vs = crate::shader::fullscreen::Shader::load(device.clone()).expect("failed to create shader module");
+            let fs = TaaShaders::fragment::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        TemporalAAStage {
+            pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            linear_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                         SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                         0.0, 1.0, 0.0, 0.0).unwrap(),
+            nearest_sampler: Sampler::new(device.clone(), Filter::Nearest, Filter::Nearest, MipmapMode::Nearest,
+                                          SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                          0.0, 1.0, 0.0, 0.0).unwrap(),
+            history_valid: false,
+        }
+    }
+}
+
+impl RenderStageDefinition for TemporalAAStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        if !info.settings.taa.enabled {
+            self.history_valid = false;
+            return None;
+        }
+
+        let descriptor_set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_sampled_image(info.attachments.scene_color.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.taa_history.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.velocity.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.main_depth.clone(), self.nearest_sampler.clone()).unwrap()
+            .build().unwrap());
+
+        let dimensions = [info.dimensions[0], info.dimensions[1], 1];
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.pipeline.clone(), &DynamicState {
+                    line_width: None,
+                    viewports: Some(vec![Viewport {
+                        origin: [0.0, 0.0],
+                        dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                        depth_range: 0.0..1.0,
+                    }]),
+                    scissors: None,
+                    compare_mask: None,
+                    write_mask: None,
+                    reference: None
+                },
+                vec![self.fullscreen_vertex_buffer.clone()],
+                descriptor_set, TaaShaders::fragment::ty::Constants {
+                    screen_dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                    feedback_min: info.settings.taa.feedback_min,
+                    feedback_max: info.settings.taa.feedback_max,
+                    sharpness: info.settings.taa.sharpness,
+                    reset_history: if self.history_valid { 0 } else { 1 },
+                    depth_mode: info.depth_mode.shader_id(),
+                }).unwrap()
+            .end_render_pass().unwrap()
+            // resolved image becomes both the scene color for tonemapping and next frame's history
+            .copy_image(info.attachments.taa_output.clone(), [0, 0, 0], 0, 0,
+                        info.attachments.taa_history.clone(), [0, 0, 0], 0, 0, dimensions, 1).unwrap()
+            .copy_image(info.attachments.taa_output.clone(), [0, 0, 0], 0, 0,
+                        info.attachments.scene_color.clone(), [0, 0, 0], 0, 0, dimensions, 1).unwrap();
+
+        self.history_valid = true;
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.get_renderpass().clone())
+                .add(info.attachments.taa_output.clone()).unwrap()
+                .build().unwrap()));
+            // history was resized along with the other attachments, so its contents are garbage
+            self.history_valid = false;
+        }
+    }
+}
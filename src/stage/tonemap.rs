@@ -0,0 +1,181 @@
+use std::sync::Arc;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::SwapchainImage;
+use vulkano::format::{ClearValue, Format};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+use cgmath::Matrix4;
+
+use crate::renderpass::FullscreenRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::shader::tonemapper as TonemapperShaders;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::RenderInfo;
+use crate::color_grading::white_balance_matrix;
+
+
+/// Curve mapping exposed HDR color to the display's 0 to 1 range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TonemapOperator {
+    /// Stephen Hill's fit of the ACES reference rendering and output transforms.
+    AcesFitted,
+    /// Reinhard on luminance, reaching white at `white_point`. Keeps hues, but bright colors
+    /// don't desaturate.
+    ReinhardExtended { white_point: f32 },
+    /// John Hable's filmic curve from Uncharted 2, reaching white at `white_point`.
+    Uncharted2 { white_point: f32 },
+    /// Troy Sobotka's AgX, with the default look. Desaturates highlights smoothly, without the
+    /// hue shifts of per-channel curves.
+    AgX,
+    /// Khronos PBR Neutral. Leaves colors below about 0.8 unchanged, so base colors come out as
+    /// authored.
+    KhronosPbrNeutral,
+}
+
+impl TonemapOperator {
+    /// `(id, white_point)` for `tonemapper.frag`.
+    fn shader_params(&self) -> (u32, f32) {
+        match *self {
+            TonemapOperator::AcesFitted => (0, 0.0),
+            TonemapOperator::ReinhardExtended { white_point } => (1, white_point.max(1e-3)),
+            TonemapOperator::Uncharted2 { white_point } => (2, white_point.max(1e-3)),
+            TonemapOperator::AgX => (3, 0.0),
+            TonemapOperator::KhronosPbrNeutral => (4, 0.0),
+        }
+    }
+}
+
+impl Default for TonemapOperator {
+    fn default() -> Self { TonemapOperator::AcesFitted }
+}
+
+/// Converts the HDR `scene_color` into the LDR `ldr_color` attachment, and draws debug views.
+/// Also applies color grading; see `color_grading`.
+pub struct TonemapStage {
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    sampler: Arc<Sampler>,
+    lut_sampler: Arc<Sampler>,
+}
+
+
+impl TonemapStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let renderpass = Arc::new(
+            FullscreenRenderPass::overwrite(Format::B8G8R8A8Srgb)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let pipeline = {
+            let vs = TonemapperShaders::vertex::Shader::load(device.clone()).expect("failed to create shader module");
+            let fs = TonemapperShaders::fragment::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        TonemapStage {
+            pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            sampler: Sampler::new(device.clone(), Filter::Nearest, Filter::Nearest, MipmapMode::Nearest,
+                                  SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                  0.0, 1.0, 0.0, 0.0).unwrap(),
+            lut_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                      SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                      0.0, 1.0, 0.0, 0.0).unwrap(),
+        }
+    }
+}
+
+impl RenderStageDefinition for TonemapStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        // the shader needs something bound even without a LUT
+        let lut = info.color_grading_lut.as_ref().unwrap_or(&info.identity_lut);
+        let descriptor_set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_sampled_image(info.attachments.scene_color.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.position.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.normal.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.albedo.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.roughness.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.metallic.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.diffuse_light.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.specular_light.clone(), self.sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.ambient_occlusion.clone(), self.sampler.clone()).unwrap()
+            .add_buffer(info.exposure.clone()).unwrap()
+            .add_sampled_image(lut.texture.clone(), self.lut_sampler.clone()).unwrap()
+            .build().unwrap());
+
+        let grading = &info.tonemapping_info;
+        let (tonemap_operator, white_point) = grading.operator.shader_params();
+        let white_balance: [[f32; 4]; 4] = Matrix4::from(white_balance_matrix(grading.temperature, grading.tint)).into();
+        // a size of zero skips the LUT
+        let lut_size = if info.color_grading_lut.is_some() { lut.size as f32 } else { 0.0 };
+
+        let cb = AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.pipeline.clone(), &DynamicState {
+                    line_width: None,
+                    viewports: Some(vec![Viewport {
+                        origin: [0.0, 0.0],
+                        dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                        depth_range: 0.0..1.0,
+                    }]),
+                    scissors: None,
+                    compare_mask: None,
+                    write_mask: None,
+                    reference: None
+                },
+                vec![self.fullscreen_vertex_buffer.clone()],
+                descriptor_set, TonemapperShaders::fragment::ty::Constants {
+                    white_balance,
+                    lut_domain_min: [lut.domain_min[0], lut.domain_min[1], lut.domain_min[2], lut_size],
+                    lut_domain_max: [lut.domain_max[0], lut.domain_max[1], lut.domain_max[2], 0.0],
+                    screen_dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                    debug_vis_mode: info.debug_visualize_setting,
+                    vignette_opacity: grading.vignette_opacity,
+                    tonemap_operator,
+                    white_point,
+                    contrast: grading.contrast.max(0.0),
+                    saturation: grading.saturation.max(0.0),
+                }).unwrap()
+            .end_render_pass().unwrap();
+
+        Some(vec![
+            (cb.build().unwrap(), info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.get_renderpass().clone())
+                .add(info.attachments.ldr_color.clone()).unwrap()
+                .build().unwrap()));
+        }
+    }
+}
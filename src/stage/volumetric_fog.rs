@@ -0,0 +1,454 @@
+//! Volumetric fog.
+//!
+//! The view frustum is divided into froxels, screen tiles split into slices spaced exponentially
+//! in depth. A compute pass injects the global height fog and the local fog volumes (see `fog`)
+//! into each froxel, lit by the shadowed sun, the other lights and the sky, and a second pass
+//! integrates them front to back along each view ray. The result is applied to `scene_color`
+//! after the skybox, so shafts of sunlight show up against the sky as well as geometry.
+//!
+//! `FogQuality::Low` skips the froxels and evaluates the height fog analytically per pixel,
+//! without shadows, local lights or fog volumes.
+//!
+//! Auto exposure runs after this, so it meters the fogged scene.
+
+use std::sync::Arc;
+use cgmath::{InnerSpace, Matrix4, Rad, SquareMatrix, Vector4};
+use vulkano::pipeline::{ComputePipeline, ComputePipelineAbstract, GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendOp, BlendFactor};
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassDesc, Subpass, RenderPassAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::buffer::BufferUsage;
+use vulkano::command_buffer::{DynamicState, AutoCommandBufferBuilder, AutoCommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::image::{Dimensions, ImageUsage, StorageImage, SwapchainImage};
+use vulkano::format::{ClearValue, Format, R16G16B16A16Sfloat};
+use vulkano::sampler::{Sampler, Filter, MipmapMode, SamplerAddressMode};
+use winit::Window;
+
+use crate::renderpass::FullscreenRenderPass;
+use crate::buffer::CpuAccessibleBufferXalloc;
+use crate::geometry::VertexPosition;
+use crate::light::LocalLightCulling;
+use crate::shader::volumetric_fog as VolumetricFogShaders;
+use crate::stage::shadow::SunCascades;
+use crate::stage::RenderStageDefinition;
+use crate::renderer::RenderInfo;
+
+
+/// View depth where the froxels start, in meters. Slices are spaced exponentially from here, so
+/// starting at the near plane would spend most of them right in front of the camera.
+pub const FROXEL_NEAR: f32 = 0.5;
+
+/// Must match the workgroup sizes in `fog_inject.comp` and `fog_integrate.comp`.
+const INJECT_WORKGROUP_SIZE: u32 = 4;
+const INTEGRATE_WORKGROUP_SIZE: u32 = 8;
+
+
+/// Volumetric fog quality presets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FogQuality {
+    /// Analytic height fog, without shadows, local lights or fog volumes.
+    Low,
+    Medium,
+    High,
+}
+impl FogQuality {
+    /// Returns `(froxel width in pixels, depth slices)` for this preset, or `None` for the
+    /// analytic fallback.
+    pub fn froxel_grid(&self) -> Option<(u32, u32)> {
+        match self {
+            FogQuality::Low    => None,
+            FogQuality::Medium => Some((16, 64)),
+            FogQuality::High   => Some((8, 128)),
+        }
+    }
+}
+
+/// Settings for volumetric fog. The global fog's density decays exponentially with height above
+/// `base_height`.
+#[derive(Debug, Clone)]
+pub struct FogSettings {
+    pub enabled: bool,
+    pub quality: FogQuality,
+    /// Extinction coefficient at `base_height`, per meter.
+    pub density: f32,
+    /// How quickly the density decays with height, per meter.
+    pub height_falloff: f32,
+    pub base_height: f32,
+    /// Fraction of extinguished light that's scattered rather than absorbed, in linear RGB.
+    pub albedo: [f32; 3],
+    /// Henyey-Greenstein asymmetry, from -1 (back scattering) through 0 (even) to 1 (forward
+    /// scattering). Higher values make light shafts brighter looking towards the light.
+    pub anisotropy: f32,
+    /// Multiplier for the sky light scattered by the fog.
+    pub ambient_intensity: f32,
+    /// Fog ends at this distance, in meters, and the froxels are spread up to it.
+    pub max_distance: f32,
+    /// Whether local lights scatter in the fog. Sun and directional lights always do.
+    pub local_lights: bool,
+}
+impl Default for FogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            quality: FogQuality::Medium,
+            density: 0.02,
+            height_falloff: 0.2,
+            base_height: 0.0,
+            albedo: [1.0, 1.0, 1.0],
+            anisotropy: 0.6,
+            ambient_intensity: 1.0,
+            max_distance: 128.0,
+            local_lights: true,
+        }
+    }
+}
+
+
+/// Froxel grids for one screen size and quality.
+struct FroxelVolumes {
+    /// Froxel counts along each axis.
+    grid_size: [u32; 3],
+    /// Froxel width in pixels.
+    tile_size: u32,
+    /// Injected in-scattering in rgb, extinction in a.
+    froxels: Arc<StorageImage<R16G16B16A16Sfloat>>,
+    /// Scattering in rgb, transmittance in a, integrated from the camera.
+    integrated: Arc<StorageImage<R16G16B16A16Sfloat>>,
+}
+
+
+/// Adds fog to `scene_color`, after the skybox.
+pub struct VolumetricFogStage {
+    inject_pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    integrate_pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    apply_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    analytic_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pub framebuffers: Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>>,
+    /// Framebuffer for blending the fog into `scene_color`.
+    pub framebuffer: Option<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    renderpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    fullscreen_vertex_buffer: Arc<CpuAccessibleBufferXalloc<[VertexPosition]>>,
+    nearest_sampler: Arc<Sampler>,
+    linear_sampler: Arc<Sampler>,
+    shadow_sampler: Arc<Sampler>,
+    volumes: Option<FroxelVolumes>,
+}
+
+
+impl VolumetricFogStage {
+    pub fn new(device: Arc<Device>) -> Self {
+        let renderpass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+            FullscreenRenderPass::blend(Format::R16G16B16A16Sfloat)
+                .build_render_pass(device.clone())
+                .unwrap()
+        );
+
+        let inject_pipeline = Arc::new({
+            let shader = VolumetricFogShaders::inject::Shader::load(device.clone()).unwrap();
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
+        });
+        let integrate_pipeline = Arc::new({
+            let shader = VolumetricFogShaders::integrate::Shader::load(device.clone()).unwrap();
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
+        });
+
+        // scene_color * transmittance + scattered light
+        let fog_blend = AttachmentBlend {
+            enabled: true,
+            color_op: BlendOp::Add,
+            color_source: BlendFactor::One,
+            color_destination: BlendFactor::SrcAlpha,
+            alpha_op: BlendOp::Add,
+            alpha_source: BlendFactor::Zero,
+            alpha_destination: BlendFactor::One,
+            mask_red: true,
+            mask_green: true,
+            mask_blue: true,
+            mask_alpha: true,
+        };
+
+        let vs = crate::shader::fullscreen::Shader::load(device.clone()).expect("failed to create shader module");
+
+        let apply_pipeline = {
+            let fs = VolumetricFogShaders::apply::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .blend_collective(fog_blend.clone())
+                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        let analytic_pipeline = {
+            let fs = VolumetricFogShaders::analytic::Shader::load(device.clone()).expect("failed to create shader module");
+
+            Arc::new(GraphicsPipeline::start()
+                .vertex_input_single_buffer::<VertexPosition>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .blend_collective(fog_blend)
+                .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap())
+        };
+
+        VolumetricFogStage {
+            inject_pipeline,
+            integrate_pipeline,
+            apply_pipeline,
+            analytic_pipeline,
+            framebuffers: None,
+            framebuffer: None,
+            renderpass,
+            fullscreen_vertex_buffer: crate::geometry::fullscreen::vertex_buffer(device.clone()),
+            nearest_sampler: Sampler::new(device.clone(), Filter::Nearest, Filter::Nearest, MipmapMode::Nearest,
+                                          SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                          0.0, 1.0, 0.0, 0.0).unwrap(),
+            linear_sampler: Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                         SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                         0.0, 1.0, 0.0, 0.0).unwrap(),
+            shadow_sampler: Sampler::compare(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                             SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge,
+                                             0.0, 1.0, 0.0, 0.0, Compare::LessOrEqual).unwrap(),
+            volumes: None,
+        }
+    }
+
+    fn dynamic_state(dimensions: [u32; 2]) -> DynamicState {
+        DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+            compare_mask: None,
+            write_mask: None,
+            reference: None
+        }
+    }
+
+    /// Recreates the froxel grids if the screen size or quality changed.
+    fn recreate_volumes_if_needed(&mut self, info: &RenderInfo, tile_size: u32, slices: u32) {
+        let grid_size = [(info.dimensions[0] + tile_size - 1) / tile_size,
+                         (info.dimensions[1] + tile_size - 1) / tile_size,
+                         slices];
+        if self.volumes.as_ref().map(|v| v.grid_size) == Some(grid_size) {
+            return;
+        }
+
+        let family = info.queues.main.as_ref().unwrap().family();
+        let dimensions = Dimensions::Dim3d { width: grid_size[0], height: grid_size[1], depth: grid_size[2] };
+        self.volumes = Some(FroxelVolumes {
+            grid_size,
+            tile_size,
+            froxels: StorageImage::with_usage(info.device.clone(), dimensions, R16G16B16A16Sfloat,
+                                              ImageUsage { storage: true, ..ImageUsage::none() },
+                                              Some(family)).unwrap(),
+            integrated: StorageImage::with_usage(info.device.clone(), dimensions, R16G16B16A16Sfloat,
+                                                 ImageUsage { storage: true, sampled: true, ..ImageUsage::none() },
+                                                 Some(family)).unwrap(),
+        });
+    }
+
+    /// Fills and integrates the froxel grids, then applies them. Skipped until light culling has
+    /// uploaded this frame's lights, which light the froxels.
+    fn froxel_command_buffer(&mut self, info: &RenderInfo, tile_size: u32, slices: u32) -> Option<AutoCommandBuffer> {
+        self.recreate_volumes_if_needed(info, tile_size, slices);
+        let volumes = self.volumes.as_ref().unwrap();
+        let settings = &info.settings.fog;
+        let far = settings.max_distance.max(FROXEL_NEAR * 2.0);
+
+        let cascades = SunCascades::compute(info);
+        let shadow_settings = &info.settings.shadows;
+        let sun_color = info.sun.color * info.sun.intensity;
+        let sun_buffer = CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(),
+            VolumetricFogShaders::inject::ty::SunData {
+                cascade_view_proj: [cascades.view_proj[0].into(), cascades.view_proj[1].into(),
+                                    cascades.view_proj[2].into(), cascades.view_proj[3].into()],
+                cascade_splits: cascades.splits,
+                cascade_texel_sizes: cascades.texel_sizes,
+                direction: [info.sun.direction.x, info.sun.direction.y, info.sun.direction.z, 0.0],
+                color: [sun_color.x, sun_color.y, sun_color.z, 1.0],
+                cascade_count: cascades.count as u32,
+                shadows_enabled: shadow_settings.enabled as u32,
+                depth_bias: shadow_settings.depth_bias,
+                normal_bias: shadow_settings.normal_bias,
+                pcf_radius: shadow_settings.pcf_radius as i32,
+            }).expect("failed to create buffer");
+
+        let inv_view = info.view_mat.invert().unwrap_or(Matrix4::identity());
+        let forward = (inv_view * Vector4::new(0.0, 0.0, -1.0, 0.0)).truncate().normalize();
+        let inv_view_proj = (info.unjittered_proj_mat * info.view_mat).invert().unwrap_or(Matrix4::identity());
+        let view_pos = info.camera_transform.position;
+        let (volume_position, volume_extents, volume_albedo) = info.fog_volumes.gpu_data();
+        let clusters = info.light_clusters.lock().clone()?;
+        let fog_buffer = CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(),
+            VolumetricFogShaders::inject::ty::FogData {
+                inv_view_proj: inv_view_proj.into(),
+                view_pos: [view_pos.x, view_pos.y, view_pos.z, 1.0],
+                view_forward: [forward.x, forward.y, forward.z, 0.0],
+                albedo: [settings.albedo[0], settings.albedo[1], settings.albedo[2], 0.0],
+                volume_position,
+                volume_extents,
+                volume_albedo,
+                grid_size: [volumes.grid_size[0], volumes.grid_size[1], volumes.grid_size[2], volumes.tile_size],
+                screen_dimensions: [info.dimensions[0] as f32, info.dimensions[1] as f32],
+                density: settings.density,
+                height_falloff: settings.height_falloff,
+                base_height: settings.base_height,
+                anisotropy: settings.anisotropy,
+                near: FROXEL_NEAR,
+                far,
+                ambient_intensity: settings.ambient_intensity,
+                volume_count: info.fog_volumes.len() as u32,
+                light_count: clusters.light_count,
+                directional_light_count: clusters.directional_light_count,
+                clustered: (info.settings.light_culling == LocalLightCulling::Clustered) as u32,
+                local_lights_enabled: settings.local_lights as u32,
+            }).expect("failed to create buffer");
+
+        let inject_set = Arc::new(PersistentDescriptorSet::start(self.inject_pipeline.clone(), 0)
+            .add_sampled_image(info.shadow_maps.sun.clone(), self.shadow_sampler.clone()).unwrap()
+            .add_buffer(sun_buffer).unwrap()
+            .add_sampled_image(info.environment.irradiance.clone(), self.linear_sampler.clone()).unwrap()
+            .add_buffer(fog_buffer).unwrap()
+            .add_buffer(clusters.lights.clone()).unwrap()
+            .add_buffer(clusters.grid.clone()).unwrap()
+            .add_buffer(clusters.indices.clone()).unwrap()
+            .add_buffer(clusters.params.clone()).unwrap()
+            .add_image(volumes.froxels.clone()).unwrap()
+            .build().unwrap());
+        let integrate_set = Arc::new(PersistentDescriptorSet::start(self.integrate_pipeline.clone(), 0)
+            .add_image(volumes.froxels.clone()).unwrap()
+            .add_image(volumes.integrated.clone()).unwrap()
+            .build().unwrap());
+        let apply_set = Arc::new(PersistentDescriptorSet::start(self.apply_pipeline.clone(), 0)
+            .add_sampled_image(volumes.integrated.clone(), self.linear_sampler.clone()).unwrap()
+            .add_sampled_image(info.attachments.main_depth.clone(), self.nearest_sampler.clone()).unwrap()
+            .build().unwrap());
+
+        let tan_y = (Rad::from(info.fov).0 * 0.5).tan();
+        let tan_x = tan_y * info.dimensions[0] as f32 / info.dimensions[1] as f32;
+        let grid_pixels = [(volumes.grid_size[0] * tile_size) as f32, (volumes.grid_size[1] * tile_size) as f32];
+        let inject_workgroups = [(volumes.grid_size[0] + INJECT_WORKGROUP_SIZE - 1) / INJECT_WORKGROUP_SIZE,
+                                 (volumes.grid_size[1] + INJECT_WORKGROUP_SIZE - 1) / INJECT_WORKGROUP_SIZE,
+                                 (volumes.grid_size[2] + INJECT_WORKGROUP_SIZE - 1) / INJECT_WORKGROUP_SIZE];
+        let integrate_workgroups = [(volumes.grid_size[0] + INTEGRATE_WORKGROUP_SIZE - 1) / INTEGRATE_WORKGROUP_SIZE,
+                                    (volumes.grid_size[1] + INTEGRATE_WORKGROUP_SIZE - 1) / INTEGRATE_WORKGROUP_SIZE,
+                                    1];
+
+        Some(AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .dispatch(inject_workgroups, self.inject_pipeline.clone(), inject_set, ()).unwrap()
+            .dispatch(integrate_workgroups, self.integrate_pipeline.clone(), integrate_set,
+                VolumetricFogShaders::integrate::ty::Constants {
+                    tan_half_fov: [tan_x, tan_y],
+                    froxel_to_uv: [tile_size as f32 / info.dimensions[0] as f32, tile_size as f32 / info.dimensions[1] as f32],
+                    near: FROXEL_NEAR,
+                    far,
+                }).unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.apply_pipeline.clone(), &Self::dynamic_state(info.dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                apply_set, VolumetricFogShaders::apply::ty::Constants {
+                    froxel_uv_scale: [1.0 / grid_pixels[0], 1.0 / grid_pixels[1]],
+                    near_plane: info.near_plane,
+                    far_plane: info.far_plane,
+                    fog_near: FROXEL_NEAR,
+                    fog_far: far,
+                    depth_mode: info.depth_mode.shader_id(),
+                }).unwrap()
+            .end_render_pass().unwrap()
+            .build().unwrap())
+    }
+
+    /// Applies the analytic height fog.
+    fn analytic_command_buffer(&mut self, info: &RenderInfo) -> AutoCommandBuffer {
+        // the froxels aren't needed until the quality goes back up
+        self.volumes = None;
+        let settings = &info.settings.fog;
+
+        let inv_view = info.view_mat.invert().unwrap_or(Matrix4::identity());
+        let forward = (inv_view * Vector4::new(0.0, 0.0, -1.0, 0.0)).truncate().normalize();
+        let inv_view_proj = (info.proj_mat * info.view_mat).invert().unwrap_or(Matrix4::identity());
+        let view_pos = info.camera_transform.position;
+        let sun_color = info.sun.color * info.sun.intensity;
+        let fog_buffer = CpuAccessibleBufferXalloc::from_data(info.device.clone(), BufferUsage::uniform_buffer(),
+            VolumetricFogShaders::analytic::ty::FogData {
+                inv_view_proj: inv_view_proj.into(),
+                view_pos: [view_pos.x, view_pos.y, view_pos.z, 1.0],
+                view_forward: [forward.x, forward.y, forward.z, 0.0],
+                albedo: [settings.albedo[0], settings.albedo[1], settings.albedo[2], 0.0],
+                sun_direction: [info.sun.direction.x, info.sun.direction.y, info.sun.direction.z, 0.0],
+                sun_color: [sun_color.x, sun_color.y, sun_color.z, 1.0],
+                density: settings.density,
+                height_falloff: settings.height_falloff,
+                base_height: settings.base_height,
+                anisotropy: settings.anisotropy,
+                near_plane: info.near_plane,
+                far_plane: info.far_plane,
+                max_distance: settings.max_distance,
+                ambient_intensity: settings.ambient_intensity,
+                depth_mode: info.depth_mode.shader_id(),
+            }).expect("failed to create buffer");
+
+        let set = Arc::new(PersistentDescriptorSet::start(self.analytic_pipeline.clone(), 0)
+            .add_sampled_image(info.attachments.main_depth.clone(), self.nearest_sampler.clone()).unwrap()
+            .add_sampled_image(info.environment.irradiance.clone(), self.linear_sampler.clone()).unwrap()
+            .add_buffer(fog_buffer).unwrap()
+            .build().unwrap());
+
+        AutoCommandBufferBuilder::primary_one_time_submit(info.device.clone(), info.queues.main.as_ref().unwrap().family())
+            .unwrap()
+            .begin_render_pass(self.framebuffer.as_ref().unwrap().clone(), false, vec![ClearValue::None]).unwrap()
+            .draw(self.analytic_pipeline.clone(), &Self::dynamic_state(info.dimensions),
+                vec![self.fullscreen_vertex_buffer.clone()],
+                set, ()).unwrap()
+            .end_render_pass().unwrap()
+            .build().unwrap()
+    }
+}
+
+impl RenderStageDefinition for VolumetricFogStage {
+    fn get_pipeline(&self) -> &Arc<dyn GraphicsPipelineAbstract + Send + Sync> { &self.apply_pipeline }
+    fn get_renderpass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> { &self.renderpass }
+    fn get_framebuffers(&self) -> &Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &self.framebuffers }
+    fn get_framebuffers_mut(&mut self) -> &mut Option<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>> { &mut self.framebuffers }
+
+    fn build_command_buffers(&mut self, info: &RenderInfo) -> Option<Vec<(AutoCommandBuffer, Arc<Queue>)>> {
+        let settings = &info.settings.fog;
+        if !settings.enabled {
+            self.volumes = None;
+            return None;
+        }
+
+        let cb = match settings.quality.froxel_grid() {
+            Some((tile_size, slices)) => self.froxel_command_buffer(info, tile_size, slices)?,
+            None => self.analytic_command_buffer(info),
+        };
+
+        Some(vec![
+            (cb, info.queues.main.as_ref().unwrap().clone()),
+        ])
+    }
+
+    fn recreate_framebuffers_if_none(&mut self, _images: &Vec<Arc<SwapchainImage<Window>>>, info: &RenderInfo) {
+        if self.framebuffer.is_none() {
+            self.framebuffer = Some(Arc::new(Framebuffer::start(self.renderpass.clone())
+                .add(info.attachments.scene_color.clone()).unwrap()
+                .build().unwrap()));
+        }
+    }
+}
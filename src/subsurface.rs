@@ -0,0 +1,109 @@
+//! Subsurface scattering profiles.
+//!
+//! Materials with a profile write its id into the w channel of the G-buffer normal, with 0
+//! meaning no subsurface scattering. After lighting, `stage::subsurface` blurs `diffuse_light`
+//! over those pixels with a separable screen-space kernel shaped by the profile, before diffuse
+//! and specular are combined into `scene_color`. Specular stays sharp, since it's reflected at
+//! the surface.
+
+use crate::shader::subsurface as SubsurfaceShaders;
+
+
+/// Profiles that can exist at once. Must match `MAX_SUBSURFACE_PROFILES` in
+/// `subsurface_blur.frag`.
+pub const MAX_SUBSURFACE_PROFILES: usize = 16;
+
+
+/// Handle for a profile added to the renderer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubsurfaceProfileId(pub(crate) u32);
+
+impl SubsurfaceProfileId {
+    /// Value written into the G-buffer, where 0 is reserved for no scattering.
+    pub(crate) fn gbuffer_value(&self) -> u32 { self.0 + 1 }
+}
+
+
+/// How far light travels under a surface before leaving it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubsurfaceProfile {
+    /// Distance in meters over which light is scattered, for the color channel that travels
+    /// the furthest.
+    pub scatter_radius: f32,
+    /// Scattering distance of each channel relative to `scatter_radius`, in linear RGB. Light
+    /// that travels further under the surface tints the blurred edges of shadows.
+    pub scatter_color: [f32; 3],
+}
+
+impl SubsurfaceProfile {
+    /// Human skin, where red scatters several times further than blue.
+    pub fn skin() -> Self {
+        Self { scatter_radius: 0.004, scatter_color: [1.0, 0.37, 0.18] }
+    }
+
+    /// Candle wax, which scatters far and fairly evenly.
+    pub fn wax() -> Self {
+        Self { scatter_radius: 0.01, scatter_color: [1.0, 0.8, 0.5] }
+    }
+
+    /// Leaves and other thin vegetation, scattering mostly green.
+    pub fn foliage() -> Self {
+        Self { scatter_radius: 0.006, scatter_color: [0.6, 1.0, 0.3] }
+    }
+
+    /// Per channel scatter distance in meters.
+    fn scatter_distance(&self) -> [f32; 4] {
+        let radius = self.scatter_radius.max(0.0);
+        let c = self.scatter_color;
+        [radius * c[0].max(0.0), radius * c[1].max(0.0), radius * c[2].max(0.0), 0.0]
+    }
+}
+
+
+/// Every profile added to the renderer. A profile's slot is its id.
+pub struct SubsurfaceProfiles {
+    profiles: Vec<SubsurfaceProfile>,
+}
+
+impl SubsurfaceProfiles {
+    pub fn new() -> Self {
+        Self { profiles: Vec::new() }
+    }
+
+    /// Returns `None` if there are already `MAX_SUBSURFACE_PROFILES` profiles.
+    pub fn add(&mut self, profile: SubsurfaceProfile) -> Option<SubsurfaceProfileId> {
+        if self.profiles.len() >= MAX_SUBSURFACE_PROFILES {
+            return None;
+        }
+        self.profiles.push(profile);
+        Some(SubsurfaceProfileId(self.profiles.len() as u32 - 1))
+    }
+
+    /// Replaces a profile. Returns false if there's no profile with that id.
+    pub fn update(&mut self, id: SubsurfaceProfileId, profile: SubsurfaceProfile) -> bool {
+        match self.profiles.get_mut(id.0 as usize) {
+            Some(existing) => {
+                *existing = profile;
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn get(&self, id: SubsurfaceProfileId) -> Option<&SubsurfaceProfile> {
+        self.profiles.get(id.0 as usize)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.profiles.is_empty()
+    }
+
+    /// Uniform buffer contents for `subsurface_blur.frag`.
+    pub(crate) fn gpu_data(&self) -> SubsurfaceShaders::blur::ty::SubsurfaceProfiles {
+        let mut scatter_distance = [[0.0; 4]; MAX_SUBSURFACE_PROFILES];
+        for (slot, profile) in scatter_distance.iter_mut().zip(self.profiles.iter()) {
+            *slot = profile.scatter_distance();
+        }
+        SubsurfaceShaders::blur::ty::SubsurfaceProfiles { scatter_distance }
+    }
+}
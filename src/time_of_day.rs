@@ -0,0 +1,67 @@
+//! Day and night cycle.
+//!
+//! `TimeOfDay` advances a `SolarTime` and hands it to the sky, which places the sun, moon and
+//! stars from it. It also steers exposure through dawn and dusk, since auto exposure alone would
+//! brighten a moonlit night until it looks like an overcast day.
+
+use crate::compute::exposure_from_ev100;
+use crate::renderer::TonemappingInfo;
+use crate::sky::{SkySettings, SolarTime, SunPosition};
+
+
+/// Drives the sky and exposure from an advancing clock.
+#[derive(Debug, Clone)]
+pub struct TimeOfDay {
+    pub time: SolarTime,
+    /// In-game hours that pass per real second. Zero stops the clock.
+    pub speed: f32,
+    /// Sets the exposure and its auto exposure limits from how much daylight there is. A
+    /// `TonemappingInfo::physical_camera` takes precedence over the exposure set here.
+    pub drive_exposure: bool,
+    /// Exposure target in EV100 in full daylight.
+    pub day_ev100: f32,
+    /// Exposure target in EV100 at night. A real moonlit scene is around -3, games usually want
+    /// something brighter.
+    pub night_ev100: f32,
+    /// How far auto exposure may move away from the target, in stops either way.
+    pub exposure_range: f32,
+}
+
+impl TimeOfDay {
+    pub fn new(time: SolarTime) -> Self {
+        Self {
+            time,
+            speed: 0.0,
+            drive_exposure: true,
+            day_ev100: 14.0,
+            night_ev100: 0.0,
+            exposure_range: 2.0,
+        }
+    }
+
+    /// Moves the clock forward by `seconds` of real time, scaled by `speed`.
+    pub fn advance(&mut self, seconds: f32) {
+        self.time.add_hours(seconds * self.speed);
+    }
+
+    /// Exposure target for the sky's current daylight, in EV100. Blends in log space, so the
+    /// change is even across dawn and dusk.
+    pub fn target_ev100(&self, sky: &SkySettings) -> f32 {
+        let daylight = sky.daylight();
+        self.night_ev100 + (self.day_ev100 - self.night_ev100) * daylight
+    }
+
+    /// Points the sky at the current time, and sets the exposure targets if `drive_exposure`
+    /// is on.
+    pub fn apply(&self, sky: &mut SkySettings, tonemapping: &mut TonemappingInfo) {
+        sky.sun = SunPosition::Time(self.time.clone());
+
+        if self.drive_exposure {
+            let exposure = exposure_from_ev100(self.target_ev100(sky));
+            let range = 2f32.powf(self.exposure_range.max(0.0));
+            tonemapping.exposure = exposure;
+            tonemapping.min_exposure = exposure / range;
+            tonemapping.max_exposure = exposure * range;
+        }
+    }
+}
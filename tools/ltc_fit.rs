@@ -0,0 +1,369 @@
+//! Fits the linearly transformed cosine tables used by rectangular area lights.
+//!
+//! Follows the fitting procedure from Heitz et al. 2016, "Real-Time Polygonal-Light Shading with
+//! Linearly Transformed Cosines": for each roughness and view angle, the isotropic GGX BRDF
+//! (times the cosine) is approximated by a clamped cosine distribution transformed by a matrix
+//! M, found with Nelder-Mead.
+//!
+//! Standalone, no dependencies. Regenerate the tables in `assets/ltc` with:
+//!
+//!     rustc -O tools/ltc_fit.rs -o ltc_fit && ./ltc_fit assets/ltc
+//!
+//! Output is little-endian f32, `LUT_SIZE` x `LUT_SIZE`, roughness along x and
+//! sqrt(1 - cos(theta_v)) along y:
+//! - `ltc_matrix.bin`: 4 floats per texel, the inverse of M normalized so that its middle
+//!   element is 1, as (m00, m20, m02, m22) in row-column order.
+//! - `ltc_amplitude.bin`: 2 floats per texel, the BRDF's directional albedo and the part of it
+//!   weighted by the Schlick Fresnel term, so that the Fresnel-scaled albedo is
+//!   `F0 * (x - y) + y`.
+
+use std::f64::consts::PI;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const LUT_SIZE: usize = 64;
+const SAMPLE_COUNT: usize = 32;
+const MIN_ALPHA: f64 = 0.00001;
+
+
+#[derive(Clone, Copy, Debug)]
+struct Vec3 { x: f64, y: f64, z: f64 }
+
+impl Vec3 {
+    fn new(x: f64, y: f64, z: f64) -> Self { Self { x, y, z } }
+    fn dot(self, o: Vec3) -> f64 { self.x * o.x + self.y * o.y + self.z * o.z }
+    fn length(self) -> f64 { self.dot(self).sqrt() }
+    fn normalize(self) -> Vec3 { self * (1.0 / self.length()) }
+    fn add(self, o: Vec3) -> Vec3 { Vec3::new(self.x + o.x, self.y + o.y, self.z + o.z) }
+}
+
+impl std::ops::Mul<f64> for Vec3 {
+    type Output = Vec3;
+    fn mul(self, s: f64) -> Vec3 { Vec3::new(self.x * s, self.y * s, self.z * s) }
+}
+
+
+/// Row-major 3x3 matrix.
+#[derive(Clone, Copy, Debug)]
+struct Mat3 { m: [[f64; 3]; 3] }
+
+impl Mat3 {
+    fn from_cols(c0: Vec3, c1: Vec3, c2: Vec3) -> Self {
+        Self { m: [[c0.x, c1.x, c2.x], [c0.y, c1.y, c2.y], [c0.z, c1.z, c2.z]] }
+    }
+    fn mul_vec(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+                  m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+                  m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z)
+    }
+    fn mul(&self, o: &Mat3) -> Mat3 {
+        let mut r = [[0.0; 3]; 3];
+        for i in 0..3 { for j in 0..3 { for k in 0..3 { r[i][j] += self.m[i][k] * o.m[k][j]; } } }
+        Mat3 { m: r }
+    }
+    fn determinant(&self) -> f64 {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+    fn inverse(&self) -> Mat3 {
+        let m = &self.m;
+        let d = 1.0 / self.determinant();
+        Mat3 { m: [
+            [(m[1][1] * m[2][2] - m[1][2] * m[2][1]) * d, (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * d, (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * d],
+            [(m[1][2] * m[2][0] - m[1][0] * m[2][2]) * d, (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * d, (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * d],
+            [(m[1][0] * m[2][1] - m[1][1] * m[2][0]) * d, (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * d, (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * d],
+        ] }
+    }
+}
+
+
+/// GGX with Smith height-correlated masking-shadowing, times the cosine. Fresnel is 1.
+fn ggx_lambda(alpha: f64, cos_theta: f64) -> f64 {
+    if cos_theta >= 1.0 { return 0.0; }
+    let a = 1.0 / alpha / cos_theta.acos().tan();
+    0.5 * (-1.0 + (1.0 + 1.0 / (a * a)).sqrt())
+}
+
+/// Returns the BRDF times cos(theta_l) and the pdf of `ggx_sample`.
+fn ggx_eval(v: Vec3, l: Vec3, alpha: f64) -> (f64, f64) {
+    if v.z <= 0.0 { return (0.0, 0.0); }
+    let lambda_v = ggx_lambda(alpha, v.z);
+    let g2 = if l.z <= 0.0 { 0.0 } else { 1.0 / (1.0 + lambda_v + ggx_lambda(alpha, l.z)) };
+
+    let h = v.add(l).normalize();
+    let slope_x = h.x / h.z;
+    let slope_y = h.y / h.z;
+    let mut d = 1.0 / (1.0 + (slope_x * slope_x + slope_y * slope_y) / alpha / alpha);
+    d = d * d;
+    d /= PI * alpha * alpha * h.z.powi(4);
+
+    let pdf = (d * h.z / 4.0 / v.dot(h)).abs();
+    (d * g2 / 4.0 / v.z, pdf)
+}
+
+fn ggx_sample(v: Vec3, alpha: f64, u1: f64, u2: f64) -> Vec3 {
+    let phi = 2.0 * PI * u1;
+    let r = alpha * (u2 / (1.0 - u2)).sqrt();
+    let n = Vec3::new(r * phi.cos(), r * phi.sin(), 1.0).normalize();
+    (v * -1.0).add(n * (2.0 * n.dot(v)))
+}
+
+
+#[derive(Clone, Copy, Debug)]
+struct Ltc {
+    m11: f64,
+    m22: f64,
+    m13: f64,
+    x: Vec3,
+    y: Vec3,
+    z: Vec3,
+    amplitude: f64,
+    m: Mat3,
+    inv_m: Mat3,
+    det_m: f64,
+}
+
+impl Ltc {
+    fn new() -> Self {
+        let mut ltc = Ltc {
+            m11: 1.0, m22: 1.0, m13: 0.0,
+            x: Vec3::new(1.0, 0.0, 0.0), y: Vec3::new(0.0, 1.0, 0.0), z: Vec3::new(0.0, 0.0, 1.0),
+            amplitude: 1.0,
+            m: Mat3::from_cols(Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0)),
+            inv_m: Mat3::from_cols(Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0)),
+            det_m: 1.0,
+        };
+        ltc.update();
+        ltc
+    }
+
+    fn update(&mut self) {
+        let basis = Mat3::from_cols(self.x, self.y, self.z);
+        let shape = Mat3::from_cols(Vec3::new(self.m11, 0.0, 0.0), Vec3::new(0.0, self.m22, 0.0), Vec3::new(self.m13, 0.0, 1.0));
+        self.m = basis.mul(&shape);
+        self.inv_m = self.m.inverse();
+        self.det_m = self.m.determinant().abs();
+    }
+
+    fn eval(&self, l: Vec3) -> f64 {
+        let original = self.inv_m.mul_vec(l).normalize();
+        let transformed = self.m.mul_vec(original);
+        let len = transformed.length();
+        let jacobian = self.det_m / (len * len * len);
+        let d = 1.0 / PI * original.z.max(0.0);
+        self.amplitude * d / jacobian
+    }
+
+    fn sample(&self, u1: f64, u2: f64) -> Vec3 {
+        let theta = u1.sqrt().acos();
+        let phi = 2.0 * PI * u2;
+        self.m.mul_vec(Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos())).normalize()
+    }
+}
+
+
+/// Directional albedo, its Fresnel-weighted part, and the average lobe direction.
+fn average_terms(v: Vec3, alpha: f64) -> (f64, f64, Vec3) {
+    let mut norm = 0.0;
+    let mut fresnel = 0.0;
+    let mut direction = Vec3::new(0.0, 0.0, 0.0);
+    for j in 0..SAMPLE_COUNT {
+        for i in 0..SAMPLE_COUNT {
+            let u1 = (i as f64 + 0.5) / SAMPLE_COUNT as f64;
+            let u2 = (j as f64 + 0.5) / SAMPLE_COUNT as f64;
+            let l = ggx_sample(v, alpha, u1, u2);
+            let (value, pdf) = ggx_eval(v, l, alpha);
+            if pdf > 0.0 {
+                let weight = value / pdf;
+                let h = v.add(l).normalize();
+                norm += weight;
+                fresnel += weight * (1.0 - v.dot(h).max(0.0)).powi(5);
+                direction = direction.add(l * weight);
+            }
+        }
+    }
+    let count = (SAMPLE_COUNT * SAMPLE_COUNT) as f64;
+    direction.y = 0.0;
+    (norm / count, fresnel / count, direction.normalize())
+}
+
+fn fit_error(ltc: &Ltc, v: Vec3, alpha: f64) -> f64 {
+    let mut error = 0.0;
+    for j in 0..SAMPLE_COUNT {
+        for i in 0..SAMPLE_COUNT {
+            let u1 = (i as f64 + 0.5) / SAMPLE_COUNT as f64;
+            let u2 = (j as f64 + 0.5) / SAMPLE_COUNT as f64;
+
+            // importance sample the LTC, then the BRDF
+            for l in [ltc.sample(u1, u2), ggx_sample(v, alpha, u1, u2)].iter() {
+                let (eval_brdf, pdf_brdf) = ggx_eval(v, *l, alpha);
+                let eval_ltc = ltc.eval(*l);
+                let pdf_ltc = eval_ltc / ltc.amplitude;
+                let e = (eval_brdf - eval_ltc).abs().powi(3);
+                if pdf_ltc + pdf_brdf > 0.0 {
+                    error += e / (pdf_ltc + pdf_brdf);
+                }
+            }
+        }
+    }
+    error / (SAMPLE_COUNT * SAMPLE_COUNT) as f64
+}
+
+fn nelder_mead<F: Fn(&[f64; 3]) -> f64>(start: [f64; 3], delta: f64, tolerance: f64, max_iterations: usize, f: F) -> [f64; 3] {
+    let mut simplex = [start; 4];
+    for i in 0..3 { simplex[i + 1][i] += delta; }
+    let mut values = [0.0; 4];
+    for i in 0..4 { values[i] = f(&simplex[i]); }
+
+    for _ in 0..max_iterations {
+        let mut order = [0, 1, 2, 3];
+        order.sort_by(|a, b| values[*a].partial_cmp(&values[*b]).unwrap_or(std::cmp::Ordering::Equal));
+        let (best, second_worst, worst) = (order[0], order[2], order[3]);
+        if (values[worst] - values[best]).abs() < tolerance { break; }
+
+        let mut centroid = [0.0; 3];
+        for &i in order[..3].iter() {
+            for k in 0..3 { centroid[k] += simplex[i][k] / 3.0; }
+        }
+        let along = |t: f64| {
+            let mut p = [0.0; 3];
+            for k in 0..3 { p[k] = centroid[k] + t * (simplex[worst][k] - centroid[k]); }
+            p
+        };
+
+        let reflected = along(-1.0);
+        let reflected_value = f(&reflected);
+        if reflected_value < values[best] {
+            let expanded = along(-2.0);
+            let expanded_value = f(&expanded);
+            if expanded_value < reflected_value {
+                simplex[worst] = expanded;
+                values[worst] = expanded_value;
+            }
+            else {
+                simplex[worst] = reflected;
+                values[worst] = reflected_value;
+            }
+            continue;
+        }
+        if reflected_value < values[second_worst] {
+            simplex[worst] = reflected;
+            values[worst] = reflected_value;
+            continue;
+        }
+
+        let contracted = if reflected_value < values[worst] { along(-0.5) } else { along(0.5) };
+        let contracted_value = f(&contracted);
+        if contracted_value < values[worst].min(reflected_value) {
+            simplex[worst] = contracted;
+            values[worst] = contracted_value;
+            continue;
+        }
+
+        // shrink towards the best point
+        for &i in order[1..].iter() {
+            for k in 0..3 { simplex[i][k] = simplex[best][k] + 0.5 * (simplex[i][k] - simplex[best][k]); }
+            values[i] = f(&simplex[i]);
+        }
+    }
+
+    let mut best = 0;
+    for i in 1..4 { if values[i] < values[best] { best = i; } }
+    simplex[best]
+}
+
+fn apply_params(ltc: &mut Ltc, params: &[f64; 3], isotropic: bool) {
+    let m11 = params[0].max(1e-7);
+    let m22 = params[1].max(1e-7);
+    if isotropic {
+        ltc.m11 = m11;
+        ltc.m22 = m11;
+        ltc.m13 = 0.0;
+    }
+    else {
+        ltc.m11 = m11;
+        ltc.m22 = m22;
+        ltc.m13 = params[2];
+    }
+    ltc.update();
+}
+
+fn fit(ltc: &mut Ltc, v: Vec3, alpha: f64, isotropic: bool) {
+    let start = [ltc.m11, ltc.m22, ltc.m13];
+    let result = nelder_mead(start, 0.05, 1e-5, 100, |params| {
+        let mut candidate = *ltc;
+        apply_params(&mut candidate, params, isotropic);
+        fit_error(&candidate, v, alpha)
+    });
+    apply_params(ltc, &result, isotropic);
+}
+
+
+fn main() {
+    let out_dir = std::env::args().nth(1).unwrap_or_else(|| ".".to_string());
+    let mut table = vec![Ltc::new(); LUT_SIZE * LUT_SIZE];
+    let mut amplitudes = vec![(0.0, 0.0); LUT_SIZE * LUT_SIZE];
+
+    // from smooth to rough would need a different start, so go from rough to smooth and start
+    // from the previous roughness
+    for a in (0..LUT_SIZE).rev() {
+        for t in 0..LUT_SIZE {
+            let x = t as f64 / (LUT_SIZE - 1) as f64;
+            let theta = (1.0 - x * x).acos().min(1.57);
+            let roughness = a as f64 / (LUT_SIZE - 1) as f64;
+            let alpha = (roughness * roughness).max(MIN_ALPHA);
+            let v = Vec3::new(theta.sin(), 0.0, theta.cos());
+
+            let (norm, fresnel, average_direction) = average_terms(v, alpha);
+
+            let mut ltc = if a == LUT_SIZE - 1 { Ltc::new() } else { table[t * LUT_SIZE + a + 1] };
+            ltc.amplitude = norm;
+            if t == 0 {
+                ltc.x = Vec3::new(1.0, 0.0, 0.0);
+                ltc.y = Vec3::new(0.0, 1.0, 0.0);
+                ltc.z = Vec3::new(0.0, 0.0, 1.0);
+                if a == LUT_SIZE - 1 {
+                    ltc.m11 = 1.0;
+                    ltc.m22 = 1.0;
+                    ltc.m13 = 0.0;
+                }
+                ltc.update();
+                fit(&mut ltc, v, alpha, true);
+            }
+            else {
+                let l = average_direction;
+                ltc.x = Vec3::new(l.z, 0.0, -l.x);
+                ltc.y = Vec3::new(0.0, 1.0, 0.0);
+                ltc.z = l;
+                ltc.update();
+                fit(&mut ltc, v, alpha, false);
+            }
+
+            table[t * LUT_SIZE + a] = ltc;
+            amplitudes[t * LUT_SIZE + a] = (norm, fresnel);
+        }
+        eprintln!("roughness {}/{}", LUT_SIZE - a, LUT_SIZE);
+    }
+
+    let mut matrix_bytes = Vec::new();
+    let mut amplitude_bytes = Vec::new();
+    for (ltc, (norm, fresnel)) in table.iter().zip(amplitudes.iter()) {
+        let inv = ltc.inv_m.m;
+        let scale = 1.0 / inv[1][1];
+        for value in [inv[0][0] * scale, inv[2][0] * scale, inv[0][2] * scale, inv[2][2] * scale].iter() {
+            matrix_bytes.extend_from_slice(&(*value as f32).to_le_bytes());
+        }
+        for value in [*norm, *fresnel].iter() {
+            amplitude_bytes.extend_from_slice(&(*value as f32).to_le_bytes());
+        }
+    }
+
+    let out_dir = Path::new(&out_dir);
+    fs::create_dir_all(out_dir).unwrap();
+    fs::File::create(out_dir.join("ltc_matrix.bin")).unwrap().write_all(&matrix_bytes).unwrap();
+    fs::File::create(out_dir.join("ltc_amplitude.bin")).unwrap().write_all(&amplitude_bytes).unwrap();
+}